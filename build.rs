@@ -0,0 +1,101 @@
+//! Embeds the web UI in `src/html` into the firmware as a table of assets
+//! (see `src/assets.rs`).  Files are gzipped unless they already are, in
+//! which case they are served under their name without `.gz`, or
+//! compressing doesn't make them smaller.
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+const ASSET_DIR: &str = "src/html";
+
+fn mime_type(path: &str) -> &'static str {
+    match path.rsplit('.').next().unwrap_or("") {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "ico" => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
+
+/// FNV-1a of the served bytes.  Changes whenever the file does, which is
+/// all an ETag needs.
+fn etag(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn collect(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed={ASSET_DIR}");
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+    let mut files = Vec::new();
+    collect(Path::new(ASSET_DIR), &mut files);
+    files.sort();
+
+    let mut table = String::from("pub static ASSETS: &[Asset] = &[\n");
+    for (i, file) in files.iter().enumerate() {
+        println!("cargo:rerun-if-changed={}", file.display());
+        let name = file
+            .strip_prefix(ASSET_DIR)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .replace('\\', "/");
+        let raw = fs::read(file).unwrap();
+        let (name, data, gzipped) = match name.strip_suffix(".gz") {
+            Some(name) => (name.to_string(), raw, true),
+            None => {
+                let compressed = gzip(&raw);
+                if compressed.len() < raw.len() {
+                    (name, compressed, true)
+                } else {
+                    (name, raw, false)
+                }
+            }
+        };
+
+        let out = out_dir.join(format!("asset{i}"));
+        fs::write(&out, &data).unwrap();
+        writeln!(
+            table,
+            "    Asset {{ path: {:?}, mime: {:?}, etag: {:?}, gzip: {gzipped}, data: include_bytes!({:?}) }},",
+            format!("/{name}"),
+            mime_type(&name),
+            format!("\"{:016x}\"", etag(&data)),
+            out.display().to_string(),
+        )
+        .unwrap();
+    }
+    table.push_str("];\n");
+    fs::write(out_dir.join("assets.rs"), table).unwrap();
+}
@@ -0,0 +1,337 @@
+use core::fmt::Write;
+
+use embassy_time::Instant;
+use esp_println::println;
+
+use crate::artnet;
+use crate::effects::EffectKind;
+use crate::http::{Connection, Method, Request, Status, JSON};
+use crate::json::Json;
+use crate::output::{Output, Scene, Source};
+use crate::preset;
+use crate::sacn;
+use crate::web::{parse_param, query_param, Context, FmtBuffer};
+use crate::{Error, Result};
+
+/// Bumped when a resource changes incompatibly.  Each version is served
+/// under its own `/api/v<n>` prefix.
+pub const VERSION: u32 = 1;
+
+const MAX_RESPONSE_LEN: usize = 2048;
+/// Longest form encoded body we take for updates.
+const MAX_FORM_LEN: usize = 256;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Resource {
+    Index,
+    Output,
+    Network,
+    Pd,
+    Charger,
+    Artnet,
+}
+
+impl Resource {
+    const ALL: [Resource; 5] = [
+        Resource::Output,
+        Resource::Network,
+        Resource::Pd,
+        Resource::Charger,
+        Resource::Artnet,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Resource::Index => "",
+            Resource::Output => "output",
+            Resource::Network => "network",
+            Resource::Pd => "pd",
+            Resource::Charger => "charger",
+            Resource::Artnet => "artnet",
+        }
+    }
+}
+
+fn write_index<W: Write>(json: &mut Json<W>) -> Result<()> {
+    json.begin_object()?;
+    json.field("version", VERSION)?;
+    json.key("resources")?;
+    json.begin_array()?;
+    for resource in Resource::ALL {
+        json.value(resource.name())?;
+    }
+    json.end_array()?;
+    json.end_object()
+}
+
+fn write_output<W: Write>(json: &mut Json<W>, output: &Output) -> Result<()> {
+    json.begin_object()?;
+    json.field("on", output.on)?;
+    json.field("brightness", output.brightness)?;
+    json.field("preset", output.preset)?;
+    json.field(
+        "fade_ms",
+        output.fade_remaining(Instant::now().as_millis() as u32),
+    )?;
+    json.field("show", output.show_frame.is_some())?;
+    json.field("smooth", output.smooth)?;
+    json.key("segments")?;
+    json.begin_array()?;
+    for segment in output.scene.segments() {
+        json.begin_object()?;
+        json.field("start", segment.start)?;
+        json.field("len", segment.len)?;
+        json.field("brightness", segment.brightness)?;
+        match &segment.source {
+            Source::Universe {
+                port_address,
+                offset,
+            } => {
+                json.field("source", "universe")?;
+                json.field("universe", *port_address)?;
+                json.field("offset", *offset)?;
+            }
+            Source::Effect(effect) => {
+                json.field("source", "effect")?;
+                json.field("effect", effect.kind.name())?;
+                json.field("palette", effect.palette_name())?;
+                json.field("speed", effect.speed)?;
+                json.field("scale", effect.scale)?;
+            }
+            Source::Solid(color) => {
+                json.field("source", "solid")?;
+                json.key("color")?;
+                json.display(format_args!("{:06x}", color.to_u32()))?;
+            }
+            Source::Script => json.field("source", "script")?,
+        }
+        json.end_object()?;
+    }
+    json.end_array()?;
+    json.end_object()
+}
+
+fn parse_bool(form: &str, key: &'static str) -> Result<Option<bool>> {
+    match query_param(form, key) {
+        None => Ok(None),
+        Some("1" | "true" | "on") => Ok(Some(true)),
+        Some("0" | "false" | "off") => Ok(Some(false)),
+        Some(_) => Err(Error::Generic(key)),
+    }
+}
+
+/// Apply `on`, `brightness`, `preset` and `effect` from a form.  Presets and
+/// effects crossfade over `fade` milliseconds.
+async fn update_output(ctx: &Context, form: &str) -> Result<()> {
+    let fade_ms = parse_param(form, "fade")?.unwrap_or(0);
+    let effect = query_param(form, "effect")
+        .map(|name| EffectKind::from_name(name).ok_or(Error::Generic("Unknown effect")))
+        .transpose()?;
+    if let Some(index) = parse_param(form, "preset")? {
+        preset::recall(index, fade_ms, ctx.output, ctx.storage).await?;
+    }
+
+    let mut output = ctx.output.lock().await;
+    if let Some(on) = parse_bool(form, "on")? {
+        output.on = on;
+    }
+    if let Some(brightness) = parse_param(form, "brightness")? {
+        output.brightness = brightness;
+    }
+    if let Some(kind) = effect {
+        let time_ms = Instant::now().as_millis() as u32;
+        output.set_scene(Scene::effect(kind), time_ms, fade_ms);
+    }
+    Ok(())
+}
+
+fn write_ipv4<W: Write>(json: &mut Json<W>, address: [u8; 4]) -> Result<()> {
+    let [a, b, c, d] = address;
+    json.display(format_args!("{a}.{b}.{c}.{d}"))
+}
+
+async fn write_network<W: Write>(json: &mut Json<W>, ctx: &Context) -> Result<()> {
+    json.begin_object()?;
+    let settings = ctx.settings.lock().await;
+    json.field("ssid", settings.ssid())?;
+    json.field("link_up", ctx.stack.is_link_up())?;
+    json.field("http_port", settings.http_port())?;
+    drop(settings);
+    json.field("portal", ctx.portal.is_some())?;
+    json.key("wifi")?;
+    ctx.networks
+        .lock()
+        .await
+        .write_status(json, Instant::now())?;
+    match ctx.stack.config() {
+        Some(config) => {
+            json.key("address")?;
+            write_ipv4(json, config.address.address().0)?;
+            json.field("prefix_len", config.address.prefix_len())?;
+            json.key("gateway")?;
+            match config.gateway {
+                Some(gateway) => write_ipv4(json, gateway.0)?,
+                None => json.null()?,
+            }
+            json.key("dns_servers")?;
+            json.begin_array()?;
+            for server in config.dns_servers.iter() {
+                write_ipv4(json, server.0)?;
+            }
+            json.end_array()?;
+        }
+        None => json.field("address", None::<&str>)?,
+    }
+    json.end_object()
+}
+
+async fn write_pd<W: Write>(json: &mut Json<W>, ctx: &Context) -> Result<()> {
+    let power = ctx.power.lock().await;
+    json.begin_object()?;
+    json.field("state", power.state.name())?;
+    json.key("contract")?;
+    let contract = power.contract.and_then(|i| Some((i, power.pdos().get(i)?)));
+    match contract {
+        Some((index, pdo)) => {
+            json.begin_object()?;
+            json.field("pdo", index)?;
+            json.field("voltage_mv", pdo.voltage())?;
+            json.field("current_ma", pdo.max_current())?;
+            json.field("power_mw", pdo.power())?;
+            json.end_object()?;
+        }
+        None => json.null()?,
+    }
+    json.key("pdos")?;
+    json.begin_array()?;
+    for pdo in power.pdos() {
+        json.begin_object()?;
+        json.field("voltage_mv", pdo.voltage())?;
+        json.field("max_current_ma", pdo.max_current())?;
+        json.field("power_mw", pdo.power())?;
+        json.end_object()?;
+    }
+    json.end_array()?;
+    json.end_object()
+}
+
+async fn write_charger<W: Write>(json: &mut Json<W>, ctx: &Context) -> Result<()> {
+    let Some(charger) = ctx.power.lock().await.charger else {
+        return Err(Error::Http(Status::ServiceUnavailable));
+    };
+    json.begin_object()?;
+    json.field("vbus_uv", charger.vbus_uv)?;
+    json.field("vsys_uv", charger.vsys_uv)?;
+    json.field("ibus_ma", charger.ibus_ma)?;
+    json.field("ibat_ma", charger.ibat_ma)?;
+    json.end_object()
+}
+
+async fn write_artnet<W: Write>(json: &mut Json<W>, ctx: &Context) -> Result<()> {
+    json.begin_object()?;
+    json.field("port", artnet::PORT)?;
+    let settings = ctx.settings.lock().await;
+    json.field("short_name", settings.short_name())?;
+    json.field("long_name", settings.long_name())?;
+    json.field("sacn", settings.sacn())?;
+    json.field("sacn_port", sacn::PORT)?;
+    drop(settings);
+    let output = ctx.output.lock().await;
+    json.field("oem", artnet::OEM_CODE)?;
+    json.key("inputs")?;
+    json.begin_array()?;
+    for (index, segment) in output.scene.segments().iter().enumerate() {
+        if let Source::Universe {
+            port_address,
+            offset,
+        } = segment.source
+        {
+            json.begin_object()?;
+            json.field("segment", index)?;
+            json.field("universe", port_address)?;
+            json.field("offset", offset)?;
+            json.end_object()?;
+        }
+    }
+    json.end_array()?;
+    json.key("received")?;
+    json.begin_array()?;
+    for (port_address, data) in output.universes.iter() {
+        json.begin_object()?;
+        json.field("universe", port_address)?;
+        json.field("len", data.len())?;
+        json.end_object()?;
+    }
+    json.end_array()?;
+    json.end_object()
+}
+
+pub async fn write_resource<W: Write>(
+    json: &mut Json<W>,
+    ctx: &Context,
+    resource: Resource,
+) -> Result<()> {
+    match resource {
+        Resource::Index => write_index(json),
+        Resource::Output => write_output(json, &*ctx.output.lock().await),
+        Resource::Network => write_network(json, ctx).await,
+        Resource::Pd => write_pd(json, ctx).await,
+        Resource::Charger => write_charger(json, ctx).await,
+        Resource::Artnet => write_artnet(json, ctx).await,
+    }
+}
+
+pub(crate) async fn send_error(conn: &mut Connection<'_, '_>, e: &Error) -> Result<()> {
+    let status = Status::for_error(e);
+    let mut buffer = [0u8; 256];
+    let mut json = Json::new(FmtBuffer::new(&mut buffer));
+    json.begin_object()?;
+    json.key("error")?;
+    json.begin_object()?;
+    json.field("code", status.code())?;
+    json.field("reason", status.reason())?;
+    json.key("message")?;
+    json.display(e)?;
+    json.end_object()?;
+    json.end_object()?;
+    conn.send(status, JSON, json.into_inner().as_bytes()).await
+}
+
+/// Serve `resource`.  `POST` and `PUT` update it from the query string or
+/// a form encoded body first.  Errors are answered in JSON too.
+pub async fn handle(
+    conn: &mut Connection<'_, '_>,
+    ctx: &Context,
+    req: &Request<'_>,
+    resource: Resource,
+) -> Result<()> {
+    let result = async {
+        if matches!(req.method, Method::Post | Method::Put) {
+            let mut body = [0u8; MAX_FORM_LEN];
+            let form = match req.query {
+                "" => core::str::from_utf8(conn.read_body(&mut body).await?)
+                    .map_err(|_| Error::Generic("Form isn't UTF-8"))?,
+                query => query,
+            };
+            match resource {
+                Resource::Output => update_output(ctx, form).await?,
+                _ => return Err(Error::Http(Status::MethodNotAllowed)),
+            }
+        }
+
+        let mut buffer = [0u8; MAX_RESPONSE_LEN];
+        let mut json = Json::new(FmtBuffer::new(&mut buffer));
+        write_resource(&mut json, ctx, resource).await?;
+        conn.send(Status::Ok, JSON, json.into_inner().as_bytes())
+            .await
+    }
+    .await;
+
+    match result {
+        Err(e) if !conn.started() => {
+            println!("api {} failed: {e:?}", req.path);
+            send_error(conn, &e).await
+        }
+        result => result,
+    }
+}
@@ -1,18 +1,29 @@
 use core::array::TryFromSliceError;
 use core::cmp::min;
+use core::fmt::Write as _;
 
 use byteorder::LittleEndian;
 use embassy_net::{udp, IpAddress, Ipv4Address};
 use embassy_net::{udp::UdpSocket, PacketMetadata, Stack};
-use embassy_time::{Duration, Timer};
-use embedded_hal_async::spi::SpiBusWrite;
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::MutexGuard};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use esp_println::println;
 use esp_wifi::wifi::WifiDevice;
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::{FromPrimitive, ToPrimitive};
 use smoltcp::wire::IpEndpoint;
 
 use crate::buffer::{self, MutBuffer, OldBuffer};
-use crate::ws2812::{self, Ws2812};
+use crate::effects::EffectKind;
+use crate::ota::{Ota, Update};
+use crate::output::{Scene, SharedOutput};
+use crate::playlist::{self, SharedPlayer};
+use crate::preset;
+use crate::settings::SharedSettings;
+use crate::show::SharedShow;
+use crate::storage::SharedStorage;
+use crate::timecode::{SharedClock, Timecode};
+use crate::web::FmtBuffer;
 
 #[derive(Debug)]
 pub enum Error {
@@ -57,6 +68,10 @@ impl From<udp::Error> for Error {
 
 type Result<T> = core::result::Result<T, Error>;
 
+pub const PORT: u16 = 6454;
+pub const SHORT_NAME: &str = "Blinky";
+pub const LONG_NAME: &str = "Konkers' Blinky Toy";
+
 #[derive(Clone, Copy, Debug, FromPrimitive, ToPrimitive)]
 #[repr(u16)]
 pub enum Opcode {
@@ -131,6 +146,8 @@ pub enum StyleCode {
 }
 
 const ARTNET_ID: &'static [u8; 8] = b"Art-Net\0";
+/// Art-Net 4.
+const PROT_VER: [u8; 2] = [0, 14];
 
 #[derive(Debug)]
 pub struct Poll {
@@ -285,6 +302,11 @@ pub struct Output<'a> {
     pub data: &'a [u8],
 }
 impl<'a> Output<'a> {
+    /// The 15 bit Port-Address made up of the net and sub-net/universe.
+    pub fn port_address(&self) -> u16 {
+        ((self.net as u16 & 0x7f) << 8) | self.sub_uni as u16
+    }
+
     fn parse(buf: &mut OldBuffer<'a, LittleEndian>) -> Result<Self> {
         let prot_ver = buf.read()?;
         let sequence = buf.read_u8()?;
@@ -305,6 +327,210 @@ impl<'a> Output<'a> {
     }
 }
 
+#[derive(Debug)]
+pub struct TimeCode {
+    pub prot_ver: [u8; 2],
+    pub stream_id: u8,
+    pub frames: u8,
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    /// 0 film (24 fps), 1 EBU (25 fps), 2 DF (29.97 fps), 3 SMPTE (30 fps).
+    pub kind: u8,
+}
+
+impl TimeCode {
+    fn parse(buf: &mut OldBuffer<LittleEndian>) -> Result<Self> {
+        let prot_ver = buf.read()?;
+        let _filler = buf.read_u8()?;
+        Ok(Self {
+            prot_ver,
+            stream_id: buf.read_u8()?,
+            frames: buf.read_u8()?,
+            seconds: buf.read_u8()?,
+            minutes: buf.read_u8()?,
+            hours: buf.read_u8()?,
+            kind: buf.read_u8()?,
+        })
+    }
+
+    pub fn timecode(&self) -> Option<Timecode> {
+        Timecode::new(
+            self.hours,
+            self.minutes,
+            self.seconds,
+            self.frames,
+            self.kind,
+        )
+    }
+}
+
+/// OEM code used in ArtTrigger packets addressed to every device.
+pub const OEM_ALL: u16 = 0xffff;
+/// The OEM code we report in ArtPollReply.
+pub const OEM_CODE: u16 = 0x00ff;
+/// ESTA manufacturer code used in ArtCommand packets addressed to every
+/// device.
+pub const ESTA_ALL: u16 = 0xffff;
+/// The ESTA manufacturer code we report in ArtPollReply, from the range
+/// ESTA keeps for prototypes.
+pub const ESTA_CODE: u16 = 0x7ff0;
+
+#[derive(Clone, Copy, Debug, FromPrimitive, ToPrimitive)]
+#[repr(u8)]
+pub enum TriggerKey {
+    Ascii = 0,
+    Macro = 1,
+    Soft = 2,
+    Show = 3,
+}
+
+#[derive(Debug)]
+pub struct Trigger<'a> {
+    pub prot_ver: [u8; 2],
+    pub oem: u16,
+    pub key: u8,
+    pub sub_key: u8,
+    pub data: &'a [u8],
+}
+
+impl<'a> Trigger<'a> {
+    fn parse(buf: &mut OldBuffer<'a, LittleEndian>) -> Result<Self> {
+        let prot_ver = buf.read()?;
+        let _filler: [u8; 2] = buf.read()?;
+        let oem: [u8; 2] = buf.read()?;
+        let key = buf.read_u8()?;
+        let sub_key = buf.read_u8()?;
+        let data = buf.take(buf.remaining())?;
+        Ok(Self {
+            prot_ver,
+            oem: u16::from_be_bytes(oem),
+            key,
+            sub_key,
+            data,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Command<'a> {
+    pub prot_ver: [u8; 2],
+    pub esta_man: u16,
+    /// `&` separated `Command=Value` pairs.
+    pub data: &'a [u8],
+}
+
+impl<'a> Command<'a> {
+    fn parse(buf: &mut OldBuffer<'a, LittleEndian>) -> Result<Self> {
+        let prot_ver = buf.read()?;
+        let esta_man: [u8; 2] = buf.read()?;
+        let len: [u8; 2] = buf.read()?;
+        let len = min(u16::from_be_bytes(len) as usize, buf.remaining());
+        let data = buf.take(len)?;
+        // The text is null terminated within the length.
+        let data = data.split(|b| *b == 0).next().unwrap_or(data);
+        Ok(Self {
+            prot_ver,
+            esta_man: u16::from_be_bytes(esta_man),
+            data,
+        })
+    }
+
+    /// Iterate the command's (command, value) pairs.
+    pub fn commands(&self) -> impl Iterator<Item = (&'a str, &'a str)> {
+        core::str::from_utf8(self.data)
+            .unwrap_or("")
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+    }
+}
+
+/// ArtFirmwareMaster block types.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, FromPrimitive, ToPrimitive)]
+#[repr(u8)]
+pub enum FirmwareKind {
+    FirmFirst = 0x00,
+    FirmCont = 0x01,
+    FirmLast = 0x02,
+    UbeaFirst = 0x03,
+    UbeaCont = 0x04,
+    UbeaLast = 0x05,
+}
+
+/// Firmware data in an ArtFirmwareMaster packet.
+pub const FIRMWARE_BLOCK_LEN: usize = 1024;
+
+#[derive(Debug)]
+pub struct Firmware<'a> {
+    pub prot_ver: [u8; 2],
+    pub kind: u8,
+    /// Counts up from 0 for the first block.
+    pub block_id: u8,
+    /// Length of the whole upload in 16 bit words.
+    pub firmware_len: u32,
+    /// Always a whole block.  The last one is padded.
+    pub data: &'a [u8],
+}
+
+impl<'a> Firmware<'a> {
+    fn parse(buf: &mut OldBuffer<'a, LittleEndian>) -> Result<Self> {
+        let prot_ver = buf.read()?;
+        let _filler: [u8; 2] = buf.read()?;
+        let kind = buf.read_u8()?;
+        let block_id = buf.read_u8()?;
+        let firmware_len: [u8; 4] = buf.read()?;
+        let _spare: [u8; 20] = buf.read()?;
+        let data = buf.take(min(FIRMWARE_BLOCK_LEN, buf.remaining()))?;
+        Ok(Self {
+            prot_ver,
+            kind,
+            block_id,
+            firmware_len: u32::from_be_bytes(firmware_len),
+            data,
+        })
+    }
+
+    /// Length of the whole upload in bytes.
+    pub fn len(&self) -> u32 {
+        self.firmware_len * 2
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, FromPrimitive, ToPrimitive)]
+#[repr(u8)]
+pub enum FirmwareReplyKind {
+    /// The block was written, send the next one.
+    BlockGood = 0x00,
+    /// The last block was written and the upload checks out.
+    AllGood = 0x01,
+    Fail = 0xff,
+}
+
+#[derive(Debug)]
+pub struct FirmwareReply {
+    pub kind: u8,
+}
+
+impl FirmwareReply {
+    fn parse(buf: &mut OldBuffer<LittleEndian>) -> Result<Self> {
+        let _prot_ver: [u8; 2] = buf.read()?;
+        let _filler: [u8; 2] = buf.read()?;
+        Ok(Self {
+            kind: buf.read_u8()?,
+        })
+    }
+
+    fn write(&self, buf: &mut MutBuffer<LittleEndian>) -> Result<()> {
+        buf.write_u16(Opcode::FirmwareReply.to_u16().unwrap())?;
+        buf.write(&PROT_VER)?;
+        buf.write(&[0u8; 2])?; // Filler
+        buf.write_u8(self.kind)?;
+        buf.write(&[0u8; 21])?; // Spare
+
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct Unknown<'a> {
     pub data: &'a [u8],
@@ -315,6 +541,11 @@ pub enum Packet<'a> {
     Poll(Poll),
     PollReply(PollReply),
     Output(Output<'a>),
+    TimeCode(TimeCode),
+    Trigger(Trigger<'a>),
+    Command(Command<'a>),
+    Firmware(Firmware<'a>),
+    FirmwareReply(FirmwareReply),
     Unknown(Unknown<'a>),
 }
 
@@ -336,6 +567,11 @@ impl<'a> Packet<'a> {
             Opcode::Poll => Ok(Packet::Poll(Poll::parse(buf)?)),
             Opcode::PollReply => Ok(Packet::PollReply(PollReply::parse(buf)?)),
             Opcode::Output => Ok(Packet::Output(Output::parse(buf)?)),
+            Opcode::TimeCode => Ok(Packet::TimeCode(TimeCode::parse(buf)?)),
+            Opcode::Trigger => Ok(Packet::Trigger(Trigger::parse(buf)?)),
+            Opcode::Command => Ok(Packet::Command(Command::parse(buf)?)),
+            Opcode::Firmware => Ok(Packet::Firmware(Firmware::parse(buf)?)),
+            Opcode::FirmwareReply => Ok(Packet::FirmwareReply(FirmwareReply::parse(buf)?)),
             _ => Ok(Packet::Unknown(Unknown { data })),
         }
     }
@@ -346,6 +582,7 @@ impl<'a> Packet<'a> {
         buf.write(ARTNET_ID)?;
         match self {
             Self::PollReply(reply) => reply.write(buf)?,
+            Self::FirmwareReply(reply) => reply.write(buf)?,
             _ => return Err(Error::Unimplemented),
         }
 
@@ -364,21 +601,24 @@ async fn send_poll_reply(
     socket: &mut UdpSocket<'_>,
     my_address: &Ipv4Address,
     _ep: &IpEndpoint,
+    short_name: [u8; 18],
+    long_name: [u8; 64],
+    node_report: [u8; 64],
     buf: &mut [u8],
 ) -> Result<()> {
     let reply = Packet::PollReply(PollReply {
         ip_address: my_address.as_bytes().try_into()?,
-        port: 0x1936,
+        port: PORT,
         vers_info: [0x0, 0x0],
         net_switch: 0,
         sub_switch: 0,
-        oem: [0x00, 0xff],
+        oem: OEM_CODE.to_be_bytes(),
         ubea_version: 0,
         status_1: 0xe0,
-        esta_man: [0xff, 0xff],
-        short_name: padded_byte_str(b"Blinky"),
-        long_name: padded_byte_str(b"Konkers' Blinky Toy"),
-        node_report: padded_byte_str(b"It's all good!"),
+        esta_man: ESTA_CODE.to_le_bytes(),
+        short_name,
+        long_name,
+        node_report,
         num_ports: [0, 1],
         port_types: [0xc0, 0x00, 0x00, 0x00],
         good_input: [8; 4],
@@ -405,17 +645,382 @@ async fn send_poll_reply(
             &buf[..len],
             IpEndpoint {
                 addr: IpAddress::Ipv4(Ipv4Address([0xff, 0xff, 0xff, 0xff])),
-                port: 6454,
+                port: PORT,
             },
         )
         .await?;
     Ok(())
 }
 
+async fn send_firmware_reply(
+    socket: &mut UdpSocket<'_>,
+    ep: IpEndpoint,
+    kind: FirmwareReplyKind,
+    buf: &mut [u8],
+) -> Result<()> {
+    let reply = Packet::FirmwareReply(FirmwareReply { kind: kind as u8 });
+    let len = reply.write(buf)?;
+    socket.send_to(&buf[..len], ep).await?;
+    Ok(())
+}
+
+/// KeySoft sub keys.  Below [`SOFT_PLAYLIST`] they control the running
+/// playlist or the recorded show, from there on they pick a playlist or an
+/// effect by number.
+const SOFT_STOP: u8 = 0x00;
+const SOFT_NEXT: u8 = 0x01;
+const SOFT_PREV: u8 = 0x02;
+const SOFT_SHOW_STOP: u8 = 0x08;
+const SOFT_SHOW_PLAY: u8 = 0x09;
+const SOFT_PLAYLIST: u8 = 0x10;
+const SOFT_EFFECT: u8 = 0x20;
+
+const LABEL_LEN: usize = 16;
+
+/// Drop a firmware upload when its controller has gone quiet for this long.
+const FIRMWARE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often the receive loop wakes up to look for stale uploads.
+const TICK: Duration = Duration::from_millis(1000);
+
+fn label(text: &[u8]) -> &str {
+    let text = text.split(|b| *b == 0).next().unwrap_or(&[]);
+    core::str::from_utf8(text).unwrap_or("")
+}
+
+/// An ArtFirmwareMaster upload in progress.
+struct FirmwareUpload {
+    /// Handed to the OTA task to check and install once the last block is
+    /// in.  The upload stays until that is done.
+    update: Option<Update>,
+    /// Keeps HTTP uploads out until this one is done.
+    _upload: MutexGuard<'static, NoopRawMutex, ()>,
+    from: IpEndpoint,
+    /// Bytes still to come.
+    remaining: u32,
+    /// The last block written.
+    block_id: u8,
+    last_block: Instant,
+}
+
+/// The local content that ArtTrigger and ArtCommand packets drive.
+struct Node {
+    output: &'static SharedOutput,
+    storage: &'static SharedStorage,
+    show: &'static SharedShow,
+    player: &'static SharedPlayer,
+    clock: &'static SharedClock,
+    ota: &'static Ota,
+    settings: &'static SharedSettings,
+    firmware: Option<FirmwareUpload>,
+    /// The controller and last block of the upload installed last, so a
+    /// FirmLast resent after a lost reply is acknowledged again.
+    installed: Option<(IpEndpoint, u8)>,
+    /// Labels consoles have given our Swout and Swin buttons.
+    swout_text: [u8; LABEL_LEN],
+    swin_text: [u8; LABEL_LEN],
+}
+
+impl Node {
+    fn node_report(&self) -> [u8; 64] {
+        let mut report = [0u8; 64];
+        let mut text = FmtBuffer::new(&mut report);
+        write!(text, "It's all good!").ok();
+        let (swout, swin) = (label(&self.swout_text), label(&self.swin_text));
+        if !swout.is_empty() || !swin.is_empty() {
+            write!(text, " Swout={swout} Swin={swin}").ok();
+        }
+        report
+    }
+
+    /// Our short and long names from the settings, zero padded.
+    async fn names(&self) -> ([u8; 18], [u8; 64]) {
+        let settings = self.settings.lock().await;
+        (
+            padded_byte_str(settings.short_name().as_bytes()),
+            padded_byte_str(settings.long_name().as_bytes()),
+        )
+    }
+
+    async fn recall_preset(&self, index: u8, fade_ms: u32) {
+        if let Err(e) = preset::recall(index, fade_ms, self.output, self.storage).await {
+            println!("artnet: failed to recall preset {index}: {e:?}");
+        }
+    }
+
+    async fn play_playlist(&self, index: u8) {
+        if let Err(e) = playlist::play(index, self.player, self.storage).await {
+            println!("artnet: failed to play playlist {index}: {e:?}");
+        }
+    }
+
+    /// Fill the strip with `kind`.  Like a preset recall this leaves the
+    /// saved scene alone.
+    async fn start_effect(&self, kind: EffectKind, fade_ms: u32) {
+        let time_ms = Instant::now().as_millis() as u32;
+        self.output
+            .lock()
+            .await
+            .set_scene(Scene::effect(kind), time_ms, fade_ms);
+    }
+
+    async fn trigger(&self, trigger: &Trigger<'_>) {
+        if trigger.oem != OEM_ALL && trigger.oem != OEM_CODE {
+            return;
+        }
+        match TriggerKey::from_u8(trigger.key) {
+            // Macro and show triggers recall the preset with the sub key's
+            // number.  A non zero first payload byte is the fade in tenths of
+            // a second.
+            Some(TriggerKey::Macro | TriggerKey::Show) => {
+                let fade_ms = trigger.data.first().map_or(0, |d| *d as u32 * 100);
+                self.recall_preset(trigger.sub_key, fade_ms).await
+            }
+            Some(TriggerKey::Soft) => match trigger.sub_key {
+                SOFT_STOP => self.player.lock().await.stop(),
+                SOFT_NEXT => self.player.lock().await.step(true),
+                SOFT_PREV => self.player.lock().await.step(false),
+                SOFT_SHOW_STOP => self.show.lock().await.stop(),
+                SOFT_SHOW_PLAY => self.show.lock().await.play(),
+                key if (SOFT_PLAYLIST..SOFT_EFFECT).contains(&key) => {
+                    self.play_playlist(key - SOFT_PLAYLIST).await
+                }
+                key if key >= SOFT_EFFECT => {
+                    if let Some(kind) = EffectKind::from_u8(key - SOFT_EFFECT) {
+                        self.start_effect(kind, 0).await
+                    }
+                }
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+
+    /// Run the commands in an ArtCommand packet in order.  Besides the
+    /// standard `SwoutText` and `SwinText` we accept `Preset=<n>`,
+    /// `Playlist=<n|next|prev|stop>`, `Effect=<name>`, `Show=<play|stop>` and
+    /// `Fade=<ms>`, which applies to the presets and effects after it.
+    /// Commands for another manufacturer's devices are ignored.
+    async fn command(&mut self, command: &Command<'_>) {
+        if command.esta_man != ESTA_ALL && command.esta_man != ESTA_CODE {
+            return;
+        }
+        let mut fade_ms = 0;
+        for (name, value) in command.commands() {
+            let is = |n: &str| name.eq_ignore_ascii_case(n);
+            if is("SwoutText") {
+                self.swout_text = padded_byte_str(value.as_bytes());
+            } else if is("SwinText") {
+                self.swin_text = padded_byte_str(value.as_bytes());
+            } else if is("Fade") {
+                fade_ms = value.parse().unwrap_or(0);
+            } else if is("Preset") {
+                if let Ok(index) = value.parse() {
+                    self.recall_preset(index, fade_ms).await;
+                }
+            } else if is("Playlist") {
+                match value {
+                    "stop" => self.player.lock().await.stop(),
+                    "next" => self.player.lock().await.step(true),
+                    "prev" => self.player.lock().await.step(false),
+                    _ => {
+                        if let Ok(index) = value.parse() {
+                            self.play_playlist(index).await;
+                        }
+                    }
+                }
+            } else if is("Effect") {
+                if let Some(kind) = EffectKind::from_name(value) {
+                    self.start_effect(kind, fade_ms).await;
+                }
+            } else if is("Show") {
+                match value {
+                    "stop" => self.show.lock().await.stop(),
+                    _ => self.show.lock().await.play(),
+                }
+            } else {
+                println!("artnet: unknown command {name}={value}");
+            }
+        }
+    }
+
+    /// Start an upload on `FirmFirst` and find the one a later block
+    /// belongs to.
+    async fn firmware_upload(
+        &mut self,
+        firmware: &Firmware<'_>,
+        from: IpEndpoint,
+    ) -> crate::Result<&mut FirmwareUpload> {
+        if firmware.kind == FirmwareKind::FirmFirst as u8 {
+            // A controller starting over replaces its own upload.
+            if self.uploading_from(from) {
+                self.firmware = None;
+            }
+            let upload = self
+                .ota
+                .try_upload()
+                .ok_or(crate::Error::Generic("Another upload is running"))?;
+            if firmware.block_id != 0 {
+                return Err(crate::Error::Generic("First block isn't block 0"));
+            }
+            let update = Update::new(self.storage).await?;
+            let len = firmware.len();
+            println!("artnet: firmware upload of {len} bytes from {from}");
+            return Ok(self.firmware.insert(FirmwareUpload {
+                update: Some(update),
+                _upload: upload,
+                from,
+                remaining: firmware.len(),
+                block_id: 0,
+                last_block: Instant::now(),
+            }));
+        }
+
+        let upload = self
+            .firmware
+            .as_mut()
+            .ok_or(crate::Error::Generic("No firmware upload running"))?;
+        if firmware.block_id != upload.block_id.wrapping_add(1) {
+            return Err(crate::Error::Generic("Firmware block out of sequence"));
+        }
+        upload.block_id = firmware.block_id;
+        upload.last_block = Instant::now();
+        Ok(upload)
+    }
+
+    /// Write a block.  The last one is handed to the OTA task, and is only
+    /// answered once that has installed it.
+    async fn receive_firmware(
+        &mut self,
+        firmware: &Firmware<'_>,
+        from: IpEndpoint,
+    ) -> crate::Result<Option<FirmwareReplyKind>> {
+        let kind = FirmwareKind::from_u8(firmware.kind);
+        if !matches!(
+            kind,
+            Some(FirmwareKind::FirmFirst | FirmwareKind::FirmCont | FirmwareKind::FirmLast)
+        ) {
+            return Err(crate::Error::Generic("Only firmware uploads are supported"));
+        }
+
+        let storage = self.storage;
+        let upload = self.firmware_upload(firmware, from).await?;
+        let update = upload.update.as_mut().ok_or(crate::Error::Index)?;
+        let len = min(upload.remaining as usize, firmware.data.len());
+        update.write(storage, &firmware.data[..len]).await?;
+        upload.remaining -= len as u32;
+        if kind != Some(FirmwareKind::FirmLast) {
+            return Ok(Some(FirmwareReplyKind::BlockGood));
+        }
+
+        if upload.remaining != 0 {
+            return Err(crate::Error::Generic("Firmware upload ended early"));
+        }
+        let update = upload.update.take().ok_or(crate::Error::Index)?;
+        self.ota.finish_later(update).await;
+        Ok(None)
+    }
+
+    /// The reply to the last block of an upload, once the OTA task is done
+    /// with it.
+    async fn firmware_finished(&mut self) -> Option<(IpEndpoint, FirmwareReplyKind)> {
+        let upload = self
+            .firmware
+            .as_ref()
+            .filter(|upload| upload.update.is_none())?;
+        let (from, block_id) = (upload.from, upload.block_id);
+        let result = self.ota.finished().await?;
+        self.firmware = None;
+        match result {
+            Ok(_) => {
+                println!("artnet: firmware upload from {from} installed");
+                self.installed = Some((from, block_id));
+                self.ota.restart_soon().await;
+                Some((from, FirmwareReplyKind::AllGood))
+            }
+            Err(e) => {
+                println!("artnet: firmware upload from {from} failed: {e:?}");
+                Some((from, FirmwareReplyKind::Fail))
+            }
+        }
+    }
+
+    /// Handle an ArtFirmwareMaster block and pick the reply, if there is
+    /// one yet.  Uploads are refused unless the `artnet_firmware` setting
+    /// is on.  A block from anyone but the controller running the upload is
+    /// refused without disturbing it, and a block we have already taken is
+    /// acknowledged again for controllers that resend when a reply gets
+    /// lost.
+    async fn firmware(
+        &mut self,
+        firmware: &Firmware<'_>,
+        from: IpEndpoint,
+    ) -> Option<FirmwareReplyKind> {
+        if !self.settings.lock().await.artnet_firmware() {
+            if firmware.kind == FirmwareKind::FirmFirst as u8 {
+                println!("artnet: refused a firmware upload from {from}, they are turned off");
+            }
+            return Some(FirmwareReplyKind::Fail);
+        }
+        let last = firmware.kind == FirmwareKind::FirmLast as u8;
+        if last && self.installed == Some((from, firmware.block_id)) {
+            return Some(FirmwareReplyKind::AllGood);
+        }
+        if let Some(upload) = &self.firmware {
+            // Nothing may touch an upload the OTA task is installing.
+            if upload.update.is_none() {
+                let resent = upload.from == from && last && firmware.block_id == upload.block_id;
+                return (!resent).then_some(FirmwareReplyKind::Fail);
+            }
+            if upload.from != from && firmware.kind != FirmwareKind::FirmFirst as u8 {
+                return Some(FirmwareReplyKind::Fail);
+            }
+            if upload.from == from
+                && firmware.kind == FirmwareKind::FirmCont as u8
+                && firmware.block_id == upload.block_id
+            {
+                return Some(FirmwareReplyKind::BlockGood);
+            }
+        }
+
+        match self.receive_firmware(firmware, from).await {
+            Ok(reply) => reply,
+            Err(e) => {
+                println!("artnet: firmware upload from {from} failed: {e:?}");
+                if self.uploading_from(from) {
+                    self.firmware = None;
+                }
+                Some(FirmwareReplyKind::Fail)
+            }
+        }
+    }
+
+    fn uploading_from(&self, from: IpEndpoint) -> bool {
+        self.firmware
+            .as_ref()
+            .map_or(false, |upload| upload.from == from)
+    }
+
+    fn expire_firmware(&mut self) {
+        if let Some(upload) = &self.firmware {
+            if upload.update.is_some() && upload.last_block.elapsed() > FIRMWARE_TIMEOUT {
+                println!("artnet: firmware upload from {} timed out", upload.from);
+                self.firmware = None;
+            }
+        }
+    }
+}
+
 #[embassy_executor::task]
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn task(
     stack: &'static Stack<WifiDevice<'static>>,
-    spi: &'static mut crate::SpiType<'static>,
+    output: &'static SharedOutput,
+    storage: &'static SharedStorage,
+    show: &'static SharedShow,
+    player: &'static SharedPlayer,
+    clock: &'static SharedClock,
+    ota: &'static Ota,
+    settings: &'static SharedSettings,
 ) {
     let mut rx_meta = [PacketMetadata::EMPTY; 16];
     let mut rx_buffer = [0; 4096];
@@ -423,10 +1028,6 @@ pub(crate) async fn task(
     let mut tx_buffer = [0; 4096];
     let mut buf = [0; 4096];
 
-    const NUM_LEDS: usize = 120;
-    const LED_BUF_LEN: usize = ws2812::buffer_len(NUM_LEDS);
-    let mut led_buf = [0u8; LED_BUF_LEN];
-
     let my_address = loop {
         if let Some(config) = stack.config() {
             break config.address.address();
@@ -441,37 +1042,73 @@ pub(crate) async fn task(
         &mut tx_meta,
         &mut tx_buffer,
     );
-    socket.bind(6454).unwrap();
+    socket.bind(PORT).unwrap();
+    let mut node = Node {
+        output,
+        storage,
+        show,
+        player,
+        clock,
+        ota,
+        settings,
+        firmware: None,
+        installed: None,
+        swout_text: [0; LABEL_LEN],
+        swin_text: [0; LABEL_LEN],
+    };
     loop {
-        let (length, ep) = socket.recv_from(&mut buf).await.unwrap();
+        if let Some((ep, reply)) = node.firmware_finished().await {
+            send_firmware_reply(&mut socket, ep, reply, &mut buf)
+                .await
+                .ok();
+        }
+        let received = with_timeout(TICK, socket.recv_from(&mut buf)).await;
+        node.expire_firmware();
+        let Ok(received) = received else {
+            continue;
+        };
+        let (length, ep) = received.unwrap();
         if let Ok(packet) = Packet::parse(&buf[..length]) {
             match packet {
                 Packet::Poll(_poll) => {
                     //println!("sending poll reply to {poll:x?}");
                     //Timer::after(Duration::from_millis(150)).await;
-                    send_poll_reply(&mut socket, &my_address, &ep, &mut buf)
+                    let node_report = node.node_report();
+                    let (short_name, long_name) = node.names().await;
+                    send_poll_reply(
+                        &mut socket,
+                        &my_address,
+                        &ep,
+                        short_name,
+                        long_name,
+                        node_report,
+                        &mut buf,
+                    )
+                    .await
+                    .ok();
+                }
+                Packet::Output(packet) => {
+                    //println!("got output packet: {packet:x?}");
+                    output
+                        .lock()
                         .await
-                        .ok();
+                        .universes
+                        .update(packet.port_address(), packet.data);
                 }
-                Packet::Output(output) => {
-                    //println!("got output packet: {output:x?}");
-                    if output.sub_uni == 0 {
-                        // let brightness = output.data[9 + 6] as u16;
-                        // let r = output.data[9] as u16 * brightness / 256;
-                        // let g = output.data[10] as u16 * brightness / 256;
-                        // let b = output.data[11] as u16 * brightness / 256;
-                        let mut ws = Ws2812::<LED_BUF_LEN>::new(&mut led_buf);
-                        for i in 0..NUM_LEDS {
-                            let base = 32 + i / (NUM_LEDS / 10) * 3;
-                            let r = output.data[base + 0]; // as u16 * brightness / 256;
-                            let g = output.data[base + 1]; // as u16 * brightness / 256;
-                            let b = output.data[base + 2]; // as u16 * brightness / 256;
-
-                            ws.set_led(i, r, g, b);
-                        }
-                        let led_buf = ws.into_buf();
-
-                        let _ret = spi.write(&led_buf).await;
+                Packet::TimeCode(timecode) => {
+                    // Only the default stream drives the clock.
+                    if let Some(timecode) = timecode.timecode().filter(|_| timecode.stream_id == 0)
+                    {
+                        node.clock.lock().await.receive(timecode, Instant::now());
+                    }
+                }
+                Packet::Trigger(trigger) => node.trigger(&trigger).await,
+                Packet::Command(command) => node.command(&command).await,
+                Packet::Firmware(firmware) => {
+                    if let Some(reply) = node.firmware(&firmware, ep).await {
+                        send_firmware_reply(&mut socket, ep, reply, &mut buf)
+                            .await
+                            .ok();
                     }
                 }
                 _ => (), //println!("artnet packet: {:x?}", &packet);
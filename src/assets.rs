@@ -0,0 +1,21 @@
+/// A file from `src/html`, embedded by `build.rs`.
+pub struct Asset {
+    pub path: &'static str,
+    pub mime: &'static str,
+    /// Quoted, ready for the `ETag` header.
+    pub etag: &'static str,
+    /// `data` is gzip compressed.
+    pub gzip: bool,
+    pub data: &'static [u8],
+}
+
+include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+/// The asset served at `path`.  Directories are served by their
+/// `index.html`.
+pub fn find(path: &str) -> Option<&'static Asset> {
+    ASSETS.iter().find(|asset| match path.strip_suffix('/') {
+        Some(dir) => asset.path.strip_prefix(dir) == Some("/index.html"),
+        None => asset.path == path,
+    })
+}
@@ -0,0 +1,691 @@
+use core::fmt::Write;
+
+use byteorder::LittleEndian;
+use embassy_futures::{block_on, yield_now};
+use embassy_net::IpAddress;
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant, Timer};
+use esp_println::println;
+
+use crate::buffer::{MutBuffer, OldBuffer};
+use crate::hal::{peripherals::RNG, Rng};
+use crate::http::{Connection, Request, Status, JSON};
+use crate::json::Json;
+use crate::ota::Hmac;
+use crate::storage::{Slot, Storage};
+use crate::web::{query_param, url_decode, Context, FmtBuffer};
+use crate::{Error, Result};
+
+const AUTH_VERSION: u16 = 2;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+/// PBKDF2 rounds.  Each is a few SHA-256 blocks, so a check takes a good
+/// part of a second on the ESP32-C3.
+const KDF_ROUNDS: u32 = 10_000;
+/// Rounds between yields, to keep the LEDs and network going meanwhile.
+const KDF_YIELD_ROUNDS: u32 = 200;
+const CONFIG_LEN: usize = 1 + 2 * (SALT_LEN + HASH_LEN);
+
+const FLAG_ADMIN: u8 = 1 << 0;
+const FLAG_VIEWER: u8 = 1 << 1;
+const FLAG_I2C: u8 = 1 << 2;
+
+pub const MIN_PASSWORD_LEN: usize = 8;
+pub const MAX_PASSWORD_LEN: usize = 64;
+/// Longest form encoded body `/auth/login` and `/auth/set` take.
+const MAX_FORM_LEN: usize = 3 * (2 * MAX_PASSWORD_LEN + 32);
+
+const MAX_SESSIONS: usize = 4;
+const TOKEN_LEN: usize = 16;
+/// Sessions that go unused for this long are logged out.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+/// Name of the cookie holding the session token for the web UI.
+const COOKIE: &str = "session";
+/// Failed logins are answered this late, and no password from the same
+/// client is checked for this long after one, to slow down guessing.
+const LOGIN_PENALTY: Duration = Duration::from_millis(1000);
+/// Clients held off after a wrong password at the same time.
+const MAX_BLOCKED: usize = 8;
+
+/// `WWW-Authenticate` for requests that need a login.
+pub const CHALLENGE: (&str, &str) = ("WWW-Authenticate", "Basic realm=\"rgb\"");
+
+/// What a client may do.  Viewers can read everything but the raw I2C
+/// endpoints, admins can change things too.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Role {
+    Viewer,
+    Admin,
+}
+
+impl Role {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "viewer" => Some(Role::Viewer),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+/// Hardware random number.  esp-wifi owns the RNG peripheral but only
+/// ever reads it, so we can too.  With the radio running it is a true
+/// random source.
+fn random(buf: &mut [u8]) {
+    let mut rng = Rng::new(unsafe { RNG::steal() });
+    for chunk in buf.chunks_mut(4) {
+        let n = rng.random().to_le_bytes();
+        chunk.copy_from_slice(&n[..chunk.len()]);
+    }
+}
+
+/// Compare without returning early so the time taken doesn't give away
+/// how much of a secret matched.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// PBKDF2-HMAC-SHA256, as in RFC 8018, for a single block of output.
+pub(crate) async fn pbkdf2(password: &[u8], salt: &[u8], rounds: u32) -> [u8; 32] {
+    let prf = Hmac::new(password);
+    let mut mac = prf.clone();
+    mac.update(salt);
+    mac.update(&1u32.to_be_bytes());
+    let mut u = mac.finish();
+    let mut key = u;
+    for round in 1..rounds {
+        if round % KDF_YIELD_ROUNDS == 0 {
+            yield_now().await;
+        }
+        let mut mac = prf.clone();
+        mac.update(&u);
+        u = mac.finish();
+        for (k, u) in key.iter_mut().zip(u) {
+            *k ^= u;
+        }
+    }
+    key
+}
+
+/// Salted hash of a password.  Only the hash is stored.
+#[derive(Clone, Copy)]
+struct Secret {
+    salt: [u8; SALT_LEN],
+    hash: [u8; HASH_LEN],
+}
+
+impl Secret {
+    async fn new(password: &str) -> Result<Self> {
+        if password.len() < MIN_PASSWORD_LEN {
+            return Err(Error::Generic("Password too short"));
+        }
+        if password.len() > MAX_PASSWORD_LEN {
+            return Err(Error::Generic("Password too long"));
+        }
+        let mut salt = [0u8; SALT_LEN];
+        random(&mut salt);
+        Ok(Self {
+            salt,
+            hash: pbkdf2(password.as_bytes(), &salt, KDF_ROUNDS).await,
+        })
+    }
+
+    async fn matches(&self, password: &str) -> bool {
+        let hash = pbkdf2(password.as_bytes(), &self.salt, KDF_ROUNDS).await;
+        constant_time_eq(&hash, &self.hash)
+    }
+
+    fn write(secret: Option<&Self>, buf: &mut MutBuffer<LittleEndian>) -> Result<()> {
+        let empty = Self {
+            salt: [0; SALT_LEN],
+            hash: [0; HASH_LEN],
+        };
+        let secret = secret.unwrap_or(&empty);
+        buf.write(&secret.salt)?;
+        buf.write(&secret.hash)?;
+        Ok(())
+    }
+
+    fn parse(buf: &mut OldBuffer<LittleEndian>, present: bool) -> Result<Option<Self>> {
+        let secret = Self {
+            salt: buf.read()?,
+            hash: buf.read()?,
+        };
+        Ok(present.then_some(secret))
+    }
+}
+
+/// Passwords and switches, kept in flash.
+///
+///   flags: u8, admin: (salt, hash), viewer: (salt, hash)
+#[derive(Clone, Copy)]
+pub struct Config {
+    /// Without an admin password anyone is an admin, but the raw I2C
+    /// endpoints stay closed.
+    admin: Option<Secret>,
+    /// Without a viewer password anyone may read.
+    viewer: Option<Secret>,
+    /// Whether the raw I2C endpoints answer at all.  Building without the
+    /// `i2c-debug` feature leaves them out regardless.
+    pub i2c_enabled: bool,
+}
+
+impl Config {
+    /// The admin password, if any, comes from `ADMIN_PASSWORD` at build
+    /// time.
+    pub fn new() -> Self {
+        let admin =
+            crate::ADMIN_PASSWORD.and_then(|password| match block_on(Secret::new(password)) {
+                Ok(secret) => Some(secret),
+                Err(e) => {
+                    println!("ignoring ADMIN_PASSWORD: {e:?}");
+                    None
+                }
+            });
+        Self {
+            admin,
+            viewer: None,
+            i2c_enabled: true,
+        }
+    }
+
+    pub fn has_password(&self, role: Role) -> bool {
+        match role {
+            Role::Viewer => self.viewer.is_some(),
+            Role::Admin => self.admin.is_some(),
+        }
+    }
+
+    /// Set or, with `None`, remove the password for `role`.  The admin
+    /// password can only be replaced.
+    pub async fn set_password(&mut self, role: Role, password: Option<&str>) -> Result<()> {
+        let secret = match password {
+            Some(password) => Some(Secret::new(password).await?),
+            None => None,
+        };
+        match role {
+            Role::Viewer => self.viewer = secret,
+            Role::Admin => {
+                self.admin = Some(secret.ok_or(Error::Generic("Admin password can't be removed"))?)
+            }
+        }
+        Ok(())
+    }
+
+    async fn check(&self, user: &str, password: &str) -> Option<Role> {
+        let role = Role::from_name(user)?;
+        let secret = match role {
+            Role::Viewer => self.viewer.as_ref(),
+            Role::Admin => self.admin.as_ref(),
+        }?;
+        secret.matches(password).await.then_some(role)
+    }
+
+    fn write(&self, buf: &mut MutBuffer<LittleEndian>) -> Result<()> {
+        let mut flags = 0;
+        if self.admin.is_some() {
+            flags |= FLAG_ADMIN;
+        }
+        if self.viewer.is_some() {
+            flags |= FLAG_VIEWER;
+        }
+        if self.i2c_enabled {
+            flags |= FLAG_I2C;
+        }
+        buf.write_u8(flags)?;
+        Secret::write(self.admin.as_ref(), buf)?;
+        Secret::write(self.viewer.as_ref(), buf)
+    }
+
+    fn parse(buf: &mut OldBuffer<LittleEndian>) -> Result<Self> {
+        let flags = buf.read_u8()?;
+        Ok(Self {
+            admin: Secret::parse(buf, flags & FLAG_ADMIN != 0)?,
+            viewer: Secret::parse(buf, flags & FLAG_VIEWER != 0)?,
+            i2c_enabled: flags & FLAG_I2C != 0,
+        })
+    }
+
+    pub fn load(storage: &mut Storage) -> Result<Option<Self>> {
+        let mut data = [0u8; CONFIG_LEN];
+        let Some((version, data)) = storage.load(Slot::Auth, &mut data)? else {
+            return Ok(None);
+        };
+        if version != AUTH_VERSION {
+            return Ok(None);
+        }
+        Self::parse(&mut OldBuffer::new(data)).map(Some)
+    }
+
+    pub fn save(&self, storage: &mut Storage) -> Result<()> {
+        let mut data = [0u8; CONFIG_LEN];
+        let buf = &mut MutBuffer::<LittleEndian>::new(&mut data);
+        self.write(buf)?;
+        let len = buf.pos();
+        storage.store(Slot::Auth, AUTH_VERSION, &data[..len])
+    }
+}
+
+/// A client that sent a wrong password.
+#[derive(Clone, Copy)]
+struct Blocked {
+    client: Option<IpAddress>,
+    until: Instant,
+}
+
+#[derive(Clone, Copy)]
+struct Session {
+    token: [u8; TOKEN_LEN],
+    role: Role,
+    last_used: Instant,
+}
+
+pub struct Auth {
+    pub config: Config,
+    sessions: [Option<Session>; MAX_SESSIONS],
+    /// Clients whose passwords aren't checked for a while, after a wrong
+    /// one.  Kept per client so that nobody can lock everyone else out.
+    blocked: [Option<Blocked>; MAX_BLOCKED],
+    /// The last `Authorization: Basic` credentials that passed, as an
+    /// HMAC under `key`, so a client sending them with every request only
+    /// waits for the password hash once.
+    basic: Option<([u8; 32], Role)>,
+    key: [u8; 32],
+}
+
+pub type SharedAuth = Mutex<NoopRawMutex, Auth>;
+
+fn parse_token(hex: &str) -> Option<[u8; TOKEN_LEN]> {
+    if hex.len() != TOKEN_LEN * 2 {
+        return None;
+    }
+    let mut token = [0u8; TOKEN_LEN];
+    for (b, digits) in token.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+        *b = u8::from_str_radix(core::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(token)
+}
+
+fn base64_decode<'a>(text: &str, out: &'a mut [u8]) -> Option<&'a [u8]> {
+    let mut len = 0;
+    let mut bits = 0u32;
+    let mut n_bits = 0;
+    for c in text.bytes().take_while(|c| *c != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | value as u32;
+        n_bits += 6;
+        if n_bits >= 8 {
+            n_bits -= 8;
+            *out.get_mut(len)? = (bits >> n_bits) as u8;
+            len += 1;
+        }
+    }
+    Some(&out[..len])
+}
+
+/// The session token in a `Cookie` header.
+fn session_cookie(cookie: &str) -> Option<&str> {
+    cookie
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == COOKIE)
+        .map(|(_, value)| value)
+}
+
+impl Auth {
+    pub fn new(config: Config) -> Self {
+        let mut key = [0u8; 32];
+        random(&mut key);
+        Self {
+            config,
+            sessions: [None; MAX_SESSIONS],
+            blocked: [None; MAX_BLOCKED],
+            basic: None,
+            key,
+        }
+    }
+
+    /// Check a password from `client`.  A wrong one, whether sent to
+    /// `/auth/login` or in `Authorization: Basic`, holds off checks for
+    /// that client for `LOGIN_PENALTY`.
+    async fn check(
+        &mut self,
+        user: &str,
+        password: &str,
+        client: Option<IpAddress>,
+    ) -> Result<Option<Role>> {
+        let now = Instant::now();
+        let blocked = self
+            .blocked
+            .iter()
+            .flatten()
+            .any(|blocked| blocked.client == client && now < blocked.until);
+        if blocked {
+            return Err(Error::Http(Status::TooManyRequests));
+        }
+        let role = self.config.check(user, password).await;
+        if role.is_none() {
+            println!("wrong password for {user}");
+            self.block(client, Instant::now() + LOGIN_PENALTY);
+        }
+        Ok(role)
+    }
+
+    /// Hold off `client` until `until`, in the entry it already has, a free
+    /// or expired one, or failing those the one that runs out first.
+    fn block(&mut self, client: Option<IpAddress>, until: Instant) {
+        let now = Instant::now();
+        let slot = self
+            .blocked
+            .iter()
+            .position(|slot| slot.map_or(false, |blocked| blocked.client == client))
+            .or_else(|| {
+                self.blocked
+                    .iter()
+                    .position(|slot| slot.map_or(true, |blocked| blocked.until <= now))
+            })
+            .or_else(|| {
+                (0..self.blocked.len())
+                    .min_by_key(|&index| self.blocked[index].map(|blocked| blocked.until))
+            });
+        if let Some(slot) = slot {
+            self.blocked[slot] = Some(Blocked { client, until });
+        }
+    }
+
+    async fn basic(
+        &mut self,
+        credentials: &str,
+        client: Option<IpAddress>,
+    ) -> Result<Option<Role>> {
+        let mut mac = Hmac::new(&self.key);
+        mac.update(credentials.as_bytes());
+        let digest = mac.finish();
+        if let Some((known, role)) = self.basic {
+            if constant_time_eq(&known, &digest) {
+                return Ok(Some(role));
+            }
+        }
+
+        let mut buf = [0u8; 2 * MAX_PASSWORD_LEN];
+        let Some((user, password)) = base64_decode(credentials, &mut buf)
+            .and_then(|decoded| core::str::from_utf8(decoded).ok())
+            .and_then(|decoded| decoded.split_once(':'))
+        else {
+            return Ok(None);
+        };
+        let role = self.check(user, password, client).await?;
+        if let Some(role) = role {
+            self.basic = Some((digest, role));
+        }
+        Ok(role)
+    }
+
+    fn session(&mut self, token: &str, now: Instant) -> Option<Role> {
+        let token = parse_token(token)?;
+        let slot = self
+            .sessions
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |session| constant_time_eq(&session.token, &token)))?;
+        let session = slot.as_mut()?;
+        if now - session.last_used > SESSION_TIMEOUT {
+            *slot = None;
+            return None;
+        }
+        session.last_used = now;
+        Some(session.role)
+    }
+
+    /// Who sent a request from `client`.  `None` is an anonymous client.
+    /// Credentials in `Authorization` have to be right; a stale session
+    /// cookie is just ignored.
+    pub async fn role(
+        &mut self,
+        req: &Request<'_>,
+        client: Option<IpAddress>,
+        now: Instant,
+    ) -> Result<Option<Role>> {
+        if self.config.admin.is_none() {
+            return Ok(Some(Role::Admin));
+        }
+        let anonymous = self.config.viewer.is_none().then_some(Role::Viewer);
+
+        if let Some(authorization) = req.header("authorization") {
+            let (scheme, credentials) = authorization.split_once(' ').unwrap_or(("", ""));
+            let role = if scheme.eq_ignore_ascii_case("basic") {
+                self.basic(credentials.trim(), client).await?
+            } else if scheme.eq_ignore_ascii_case("bearer") {
+                self.session(credentials.trim(), now)
+            } else {
+                None
+            };
+            return role.map(Some).ok_or(Error::Http(Status::Unauthorized));
+        }
+
+        let session = req
+            .header("cookie")
+            .and_then(session_cookie)
+            .and_then(|token| self.session(token, now));
+        Ok(session.or(anonymous))
+    }
+
+    /// Start a session for `role`, pushing out the least recently used
+    /// one if they are all taken.
+    fn login(&mut self, role: Role, now: Instant) -> [u8; TOKEN_LEN] {
+        let mut token = [0u8; TOKEN_LEN];
+        random(&mut token);
+        let slot = self
+            .sessions
+            .iter_mut()
+            .min_by_key(|slot| slot.map(|session| session.last_used))
+            .unwrap();
+        *slot = Some(Session {
+            token,
+            role,
+            last_used: now,
+        });
+        token
+    }
+
+    fn logout(&mut self, token: &str) {
+        if let Some(token) = parse_token(token) {
+            for slot in self.sessions.iter_mut() {
+                if slot.map_or(false, |session| session.token == token) {
+                    *slot = None;
+                }
+            }
+        }
+    }
+
+    pub fn end_sessions(&mut self) {
+        self.sessions = [None; MAX_SESSIONS];
+        self.basic = None;
+    }
+}
+
+async fn read_form<'a>(
+    conn: &mut Connection<'_, '_>,
+    req: &Request<'_>,
+    body: &'a mut [u8],
+) -> Result<&'a str> {
+    // Passwords in a URL end up in logs and browser history.
+    if !req.query.is_empty() {
+        return Err(Error::Generic("Send the form in the body"));
+    }
+    core::str::from_utf8(conn.read_body(body).await?)
+        .map_err(|_| Error::Generic("Form isn't UTF-8"))
+}
+
+/// Decoded form value `key`.
+fn form_value<'a>(form: &str, key: &'static str, buf: &'a mut [u8]) -> Result<Option<&'a str>> {
+    query_param(form, key)
+        .map(|value| core::str::from_utf8(url_decode(value, buf)?).map_err(|_| Error::Generic(key)))
+        .transpose()
+}
+
+async fn send_status(
+    conn: &mut Connection<'_, '_>,
+    ctx: &Context,
+    role: Option<Role>,
+) -> Result<()> {
+    let auth = ctx.auth.lock().await;
+    let mut buffer = [0u8; 256];
+    let mut json = Json::new(FmtBuffer::new(&mut buffer));
+    json.begin_object()?;
+    json.field("role", role.map(|role| role.name()))?;
+    json.field("admin_password", auth.config.has_password(Role::Admin))?;
+    json.field("viewer_password", auth.config.has_password(Role::Viewer))?;
+    json.field("i2c_built", cfg!(feature = "i2c-debug"))?;
+    json.field("i2c_enabled", auth.config.i2c_enabled)?;
+    json.end_object()?;
+    drop(auth);
+    conn.send(Status::Ok, JSON, json.into_inner().as_bytes())
+        .await
+}
+
+/// Check a user name and password and hand out a session token, both in
+/// the body and as a cookie for the web UI.
+async fn login(conn: &mut Connection<'_, '_>, ctx: &Context, req: &Request<'_>) -> Result<()> {
+    let mut body = [0u8; MAX_FORM_LEN];
+    let form = read_form(conn, req, &mut body).await?;
+    let mut buf = [0u8; MAX_PASSWORD_LEN];
+    let user = query_param(form, "user").unwrap_or("admin");
+    let role = match form_value(form, "password", &mut buf)? {
+        Some(password) => {
+            let client = conn.remote();
+            ctx.auth.lock().await.check(user, password, client).await?
+        }
+        None => None,
+    };
+    let Some(role) = role else {
+        Timer::after(LOGIN_PENALTY).await;
+        return Err(Error::Http(Status::Unauthorized));
+    };
+
+    let token = ctx.auth.lock().await.login(role, Instant::now());
+    let mut hex = [0u8; TOKEN_LEN * 2];
+    let mut hex = FmtBuffer::new(&mut hex);
+    for b in token {
+        write!(hex, "{b:02x}").map_err(|_| Error::Index)?;
+    }
+    let hex = core::str::from_utf8(hex.as_bytes()).map_err(|_| Error::Index)?;
+
+    let mut cookie = [0u8; 128];
+    let mut cookie = FmtBuffer::new(&mut cookie);
+    write!(
+        cookie,
+        "{COOKIE}={hex}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}",
+        SESSION_TIMEOUT.as_secs()
+    )
+    .map_err(|_| Error::Index)?;
+    let cookie = core::str::from_utf8(cookie.as_bytes()).map_err(|_| Error::Index)?;
+
+    let mut buffer = [0u8; 128];
+    let mut json = Json::new(FmtBuffer::new(&mut buffer));
+    json.begin_object()?;
+    json.field("role", role.name())?;
+    json.field("token", hex)?;
+    json.field("timeout_s", SESSION_TIMEOUT.as_secs())?;
+    json.end_object()?;
+    let headers = [
+        JSON[0],
+        ("Set-Cookie", cookie),
+        ("Cache-Control", "no-store"),
+    ];
+    conn.send(Status::Ok, &headers, json.into_inner().as_bytes())
+        .await
+}
+
+async fn logout(conn: &mut Connection<'_, '_>, ctx: &Context, req: &Request<'_>) -> Result<()> {
+    let bearer = req
+        .header("authorization")
+        .and_then(|authorization| authorization.strip_prefix("Bearer "));
+    let cookie = req.header("cookie").and_then(session_cookie);
+    let mut auth = ctx.auth.lock().await;
+    for token in [bearer, cookie].into_iter().flatten() {
+        auth.logout(token.trim());
+    }
+    drop(auth);
+    let expire = (
+        "Set-Cookie",
+        "session=; Path=/; HttpOnly; SameSite=Strict; Max-Age=0",
+    );
+    conn.send(Status::NoContent, &[expire], &[]).await
+}
+
+/// Change passwords and the I2C switch.  Changing a password logs
+/// everyone out.
+async fn set(
+    conn: &mut Connection<'_, '_>,
+    ctx: &Context,
+    req: &Request<'_>,
+    role: Option<Role>,
+) -> Result<()> {
+    let mut body = [0u8; MAX_FORM_LEN];
+    let form = read_form(conn, req, &mut body).await?;
+    let mut config = ctx.auth.lock().await.config;
+
+    let mut buf = [0u8; MAX_PASSWORD_LEN];
+    let admin = form_value(form, "admin_password", &mut buf)?;
+    let changed = admin.is_some();
+    if let Some(password) = admin {
+        config.set_password(Role::Admin, Some(password)).await?;
+    }
+    let mut buf = [0u8; MAX_PASSWORD_LEN];
+    let viewer = form_value(form, "viewer_password", &mut buf)?;
+    let changed = changed || viewer.is_some();
+    if let Some(password) = viewer {
+        config
+            .set_password(Role::Viewer, Some(password).filter(|p| !p.is_empty()))
+            .await?;
+    }
+    match query_param(form, "i2c") {
+        None => (),
+        Some("1") => config.i2c_enabled = true,
+        Some("0") => config.i2c_enabled = false,
+        Some(_) => return Err(Error::Generic("i2c")),
+    }
+
+    {
+        let mut auth = ctx.auth.lock().await;
+        auth.config = config;
+        if changed {
+            auth.end_sessions();
+        }
+    }
+    if let Err(e) = config.save(&mut *ctx.storage.lock().await) {
+        println!("failed to save auth config: {e:?}");
+    }
+    send_status(conn, ctx, role).await
+}
+
+/// `/auth` reports who the client is and what is protected.  `login`,
+/// `logout` and `set` take a form encoded body.
+pub async fn handle(
+    conn: &mut Connection<'_, '_>,
+    ctx: &Context,
+    req: &Request<'_>,
+    role: Option<Role>,
+) -> Result<()> {
+    match req.param("command") {
+        None => send_status(conn, ctx, role).await,
+        Some("login") => login(conn, ctx, req).await,
+        Some("logout") => logout(conn, ctx, req).await,
+        Some("set") => set(conn, ctx, req, role).await,
+        _ => Err(Error::Generic("Unknown auth command")),
+    }
+}
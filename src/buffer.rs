@@ -84,6 +84,11 @@ impl<'a, ENDIAN: ByteOrder> OldBuffer<'a, ENDIAN> {
         self.pos
     }
 
+    /// Number of bytes left to read.
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
     fn ensure_space(&self, n_bytes: usize) -> Result<()> {
         if (self.pos + n_bytes) > self.data.len() {
             Err(Error::Eof)
@@ -0,0 +1,506 @@
+use core::cmp::{max, min};
+
+use crate::{Error, Result};
+
+/// Scale `val` by `scale / 256`, treating a scale of 255 as full brightness.
+pub const fn scale8(val: u8, scale: u8) -> u8 {
+    ((val as u16 * (scale as u16 + 1)) >> 8) as u8
+}
+
+/// Linear interpolation between `a` and `b` where `frac` of 0 yields `a` and
+/// 255 yields `b`.
+pub const fn lerp8(a: u8, b: u8, frac: u8) -> u8 {
+    if b > a {
+        a + scale8(b - a, frac)
+    } else {
+        a - scale8(a - b, frac)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const BLACK: Rgb = Rgb::new(0, 0, 0);
+    pub const WHITE: Rgb = Rgb::new(0xff, 0xff, 0xff);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// Build a colour from a `0xRRGGBB` value.
+    pub const fn from_u32(val: u32) -> Self {
+        Self::new((val >> 16) as u8, (val >> 8) as u8, val as u8)
+    }
+
+    pub const fn to_u32(self) -> u32 {
+        (self.r as u32) << 16 | (self.g as u32) << 8 | self.b as u32
+    }
+
+    pub const fn scale(self, scale: u8) -> Self {
+        Self::new(
+            scale8(self.r, scale),
+            scale8(self.g, scale),
+            scale8(self.b, scale),
+        )
+    }
+
+    /// Blend towards `other` by `amount`/255.
+    pub const fn blend(self, other: Rgb, amount: u8) -> Self {
+        Self::new(
+            lerp8(self.r, other.r, amount),
+            lerp8(self.g, other.g, amount),
+            lerp8(self.b, other.b, amount),
+        )
+    }
+
+    pub const fn saturating_add(self, other: Rgb) -> Self {
+        Self::new(
+            self.r.saturating_add(other.r),
+            self.g.saturating_add(other.g),
+            self.b.saturating_add(other.b),
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rgbw {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub w: u8,
+}
+
+impl Rgbw {
+    pub const fn new(r: u8, g: u8, b: u8, w: u8) -> Self {
+        Self { r, g, b, w }
+    }
+}
+
+/// Hue, saturation and value with the hue wrapping around the colour wheel
+/// once over the full `u8` range.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Hsv {
+    pub h: u8,
+    pub s: u8,
+    pub v: u8,
+}
+
+impl Hsv {
+    pub const fn new(h: u8, s: u8, v: u8) -> Self {
+        Self { h, s, v }
+    }
+}
+
+/// Hue, saturation and lightness.  Hue uses the same scale as [`Hsv`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Hsl {
+    pub h: u8,
+    pub s: u8,
+    pub l: u8,
+}
+
+impl Hsl {
+    pub const fn new(h: u8, s: u8, l: u8) -> Self {
+        Self { h, s, l }
+    }
+}
+
+impl From<Hsv> for Rgb {
+    fn from(hsv: Hsv) -> Self {
+        if hsv.s == 0 {
+            return Rgb::new(hsv.v, hsv.v, hsv.v);
+        }
+
+        // Split the wheel into six sectors and work out how far into the
+        // current sector we are.
+        let scaled = hsv.h as u16 * 6;
+        let sector = scaled >> 8;
+        let frac = (scaled & 0xff) as u8;
+
+        let v = hsv.v;
+        let p = scale8(v, 255 - hsv.s);
+        let q = scale8(v, 255 - scale8(hsv.s, frac));
+        let t = scale8(v, 255 - scale8(hsv.s, 255 - frac));
+
+        match sector {
+            0 => Rgb::new(v, t, p),
+            1 => Rgb::new(q, v, p),
+            2 => Rgb::new(p, v, t),
+            3 => Rgb::new(p, q, v),
+            4 => Rgb::new(t, p, v),
+            _ => Rgb::new(v, p, q),
+        }
+    }
+}
+
+/// Returns the hue of `rgb` along with its max and min channel values.
+fn hue(rgb: Rgb) -> (u8, u8, u8) {
+    let hi = max(rgb.r, max(rgb.g, rgb.b));
+    let lo = min(rgb.r, min(rgb.g, rgb.b));
+    let delta = (hi - lo) as i32;
+    if delta == 0 {
+        return (0, hi, lo);
+    }
+
+    // Each sector of the wheel spans 256 / 6 hue steps.
+    let (base, diff) = if hi == rgb.r {
+        (0, rgb.g as i32 - rgb.b as i32)
+    } else if hi == rgb.g {
+        (85, rgb.b as i32 - rgb.r as i32)
+    } else {
+        (171, rgb.r as i32 - rgb.g as i32)
+    };
+    let h = base + diff * 43 / delta;
+
+    (h.rem_euclid(256) as u8, hi, lo)
+}
+
+impl From<Rgb> for Hsv {
+    fn from(rgb: Rgb) -> Self {
+        let (h, hi, lo) = hue(rgb);
+        let s = if hi == 0 {
+            0
+        } else {
+            ((hi - lo) as u16 * 255 / hi as u16) as u8
+        };
+        Hsv::new(h, s, hi)
+    }
+}
+
+impl From<Hsl> for Rgb {
+    fn from(hsl: Hsl) -> Self {
+        // Go through HSV rather than duplicating the sector logic.
+        let l = hsl.l as u16;
+        let v = l + hsl.s as u16 * min(l, 255 - l) / 255;
+        let s = if v == 0 { 0 } else { 2 * (v - l) * 255 / v };
+        Hsv::new(hsl.h, min(s, 255) as u8, v as u8).into()
+    }
+}
+
+impl From<Rgb> for Hsl {
+    fn from(rgb: Rgb) -> Self {
+        let (h, hi, lo) = hue(rgb);
+        let sum = hi as u16 + lo as u16;
+        let l = (sum / 2) as u8;
+        let s = if hi == lo {
+            0
+        } else {
+            let range = 255 - (sum as i16 - 255).unsigned_abs();
+            min((hi - lo) as u16 * 255 / range, 255) as u8
+        };
+        Hsl::new(h, s, l)
+    }
+}
+
+impl From<Rgb> for Rgbw {
+    /// Moves the common component of the three channels onto the white LED.
+    fn from(rgb: Rgb) -> Self {
+        let w = min(rgb.r, min(rgb.g, rgb.b));
+        Rgbw::new(rgb.r - w, rgb.g - w, rgb.b - w, w)
+    }
+}
+
+impl From<Rgbw> for Rgb {
+    fn from(rgbw: Rgbw) -> Self {
+        Rgb::new(
+            rgbw.r.saturating_add(rgbw.w),
+            rgbw.g.saturating_add(rgbw.w),
+            rgbw.b.saturating_add(rgbw.w),
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stop {
+    pub pos: u8,
+    pub color: Rgb,
+}
+
+impl Stop {
+    pub const fn new(pos: u8, color: u32) -> Self {
+        Self {
+            pos,
+            color: Rgb::from_u32(color),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Blend {
+    /// Use the colour of the closest stop at or below the position.
+    None,
+    /// Linearly interpolate between neighbouring stops.
+    Linear,
+}
+
+pub const MAX_STOPS: usize = 32;
+
+/// A gradient made from up to [`MAX_STOPS`] stops ordered by position.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palette {
+    stops: [Stop; MAX_STOPS],
+    num_stops: usize,
+}
+
+impl Palette {
+    pub const fn new(stops: &[Stop]) -> Self {
+        assert!(stops.len() <= MAX_STOPS);
+        let mut palette = Self {
+            stops: [Stop::new(0, 0); MAX_STOPS],
+            num_stops: stops.len(),
+        };
+        let mut i = 0;
+        while i < stops.len() {
+            palette.stops[i] = stops[i];
+            i += 1;
+        }
+        palette
+    }
+
+    pub fn stops(&self) -> &[Stop] {
+        &self.stops[..self.num_stops]
+    }
+
+    /// Insert a stop, keeping the stops sorted by position.
+    pub fn push(&mut self, stop: Stop) -> Result<()> {
+        if self.num_stops >= MAX_STOPS {
+            return Err(Error::Generic("Too many stops"));
+        }
+        let index = self.stops[..self.num_stops]
+            .iter()
+            .position(|s| s.pos > stop.pos)
+            .unwrap_or(self.num_stops);
+        self.stops.copy_within(index..self.num_stops, index + 1);
+        self.stops[index] = stop;
+        self.num_stops += 1;
+        Ok(())
+    }
+
+    pub fn color_at(&self, pos: u8, blend: Blend) -> Rgb {
+        let stops = self.stops();
+        let Some(first) = stops.first() else {
+            return Rgb::BLACK;
+        };
+        if pos <= first.pos {
+            return first.color;
+        }
+
+        for pair in stops.windows(2) {
+            let (lo, hi) = (pair[0], pair[1]);
+            if pos < hi.pos {
+                return match blend {
+                    Blend::None => lo.color,
+                    Blend::Linear => {
+                        let span = (hi.pos - lo.pos) as u16;
+                        let frac = (pos - lo.pos) as u16 * 255 / span;
+                        lo.color.blend(hi.color, frac as u8)
+                    }
+                };
+            }
+        }
+
+        // Past the last stop.
+        stops[stops.len() - 1].color
+    }
+
+    /// Blend this palette towards `other`, resampling both onto 16 evenly
+    /// spaced stops.
+    pub fn blend(&self, other: &Palette, amount: u8) -> Palette {
+        let mut palette = Palette::new(&[]);
+        for i in 0..16u16 {
+            let pos = (i * 255 / 15) as u8;
+            let a = self.color_at(pos, Blend::Linear);
+            let b = other.color_at(pos, Blend::Linear);
+            palette.stops[i as usize] = Stop {
+                pos,
+                color: a.blend(b, amount),
+            };
+        }
+        palette.num_stops = 16;
+        palette
+    }
+}
+
+pub const BUILTIN_PALETTES: [(&str, Palette); 8] = [
+    (
+        "rainbow",
+        Palette::new(&[
+            Stop::new(0, 0xff0000),
+            Stop::new(16, 0xd52a00),
+            Stop::new(32, 0xab5500),
+            Stop::new(48, 0xab7f00),
+            Stop::new(64, 0xabab00),
+            Stop::new(80, 0x56d500),
+            Stop::new(96, 0x00ff00),
+            Stop::new(112, 0x00d52a),
+            Stop::new(128, 0x00ab55),
+            Stop::new(144, 0x0056aa),
+            Stop::new(160, 0x0000ff),
+            Stop::new(176, 0x2a00d5),
+            Stop::new(192, 0x5500ab),
+            Stop::new(208, 0x7f0081),
+            Stop::new(224, 0xab0055),
+            Stop::new(240, 0xd5002b),
+            Stop::new(255, 0xff0000),
+        ]),
+    ),
+    (
+        "party",
+        Palette::new(&[
+            Stop::new(0, 0x5500ab),
+            Stop::new(16, 0x84007c),
+            Stop::new(32, 0xb5004b),
+            Stop::new(48, 0xe5001b),
+            Stop::new(64, 0xe81700),
+            Stop::new(80, 0xb84700),
+            Stop::new(96, 0xab7700),
+            Stop::new(112, 0xabab00),
+            Stop::new(128, 0xab5500),
+            Stop::new(144, 0xdd2200),
+            Stop::new(160, 0xf2000e),
+            Stop::new(176, 0xc2003e),
+            Stop::new(192, 0x8f0071),
+            Stop::new(208, 0x5f00a1),
+            Stop::new(224, 0x2f00d0),
+            Stop::new(240, 0x0007f9),
+            Stop::new(255, 0x5500ab),
+        ]),
+    ),
+    (
+        "ocean",
+        Palette::new(&[
+            Stop::new(0, 0x191970),
+            Stop::new(16, 0x00008b),
+            Stop::new(32, 0x191970),
+            Stop::new(48, 0x000080),
+            Stop::new(64, 0x00008b),
+            Stop::new(80, 0x0000cd),
+            Stop::new(96, 0x2e8b57),
+            Stop::new(112, 0x008080),
+            Stop::new(128, 0x5f9ea0),
+            Stop::new(144, 0x0000ff),
+            Stop::new(160, 0x008b8b),
+            Stop::new(176, 0x6495ed),
+            Stop::new(192, 0x7fffd4),
+            Stop::new(208, 0x2e8b57),
+            Stop::new(224, 0x00ffff),
+            Stop::new(240, 0x87cefa),
+            Stop::new(255, 0x191970),
+        ]),
+    ),
+    (
+        "forest",
+        Palette::new(&[
+            Stop::new(0, 0x006400),
+            Stop::new(16, 0x006400),
+            Stop::new(32, 0x556b2f),
+            Stop::new(48, 0x006400),
+            Stop::new(64, 0x008000),
+            Stop::new(80, 0x228b22),
+            Stop::new(96, 0x6b8e23),
+            Stop::new(112, 0x008000),
+            Stop::new(128, 0x2e8b57),
+            Stop::new(144, 0x66cdaa),
+            Stop::new(160, 0x32cd32),
+            Stop::new(176, 0x9acd32),
+            Stop::new(192, 0x90ee90),
+            Stop::new(208, 0x7cfc00),
+            Stop::new(224, 0x66cdaa),
+            Stop::new(240, 0x228b22),
+            Stop::new(255, 0x006400),
+        ]),
+    ),
+    (
+        "lava",
+        Palette::new(&[
+            Stop::new(0, 0x000000),
+            Stop::new(16, 0x800000),
+            Stop::new(32, 0x000000),
+            Stop::new(48, 0x800000),
+            Stop::new(64, 0x8b0000),
+            Stop::new(80, 0x800000),
+            Stop::new(96, 0x8b0000),
+            Stop::new(112, 0x8b0000),
+            Stop::new(128, 0x8b0000),
+            Stop::new(144, 0xff0000),
+            Stop::new(160, 0xffa500),
+            Stop::new(176, 0xffffff),
+            Stop::new(192, 0xffa500),
+            Stop::new(208, 0xff0000),
+            Stop::new(224, 0x8b0000),
+            Stop::new(255, 0x000000),
+        ]),
+    ),
+    (
+        "heat",
+        Palette::new(&[
+            Stop::new(0, 0x000000),
+            Stop::new(64, 0xff0000),
+            Stop::new(128, 0xff9900),
+            Stop::new(192, 0xffff00),
+            Stop::new(255, 0xffffff),
+        ]),
+    ),
+    (
+        "cloud",
+        Palette::new(&[
+            Stop::new(0, 0x0000ff),
+            Stop::new(32, 0x00008b),
+            Stop::new(64, 0x00008b),
+            Stop::new(96, 0x00008b),
+            Stop::new(128, 0x00008b),
+            Stop::new(160, 0x00008b),
+            Stop::new(176, 0x00008b),
+            Stop::new(192, 0x00008b),
+            Stop::new(208, 0x0000ff),
+            Stop::new(224, 0x00008b),
+            Stop::new(232, 0x87ceeb),
+            Stop::new(240, 0x87ceeb),
+            Stop::new(244, 0xadd8e6),
+            Stop::new(248, 0xffffff),
+            Stop::new(252, 0xadd8e6),
+            Stop::new(255, 0x87ceeb),
+        ]),
+    ),
+    (
+        "sunset",
+        Palette::new(&[
+            Stop::new(0, 0x780000),
+            Stop::new(22, 0xb31600),
+            Stop::new(51, 0xff6800),
+            Stop::new(85, 0xa71601),
+            Stop::new(135, 0x640067),
+            Stop::new(198, 0x1000a0),
+            Stop::new(255, 0x0000a0),
+        ]),
+    ),
+];
+
+/// Index of the built-in palette called `name`.
+pub fn palette_index(name: &str) -> Option<u8> {
+    BUILTIN_PALETTES
+        .iter()
+        .position(|(n, _)| *n == name)
+        .map(|i| i as u8)
+}
+
+/// Built-in palette at `index`, falling back to the rainbow palette for
+/// unknown indices.
+pub fn palette(index: u8) -> &'static Palette {
+    BUILTIN_PALETTES
+        .get(index as usize)
+        .map(|(_, palette)| palette)
+        .unwrap_or(&BUILTIN_PALETTES[0].1)
+}
+
+pub fn palette_name(index: u8) -> &'static str {
+    BUILTIN_PALETTES
+        .get(index as usize)
+        .map(|(name, _)| *name)
+        .unwrap_or("unknown")
+}
@@ -0,0 +1,287 @@
+//! A DHCP server for the Wi-Fi setup portal's network.  Every client gets
+//! an address of its own with the node as its router and DNS server, which
+//! is all [`crate::dns`] needs to send them to the setup page.
+
+use embassy_net::{udp::UdpSocket, IpAddress, Ipv4Address, PacketMetadata, Stack};
+use embassy_time::{Duration, Timer};
+use esp_println::println;
+use esp_wifi::wifi::WifiDevice;
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+use smoltcp::wire::IpEndpoint;
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+/// Clients are allowed to send messages up to this long.
+pub const MAX_PACKET_LEN: usize = 576;
+/// BOOTP fields before the options, including the DHCP magic cookie.
+const HEADER_LEN: usize = 240;
+/// Some clients ignore replies shorter than a BOOTP packet.
+const MIN_REPLY_LEN: usize = 300;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVER: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_END: u8 = 255;
+
+/// Clients come back long before this runs out, as long as the portal does.
+const LEASE_TIME: u32 = 3600;
+/// Addresses handed out, following the server's own.
+pub const POOL_SIZE: usize = 8;
+/// The portal network is a /24.
+const SUBNET_MASK: [u8; 4] = [255, 255, 255, 0];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, FromPrimitive)]
+pub enum MessageType {
+    Discover = 1,
+    Offer = 2,
+    Request = 3,
+    Decline = 4,
+    Ack = 5,
+    Nak = 6,
+    Release = 7,
+    Inform = 8,
+}
+
+/// The parts of a client's message the server cares about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub kind: MessageType,
+    pub xid: [u8; 4],
+    pub flags: [u8; 2],
+    pub client_address: [u8; 4],
+    pub hardware_address: [u8; 6],
+    pub requested_address: Option<[u8; 4]>,
+    pub server_id: Option<[u8; 4]>,
+}
+
+impl Message {
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        let header = packet.get(..HEADER_LEN)?;
+        if header[0] != OP_REQUEST
+            || header[1] != HTYPE_ETHERNET
+            || header[2] != 6
+            || header[236..240] != MAGIC_COOKIE
+        {
+            return None;
+        }
+
+        let mut kind = None;
+        let mut requested_address = None;
+        let mut server_id = None;
+        let mut pos = HEADER_LEN;
+        while let Some(&code) = packet.get(pos) {
+            match code {
+                OPTION_PAD => {
+                    pos += 1;
+                    continue;
+                }
+                OPTION_END => break,
+                _ => (),
+            }
+            let len = *packet.get(pos + 1)? as usize;
+            let data = packet.get(pos + 2..pos + 2 + len)?;
+            match (code, data) {
+                (OPTION_MESSAGE_TYPE, [value]) => kind = MessageType::from_u8(*value),
+                (OPTION_REQUESTED_ADDRESS, [a, b, c, d]) => {
+                    requested_address = Some([*a, *b, *c, *d])
+                }
+                (OPTION_SERVER_ID, [a, b, c, d]) => server_id = Some([*a, *b, *c, *d]),
+                _ => (),
+            }
+            pos += 2 + len;
+        }
+
+        Some(Self {
+            kind: kind?,
+            xid: header[4..8].try_into().unwrap(),
+            flags: header[10..12].try_into().unwrap(),
+            client_address: header[12..16].try_into().unwrap(),
+            hardware_address: header[28..34].try_into().unwrap(),
+            requested_address,
+            server_id,
+        })
+    }
+}
+
+/// Hands out the addresses of the pool, one per client.
+pub struct Leases {
+    server: [u8; 4],
+    clients: [Option<[u8; 6]>; POOL_SIZE],
+    /// Lease to take over once the pool is used up.
+    next: usize,
+}
+
+impl Leases {
+    pub fn new(server: Ipv4Address) -> Self {
+        Self {
+            server: server.0,
+            clients: [None; POOL_SIZE],
+            next: 0,
+        }
+    }
+
+    fn address(&self, index: usize) -> [u8; 4] {
+        let mut address = self.server;
+        address[3] = address[3].wrapping_add(1 + index as u8);
+        address
+    }
+
+    /// The address of `client`, giving it one if it has none.  Once the
+    /// pool runs out the oldest leases are taken over, which is fine for
+    /// the few clients that set up a node.
+    fn lease(&mut self, client: [u8; 6]) -> [u8; 4] {
+        let index = match self.clients.iter().position(|c| *c == Some(client)) {
+            Some(index) => index,
+            None => {
+                let index = self
+                    .clients
+                    .iter()
+                    .position(Option::is_none)
+                    .unwrap_or_else(|| {
+                        let index = self.next;
+                        self.next = (self.next + 1) % POOL_SIZE;
+                        index
+                    });
+                self.clients[index] = Some(client);
+                index
+            }
+        };
+        self.address(index)
+    }
+
+    fn release(&mut self, client: [u8; 6]) {
+        for lease in self.clients.iter_mut().filter(|c| **c == Some(client)) {
+            *lease = None;
+        }
+    }
+
+    /// Write the reply to `message` to `buf` and return its length, if it
+    /// needs one.
+    pub fn reply(&mut self, message: &Message, buf: &mut [u8]) -> Option<usize> {
+        let client = message.hardware_address;
+        let (kind, address) = match message.kind {
+            MessageType::Discover => (MessageType::Offer, self.lease(client)),
+            // A request for another server means the client took its offer.
+            MessageType::Request if message.server_id.map_or(false, |id| id != self.server) => {
+                self.release(client);
+                return None;
+            }
+            MessageType::Request => {
+                let address = self.lease(client);
+                let requested = message.requested_address.unwrap_or(message.client_address);
+                if requested == address {
+                    (MessageType::Ack, address)
+                } else {
+                    (MessageType::Nak, [0; 4])
+                }
+            }
+            MessageType::Decline | MessageType::Release => {
+                self.release(client);
+                return None;
+            }
+            _ => return None,
+        };
+
+        let reply = buf.get_mut(..MIN_REPLY_LEN)?;
+        reply.fill(0);
+        reply[0] = OP_REPLY;
+        reply[1] = HTYPE_ETHERNET;
+        reply[2] = 6;
+        reply[4..8].copy_from_slice(&message.xid);
+        reply[10..12].copy_from_slice(&message.flags);
+        reply[16..20].copy_from_slice(&address);
+        reply[20..24].copy_from_slice(&self.server);
+        reply[28..34].copy_from_slice(&client);
+        reply[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+        let mut options = Options {
+            buf: &mut reply[HEADER_LEN..],
+            pos: 0,
+        };
+        options.put(OPTION_MESSAGE_TYPE, &[kind as u8]);
+        options.put(OPTION_SERVER_ID, &self.server);
+        if kind != MessageType::Nak {
+            options.put(OPTION_LEASE_TIME, &LEASE_TIME.to_be_bytes());
+            options.put(OPTION_SUBNET_MASK, &SUBNET_MASK);
+            options.put(OPTION_ROUTER, &self.server);
+            options.put(OPTION_DNS_SERVER, &self.server);
+        }
+        options.buf[options.pos] = OPTION_END;
+        Some(MIN_REPLY_LEN)
+    }
+}
+
+struct Options<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl Options<'_> {
+    fn put(&mut self, code: u8, data: &[u8]) {
+        self.buf[self.pos] = code;
+        self.buf[self.pos + 1] = data.len() as u8;
+        self.buf[self.pos + 2..self.pos + 2 + data.len()].copy_from_slice(data);
+        self.pos += 2 + data.len();
+    }
+}
+
+/// Hands out addresses while the setup portal runs.
+#[embassy_executor::task]
+pub(crate) async fn task(stack: &'static Stack<WifiDevice<'static>>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 2 * MAX_PACKET_LEN];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 2 * MIN_REPLY_LEN];
+    let mut buf = [0; MAX_PACKET_LEN];
+
+    let address = loop {
+        if let Some(config) = stack.config() {
+            break config.address.address();
+        }
+        Timer::after(Duration::from_millis(500)).await;
+    };
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(SERVER_PORT).unwrap();
+    // Clients without an address yet can only be reached by broadcast.
+    let clients = IpEndpoint {
+        addr: IpAddress::Ipv4(Ipv4Address::BROADCAST),
+        port: CLIENT_PORT,
+    };
+    let mut leases = Leases::new(address);
+    loop {
+        let len = match socket.recv_from(&mut buf).await {
+            Ok((len, _)) => len,
+            Err(e) => {
+                println!("dhcp: receive failed: {e:?}");
+                continue;
+            }
+        };
+        let Some(message) = Message::parse(&buf[..len]) else {
+            continue;
+        };
+        let Some(len) = leases.reply(&message, &mut buf) else {
+            continue;
+        };
+        if let Err(e) = socket.send_to(&buf[..len], clients).await {
+            println!("dhcp: failed to reply to {:?}: {e:?}", message.kind);
+        }
+    }
+}
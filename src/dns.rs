@@ -0,0 +1,122 @@
+//! A DNS server for the Wi-Fi setup portal that answers every name with
+//! the node's own address, so whatever a client looks up leads it to the
+//! setup page.
+
+use embassy_net::{udp::UdpSocket, Ipv4Address, PacketMetadata, Stack};
+use embassy_time::{Duration, Timer};
+use esp_println::println;
+use esp_wifi::wifi::WifiDevice;
+
+const DNS_PORT: u16 = 53;
+/// Queries are never longer than this over UDP.
+pub const MAX_PACKET_LEN: usize = 512;
+const HEADER_LEN: usize = 12;
+/// A pointer to the question's name, type, class, TTL, length and address.
+const ANSWER_LEN: usize = 16;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+/// Anything set here is a response or a query other than a standard one.
+const OPCODE_MASK: u16 = 0xf800;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const FLAG_RECURSION_AVAILABLE: u16 = 0x0080;
+
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+/// Short, so that names resolve properly again soon after setup.
+const TTL: u32 = 60;
+
+/// Write the answer to `query` to `buf` and return its length.  Questions
+/// for an IPv4 address get `address`, other questions get an answer
+/// without records.  Anything but a standard query with one question is
+/// ignored.
+pub fn answer(query: &[u8], address: Ipv4Address, buf: &mut [u8]) -> Option<usize> {
+    let header = query.get(..HEADER_LEN)?;
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    if flags & OPCODE_MASK != 0 || questions != 1 {
+        return None;
+    }
+
+    // Step over the labels of the name to its type and class.
+    let mut pos = HEADER_LEN;
+    loop {
+        let len = *query.get(pos)? as usize;
+        pos += 1;
+        match len {
+            0 => break,
+            1..=63 => pos += len,
+            // Compression pointers have no business in a question.
+            _ => return None,
+        }
+    }
+    let question = query.get(HEADER_LEN..pos + 4)?;
+    let kind = u16::from_be_bytes([query[pos], query[pos + 1]]);
+    let class = u16::from_be_bytes([query[pos + 2], query[pos + 3]]);
+    let answers = (kind == TYPE_A && class == CLASS_IN) as u16;
+
+    let len = HEADER_LEN + question.len() + answers as usize * ANSWER_LEN;
+    let reply = buf.get_mut(..len)?;
+    let flags = FLAG_RESPONSE
+        | FLAG_AUTHORITATIVE
+        | FLAG_RECURSION_AVAILABLE
+        | flags & FLAG_RECURSION_DESIRED;
+    reply[0..2].copy_from_slice(&header[0..2]);
+    reply[2..4].copy_from_slice(&flags.to_be_bytes());
+    reply[4..6].copy_from_slice(&1u16.to_be_bytes());
+    reply[6..8].copy_from_slice(&answers.to_be_bytes());
+    reply[8..12].fill(0);
+    reply[HEADER_LEN..HEADER_LEN + question.len()].copy_from_slice(question);
+    if answers != 0 {
+        let answer = &mut reply[HEADER_LEN + question.len()..];
+        answer[0..2].copy_from_slice(&(0xc000 | HEADER_LEN as u16).to_be_bytes());
+        answer[2..4].copy_from_slice(&TYPE_A.to_be_bytes());
+        answer[4..6].copy_from_slice(&CLASS_IN.to_be_bytes());
+        answer[6..10].copy_from_slice(&TTL.to_be_bytes());
+        answer[10..12].copy_from_slice(&4u16.to_be_bytes());
+        answer[12..16].copy_from_slice(&address.0);
+    }
+    Some(len)
+}
+
+/// Answers DNS queries while the setup portal runs.
+#[embassy_executor::task]
+pub(crate) async fn task(stack: &'static Stack<WifiDevice<'static>>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 1024];
+    let mut query = [0; MAX_PACKET_LEN];
+    let mut reply = [0; MAX_PACKET_LEN];
+
+    let address = loop {
+        if let Some(config) = stack.config() {
+            break config.address.address();
+        }
+        Timer::after(Duration::from_millis(500)).await;
+    };
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(DNS_PORT).unwrap();
+    loop {
+        let (len, remote) = match socket.recv_from(&mut query).await {
+            Ok(received) => received,
+            Err(e) => {
+                println!("dns: receive failed: {e:?}");
+                continue;
+            }
+        };
+        let Some(len) = answer(&query[..len], address, &mut reply) else {
+            continue;
+        };
+        if let Err(e) = socket.send_to(&reply[..len], remote).await {
+            println!("dns: failed to answer {remote}: {e:?}");
+        }
+    }
+}
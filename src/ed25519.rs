@@ -0,0 +1,442 @@
+//! Ed25519 signature checks, as in RFC 8032, for firmware updates.  The
+//! node only ever checks signatures against a public key, so there is no
+//! signing here, and as everything it works on is public, nothing has to
+//! run in constant time.  The field and point arithmetic follows TweetNaCl.
+
+/// An element of the field mod 2^255 - 19, as sixteen 16 bit limbs with
+/// room for carries.
+type Fe = [i64; 16];
+/// A point in extended coordinates: X, Y, Z and T = XY/Z.
+type Point = [Fe; 4];
+
+const GF0: Fe = [0; 16];
+const GF1: Fe = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+/// The curve constant d = -121665/121666.
+const D: Fe = [
+    0x78a3, 0x1359, 0x4dca, 0x75eb, 0xd8ab, 0x4141, 0x0a4d, 0x0070, 0xe898, 0x7779, 0x4079, 0x8cc7,
+    0xfe73, 0x2b6f, 0x6cee, 0x5203,
+];
+const D2: Fe = [
+    0xf159, 0x26b2, 0x9b94, 0xebd6, 0xb156, 0x8283, 0x149a, 0x00e0, 0xd130, 0xeef3, 0x80f2, 0x198e,
+    0xfce7, 0x56df, 0xd9dc, 0x2406,
+];
+/// The base point.
+const X: Fe = [
+    0xd51a, 0x8f25, 0x2d60, 0xc956, 0xa7b2, 0x9525, 0xc760, 0x692c, 0xdc5c, 0xfdd6, 0xe231, 0xc0a4,
+    0x53fe, 0xcd6e, 0x36d3, 0x2169,
+];
+const Y: Fe = [
+    0x6658, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666,
+    0x6666, 0x6666, 0x6666, 0x6666,
+];
+/// A square root of -1.
+const I: Fe = [
+    0xa0b0, 0x4a0e, 0x1b27, 0xc4ee, 0xe478, 0xad2f, 0x1806, 0x2f43, 0xd7a7, 0x3dfb, 0x0099, 0x2b4d,
+    0xdf0b, 0x4fc1, 0x2480, 0x2b83,
+];
+/// The order of the base point, little endian.
+const L: [i64; 32] = [
+    0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9, 0xde, 0x14,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10,
+];
+
+const K: [u64; 80] = [
+    0x428a2f98d728ae22,
+    0x7137449123ef65cd,
+    0xb5c0fbcfec4d3b2f,
+    0xe9b5dba58189dbbc,
+    0x3956c25bf348b538,
+    0x59f111f1b605d019,
+    0x923f82a4af194f9b,
+    0xab1c5ed5da6d8118,
+    0xd807aa98a3030242,
+    0x12835b0145706fbe,
+    0x243185be4ee4b28c,
+    0x550c7dc3d5ffb4e2,
+    0x72be5d74f27b896f,
+    0x80deb1fe3b1696b1,
+    0x9bdc06a725c71235,
+    0xc19bf174cf692694,
+    0xe49b69c19ef14ad2,
+    0xefbe4786384f25e3,
+    0x0fc19dc68b8cd5b5,
+    0x240ca1cc77ac9c65,
+    0x2de92c6f592b0275,
+    0x4a7484aa6ea6e483,
+    0x5cb0a9dcbd41fbd4,
+    0x76f988da831153b5,
+    0x983e5152ee66dfab,
+    0xa831c66d2db43210,
+    0xb00327c898fb213f,
+    0xbf597fc7beef0ee4,
+    0xc6e00bf33da88fc2,
+    0xd5a79147930aa725,
+    0x06ca6351e003826f,
+    0x142929670a0e6e70,
+    0x27b70a8546d22ffc,
+    0x2e1b21385c26c926,
+    0x4d2c6dfc5ac42aed,
+    0x53380d139d95b3df,
+    0x650a73548baf63de,
+    0x766a0abb3c77b2a8,
+    0x81c2c92e47edaee6,
+    0x92722c851482353b,
+    0xa2bfe8a14cf10364,
+    0xa81a664bbc423001,
+    0xc24b8b70d0f89791,
+    0xc76c51a30654be30,
+    0xd192e819d6ef5218,
+    0xd69906245565a910,
+    0xf40e35855771202a,
+    0x106aa07032bbd1b8,
+    0x19a4c116b8d2d0c8,
+    0x1e376c085141ab53,
+    0x2748774cdf8eeb99,
+    0x34b0bcb5e19b48a8,
+    0x391c0cb3c5c95a63,
+    0x4ed8aa4ae3418acb,
+    0x5b9cca4f7763e373,
+    0x682e6ff3d6b2b8a3,
+    0x748f82ee5defb2fc,
+    0x78a5636f43172f60,
+    0x84c87814a1f0ab72,
+    0x8cc702081a6439ec,
+    0x90befffa23631e28,
+    0xa4506cebde82bde9,
+    0xbef9a3f7b2c67915,
+    0xc67178f2e372532b,
+    0xca273eceea26619c,
+    0xd186b8c721c0c207,
+    0xeada7dd6cde0eb1e,
+    0xf57d4f7fee6ed178,
+    0x06f067aa72176fba,
+    0x0a637dc5a2c898a6,
+    0x113f9804bef90dae,
+    0x1b710b35131c471b,
+    0x28db77f523047d84,
+    0x32caab7b40c72493,
+    0x3c9ebe0a15c9bebc,
+    0x431d67c49c100d4c,
+    0x4cc5d4becb3e42b6,
+    0x597f299cfc657e2a,
+    0x5fcb6fab3ad6faec,
+    0x6c44198c4a475817,
+];
+
+/// SHA-512 over data fed in as it arrives.
+struct Sha512 {
+    h: [u64; 8],
+    block: [u8; 128],
+    len: u64,
+}
+
+impl Sha512 {
+    fn new() -> Self {
+        Self {
+            h: [
+                0x6a09e667f3bcc908,
+                0xbb67ae8584caa73b,
+                0x3c6ef372fe94f82b,
+                0xa54ff53a5f1d36f1,
+                0x510e527fade682d1,
+                0x9b05688c2b3e6c1f,
+                0x1f83d9abfb41bd6b,
+                0x5be0cd19137e2179,
+            ],
+            block: [0; 128],
+            len: 0,
+        }
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u64; 80];
+        for (i, word) in self.block.chunks_exact(8).enumerate() {
+            w[i] = u64::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            let s0 = w[i - 15].rotate_right(1) ^ w[i - 15].rotate_right(8) ^ (w[i - 15] >> 7);
+            let s1 = w[i - 2].rotate_right(19) ^ w[i - 2].rotate_right(61) ^ (w[i - 2] >> 6);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.h;
+        for (k, w) in K.iter().zip(w) {
+            let s1 = e.rotate_right(14) ^ e.rotate_right(18) ^ e.rotate_right(41);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(*k)
+                .wrapping_add(w);
+            let s0 = a.rotate_right(28) ^ a.rotate_right(34) ^ a.rotate_right(39);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            (h, g, f, e, d, c, b, a) = (g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2));
+        }
+        for (h, v) in self.h.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let used = (self.len % 128) as usize;
+            let len = (128 - used).min(data.len());
+            self.block[used..used + len].copy_from_slice(&data[..len]);
+            self.len += len as u64;
+            data = &data[len..];
+            if used + len == 128 {
+                self.compress();
+            }
+        }
+    }
+
+    fn finish(mut self) -> [u8; 64] {
+        let bits = self.len as u128 * 8;
+        self.update(&[0x80]);
+        while self.len % 128 != 112 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+
+        let mut digest = [0u8; 64];
+        for (out, h) in digest.chunks_exact_mut(8).zip(self.h) {
+            out.copy_from_slice(&h.to_be_bytes());
+        }
+        digest
+    }
+}
+
+fn carry(o: &mut Fe) {
+    for i in 0..16 {
+        o[i] += 1 << 16;
+        let c = o[i] >> 16;
+        if i < 15 {
+            o[i + 1] += c - 1;
+        } else {
+            o[0] += 38 * (c - 1);
+        }
+        o[i] -= c << 16;
+    }
+}
+
+fn add(a: &Fe, b: &Fe) -> Fe {
+    core::array::from_fn(|i| a[i] + b[i])
+}
+
+fn sub(a: &Fe, b: &Fe) -> Fe {
+    core::array::from_fn(|i| a[i] - b[i])
+}
+
+fn mul(a: &Fe, b: &Fe) -> Fe {
+    let mut t = [0i64; 31];
+    for i in 0..16 {
+        for j in 0..16 {
+            t[i + j] += a[i] * b[j];
+        }
+    }
+    for i in 0..15 {
+        t[i] += 38 * t[i + 16];
+    }
+    let mut o: Fe = t[..16].try_into().unwrap();
+    carry(&mut o);
+    carry(&mut o);
+    o
+}
+
+fn square(a: &Fe) -> Fe {
+    mul(a, a)
+}
+
+/// a^(p - 2).
+fn invert(a: &Fe) -> Fe {
+    let mut c = *a;
+    for i in (0..=253).rev() {
+        c = square(&c);
+        if i != 2 && i != 4 {
+            c = mul(&c, a);
+        }
+    }
+    c
+}
+
+/// a^((p - 5) / 8).
+fn pow2523(a: &Fe) -> Fe {
+    let mut c = *a;
+    for i in (0..=250).rev() {
+        c = square(&c);
+        if i != 1 {
+            c = mul(&c, a);
+        }
+    }
+    c
+}
+
+/// Fully reduced, little endian.
+fn pack(n: &Fe) -> [u8; 32] {
+    let mut t = *n;
+    carry(&mut t);
+    carry(&mut t);
+    carry(&mut t);
+    for _ in 0..2 {
+        let mut m = [0i64; 16];
+        m[0] = t[0] - 0xffed;
+        for i in 1..15 {
+            m[i] = t[i] - 0xffff - ((m[i - 1] >> 16) & 1);
+            m[i - 1] &= 0xffff;
+        }
+        m[15] = t[15] - 0x7fff - ((m[14] >> 16) & 1);
+        m[14] &= 0xffff;
+        if (m[15] >> 16) & 1 == 0 {
+            t = m;
+        }
+    }
+    let mut out = [0u8; 32];
+    for (out, t) in out.chunks_exact_mut(2).zip(t) {
+        out.copy_from_slice(&(t as u16).to_le_bytes());
+    }
+    out
+}
+
+fn unpack(n: &[u8; 32]) -> Fe {
+    let mut o: Fe = core::array::from_fn(|i| u16::from_le_bytes([n[2 * i], n[2 * i + 1]]) as i64);
+    o[15] &= 0x7fff;
+    o
+}
+
+fn parity(a: &Fe) -> u8 {
+    pack(a)[0] & 1
+}
+
+fn point_add(p: &Point, q: &Point) -> Point {
+    let a = mul(&sub(&p[1], &p[0]), &sub(&q[1], &q[0]));
+    let b = mul(&add(&p[0], &p[1]), &add(&q[0], &q[1]));
+    let c = mul(&mul(&p[3], &q[3]), &D2);
+    let d = mul(&p[2], &q[2]);
+    let d = add(&d, &d);
+    let (e, f, g, h) = (sub(&b, &a), sub(&d, &c), add(&d, &c), add(&b, &a));
+    [mul(&e, &f), mul(&h, &g), mul(&g, &f), mul(&e, &h)]
+}
+
+/// `s` times `q`, for a little endian scalar.
+fn scalar_mul(q: &Point, s: &[u8; 32]) -> Point {
+    let mut p = [GF0, GF1, GF1, GF0];
+    for i in (0..256).rev() {
+        p = point_add(&p, &p);
+        if (s[i / 8] >> (i % 8)) & 1 == 1 {
+            p = point_add(&p, q);
+        }
+    }
+    p
+}
+
+fn pack_point(p: &Point) -> [u8; 32] {
+    let zi = invert(&p[2]);
+    let mut r = pack(&mul(&p[1], &zi));
+    r[31] ^= parity(&mul(&p[0], &zi)) << 7;
+    r
+}
+
+/// The negation of the point encoded in `p`, if `p` is a valid encoding.
+fn unpack_neg(p: &[u8; 32]) -> Option<Point> {
+    let y = unpack(p);
+    let mut canonical = *p;
+    canonical[31] &= 0x7f;
+    if pack(&y) != canonical {
+        return None;
+    }
+
+    // x^2 = (y^2 - 1) / (d y^2 + 1)
+    let num = square(&y);
+    let den = add(&GF1, &mul(&num, &D));
+    let num = sub(&num, &GF1);
+    let den2 = square(&den);
+    let den4 = square(&den2);
+    let den6 = mul(&den4, &den2);
+    let t = mul(&mul(&den6, &num), &den);
+    let t = mul(&mul(&mul(&pow2523(&t), &num), &den), &den);
+    let mut x = mul(&t, &den);
+    if pack(&mul(&square(&x), &den)) != pack(&num) {
+        x = mul(&x, &I);
+    }
+    if pack(&mul(&square(&x), &den)) != pack(&num) {
+        return None;
+    }
+    if parity(&x) == p[31] >> 7 {
+        x = sub(&GF0, &x);
+    }
+    Some([x, y, GF1, mul(&x, &y)])
+}
+
+/// A 512 bit little endian number mod L.
+fn mod_l(x: &mut [i64; 64]) -> [u8; 32] {
+    for i in (32..64).rev() {
+        let mut carry = 0;
+        let mut j = i - 32;
+        while j < i - 12 {
+            x[j] += carry - 16 * x[i] * L[j - (i - 32)];
+            carry = (x[j] + 128) >> 8;
+            x[j] -= carry << 8;
+            j += 1;
+        }
+        x[j] += carry;
+        x[i] = 0;
+    }
+    let mut carry = 0;
+    for j in 0..32 {
+        x[j] += carry - (x[31] >> 4) * L[j];
+        carry = x[j] >> 8;
+        x[j] &= 0xff;
+    }
+    for j in 0..32 {
+        x[j] -= carry * L[j];
+    }
+    let mut r = [0u8; 32];
+    for i in 0..32 {
+        x[i + 1] += x[i] >> 8;
+        r[i] = x[i] as u8;
+    }
+    r
+}
+
+/// Whether the little endian scalar `s` is below L, as RFC 8032 requires
+/// of the second half of a signature.
+fn below_l(s: &[u8; 32]) -> bool {
+    for (s, l) in s.iter().zip(L).rev() {
+        if *s as i64 != l {
+            return (*s as i64) < l;
+        }
+    }
+    false
+}
+
+/// Whether `signature` is the signature of `message` by the holder of
+/// `public_key`.
+pub fn verify(public_key: &[u8; 32], message: &[u8], signature: &[u8; 64]) -> bool {
+    let (r, s) = signature.split_at(32);
+    let s: &[u8; 32] = s.try_into().unwrap();
+    if !below_l(s) {
+        return false;
+    }
+    let Some(neg_a) = unpack_neg(public_key) else {
+        return false;
+    };
+
+    let mut sha = Sha512::new();
+    sha.update(r);
+    sha.update(public_key);
+    sha.update(message);
+    let mut h = [0i64; 64];
+    for (h, b) in h.iter_mut().zip(sha.finish()) {
+        *h = b as i64;
+    }
+    let h = mod_l(&mut h);
+
+    // R has to be sB - hA.
+    let base = [X, Y, GF1, mul(&X, &Y)];
+    let p = point_add(&scalar_mul(&base, s), &scalar_mul(&neg_a, &h));
+    pack_point(&p) == r
+}
@@ -0,0 +1,284 @@
+use num_derive::{FromPrimitive, ToPrimitive};
+
+use crate::color::{palette, palette_name, scale8, Blend, Hsv, Rgb};
+use crate::matrix::Layout;
+
+/// Fixed point sine where a full turn is 256 and the output is biased so
+/// that it spans 0..=255.
+pub fn sin8(theta: u8) -> u8 {
+    // Quarter wave table, 0..=64 inclusive.
+    const QUARTER: [u8; 65] = [
+        0, 3, 6, 9, 12, 16, 19, 22, 25, 28, 31, 34, 37, 40, 43, 46, 49, 51, 54, 57, 60, 63, 65, 68,
+        71, 73, 76, 78, 81, 83, 85, 88, 90, 92, 94, 96, 98, 100, 102, 104, 106, 107, 109, 111, 112,
+        113, 115, 116, 117, 118, 120, 121, 122, 122, 123, 124, 125, 125, 126, 126, 126, 127, 127,
+        127, 127,
+    ];
+    let quadrant = theta >> 6;
+    let offset = (theta & 0x3f) as usize;
+    let val = match quadrant {
+        0 => QUARTER[offset] as i16,
+        1 => QUARTER[64 - offset] as i16,
+        2 => -(QUARTER[offset] as i16),
+        _ => -(QUARTER[64 - offset] as i16),
+    };
+    (val + 128) as u8
+}
+
+pub fn cos8(theta: u8) -> u8 {
+    sin8(theta.wrapping_add(64))
+}
+
+/// Cheap integer hash used to derive repeatable pseudo random values.
+pub fn hash(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^= x >> 16;
+    x
+}
+
+fn ease8(t: u8) -> u8 {
+    // Smoothstep: 3t^2 - 2t^3
+    let t = t as u32;
+    ((t * t * (3 * 255 - 2 * t)) / (255 * 255)) as u8
+}
+
+/// 1D value noise.  `x` is a 24.8 fixed point coordinate.
+pub fn noise1(x: u32) -> u8 {
+    let cell = x >> 8;
+    let frac = ease8(x as u8);
+    let a = hash(cell) as u8;
+    let b = hash(cell.wrapping_add(1)) as u8;
+    crate::color::lerp8(a, b, frac)
+}
+
+/// 2D value noise.  `x` and `y` are 24.8 fixed point coordinates.
+pub fn noise2(x: u32, y: u32) -> u8 {
+    let (cx, cy) = (x >> 8, y >> 8);
+    let (fx, fy) = (ease8(x as u8), ease8(y as u8));
+    let corner = |dx: u32, dy: u32| hash(cx.wrapping_add(dx) ^ hash(cy.wrapping_add(dy))) as u8;
+    let top = crate::color::lerp8(corner(0, 0), corner(1, 0), fx);
+    let bottom = crate::color::lerp8(corner(0, 1), corner(1, 1), fx);
+    crate::color::lerp8(top, bottom, fy)
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, FromPrimitive, ToPrimitive)]
+#[repr(u8)]
+pub enum EffectKind {
+    Solid = 0,
+    Rainbow = 1,
+    Palette = 2,
+    Plasma = 3,
+    Rain = 4,
+    Noise = 5,
+    Plasma2d = 6,
+    Rain2d = 7,
+    Noise2d = 8,
+}
+
+const EFFECT_NAMES: [(&str, EffectKind); 9] = [
+    ("solid", EffectKind::Solid),
+    ("rainbow", EffectKind::Rainbow),
+    ("palette", EffectKind::Palette),
+    ("plasma", EffectKind::Plasma),
+    ("rain", EffectKind::Rain),
+    ("noise", EffectKind::Noise),
+    ("plasma2d", EffectKind::Plasma2d),
+    ("rain2d", EffectKind::Rain2d),
+    ("noise2d", EffectKind::Noise2d),
+];
+
+impl EffectKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        EFFECT_NAMES
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, kind)| *kind)
+    }
+
+    pub fn name(&self) -> &'static str {
+        EFFECT_NAMES
+            .iter()
+            .find(|(_, kind)| kind == self)
+            .map(|(n, _)| *n)
+            .unwrap_or("unknown")
+    }
+
+    pub fn is_2d(&self) -> bool {
+        matches!(
+            self,
+            EffectKind::Plasma2d | EffectKind::Rain2d | EffectKind::Noise2d
+        )
+    }
+}
+
+/// A locally generated effect.
+///
+/// Effects are pure functions of time and pixel index so that the same
+/// effect rendered on two nodes with the same clock produces the same frame.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Effect {
+    pub kind: EffectKind,
+    /// Index into [`crate::color::BUILTIN_PALETTES`].
+    pub palette: u8,
+    pub speed: u8,
+    /// Spatial scale; larger values pack more of the pattern into a strip.
+    pub scale: u8,
+    pub color: Rgb,
+}
+
+impl Effect {
+    pub const fn new(kind: EffectKind) -> Self {
+        Self {
+            kind,
+            palette: 0,
+            speed: 128,
+            scale: 128,
+            color: Rgb::WHITE,
+        }
+    }
+
+    pub fn palette_name(&self) -> &'static str {
+        palette_name(self.palette)
+    }
+
+    /// Animation phase for `time_ms`.  A speed of 128 advances the phase by
+    /// roughly 64 steps per second.
+    fn phase(&self, time_ms: u32) -> u32 {
+        time_ms.wrapping_mul(self.speed as u32) / 2000
+    }
+
+    /// Render the effect into `pixels`.
+    ///
+    /// 2D effects look up the coordinates of each pixel in `layout`, where
+    /// `pixels[0]` is strip pixel `first`.  Without a layout the pixels are
+    /// treated as a single row.
+    pub fn render(&self, time_ms: u32, pixels: &mut [Rgb], layout: Option<&Layout>, first: usize) {
+        if self.kind.is_2d() {
+            for (i, pixel) in pixels.iter_mut().enumerate() {
+                let (x, y) = layout
+                    .and_then(|l| l.coords(first + i))
+                    .unwrap_or((i as u16, 0));
+                *pixel = self.pixel_2d(time_ms, x, y);
+            }
+            return;
+        }
+
+        let phase = self.phase(time_ms);
+        let scale = self.scale as u32;
+        let palette = palette(self.palette);
+
+        match self.kind {
+            EffectKind::Solid => pixels.fill(self.color),
+            EffectKind::Rainbow => {
+                for (i, pixel) in pixels.iter_mut().enumerate() {
+                    let hue = (i as u32 * scale / 16).wrapping_add(phase);
+                    *pixel = Hsv::new(hue as u8, 255, 255).into();
+                }
+            }
+            EffectKind::Palette => {
+                for (i, pixel) in pixels.iter_mut().enumerate() {
+                    let pos = (i as u32 * scale / 16).wrapping_add(phase);
+                    *pixel = palette.color_at(pos as u8, Blend::Linear);
+                }
+            }
+            EffectKind::Plasma => {
+                for (i, pixel) in pixels.iter_mut().enumerate() {
+                    let x = i as u32 * scale / 32;
+                    let a = sin8(x.wrapping_add(phase) as u8) as u16;
+                    let b = sin8((x / 2).wrapping_sub(phase / 2) as u8) as u16;
+                    let c = cos8((x / 3).wrapping_add(phase / 3) as u8) as u16;
+                    *pixel = palette.color_at(((a + b + c) / 3) as u8, Blend::Linear);
+                }
+            }
+            EffectKind::Rain => {
+                for (i, pixel) in pixels.iter_mut().enumerate() {
+                    let level = rain((i as u32).wrapping_add(phase / 4), self.scale, 0);
+                    *pixel = palette.color_at(level, Blend::Linear).scale(level);
+                }
+            }
+            EffectKind::Noise => {
+                for (i, pixel) in pixels.iter_mut().enumerate() {
+                    let x = (i as u32 * scale).wrapping_add(phase * 4);
+                    *pixel = palette.color_at(noise1(x), Blend::Linear);
+                }
+            }
+            EffectKind::Plasma2d | EffectKind::Rain2d | EffectKind::Noise2d => (),
+        }
+    }
+
+    /// Colour of a 2D effect at display coordinates (`x`, `y`).
+    pub fn pixel_2d(&self, time_ms: u32, x: u16, y: u16) -> Rgb {
+        let phase = self.phase(time_ms);
+        let scale = self.scale as u32;
+        let palette = palette(self.palette);
+        let (x, y) = (x as u32, y as u32);
+
+        match self.kind {
+            EffectKind::Plasma2d => {
+                let (sx, sy) = (x * scale / 16, y * scale / 16);
+                let a = sin8(sx.wrapping_add(phase) as u8) as u16;
+                let b = sin8(sy.wrapping_sub(phase / 2) as u8) as u16;
+                let c = sin8(((sx + sy) / 2).wrapping_add(phase / 3) as u8) as u16;
+                let d = cos8((isqrt(sx * sx + sy * sy) as u32).wrapping_sub(phase) as u8) as u16;
+                palette.color_at(((a + b + c + d) / 4) as u8, Blend::Linear)
+            }
+            EffectKind::Rain2d => {
+                // Every column falls at its own speed from its own offset.
+                let column = hash(x);
+                let speed = 2 + column % 3;
+                let pos = (phase * speed / 8)
+                    .wrapping_add(column >> 8)
+                    .wrapping_sub(y);
+                let level = rain(pos, self.scale, column);
+                if level == 255 {
+                    // Drop heads are drawn brighter than their trails.
+                    Rgb::WHITE.blend(self.color, 128)
+                } else {
+                    self.color.scale(level)
+                }
+            }
+            EffectKind::Noise2d => {
+                let nx = (x * scale).wrapping_add(phase * 4);
+                let ny = (y * scale).wrapping_add(phase * 2);
+                palette.color_at(noise2(nx, ny), Blend::Linear)
+            }
+            _ => Rgb::BLACK,
+        }
+    }
+}
+
+fn isqrt(val: u32) -> u16 {
+    let mut root = 0u32;
+    let mut bit = 1u32 << 30;
+    let mut val = val;
+    while bit > val {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if val >= root + bit {
+            val -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root as u16
+}
+
+const RAIN_TRAIL: u32 = 8;
+
+/// Brightness of a falling drop field at `pos`.  Drop heads are scattered
+/// pseudo randomly with a density controlled by `density` and each head
+/// leaves a fading trail behind it.  Different `seed`s give independent
+/// fields.
+fn rain(pos: u32, density: u8, seed: u32) -> u8 {
+    let threshold = density as u32 / 8 + 1;
+    (0..RAIN_TRAIL)
+        .filter(|k| hash(pos.wrapping_sub(*k) ^ seed) % 64 < threshold)
+        .map(|k| scale8(255, (255 - k * (256 / RAIN_TRAIL)) as u8))
+        .max()
+        .unwrap_or(0)
+}
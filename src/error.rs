@@ -1,7 +1,9 @@
 use core::convert::Infallible;
 use embassy_net::tcp;
 
+use crate::buffer;
 use crate::hal;
+use crate::http;
 
 pub enum Error {
     I2cError(hal::i2c::Error),
@@ -11,7 +13,11 @@ pub enum Error {
     Index,
     Infallible,
     Tcp(tcp::Error),
+    Buffer(buffer::Error),
+    Flash(esp_storage::FlashStorageError),
     Generic(&'static str),
+    /// Fail an HTTP request with a specific status.
+    Http(http::Status),
 }
 
 impl core::fmt::Debug for Error {
@@ -24,7 +30,10 @@ impl core::fmt::Debug for Error {
             Self::Index => write!(f, "Index error"),
             Self::Infallible => write!(f, "Infalible"),
             Self::Tcp(arg0) => f.debug_tuple("TcpError").field(arg0).finish(),
+            Self::Buffer(arg0) => f.debug_tuple("BufferError").field(arg0).finish(),
+            Self::Flash(arg0) => f.debug_tuple("FlashError").field(arg0).finish(),
             Self::Generic(arg0) => f.debug_tuple("GenericError").field(arg0).finish(),
+            Self::Http(arg0) => write!(f, "HTTP {} {}", arg0.code(), arg0.reason()),
         }
     }
 }
@@ -55,4 +64,16 @@ impl From<tcp::Error> for Error {
     }
 }
 
+impl From<buffer::Error> for Error {
+    fn from(value: buffer::Error) -> Self {
+        Self::Buffer(value)
+    }
+}
+
+impl From<esp_storage::FlashStorageError> for Error {
+    fn from(value: esp_storage::FlashStorageError) -> Self {
+        Self::Flash(value)
+    }
+}
+
 pub type Result<T> = core::result::Result<T, Error>;
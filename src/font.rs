@@ -0,0 +1,257 @@
+//! Bitmap fonts for text on LED matrices.
+//!
+//! Glyphs cover printable ASCII (0x20..=0x7e) and are stored column by
+//! column, one byte per column with bit 0 as the top row.
+
+pub struct Font {
+    pub name: &'static str,
+    pub width: u8,
+    pub height: u8,
+    glyphs: &'static [u8],
+}
+
+impl Font {
+    /// Columns of the glyph for `c`.  Characters outside the font render as
+    /// `?`.
+    pub fn glyph(&self, c: u8) -> &'static [u8] {
+        let c = if (0x20..0x7f).contains(&c) { c } else { b'?' };
+        let start = (c - 0x20) as usize * self.width as usize;
+        &self.glyphs[start..start + self.width as usize]
+    }
+
+    /// Horizontal distance from the start of one glyph to the next.
+    pub fn advance(&self) -> u16 {
+        self.width as u16 + 1
+    }
+
+    /// Width in pixels of `text` rendered in this font.
+    pub fn text_width(&self, text: &[u8]) -> u16 {
+        (text.len() as u16 * self.advance()).saturating_sub(1)
+    }
+}
+
+pub const FONTS: [Font; 2] = [
+    Font {
+        name: "5x7",
+        width: 5,
+        height: 7,
+        glyphs: &GLYPHS_5X7,
+    },
+    // Lower case letters share the upper case glyphs.
+    Font {
+        name: "3x5",
+        width: 3,
+        height: 5,
+        glyphs: &GLYPHS_3X5,
+    },
+];
+
+pub fn font_index(name: &str) -> Option<u8> {
+    FONTS
+        .iter()
+        .position(|font| font.name == name)
+        .map(|i| i as u8)
+}
+
+/// Look up a font by index, falling back to the first font.
+pub fn font(index: u8) -> &'static Font {
+    FONTS.get(index as usize).unwrap_or(&FONTS[0])
+}
+
+#[rustfmt::skip]
+const GLYPHS_5X7: [u8; 95 * 5] = [
+    0x00, 0x00, 0x00, 0x00, 0x00, // space
+    0x00, 0x00, 0x5f, 0x00, 0x00, // !
+    0x00, 0x07, 0x00, 0x07, 0x00, // "
+    0x14, 0x7f, 0x14, 0x7f, 0x14, // #
+    0x24, 0x2a, 0x7f, 0x2a, 0x12, // $
+    0x23, 0x13, 0x08, 0x64, 0x62, // %
+    0x36, 0x49, 0x55, 0x22, 0x50, // &
+    0x00, 0x05, 0x03, 0x00, 0x00, // '
+    0x00, 0x1c, 0x22, 0x41, 0x00, // (
+    0x00, 0x41, 0x22, 0x1c, 0x00, // )
+    0x2a, 0x1c, 0x7f, 0x1c, 0x2a, // *
+    0x08, 0x08, 0x3e, 0x08, 0x08, // +
+    0x00, 0x50, 0x30, 0x00, 0x00, // ,
+    0x08, 0x08, 0x08, 0x08, 0x08, // -
+    0x00, 0x60, 0x60, 0x00, 0x00, // .
+    0x20, 0x10, 0x08, 0x04, 0x02, // /
+    0x3e, 0x51, 0x49, 0x45, 0x3e, // 0
+    0x00, 0x42, 0x7f, 0x40, 0x00, // 1
+    0x42, 0x61, 0x51, 0x49, 0x46, // 2
+    0x21, 0x41, 0x45, 0x4b, 0x31, // 3
+    0x18, 0x14, 0x12, 0x7f, 0x10, // 4
+    0x27, 0x45, 0x45, 0x45, 0x39, // 5
+    0x3c, 0x4a, 0x49, 0x49, 0x30, // 6
+    0x01, 0x71, 0x09, 0x05, 0x03, // 7
+    0x36, 0x49, 0x49, 0x49, 0x36, // 8
+    0x06, 0x49, 0x49, 0x29, 0x1e, // 9
+    0x00, 0x36, 0x36, 0x00, 0x00, // :
+    0x00, 0x56, 0x36, 0x00, 0x00, // ;
+    0x08, 0x14, 0x22, 0x41, 0x00, // <
+    0x14, 0x14, 0x14, 0x14, 0x14, // =
+    0x00, 0x41, 0x22, 0x14, 0x08, // >
+    0x02, 0x01, 0x51, 0x09, 0x06, // ?
+    0x32, 0x49, 0x79, 0x41, 0x3e, // @
+    0x7e, 0x11, 0x11, 0x11, 0x7e, // A
+    0x7f, 0x49, 0x49, 0x49, 0x36, // B
+    0x3e, 0x41, 0x41, 0x41, 0x22, // C
+    0x7f, 0x41, 0x41, 0x22, 0x1c, // D
+    0x7f, 0x49, 0x49, 0x49, 0x41, // E
+    0x7f, 0x09, 0x09, 0x09, 0x01, // F
+    0x3e, 0x41, 0x49, 0x49, 0x7a, // G
+    0x7f, 0x08, 0x08, 0x08, 0x7f, // H
+    0x00, 0x41, 0x7f, 0x41, 0x00, // I
+    0x20, 0x40, 0x41, 0x3f, 0x01, // J
+    0x7f, 0x08, 0x14, 0x22, 0x41, // K
+    0x7f, 0x40, 0x40, 0x40, 0x40, // L
+    0x7f, 0x02, 0x0c, 0x02, 0x7f, // M
+    0x7f, 0x04, 0x08, 0x10, 0x7f, // N
+    0x3e, 0x41, 0x41, 0x41, 0x3e, // O
+    0x7f, 0x09, 0x09, 0x09, 0x06, // P
+    0x3e, 0x41, 0x51, 0x21, 0x5e, // Q
+    0x7f, 0x09, 0x19, 0x29, 0x46, // R
+    0x46, 0x49, 0x49, 0x49, 0x31, // S
+    0x01, 0x01, 0x7f, 0x01, 0x01, // T
+    0x3f, 0x40, 0x40, 0x40, 0x3f, // U
+    0x1f, 0x20, 0x40, 0x20, 0x1f, // V
+    0x3f, 0x40, 0x38, 0x40, 0x3f, // W
+    0x63, 0x14, 0x08, 0x14, 0x63, // X
+    0x07, 0x08, 0x70, 0x08, 0x07, // Y
+    0x61, 0x51, 0x49, 0x45, 0x43, // Z
+    0x00, 0x7f, 0x41, 0x41, 0x00, // [
+    0x02, 0x04, 0x08, 0x10, 0x20, // \
+    0x00, 0x41, 0x41, 0x7f, 0x00, // ]
+    0x04, 0x02, 0x01, 0x02, 0x04, // ^
+    0x40, 0x40, 0x40, 0x40, 0x40, // _
+    0x00, 0x01, 0x02, 0x04, 0x00, // `
+    0x20, 0x54, 0x54, 0x54, 0x78, // a
+    0x7f, 0x48, 0x44, 0x44, 0x38, // b
+    0x38, 0x44, 0x44, 0x44, 0x20, // c
+    0x38, 0x44, 0x44, 0x48, 0x7f, // d
+    0x38, 0x54, 0x54, 0x54, 0x18, // e
+    0x08, 0x7e, 0x09, 0x01, 0x02, // f
+    0x0c, 0x52, 0x52, 0x52, 0x3e, // g
+    0x7f, 0x08, 0x04, 0x04, 0x78, // h
+    0x00, 0x44, 0x7d, 0x40, 0x00, // i
+    0x20, 0x40, 0x44, 0x3d, 0x00, // j
+    0x7f, 0x10, 0x28, 0x44, 0x00, // k
+    0x00, 0x41, 0x7f, 0x40, 0x00, // l
+    0x7c, 0x04, 0x18, 0x04, 0x78, // m
+    0x7c, 0x08, 0x04, 0x04, 0x78, // n
+    0x38, 0x44, 0x44, 0x44, 0x38, // o
+    0x7c, 0x14, 0x14, 0x14, 0x08, // p
+    0x08, 0x14, 0x14, 0x18, 0x7c, // q
+    0x7c, 0x08, 0x04, 0x04, 0x08, // r
+    0x48, 0x54, 0x54, 0x54, 0x20, // s
+    0x04, 0x3f, 0x44, 0x40, 0x20, // t
+    0x3c, 0x40, 0x40, 0x20, 0x7c, // u
+    0x1c, 0x20, 0x40, 0x20, 0x1c, // v
+    0x3c, 0x40, 0x30, 0x40, 0x3c, // w
+    0x44, 0x28, 0x10, 0x28, 0x44, // x
+    0x0c, 0x50, 0x50, 0x50, 0x3c, // y
+    0x44, 0x64, 0x54, 0x4c, 0x44, // z
+    0x00, 0x08, 0x36, 0x41, 0x00, // {
+    0x00, 0x00, 0x7f, 0x00, 0x00, // |
+    0x00, 0x41, 0x36, 0x08, 0x00, // }
+    0x08, 0x04, 0x08, 0x10, 0x08, // ~
+];
+
+#[rustfmt::skip]
+const GLYPHS_3X5: [u8; 95 * 3] = [
+    0x00, 0x00, 0x00, // space
+    0x00, 0x17, 0x00, // !
+    0x03, 0x00, 0x03, // "
+    0x1f, 0x0a, 0x1f, // #
+    0x12, 0x1f, 0x09, // $
+    0x09, 0x04, 0x12, // %
+    0x0a, 0x15, 0x1a, // &
+    0x00, 0x03, 0x00, // '
+    0x00, 0x0e, 0x11, // (
+    0x11, 0x0e, 0x00, // )
+    0x05, 0x02, 0x05, // *
+    0x04, 0x0e, 0x04, // +
+    0x10, 0x08, 0x00, // ,
+    0x04, 0x04, 0x04, // -
+    0x00, 0x10, 0x00, // .
+    0x18, 0x04, 0x03, // /
+    0x1f, 0x11, 0x1f, // 0
+    0x12, 0x1f, 0x10, // 1
+    0x19, 0x15, 0x12, // 2
+    0x11, 0x15, 0x0a, // 3
+    0x07, 0x04, 0x1f, // 4
+    0x17, 0x15, 0x09, // 5
+    0x1e, 0x15, 0x1d, // 6
+    0x01, 0x1d, 0x03, // 7
+    0x1f, 0x15, 0x1f, // 8
+    0x17, 0x15, 0x0f, // 9
+    0x00, 0x0a, 0x00, // :
+    0x10, 0x0a, 0x00, // ;
+    0x04, 0x0a, 0x11, // <
+    0x0a, 0x0a, 0x0a, // =
+    0x11, 0x0a, 0x04, // >
+    0x01, 0x15, 0x02, // ?
+    0x0e, 0x15, 0x16, // @
+    0x1e, 0x05, 0x1e, // A
+    0x1f, 0x15, 0x0a, // B
+    0x0e, 0x11, 0x11, // C
+    0x1f, 0x11, 0x0e, // D
+    0x1f, 0x15, 0x11, // E
+    0x1f, 0x05, 0x01, // F
+    0x0e, 0x11, 0x1d, // G
+    0x1f, 0x04, 0x1f, // H
+    0x11, 0x1f, 0x11, // I
+    0x08, 0x10, 0x0f, // J
+    0x1f, 0x04, 0x1b, // K
+    0x1f, 0x10, 0x10, // L
+    0x1f, 0x06, 0x1f, // M
+    0x1f, 0x01, 0x1e, // N
+    0x0e, 0x11, 0x0e, // O
+    0x1f, 0x05, 0x02, // P
+    0x0e, 0x19, 0x1e, // Q
+    0x1f, 0x05, 0x1a, // R
+    0x12, 0x15, 0x09, // S
+    0x01, 0x1f, 0x01, // T
+    0x0f, 0x10, 0x1f, // U
+    0x07, 0x18, 0x07, // V
+    0x1f, 0x0c, 0x1f, // W
+    0x1b, 0x04, 0x1b, // X
+    0x03, 0x1c, 0x03, // Y
+    0x19, 0x15, 0x13, // Z
+    0x1f, 0x11, 0x11, // [
+    0x03, 0x04, 0x18, // \
+    0x11, 0x11, 0x1f, // ]
+    0x02, 0x01, 0x02, // ^
+    0x10, 0x10, 0x10, // _
+    0x01, 0x02, 0x00, // `
+    0x1e, 0x05, 0x1e, // a
+    0x1f, 0x15, 0x0a, // b
+    0x0e, 0x11, 0x11, // c
+    0x1f, 0x11, 0x0e, // d
+    0x1f, 0x15, 0x11, // e
+    0x1f, 0x05, 0x01, // f
+    0x0e, 0x11, 0x1d, // g
+    0x1f, 0x04, 0x1f, // h
+    0x11, 0x1f, 0x11, // i
+    0x08, 0x10, 0x0f, // j
+    0x1f, 0x04, 0x1b, // k
+    0x1f, 0x10, 0x10, // l
+    0x1f, 0x06, 0x1f, // m
+    0x1f, 0x01, 0x1e, // n
+    0x0e, 0x11, 0x0e, // o
+    0x1f, 0x05, 0x02, // p
+    0x0e, 0x19, 0x1e, // q
+    0x1f, 0x05, 0x1a, // r
+    0x12, 0x15, 0x09, // s
+    0x01, 0x1f, 0x01, // t
+    0x0f, 0x10, 0x1f, // u
+    0x07, 0x18, 0x07, // v
+    0x1f, 0x0c, 0x1f, // w
+    0x1b, 0x04, 0x1b, // x
+    0x03, 0x1c, 0x03, // y
+    0x19, 0x15, 0x13, // z
+    0x04, 0x1f, 0x11, // {
+    0x00, 0x1f, 0x00, // |
+    0x11, 0x1f, 0x04, // }
+    0x02, 0x06, 0x04, // ~
+];
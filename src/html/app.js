@@ -0,0 +1,682 @@
+"use strict";
+
+const EFFECTS = ["solid", "rainbow", "palette", "plasma", "rain", "noise", "plasma2d", "rain2d", "noise2d"];
+const PALETTES = ["rainbow", "party", "ocean", "forest", "lava", "heat", "cloud", "sunset"];
+
+const $ = (selector) => document.querySelector(selector);
+
+function log(message) {
+	const pre = $("#log");
+	const time = new Date().toLocaleTimeString();
+	pre.textContent = `${time} ${message}\n` + pre.textContent.slice(0, 4000);
+}
+
+async function request(method, path, body) {
+	const options = { method };
+	if (body !== undefined) {
+		options.body = body;
+		if (typeof body === "string") {
+			options.headers = { "Content-Type": "application/x-www-form-urlencoded" };
+		}
+	}
+	const response = await fetch(path, options);
+	const text = await response.text();
+	if (!response.ok) {
+		let message = text;
+		try {
+			message = JSON.parse(text).error.message;
+		} catch (e) { }
+		throw new Error(`${method} ${path}: ${response.status} ${message}`);
+	}
+	return text;
+}
+
+const getJson = async (path) => JSON.parse(await request("GET", path));
+
+// Runs `action`, logging failures instead of throwing them at the console.
+async function attempt(action) {
+	try {
+		await action();
+	} catch (e) {
+		log(e.message);
+	}
+}
+
+function fillList(dl, entries) {
+	dl.replaceChildren();
+	for (const [name, value] of entries) {
+		const dt = document.createElement("dt");
+		const dd = document.createElement("dd");
+		dt.textContent = name;
+		dd.textContent = value ?? "—";
+		dl.append(dt, dd);
+	}
+}
+
+function fillTable(table, rows) {
+	const tbody = table.querySelector("tbody");
+	tbody.replaceChildren();
+	for (const cells of rows) {
+		const tr = document.createElement("tr");
+		for (const cell of cells) {
+			const td = document.createElement("td");
+			td.append(cell ?? "");
+			tr.append(td);
+		}
+		tbody.append(tr);
+	}
+}
+
+function button(label, onclick, className = "small") {
+	const b = document.createElement("button");
+	b.textContent = label;
+	b.className = className;
+	b.onclick = onclick;
+	return b;
+}
+
+function fillSelect(select, names) {
+	for (const name of names) {
+		const option = document.createElement("option");
+		option.textContent = name;
+		select.append(option);
+	}
+}
+
+const hex = (color) => color.replace("#", "");
+const volts = (mv) => (mv / 1000).toFixed(2) + " V";
+const amps = (ma) => (ma / 1000).toFixed(2) + " A";
+const watts = (mw) => (mw / 1000).toFixed(1) + " W";
+
+// Form fields as a query string.  Checkboxes are sent as 0/1 and
+// fieldsets that are hidden are left out.
+function formQuery(form, skip = []) {
+	const params = new URLSearchParams();
+	for (const element of form.elements) {
+		if (!element.name || skip.includes(element.name)) {
+			continue;
+		}
+		const fieldset = element.closest("fieldset");
+		if (fieldset && fieldset.hidden) {
+			continue;
+		}
+		if (element.type === "checkbox") {
+			params.set(element.name, element.checked ? "1" : "0");
+		} else if (element.value !== "") {
+			params.set(element.name, element.type === "color" ? hex(element.value) : element.value);
+		}
+	}
+	return params;
+}
+
+// Status tab.
+
+let segments = [];
+
+async function loadOutput() {
+	const output = await getJson("/api/v1/output");
+	const form = $("#output-form");
+	form.on.checked = output.on;
+	form.brightness.value = output.brightness;
+	segments = output.segments;
+	fillList($("#output-info"), [
+		["Preset", output.preset],
+		["Fading", output.fade_ms ? `${output.fade_ms} ms` : "no"],
+		["Show playing", output.show ? "yes" : "no"],
+		["Segments", output.segments.length],
+	]);
+	fillSegments();
+}
+
+async function loadNetwork() {
+	const network = await getJson("/api/v1/network");
+	const { wifi } = network;
+	fillList($("#network-info"), [
+		["SSID", wifi.ssid],
+		["Link", network.link_up ? "up" : "down"],
+		["Address", network.address && `${network.address}/${network.prefix_len}`],
+		["Gateway", network.gateway],
+		["DNS", (network.dns_servers || []).join(", ")],
+		["HTTP port", network.http_port],
+		["Setup portal", network.portal ? "running" : "off"],
+		["Signal", wifi.ssid && `${wifi.rssi} dBm`],
+		["Access point", wifi.bssid && `${wifi.bssid} (channel ${wifi.channel})`],
+		["Connected for", wifi.ssid && `${wifi.connected_s} s`],
+	]);
+	fillTable($("#saved-networks"), wifi.saved.map((saved) => [
+		saved.ssid,
+		saved.priority,
+		saved.retry_in_s === null ? "" : `in ${saved.retry_in_s} s`,
+		button("Remove", () => attempt(async () => {
+			const params = new URLSearchParams({ ssid: saved.ssid });
+			await request("POST", "/networks/remove", params.toString());
+			await loadNetwork();
+		}), "small danger"),
+	]));
+	fillTable($("#wifi-history"), wifi.history.map((entry) => [
+		`${entry.ago_s} s ago`,
+		entry.event,
+		entry.ssid,
+		entry.rssi,
+	]));
+}
+
+$("#saved-network-form").onsubmit = (event) => {
+	event.preventDefault();
+	const form = event.target;
+	attempt(async () => {
+		await request("POST", "/networks/add", formQuery(form).toString());
+		form.reset();
+		await loadNetwork();
+	});
+};
+
+$("#output-form").onsubmit = (event) => {
+	event.preventDefault();
+	const params = formQuery(event.target);
+	attempt(async () => {
+		await request("POST", "/api/v1/output", params.toString());
+		await loadOutput();
+	});
+};
+
+// Outputs tab.
+
+function describeSource(segment) {
+	switch (segment.source) {
+		case "effect":
+			return `${segment.effect} (${segment.palette})`;
+		case "universe":
+			return `universe ${segment.universe} @ ${segment.offset}`;
+		case "solid":
+			return `#${segment.color}`;
+		default:
+			return segment.source;
+	}
+}
+
+function editSegment(index) {
+	const segment = segments[index];
+	const form = $("#segment-form");
+	form.index.value = index;
+	form.start.value = segment.start;
+	form.len.value = segment.len;
+	form.brightness.value = segment.brightness;
+	switch (segment.source) {
+		case "effect":
+			form.source.value = "effect";
+			form.effect.value = segment.effect;
+			form.palette.value = segment.palette;
+			form.speed.value = segment.speed;
+			form.scale.value = segment.scale;
+			break;
+		case "universe":
+			form.source.value = "universe";
+			form.universe.value = segment.universe;
+			form.offset.value = segment.offset;
+			break;
+		case "solid":
+			form.source.value = "color";
+			form.color.value = "#" + segment.color;
+			break;
+		default:
+			form.source.value = "script";
+	}
+	showSource();
+}
+
+function fillSegments() {
+	fillTable($("#segments"), segments.map((segment, index) => [
+		index,
+		segment.start,
+		segment.len,
+		describeSource(segment),
+		segment.brightness,
+		button("Edit", () => editSegment(index)),
+	]));
+	fillTable($("#artnet-inputs"), segments
+		.map((segment, index) => [index, segment])
+		.filter(([, segment]) => segment.source === "universe")
+		.map(([index, segment]) => [index, segment.universe, segment.offset]));
+}
+
+function showSource() {
+	const source = $("#segment-form").source.value;
+	for (const fieldset of document.querySelectorAll("#segment-form fieldset")) {
+		fieldset.hidden = fieldset.dataset.source !== source;
+	}
+}
+
+$("#segment-form").source.onchange = showSource;
+
+$("#segment-form").onsubmit = (event) => {
+	event.preventDefault();
+	const form = event.target;
+	const params = formQuery(form, ["index", "source"]);
+	// The effect's color shares the `color` parameter with solid segments.
+	if (params.has("effect_color")) {
+		params.set("color", params.get("effect_color"));
+		params.delete("effect_color");
+	}
+	if (form.source.value === "script") {
+		params.set("script", "1");
+	}
+	attempt(async () => {
+		await request("POST", `/segments/set/${form.index.value}?${params}`);
+		await loadOutput();
+	});
+};
+
+$("#segments-reset").onclick = () => attempt(async () => {
+	if (confirm("Replace all segments with the default?")) {
+		await request("POST", "/segments/reset");
+		await loadOutput();
+	}
+});
+
+async function loadMatrix() {
+	const text = (await request("GET", "/matrix")).trim();
+	const form = $("#matrix-form");
+	if (text === "no matrix") {
+		return;
+	}
+	for (const pair of text.split(" ")) {
+		const [name, value] = pair.split("=");
+		const element = form.elements[name];
+		if (!element) {
+			continue;
+		}
+		if (element.type === "checkbox") {
+			element.checked = value === "1";
+		} else {
+			element.value = value;
+		}
+	}
+}
+
+$("#matrix-form").onsubmit = (event) => {
+	event.preventDefault();
+	const params = formQuery(event.target);
+	attempt(async () => {
+		await request("POST", `/matrix/set?${params}`);
+		await loadMatrix();
+	});
+};
+
+$("#matrix-clear").onclick = () => attempt(async () => {
+	await request("POST", "/matrix/clear");
+	$("#matrix-form").reset();
+});
+
+// Art-Net tab.
+
+async function loadArtnet() {
+	const artnet = await getJson("/api/v1/artnet");
+	fillList($("#artnet-info"), [
+		["Short name", artnet.short_name],
+		["Long name", artnet.long_name],
+		["UDP port", artnet.port],
+		["OEM code", "0x" + artnet.oem.toString(16).padStart(4, "0")],
+		["sACN", artnet.sacn ? `on, UDP port ${artnet.sacn_port}` : "off"],
+	]);
+	showUniverses(artnet.received.map((universe) => ({ ...universe, packets: null, fps: null })));
+}
+
+const channels = new Map();
+
+function showUniverses(universes) {
+	for (const universe of universes) {
+		if (universe.len !== undefined) {
+			channels.set(universe.universe, universe.len);
+		}
+	}
+	fillTable($("#artnet-received"), universes.map((universe) => [
+		universe.universe,
+		channels.get(universe.universe),
+		universe.packets,
+		universe.fps,
+	]));
+}
+
+// Power tab.
+
+function showPd(pd) {
+	const contract = pd.contract;
+	fillList($("#pd-info"), [
+		["State", pd.state],
+		["Contract", contract && `PDO ${contract.pdo}: ${volts(contract.voltage_mv)}, ${amps(contract.current_ma)}`],
+		["Power", contract && watts(contract.power_mw)],
+	]);
+	fillTable($("#pdos"), pd.pdos.map((pdo, index) => [
+		index,
+		volts(pdo.voltage_mv),
+		amps(pdo.max_current_ma),
+		watts(pdo.power_mw),
+	]));
+}
+
+function showCharger(charger) {
+	fillList($("#charger-info"), [
+		["VBUS", volts(charger.vbus_uv / 1000)],
+		["VSYS", volts(charger.vsys_uv / 1000)],
+		["IBUS", amps(charger.ibus_ma)],
+		["IBAT", amps(charger.ibat_ma)],
+	]);
+}
+
+async function loadPower() {
+	showPd(await getJson("/api/v1/pd"));
+	try {
+		showCharger(await getJson("/api/v1/charger"));
+	} catch (e) {
+		fillList($("#charger-info"), [["Status", "no charger"]]);
+	}
+}
+
+// Presets tab.
+
+async function loadPresets() {
+	const text = await request("GET", "/presets");
+	const rows = [];
+	for (const line of text.split("\n")) {
+		const match = line.match(/^(\d+): name=(.*) capture=(\d)$/);
+		if (!match) {
+			continue;
+		}
+		const index = match[1];
+		const actions = document.createElement("span");
+		actions.append(
+			button("Recall", () => attempt(async () => {
+				await request("POST", `/presets/recall/${index}?fade=${$("#recall-fade").value}`);
+				await loadOutput();
+			})),
+			" ",
+			button("Delete", () => attempt(async () => {
+				await request("POST", `/presets/delete/${index}`);
+				await loadPresets();
+			}), "small danger"),
+		);
+		rows.push([index, match[2], match[3] === "1" ? "yes" : "no", actions]);
+	}
+	fillTable($("#preset-list"), rows);
+}
+
+$("#preset-form").onsubmit = (event) => {
+	event.preventDefault();
+	const form = event.target;
+	const params = formQuery(form, ["index"]);
+	attempt(async () => {
+		await request("POST", `/presets/save/${form.index.value}?${params}`);
+		await loadPresets();
+	});
+};
+
+// Diagnostics tab.
+
+async function loadAuth() {
+	const auth = await getJson("/auth");
+	fillList($("#auth-info"), [
+		["Logged in as", auth.role ?? "nobody"],
+		["Admin password", auth.admin_password ? "set" : "not set"],
+		["Viewer password", auth.viewer_password ? "set" : "not set"],
+		["Raw I2C", !auth.i2c_built ? "not built" : auth.i2c_enabled ? "on" : "off"],
+	]);
+	$("#auth-form").i2c.checked = auth.i2c_enabled;
+	$("#auth-form").i2c.disabled = !auth.i2c_built;
+}
+
+async function loadSettings() {
+	const { settings } = await getJson("/settings");
+	const form = $("#settings-form");
+	for (const name of ["ssid", "http_port", "num_leds", "short_name", "long_name"]) {
+		form[name].value = settings[name];
+	}
+	form.smooth.checked = settings.smooth === 1;
+	form.artnet_firmware.checked = settings.artnet_firmware === 1;
+	form.sacn.checked = settings.sacn === 1;
+	form.password.value = "";
+	form.password.placeholder = settings.password ? "unchanged" : "none";
+}
+
+function settingsSaved(result) {
+	if (result.restart_needed) {
+		log("settings saved, restart to apply them");
+	}
+	return loadSettings();
+}
+
+$("#settings-form").onsubmit = (event) => {
+	event.preventDefault();
+	const form = event.target;
+	const params = new URLSearchParams();
+	for (const name of ["ssid", "http_port", "num_leds", "short_name", "long_name"]) {
+		params.set(name, form[name].value);
+	}
+	params.set("smooth", form.smooth.checked ? "1" : "0");
+	params.set("artnet_firmware", form.artnet_firmware.checked ? "1" : "0");
+	params.set("sacn", form.sacn.checked ? "1" : "0");
+	if (form.password.value) {
+		params.set("password", form.password.value);
+	}
+	attempt(async () => {
+		await settingsSaved(JSON.parse(await request("POST", "/settings/set", params.toString())));
+	});
+};
+
+$("#settings-reset").onclick = () => attempt(async () => {
+	await settingsSaved(JSON.parse(await request("POST", "/settings/reset")));
+});
+
+async function loadFirmware() {
+	const ota = await getJson("/ota");
+	fillList($("#ota-info"), [
+		["Running", `ota_${ota.running} (${ota.state})`],
+		["Updates go to", `ota_${ota.next}`],
+		["Largest update", `${ota.max_len} bytes`],
+		["Update key", ota.signed ? "built in" : "not built, updates refused"],
+	]);
+	$("#ota-confirm").disabled = ota.state !== "pending_verify";
+}
+
+async function loadDiagnostics() {
+	$("#sync-info").textContent = await request("GET", "/sync");
+	$("#timecode-info").textContent = await request("GET", "/timecode");
+	await loadAuth();
+	await loadSettings();
+	await loadFirmware();
+}
+
+$("#auth-form").onsubmit = (event) => {
+	event.preventDefault();
+	const form = event.target;
+	const params = new URLSearchParams();
+	if (form.admin_password.value) {
+		params.set("admin_password", form.admin_password.value);
+	}
+	if (form.no_viewer.checked) {
+		params.set("viewer_password", "");
+	} else if (form.viewer_password.value) {
+		params.set("viewer_password", form.viewer_password.value);
+	}
+	if (!form.i2c.disabled) {
+		params.set("i2c", form.i2c.checked ? "1" : "0");
+	}
+	attempt(async () => {
+		await request("POST", "/auth/set", params.toString());
+		form.reset();
+		await loadAuth();
+	});
+};
+
+$("#logout").onclick = () => attempt(async () => {
+	await request("POST", "/auth/logout");
+	await loadAuth();
+});
+
+$("#ota-form").onsubmit = (event) => {
+	event.preventDefault();
+	const file = event.target.image.files[0];
+	if (!file) {
+		return;
+	}
+	attempt(async () => {
+		log(`uploading ${file.name}`);
+		const result = JSON.parse(await request("POST", "/ota", file));
+		log(`installed ${result.len} bytes into ota_${result.slot}, restarting`);
+	});
+};
+
+$("#ota-confirm").onclick = () => attempt(async () => {
+	await request("POST", "/ota/confirm");
+	await loadFirmware();
+});
+
+$("#ota-rollback").onclick = () => attempt(async () => {
+	await request("POST", "/ota/rollback");
+	log("rolling back, restarting");
+});
+
+const hexByte = (n) => n === null ? "--" : n.toString(16).padStart(2, "0");
+
+$("#i2c-scan").onclick = () => attempt(async () => {
+	const scan = await getJson("/i2c/scan");
+	$("#i2c-out").textContent = "devices: " + scan.devices.map(hexByte).join(" ");
+});
+
+function i2cQuery(form) {
+	return form.addr16.checked ? "?addr16=1" : "";
+}
+
+$("#i2c-form").onsubmit = (event) => {
+	event.preventDefault();
+	const form = event.target;
+	attempt(async () => {
+		const dump = await getJson(`/i2c/dump/${form.device.value}/${form.first.value}/${form.last.value}${i2cQuery(form)}`);
+		const lines = [];
+		for (let i = 0; i < dump.values.length; i += 16) {
+			const address = (dump.first + i).toString(16).padStart(4, "0");
+			lines.push(`${address}: ${dump.values.slice(i, i + 16).map(hexByte).join(" ")}`);
+		}
+		$("#i2c-out").textContent = lines.join("\n");
+	});
+};
+
+$("#i2c-write").onclick = () => attempt(async () => {
+	const form = $("#i2c-form");
+	await request("POST", `/i2c/write/${form.device.value}/${form.first.value}${i2cQuery(form)}`, form.data.value);
+	form.requestSubmit();
+});
+
+function registerLine(reg) {
+	const fields = Object.entries(reg.fields).map(([name, value]) => `${name}=${+value}`);
+	return `${reg.name} (${hexByte(reg.address)}): ${hexByte(reg.raw)} ${fields.join(" ")}`;
+}
+
+$("#i2c-reg-form").onsubmit = (event) => {
+	event.preventDefault();
+	const form = event.target;
+	attempt(async () => {
+		if (form.register.value === "") {
+			const chip = await getJson(`/i2c/reg/${form.chip.value}`);
+			$("#i2c-out").textContent = chip.registers.map(registerLine).join("\n");
+		} else {
+			const reg = await getJson(`/i2c/reg/${form.chip.value}/${form.register.value}`);
+			$("#i2c-out").textContent = registerLine(reg);
+		}
+	});
+};
+
+$("#i2c-reg-write").onclick = () => attempt(async () => {
+	const form = $("#i2c-reg-form");
+	const text = await request("POST", `/i2c/reg/${form.chip.value}/${form.register.value}`, form.fields.value);
+	$("#i2c-out").textContent = registerLine(JSON.parse(text));
+});
+
+// Live updates over the event stream.
+
+let socket = null;
+
+function drawPreview(data) {
+	const canvas = $("#preview");
+	const context = canvas.getContext("2d");
+	const pixels = new Uint8Array(data);
+	const count = pixels.length / 3;
+	const width = canvas.width / count;
+	context.clearRect(0, 0, canvas.width, canvas.height);
+	for (let i = 0; i < count; i++) {
+		const [r, g, b] = pixels.subarray(i * 3, i * 3 + 3);
+		context.fillStyle = `rgb(${r},${g},${b})`;
+		context.fillRect(i * width, 0, Math.ceil(width), canvas.height);
+	}
+}
+
+function connect() {
+	const params = formQuery($("#stream-form"));
+	const scheme = location.protocol === "https:" ? "wss:" : "ws:";
+	socket = new WebSocket(`${scheme}//${location.host}/api/v1/events?${params}`);
+	socket.binaryType = "arraybuffer";
+	socket.onopen = () => {
+		$("#live").textContent = "live";
+		$("#live").classList.add("ok");
+	};
+	socket.onclose = () => {
+		$("#live").textContent = "offline";
+		$("#live").classList.remove("ok");
+		socket = null;
+		setTimeout(connect, 5000);
+	};
+	socket.onmessage = (event) => {
+		if (event.data instanceof ArrayBuffer) {
+			drawPreview(event.data);
+			return;
+		}
+		const message = JSON.parse(event.data);
+		switch (message.stream) {
+			case "pd":
+				showPd(message.data);
+				break;
+			case "charger":
+				showCharger(message.data);
+				break;
+			case "artnet":
+				showUniverses(message.data.universes);
+				break;
+		}
+	};
+}
+
+$("#stream-form").onsubmit = (event) => {
+	event.preventDefault();
+	if (socket && socket.readyState === WebSocket.OPEN) {
+		socket.send(formQuery(event.target).toString());
+	}
+};
+
+// Tabs.
+
+const loaders = {
+	status: () => Promise.all([loadOutput(), loadNetwork()]),
+	outputs: () => Promise.all([loadOutput(), loadMatrix()]),
+	artnet: () => Promise.all([loadArtnet(), loadOutput()]),
+	power: loadPower,
+	presets: loadPresets,
+	diagnostics: loadDiagnostics,
+};
+
+for (const tab of document.querySelectorAll("nav button")) {
+	tab.onclick = () => {
+		for (const other of document.querySelectorAll("nav button, main section")) {
+			other.classList.remove("active");
+		}
+		tab.classList.add("active");
+		$(`#${tab.dataset.tab}`).classList.add("active");
+		attempt(loaders[tab.dataset.tab]);
+	};
+}
+
+fillSelect($("#output-form").effect, EFFECTS);
+fillSelect($("#segment-form").effect, EFFECTS);
+fillSelect($("#segment-form").palette, PALETTES);
+showSource();
+attempt(loaders.status);
+connect();
@@ -0,0 +1,87 @@
+"use strict";
+
+const $ = (selector) => document.querySelector(selector);
+
+function setStatus(message) {
+	$("#status").textContent = message;
+}
+
+async function request(method, path, body) {
+	const options = { method };
+	if (body !== undefined) {
+		options.body = body;
+		options.headers = { "Content-Type": "application/x-www-form-urlencoded" };
+	}
+	const response = await fetch(path, options);
+	const text = await response.text();
+	if (!response.ok) {
+		let message = text;
+		try {
+			message = JSON.parse(text).error.message;
+		} catch (e) { }
+		throw new Error(`${response.status} ${message}`);
+	}
+	return JSON.parse(text);
+}
+
+function fillNetworks(setup) {
+	const form = $("#setup-form");
+	if (!form.ssid.value) {
+		form.ssid.value = setup.ssid;
+	}
+	const tbody = $("#networks tbody");
+	tbody.replaceChildren();
+	for (const network of setup.networks) {
+		const tr = document.createElement("tr");
+		for (const text of [network.ssid, `${network.rssi} dBm`, network.secure ? "secured" : "open"]) {
+			const td = document.createElement("td");
+			td.textContent = text;
+			tr.append(td);
+		}
+		tr.onclick = () => {
+			form.ssid.value = network.ssid;
+			form.password.focus();
+		};
+		tbody.append(tr);
+	}
+}
+
+async function load() {
+	try {
+		const setup = await request("GET", "/setup");
+		fillNetworks(setup);
+		if (setup.scanning) {
+			setTimeout(load, 2000);
+		}
+	} catch (e) {
+		if (e instanceof TypeError) {
+			// A request can get lost while the node scans; keep trying.
+			setTimeout(load, 2000);
+		} else {
+			setStatus(`Failed to list networks: ${e.message}`);
+		}
+	}
+}
+
+$("#scan").onclick = async () => {
+	try {
+		await request("POST", "/setup/scan", "");
+		setStatus("Scanning…");
+		setTimeout(load, 5000);
+	} catch (e) {
+		setStatus(`Scan failed: ${e.message}`);
+	}
+};
+
+$("#setup-form").onsubmit = async (event) => {
+	event.preventDefault();
+	const body = new URLSearchParams(new FormData(event.target)).toString();
+	try {
+		await request("POST", "/setup/connect", body);
+		setStatus(`Saved. Restarting to join ${event.target.ssid.value}…`);
+	} catch (e) {
+		setStatus(`Failed to save: ${e.message}`);
+	}
+};
+
+load();
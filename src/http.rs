@@ -0,0 +1,742 @@
+use core::fmt::Write as _;
+use core::str::FromStr;
+
+use embassy_net::tcp::TcpSocket;
+use embassy_net::IpAddress;
+use embassy_time::{with_timeout, Duration};
+use embedded_io::asynch::Write;
+
+use crate::web::FmtBuffer;
+use crate::{Error, Result};
+
+/// Longest request line plus headers we accept.
+pub const MAX_HEAD_LEN: usize = 1024;
+pub const MAX_HEADERS: usize = 24;
+const MAX_PARAMS: usize = 4;
+/// Request bodies a handler didn't read are skipped so that the connection
+/// can be reused, as long as they are no larger than this.
+const MAX_DRAIN_LEN: usize = 4096;
+/// Requests served on one connection before we close it.
+const MAX_REQUESTS: u32 = 100;
+/// How long a kept alive connection may wait for its next request.  There
+/// are only a few server tasks, so idle clients mustn't hold on to them.
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub const TEXT: &[(&str, &str)] = &[("Content-Type", "text/plain; charset=utf-8")];
+pub const JSON: &[(&str, &str)] = &[("Content-Type", "application/json")];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+}
+
+impl Method {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "OPTIONS" => Method::Options,
+            _ => return None,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Options => "OPTIONS",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Status {
+    SwitchingProtocols,
+    Ok,
+    NoContent,
+    Found,
+    NotModified,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    PayloadTooLarge,
+    TooManyRequests,
+    HeaderFieldsTooLarge,
+    InternalServerError,
+    NotImplemented,
+    ServiceUnavailable,
+}
+
+impl Status {
+    pub fn code(&self) -> u16 {
+        match self {
+            Status::SwitchingProtocols => 101,
+            Status::Ok => 200,
+            Status::NoContent => 204,
+            Status::Found => 302,
+            Status::NotModified => 304,
+            Status::BadRequest => 400,
+            Status::Unauthorized => 401,
+            Status::Forbidden => 403,
+            Status::NotFound => 404,
+            Status::MethodNotAllowed => 405,
+            Status::PayloadTooLarge => 413,
+            Status::TooManyRequests => 429,
+            Status::HeaderFieldsTooLarge => 431,
+            Status::InternalServerError => 500,
+            Status::NotImplemented => 501,
+            Status::ServiceUnavailable => 503,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Status::SwitchingProtocols => "Switching Protocols",
+            Status::Ok => "OK",
+            Status::NoContent => "No Content",
+            Status::Found => "Found",
+            Status::NotModified => "Not Modified",
+            Status::BadRequest => "Bad Request",
+            Status::Unauthorized => "Unauthorized",
+            Status::Forbidden => "Forbidden",
+            Status::NotFound => "Not Found",
+            Status::MethodNotAllowed => "Method Not Allowed",
+            Status::PayloadTooLarge => "Payload Too Large",
+            Status::TooManyRequests => "Too Many Requests",
+            Status::HeaderFieldsTooLarge => "Request Header Fields Too Large",
+            Status::InternalServerError => "Internal Server Error",
+            Status::NotImplemented => "Not Implemented",
+            Status::ServiceUnavailable => "Service Unavailable",
+        }
+    }
+
+    /// The status to answer a request that failed with `e`.  Bad input
+    /// from the client shows up as one of our generic errors, everything
+    /// else is our fault, including index and buffer errors, which mostly
+    /// come from a response not fitting its buffer.
+    pub fn for_error(e: &Error) -> Self {
+        match e {
+            Error::Http(status) => *status,
+            Error::Generic(_) => Status::BadRequest,
+            _ => Status::InternalServerError,
+        }
+    }
+}
+
+/// Maps requests to `target`.
+///
+/// Patterns are matched a path segment at a time.  Segments starting with
+/// `:` match any non-empty segment and capture it as a parameter.
+pub struct Route<T> {
+    methods: &'static [Method],
+    pattern: &'static str,
+    pub target: T,
+}
+
+impl<T> Route<T> {
+    pub const fn new(methods: &'static [Method], pattern: &'static str, target: T) -> Self {
+        Self {
+            methods,
+            pattern,
+            target,
+        }
+    }
+
+    fn allows(&self, method: Method) -> bool {
+        // HEAD is a GET without the body.
+        let method = match method {
+            Method::Head => Method::Get,
+            method => method,
+        };
+        self.methods.contains(&method)
+    }
+
+    fn matches<'a>(&self, path: &'a str, params: &mut Params<'a>) -> bool {
+        let mut pattern = self.pattern.split('/');
+        let mut path = path.split('/');
+        params.len = 0;
+        loop {
+            match (pattern.next(), path.next()) {
+                (None, None) => return true,
+                (Some(p), Some(value)) if p.starts_with(':') => {
+                    if value.is_empty() || params.len == MAX_PARAMS {
+                        return false;
+                    }
+                    params.names[params.len] = &p[1..];
+                    params.values[params.len] = value;
+                    params.len += 1;
+                }
+                (Some(p), Some(value)) if p == value => (),
+                _ => return false,
+            }
+        }
+    }
+}
+
+/// Path parameters captured by a route.
+#[derive(Clone, Copy, Debug)]
+pub struct Params<'a> {
+    names: [&'static str; MAX_PARAMS],
+    values: [&'a str; MAX_PARAMS],
+    len: usize,
+}
+
+impl<'a> Params<'a> {
+    pub const fn new() -> Self {
+        Self {
+            names: [""; MAX_PARAMS],
+            values: [""; MAX_PARAMS],
+            len: 0,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&'a str> {
+        self.names[..self.len]
+            .iter()
+            .position(|n| *n == name)
+            .map(|i| self.values[i])
+    }
+}
+
+/// Ignore a trailing slash.
+fn normalize(path: &str) -> &str {
+    match path.strip_suffix('/') {
+        Some(stripped) if !stripped.is_empty() => stripped,
+        _ => path,
+    }
+}
+
+/// Find the route for `method` and `path`, filling in `params`.
+///
+/// Fails with `NotFound` if no route matches the path and with
+/// `MethodNotAllowed` if one does but not for `method`.
+pub fn route<'r, 'a, T>(
+    routes: &'r [Route<T>],
+    method: Method,
+    path: &'a str,
+    params: &mut Params<'a>,
+) -> core::result::Result<&'r T, Status> {
+    let path = normalize(path);
+    let mut found = false;
+    for route in routes {
+        if route.matches(path, params) {
+            if route.allows(method) {
+                return Ok(&route.target);
+            }
+            found = true;
+        }
+    }
+    Err(if found {
+        Status::MethodNotAllowed
+    } else {
+        Status::NotFound
+    })
+}
+
+/// Write the methods `path` can be requested with, for an `Allow` header.
+pub fn allowed<T>(routes: &[Route<T>], path: &str, out: &mut impl core::fmt::Write) {
+    let path = normalize(path);
+    let mut params = Params::new();
+    let mut seen = [false; 6];
+    let all = [
+        Method::Get,
+        Method::Head,
+        Method::Post,
+        Method::Put,
+        Method::Delete,
+        Method::Options,
+    ];
+    for route in routes.iter().filter(|r| r.matches(path, &mut params)) {
+        for (i, method) in all.iter().enumerate() {
+            seen[i] |= route.allows(*method) || *method == Method::Options;
+        }
+    }
+    let mut methods = all.iter().zip(seen).filter(|(_, seen)| *seen);
+    if let Some((method, _)) = methods.next() {
+        write!(out, "{}", method.name()).ok();
+    }
+    for (method, _) in methods {
+        write!(out, ", {}", method.name()).ok();
+    }
+}
+
+pub struct Request<'a> {
+    pub method: Method,
+    pub path: &'a str,
+    pub query: &'a str,
+    /// Filled in by [`route`].
+    pub params: Params<'a>,
+    headers: &'a [httparse::Header<'a>],
+}
+
+impl<'a> Request<'a> {
+    pub fn header(&self, name: &str) -> Option<&'a str> {
+        self.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .and_then(|h| core::str::from_utf8(h.value).ok())
+            .map(str::trim)
+    }
+
+    pub fn param(&self, name: &str) -> Option<&'a str> {
+        self.params.get(name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Body {
+    /// Bytes left in a `Content-Length` body.
+    Length(usize),
+    /// Bytes left in the current chunk, and whether the CRLF after the
+    /// previous chunk is still to be read.
+    Chunked {
+        remaining: usize,
+        crlf: bool,
+    },
+    Done,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Response {
+    NotStarted,
+    /// Sent with a `Content-Length`.
+    Length,
+    Chunked,
+    /// HTTP/1.0 clients get streamed bodies delimited by closing the
+    /// connection.
+    Close,
+    /// The connection switched to another protocol.
+    Upgraded,
+}
+
+/// One HTTP/1.1 connection: reads requests and their bodies and writes the
+/// responses.
+pub struct Connection<'s, 'b> {
+    socket: &'s mut TcpSocket<'b>,
+    /// Received data not yet consumed.  Anything past the current request
+    /// head belongs to its body or to the next request.
+    rx: [u8; MAX_HEAD_LEN],
+    start: usize,
+    end: usize,
+    body: Body,
+    response: Response,
+    http11: bool,
+    keep_alive: bool,
+    head_only: bool,
+    requests: u32,
+}
+
+impl<'s, 'b> Connection<'s, 'b> {
+    pub fn new(socket: &'s mut TcpSocket<'b>) -> Self {
+        Self {
+            socket,
+            rx: [0; MAX_HEAD_LEN],
+            start: 0,
+            end: 0,
+            body: Body::Done,
+            response: Response::NotStarted,
+            http11: false,
+            keep_alive: false,
+            head_only: false,
+            requests: 0,
+        }
+    }
+
+    /// Read more data into `rx`, first moving what is left to the front.
+    async fn fill(&mut self) -> Result<usize> {
+        self.rx.copy_within(self.start..self.end, 0);
+        self.end -= self.start;
+        self.start = 0;
+        if self.end == self.rx.len() {
+            return Ok(0);
+        }
+        let len = self.socket.read(&mut self.rx[self.end..]).await?;
+        self.end += len;
+        Ok(len)
+    }
+
+    async fn read_byte(&mut self) -> Result<u8> {
+        if self.start == self.end && self.fill().await? == 0 {
+            return Err(Error::Generic("Body truncated"));
+        }
+        self.start += 1;
+        Ok(self.rx[self.start - 1])
+    }
+
+    /// Read a CRLF terminated line of a chunked body into `line`, returning
+    /// its length.  Overlong lines are truncated.
+    async fn read_line(&mut self, line: &mut [u8]) -> Result<usize> {
+        let mut len = 0;
+        loop {
+            match self.read_byte().await? {
+                b'\n' => return Ok(len),
+                b'\r' => (),
+                b => {
+                    if let Some(slot) = line.get_mut(len) {
+                        *slot = b;
+                        len += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    /// The address of the client.
+    pub fn remote(&self) -> Option<IpAddress> {
+        self.socket.remote_endpoint().map(|endpoint| endpoint.addr)
+    }
+
+    /// Read the next request head into `head`.  Returns `None` if the
+    /// client closed the connection, or let it idle out, between requests.
+    pub async fn read_request<'h>(
+        &mut self,
+        head: &'h mut [u8; MAX_HEAD_LEN],
+        headers: &'h mut [httparse::Header<'h>; MAX_HEADERS],
+    ) -> Result<Option<Request<'h>>> {
+        self.response = Response::NotStarted;
+        self.head_only = false;
+        let len = loop {
+            let pending = &self.rx[self.start..self.end];
+            if let Some(pos) = pending.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+            if pending.len() == MAX_HEAD_LEN {
+                return Err(Error::Http(Status::HeaderFieldsTooLarge));
+            }
+            let idle = pending.is_empty();
+            let filled = if idle && self.requests > 0 {
+                with_timeout(KEEP_ALIVE_TIMEOUT, self.fill())
+                    .await
+                    .unwrap_or(Ok(0))
+            } else {
+                self.fill().await
+            };
+            match filled {
+                Ok(0) | Err(_) if idle => return Ok(None),
+                Ok(0) => return Err(Error::Generic("Incomplete headers")),
+                Ok(_) => (),
+                Err(e) => return Err(e),
+            }
+        };
+        head[..len].copy_from_slice(&self.rx[self.start..self.start + len]);
+        self.start += len;
+        let head: &'h [u8] = head;
+
+        let mut req = httparse::Request::new(headers);
+        match req.parse(&head[..len]) {
+            Ok(httparse::Status::Complete(_)) => (),
+            Err(httparse::Error::TooManyHeaders) => {
+                return Err(Error::Http(Status::HeaderFieldsTooLarge))
+            }
+            _ => return Err(Error::Generic("Malformed request")),
+        }
+        let httparse::Request {
+            method,
+            path,
+            version,
+            headers,
+        } = req;
+
+        self.requests += 1;
+        self.http11 = version == Some(1);
+        self.body = Body::Done;
+        self.keep_alive = self.http11;
+        let mut req = Request {
+            method: Method::Get,
+            path: "/",
+            query: "",
+            params: Params::new(),
+            headers,
+        };
+        if let Some(connection) = req.header("connection") {
+            if connection.eq_ignore_ascii_case("close") {
+                self.keep_alive = false;
+            } else if connection.eq_ignore_ascii_case("keep-alive") {
+                self.keep_alive = true;
+            }
+        }
+
+        if let Some(encoding) = req.header("transfer-encoding") {
+            if !encoding.eq_ignore_ascii_case("chunked") {
+                return Err(Error::Http(Status::NotImplemented));
+            }
+            self.body = Body::Chunked {
+                remaining: 0,
+                crlf: false,
+            };
+        } else if let Some(length) = req.header("content-length") {
+            let length =
+                usize::from_str(length).map_err(|_| Error::Generic("Bad Content-Length"))?;
+            self.body = Body::Length(length);
+        }
+
+        req.method =
+            Method::parse(method.unwrap_or("")).ok_or(Error::Http(Status::NotImplemented))?;
+        self.head_only = req.method == Method::Head;
+        let path = path.unwrap_or("/");
+        (req.path, req.query) = path.split_once('?').unwrap_or((path, ""));
+        Ok(Some(req))
+    }
+
+    /// Read some of the request body into `buf`.  Returns 0 at the end of
+    /// the body.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let remaining = match self.body {
+            Body::Done => return Ok(0),
+            Body::Length(0) => {
+                self.body = Body::Done;
+                return Ok(0);
+            }
+            Body::Length(remaining) => remaining,
+            Body::Chunked { remaining: 0, crlf } => {
+                if crlf {
+                    self.read_line(&mut []).await?;
+                }
+                let mut line = [0u8; 16];
+                let len = self.read_line(&mut line).await?;
+                let size = line[..len].split(|b| *b == b';').next().unwrap_or(&[]);
+                let size = core::str::from_utf8(size)
+                    .ok()
+                    .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+                    .ok_or(Error::Generic("Bad chunk size"))?;
+                if size == 0 {
+                    // Skip any trailers.
+                    while self.read_line(&mut []).await? != 0 {}
+                    self.body = Body::Done;
+                    return Ok(0);
+                }
+                size
+            }
+            Body::Chunked { remaining, .. } => remaining,
+        };
+
+        if self.start == self.end && self.fill().await? == 0 {
+            return Err(Error::Generic("Body truncated"));
+        }
+        let len = remaining.min(buf.len()).min(self.end - self.start);
+        buf[..len].copy_from_slice(&self.rx[self.start..self.start + len]);
+        self.start += len;
+        self.body = match self.body {
+            Body::Length(_) => Body::Length(remaining - len),
+            _ => Body::Chunked {
+                remaining: remaining - len,
+                crlf: true,
+            },
+        };
+        Ok(len)
+    }
+
+    /// Read the whole request body into `buf`, failing with
+    /// `PayloadTooLarge` if it doesn't fit.
+    pub async fn read_body<'a>(&mut self, buf: &'a mut [u8]) -> Result<&'a [u8]> {
+        if matches!(self.body, Body::Length(len) if len > buf.len()) {
+            return Err(Error::Http(Status::PayloadTooLarge));
+        }
+        let mut len = 0;
+        loop {
+            if len == buf.len() {
+                let mut probe = [0u8; 1];
+                if self.read(&mut probe).await? != 0 {
+                    return Err(Error::Http(Status::PayloadTooLarge));
+                }
+                break;
+            }
+            match self.read(&mut buf[len..]).await? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        Ok(&buf[..len])
+    }
+
+    /// Whether the response has been started, after which errors can no
+    /// longer be reported to the client.
+    pub fn started(&self) -> bool {
+        self.response != Response::NotStarted
+    }
+
+    async fn write_head(
+        &mut self,
+        status: Status,
+        headers: &[(&str, &str)],
+        len: Option<usize>,
+    ) -> Result<()> {
+        if self.started() {
+            return Err(Error::Generic("Response already started"));
+        }
+        self.response = match len {
+            Some(_) => Response::Length,
+            None if self.http11 => Response::Chunked,
+            None => Response::Close,
+        };
+        if self.response == Response::Close {
+            self.keep_alive = false;
+        }
+
+        let mut buffer = [0u8; 512];
+        let mut head = FmtBuffer::new(&mut buffer);
+        write!(head, "HTTP/1.1 {} {}\r\n", status.code(), status.reason())
+            .map_err(|_| Error::Index)?;
+        for (name, value) in headers {
+            write!(head, "{name}: {value}\r\n").map_err(|_| Error::Index)?;
+        }
+        // These never have a body, so a length would only describe the
+        // representation they stand in for.
+        let bodyless = matches!(status, Status::NoContent | Status::NotModified);
+        match self.response {
+            Response::Length if bodyless => Ok(()),
+            Response::Length => write!(head, "Content-Length: {}\r\n", len.unwrap_or(0)),
+            Response::Chunked => write!(head, "Transfer-Encoding: chunked\r\n"),
+            _ => Ok(()),
+        }
+        .map_err(|_| Error::Index)?;
+        let connection = if self.keep_alive {
+            "keep-alive"
+        } else {
+            "close"
+        };
+        write!(head, "Connection: {connection}\r\n\r\n").map_err(|_| Error::Index)?;
+        self.socket.write_all(head.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Send a complete response.
+    pub async fn send(
+        &mut self,
+        status: Status,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<()> {
+        self.write_head(status, headers, Some(body.len())).await?;
+        self.write(body).await
+    }
+
+    /// Start a response whose body is streamed with [`Connection::write`].
+    pub async fn start(&mut self, status: Status, headers: &[(&str, &str)]) -> Result<()> {
+        self.write_head(status, headers, None).await
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+        if self.head_only || data.is_empty() {
+            return Ok(());
+        }
+        if self.response == Response::Chunked {
+            let mut buffer = [0u8; 10];
+            let mut size = FmtBuffer::new(&mut buffer);
+            write!(size, "{:x}\r\n", data.len()).map_err(|_| Error::Index)?;
+            self.socket.write_all(size.as_bytes()).await?;
+            self.socket.write_all(data).await?;
+            self.socket.write_all(b"\r\n").await?;
+        } else {
+            self.socket.write_all(data).await?;
+        }
+        Ok(())
+    }
+
+    /// Accept a protocol upgrade.  From then on the connection only moves
+    /// raw data with [`Connection::receive`], [`Connection::pending`] and
+    /// [`Connection::write_raw`], and it is closed after this request.
+    pub async fn upgrade(&mut self, protocol: &str, headers: &[(&str, &str)]) -> Result<()> {
+        if self.started() {
+            return Err(Error::Generic("Response already started"));
+        }
+        self.response = Response::Upgraded;
+        self.keep_alive = false;
+
+        let mut buffer = [0u8; 256];
+        let mut head = FmtBuffer::new(&mut buffer);
+        let status = Status::SwitchingProtocols;
+        write!(head, "HTTP/1.1 {} {}\r\n", status.code(), status.reason())
+            .map_err(|_| Error::Index)?;
+        for (name, value) in headers {
+            write!(head, "{name}: {value}\r\n").map_err(|_| Error::Index)?;
+        }
+        write!(head, "Upgrade: {protocol}\r\nConnection: Upgrade\r\n\r\n")
+            .map_err(|_| Error::Index)?;
+        self.write_raw(head.as_bytes()).await
+    }
+
+    /// Wait for more data from the client.  Returns 0 if the client closed
+    /// the connection or [`Connection::pending`] is full.  Nothing is lost
+    /// if this is cancelled.
+    pub async fn receive(&mut self) -> Result<usize> {
+        self.fill().await
+    }
+
+    /// Data received but not consumed yet.
+    pub fn pending(&self) -> &[u8] {
+        &self.rx[self.start..self.end]
+    }
+
+    pub fn consume(&mut self, len: usize) {
+        self.start = (self.start + len).min(self.end);
+    }
+
+    /// Write `data` as is, bypassing any response framing.
+    pub async fn write_raw(&mut self, data: &[u8]) -> Result<()> {
+        self.socket.write_all(data).await?;
+        self.socket.flush().await?;
+        Ok(())
+    }
+
+    /// Answer a failed request with the matching status and the error as
+    /// the body.
+    pub async fn send_error(&mut self, e: &Error, headers: &[(&str, &str)]) -> Result<()> {
+        let status = Status::for_error(e);
+        // We may not have read all of a bad request.
+        if status != Status::NotFound && status != Status::MethodNotAllowed {
+            self.keep_alive = false;
+        }
+        let mut buffer = [0u8; 128];
+        let mut text = FmtBuffer::new(&mut buffer);
+        match e {
+            Error::Http(_) => write!(text, "{} {}\n", status.code(), status.reason()),
+            e => write!(text, "{} {}: {e}\n", status.code(), status.reason()),
+        }
+        .ok();
+        let mut all = [("", ""); 4];
+        all[0] = TEXT[0];
+        let len = 1 + headers.len().min(all.len() - 1);
+        all[1..len].copy_from_slice(&headers[..len - 1]);
+        self.send(status, &all[..len], text.as_bytes()).await
+    }
+
+    /// Finish the response and skip whatever the handler left of the
+    /// request body.  Returns whether the connection can take another
+    /// request.
+    pub async fn finish(&mut self) -> Result<bool> {
+        if self.response == Response::Chunked && !self.head_only {
+            self.socket.write_all(b"0\r\n\r\n").await?;
+        }
+        self.socket.flush().await?;
+        if !self.keep_alive || self.requests >= MAX_REQUESTS {
+            return Ok(false);
+        }
+
+        let mut drained = 0;
+        let mut scratch = [0u8; 64];
+        while drained <= MAX_DRAIN_LEN {
+            if matches!(self.body, Body::Length(len) if len > MAX_DRAIN_LEN) {
+                break;
+            }
+            match self.read(&mut scratch).await? {
+                0 => return Ok(true),
+                n => drained += n,
+            }
+        }
+        Ok(false)
+    }
+}
@@ -0,0 +1,508 @@
+//! Raw register access to the devices on the I2C bus, for bringing up
+//! boards and debugging in the field without a UART cable.  Everything is
+//! answered in JSON.  Built with the `i2c-debug` feature.
+//!
+//! The chips the firmware drives can also be read and written by register
+//! and field name, see `regmap`.
+
+use core::fmt::Write;
+
+use embedded_hal_async::i2c::I2c;
+use esp_println::println;
+
+use crate::api;
+use crate::auth::Role;
+use crate::http::{Connection, Method, Request, Status, JSON};
+use crate::json::Json;
+use crate::regmap::{self, Chip};
+use crate::web::{parse_param, Context, FmtBuffer};
+use crate::{Error, Result};
+
+/// Most bytes read or written in one request.
+const MAX_TRANSFER: usize = 256;
+/// Room for `MAX_TRANSFER` bytes written as hex with separators.
+const MAX_BODY_LEN: usize = 3 * MAX_TRANSFER;
+const MAX_RESPONSE_LEN: usize = 2048;
+/// Most registers in one chip's map.
+const MAX_REGISTERS: usize = 64;
+/// Room for one decoded register.
+const MAX_REGISTER_LEN: usize = 1024;
+/// Largest burst read of a dump.  Smaller bursts keep a device that
+/// doesn't auto-increment from returning a whole range of one register.
+const DUMP_BURST: usize = 16;
+
+/// 7 bit addresses outside of the reserved ones at either end.
+const SCAN_FIRST: u8 = 0x08;
+const SCAN_LAST: u8 = 0x77;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Command {
+    Scan,
+    Read,
+    Dump,
+    Write,
+    /// The chips with a register map.
+    Chips,
+    /// Every register of a chip, decoded.
+    Registers,
+    /// One register of a chip, decoded or written by field.
+    Register,
+}
+
+/// A register address, sent as one byte or, for devices with 16 bit
+/// addressing, two bytes big endian.
+#[derive(Clone, Copy, Debug)]
+struct Register {
+    addr: u16,
+    wide: bool,
+}
+
+impl Register {
+    /// Parse the hex path parameter `name`.  More than two digits, or
+    /// `addr16=1` in the query, selects 16 bit addressing.
+    fn param(req: &Request<'_>, name: &'static str) -> Result<Self> {
+        let val = req.param(name).ok_or(Error::Generic(name))?;
+        let addr = u16::from_str_radix(val, 16).map_err(|_| Error::Generic(name))?;
+        let wide = val.len() > 2 || parse_param::<u8>(req.query, "addr16")?.unwrap_or(0) != 0;
+        Ok(Self { addr, wide })
+    }
+
+    fn offset(self, n: usize) -> Result<Self> {
+        let max = if self.wide { u16::MAX } else { u8::MAX as u16 };
+        let addr = self.addr as usize + n;
+        if addr > max as usize {
+            return Err(Error::Generic("Register out of range"));
+        }
+        Ok(Self {
+            addr: addr as u16,
+            ..self
+        })
+    }
+
+    /// The address bytes followed by `data`, into `buf`.
+    fn frame<'a>(&self, data: &[u8], buf: &'a mut [u8]) -> Result<&'a [u8]> {
+        let addr = self.addr.to_be_bytes();
+        let addr = if self.wide { &addr[..] } else { &addr[1..] };
+        let len = addr.len() + data.len();
+        let buf = buf.get_mut(..len).ok_or(Error::Index)?;
+        buf[..addr.len()].copy_from_slice(addr);
+        buf[addr.len()..].copy_from_slice(data);
+        Ok(buf)
+    }
+}
+
+fn device_param(req: &Request<'_>) -> Result<u8> {
+    req.param("dev_addr")
+        .and_then(|val| u8::from_str_radix(val, 16).ok())
+        .filter(|addr| *addr < 0x80)
+        .ok_or(Error::Generic("dev_addr"))
+}
+
+/// Parse hex bytes into `buf`.  Pairs of digits may be separated by
+/// spaces, commas or colons and prefixed with `0x`.
+fn parse_hex<'a>(text: &str, buf: &'a mut [u8]) -> Result<&'a [u8]> {
+    let mut len = 0;
+    for word in text.split(|c: char| c.is_ascii_whitespace() || c == ',' || c == ':') {
+        let word = word.strip_prefix("0x").unwrap_or(word);
+        if word.len() % 2 != 0 {
+            return Err(Error::Generic("Odd number of hex digits"));
+        }
+        for digits in word.as_bytes().chunks_exact(2) {
+            let digits = core::str::from_utf8(digits).map_err(|_| Error::Generic("Bad hex"))?;
+            let b = u8::from_str_radix(digits, 16).map_err(|_| Error::Generic("Bad hex"))?;
+            *buf.get_mut(len).ok_or(Error::Generic("Too much data"))? = b;
+            len += 1;
+        }
+    }
+    Ok(&buf[..len])
+}
+
+fn write_hex<W: Write>(json: &mut Json<W>, data: &[u8]) -> Result<()> {
+    struct Hex<'a>(&'a [u8]);
+    impl core::fmt::Display for Hex<'_> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+        }
+    }
+    json.display(Hex(data))
+}
+
+/// Addresses that acknowledge a one byte read.
+async fn scan<I, E, W>(i2c: &mut I, json: &mut Json<W>) -> Result<()>
+where
+    I: I2c<Error = E>,
+    W: Write,
+{
+    json.begin_object()?;
+    json.key("devices")?;
+    json.begin_array()?;
+    for addr in SCAN_FIRST..=SCAN_LAST {
+        if i2c.read(addr, &mut [0u8]).await.is_ok() {
+            json.value(addr)?;
+        }
+    }
+    json.end_array()?;
+    json.end_object()
+}
+
+async fn read<I, E, W>(
+    i2c: &mut I,
+    json: &mut Json<W>,
+    dev_addr: u8,
+    reg: Register,
+    len: usize,
+) -> Result<()>
+where
+    I: I2c<Error = E>,
+    Error: From<E>,
+    W: Write,
+{
+    if len == 0 || len > MAX_TRANSFER {
+        return Err(Error::Generic("len"));
+    }
+    let mut data = [0u8; MAX_TRANSFER];
+    let mut addr = [0u8; 2];
+    i2c.write_read(dev_addr, reg.frame(&[], &mut addr)?, &mut data[..len])
+        .await?;
+
+    json.begin_object()?;
+    json.field("device", dev_addr)?;
+    json.field("register", reg.addr)?;
+    json.key("data")?;
+    json.begin_array()?;
+    for b in &data[..len] {
+        json.value(*b)?;
+    }
+    json.end_array()?;
+    json.key("hex")?;
+    write_hex(json, &data[..len])?;
+    json.end_object()
+}
+
+/// Registers `first` to `last` read in bursts.  A burst that fails shows
+/// up as `null`s rather than failing the whole dump.
+async fn dump<I, E, W>(
+    i2c: &mut I,
+    json: &mut Json<W>,
+    dev_addr: u8,
+    first: Register,
+    last: Register,
+) -> Result<()>
+where
+    I: I2c<Error = E>,
+    W: Write,
+{
+    let count = last.addr.checked_sub(first.addr).map(|n| n as usize + 1);
+    let Some(count) = count.filter(|count| *count <= MAX_TRANSFER) else {
+        return Err(Error::Generic("Bad register range"));
+    };
+
+    json.begin_object()?;
+    json.field("device", dev_addr)?;
+    json.field("first", first.addr)?;
+    json.field("last", last.addr)?;
+    json.key("values")?;
+    json.begin_array()?;
+    let mut done = 0;
+    while done < count {
+        let len = DUMP_BURST.min(count - done);
+        let mut data = [0u8; DUMP_BURST];
+        let mut addr = [0u8; 2];
+        let reg = first.offset(done)?;
+        let result = i2c
+            .write_read(dev_addr, reg.frame(&[], &mut addr)?, &mut data[..len])
+            .await;
+        for b in &data[..len] {
+            json.value(result.is_ok().then_some(*b))?;
+        }
+        done += len;
+    }
+    json.end_array()?;
+    json.end_object()
+}
+
+async fn write<I, E, W>(
+    i2c: &mut I,
+    json: &mut Json<W>,
+    dev_addr: u8,
+    reg: Register,
+    data: &[u8],
+) -> Result<()>
+where
+    I: I2c<Error = E>,
+    Error: From<E>,
+    W: Write,
+{
+    if data.is_empty() {
+        return Err(Error::Generic("No data"));
+    }
+    let mut frame = [0u8; 2 + MAX_TRANSFER];
+    i2c.write(dev_addr, reg.frame(data, &mut frame)?).await?;
+
+    json.begin_object()?;
+    json.field("device", dev_addr)?;
+    json.field("register", reg.addr)?;
+    json.field("written", data.len())?;
+    json.end_object()
+}
+
+fn chip_param(req: &Request<'_>) -> Result<&'static Chip> {
+    let name = req.param("chip").ok_or(Error::Generic("chip"))?;
+    regmap::chip(name).ok_or(Error::Http(Status::NotFound))
+}
+
+fn register_param(req: &Request<'_>, chip: &Chip) -> Result<&'static regmap::Register> {
+    let name = req.param("register").ok_or(Error::Generic("register"))?;
+    chip.register(name).ok_or(Error::Http(Status::NotFound))
+}
+
+/// `true`, `on` and `false`, `off` for one bit fields, otherwise decimal
+/// or hex with a `0x` prefix.
+fn parse_value(text: &str) -> Result<u16> {
+    let value = match text {
+        "true" | "on" => return Ok(1),
+        "false" | "off" => return Ok(0),
+        _ => match text.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => text.parse(),
+        },
+    };
+    value.map_err(|_| Error::Generic("Bad value"))
+}
+
+/// Apply a form of `field=value` pairs, or `raw=value` for the whole
+/// register, to `raw`.  Later pairs win.
+fn apply_form(reg: &regmap::Register, mut raw: u16, form: &str) -> Result<u16> {
+    for pair in form.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = parse_value(value)?;
+        raw = match name {
+            "raw" if reg.len == 1 && value > u8::MAX as u16 => {
+                return Err(Error::Generic("Value doesn't fit the register"))
+            }
+            "raw" => value,
+            _ => reg.set_field(raw, name, value)?,
+        };
+    }
+    Ok(raw)
+}
+
+async fn read_register<I, E>(i2c: &mut I, chip: &Chip, reg: &regmap::Register) -> Result<u16>
+where
+    I: I2c<Error = E>,
+    Error: From<E>,
+{
+    let mut data = [0u8; 2];
+    i2c.write_read(chip.addr, &[reg.addr], &mut data[..reg.len as usize])
+        .await?;
+    Ok(reg.from_bytes(&data))
+}
+
+async fn write_register<I, E>(
+    i2c: &mut I,
+    chip: &Chip,
+    reg: &regmap::Register,
+    raw: u16,
+) -> Result<()>
+where
+    I: I2c<Error = E>,
+    Error: From<E>,
+{
+    let mut data = [0u8; 2];
+    let len = reg.to_bytes(raw, &mut data);
+    let mut frame = [0u8; 3];
+    let frame = Register {
+        addr: reg.addr as u16,
+        wide: false,
+    }
+    .frame(&data[..len], &mut frame)?;
+    i2c.write(chip.addr, frame).await?;
+    Ok(())
+}
+
+fn chips<W: Write>(json: &mut Json<W>) -> Result<()> {
+    json.begin_object()?;
+    json.key("chips")?;
+    json.begin_array()?;
+    for chip in regmap::CHIPS {
+        json.begin_object()?;
+        json.field("name", chip.name)?;
+        json.field("address", chip.addr)?;
+        json.field("registers", chip.registers.len())?;
+        json.end_object()?;
+    }
+    json.end_array()?;
+    json.end_object()
+}
+
+/// Read one register and, given a form body, write it back changed.
+/// Registers that clear on read are read here when asked for by name.
+async fn register<I, E, W>(
+    i2c: &mut I,
+    json: &mut Json<W>,
+    chip: &Chip,
+    reg: &regmap::Register,
+    form: Option<&str>,
+) -> Result<()>
+where
+    I: I2c<Error = E>,
+    Error: From<E>,
+    W: Write,
+{
+    let mut raw = read_register(i2c, chip, reg).await?;
+    if let Some(form) = form {
+        let new = apply_form(reg, raw, form)?;
+        println!(
+            "writing {new:x} to {}.{} (was {raw:x})",
+            chip.name, reg.name
+        );
+        write_register(i2c, chip, reg, new).await?;
+        raw = read_register(i2c, chip, reg).await?;
+    }
+    reg.decode(json, raw)
+}
+
+/// The raw I2C endpoints only answer when switched on, and not before an
+/// admin password is set.
+async fn check_enabled(ctx: &Context) -> Result<()> {
+    let auth = ctx.auth.lock().await;
+    if !auth.config.i2c_enabled {
+        return Err(Error::Http(Status::NotFound));
+    }
+    if !auth.config.has_password(Role::Admin) {
+        return Err(Error::Generic("Set an admin password to use I2C"));
+    }
+    Ok(())
+}
+
+async fn run(
+    conn: &mut Connection<'_, '_>,
+    ctx: &Context,
+    req: &Request<'_>,
+    command: Command,
+    json: &mut Json<FmtBuffer<'_>>,
+) -> Result<()> {
+    check_enabled(ctx).await?;
+
+    // Read the body before taking the bus.
+    let mut body = [0u8; MAX_BODY_LEN];
+    let mut data = [0u8; MAX_TRANSFER];
+    let data = match (command, req.param("data")) {
+        (Command::Write, Some(hex)) => parse_hex(hex, &mut data)?,
+        (Command::Write, None) if matches!(req.method, Method::Post | Method::Put) => {
+            let body = conn.read_body(&mut body).await?;
+            let body = core::str::from_utf8(body).map_err(|_| Error::Generic("Body isn't hex"))?;
+            parse_hex(body.trim(), &mut data)?
+        }
+        (Command::Register, _) if matches!(req.method, Method::Post | Method::Put) => {
+            conn.read_body(&mut body).await?
+        }
+        _ => &[],
+    };
+
+    let mut i2c = ctx.i2c.lock().await;
+    let i2c = &mut **i2c;
+    match command {
+        Command::Scan => scan(i2c, json).await,
+        Command::Read => {
+            let len = match req.param("len") {
+                Some(len) => usize::from_str_radix(len, 16).map_err(|_| Error::Generic("len"))?,
+                None => parse_param(req.query, "len")?.unwrap_or(1),
+            };
+            let reg = Register::param(req, "reg_addr")?;
+            read(i2c, json, device_param(req)?, reg, len).await
+        }
+        Command::Dump => {
+            let first = Register::param(req, "first")?;
+            let last = Register::param(req, "last")?;
+            let first = Register {
+                wide: first.wide || last.wide,
+                ..first
+            };
+            dump(i2c, json, device_param(req)?, first, last).await
+        }
+        Command::Write => {
+            let (dev_addr, reg) = (device_param(req)?, Register::param(req, "reg_addr")?);
+            println!("writing {data:x?} to {:x} of {dev_addr:x}", reg.addr);
+            write(i2c, json, dev_addr, reg, data).await
+        }
+        Command::Chips => chips(json),
+        Command::Registers => Err(Error::Generic("Registers are streamed")),
+        Command::Register => {
+            let chip = chip_param(req)?;
+            let form = core::str::from_utf8(data).map_err(|_| Error::Generic("Bad form"))?;
+            let form = matches!(req.method, Method::Post | Method::Put).then_some(form);
+            register(i2c, json, chip, register_param(req, chip)?, form).await
+        }
+    }
+}
+
+/// A whole register map is too big to buffer, so it is read first and
+/// then sent a register at a time.  Registers that clear on read are
+/// skipped.
+async fn registers(conn: &mut Connection<'_, '_>, ctx: &Context, req: &Request<'_>) -> Result<()> {
+    check_enabled(ctx).await?;
+    let chip = chip_param(req)?;
+    let regs = chip.registers.iter().filter(|reg| !reg.read_clears);
+
+    let mut raws = [0u16; MAX_REGISTERS];
+    {
+        let mut i2c = ctx.i2c.lock().await;
+        for (reg, raw) in regs.clone().zip(raws.iter_mut()) {
+            *raw = read_register(&mut **i2c, chip, reg).await?;
+        }
+    }
+
+    conn.start(Status::Ok, JSON).await?;
+    let mut buffer = [0u8; MAX_REGISTER_LEN];
+    let mut json = Json::new(FmtBuffer::new(&mut buffer));
+    json.begin_object()?;
+    json.field("chip", chip.name)?;
+    json.field("address", chip.addr)?;
+    json.key("registers")?;
+    json.begin_array()?;
+    conn.write(json.into_inner().as_bytes()).await?;
+    for (i, (reg, raw)) in regs.zip(raws).enumerate() {
+        let mut json = Json::new(FmtBuffer::new(&mut buffer));
+        reg.decode(&mut json, raw)?;
+        let sep = if i == 0 { "" } else { "," };
+        conn.write(sep.as_bytes()).await?;
+        conn.write(json.into_inner().as_bytes()).await?;
+    }
+    conn.write(b"]}").await
+}
+
+async fn respond(
+    conn: &mut Connection<'_, '_>,
+    ctx: &Context,
+    req: &Request<'_>,
+    command: Command,
+) -> Result<()> {
+    if let Command::Registers = command {
+        return registers(conn, ctx, req).await;
+    }
+    let mut buffer = [0u8; MAX_RESPONSE_LEN];
+    let mut json = Json::new(FmtBuffer::new(&mut buffer));
+    run(conn, ctx, req, command, &mut json).await?;
+    conn.send(Status::Ok, JSON, json.into_inner().as_bytes())
+        .await
+}
+
+/// Device and register addresses in the path are hex.  Reads take a
+/// `len`, writes take hex bytes in the path or the body.  Named registers
+/// are written with a form body of field values.
+pub async fn handle(
+    conn: &mut Connection<'_, '_>,
+    ctx: &Context,
+    req: &Request<'_>,
+    command: Command,
+) -> Result<()> {
+    match respond(conn, ctx, req, command).await {
+        Ok(()) => Ok(()),
+        Err(e) if conn.started() => Err(e),
+        Err(e) => {
+            println!("i2c {} failed: {e:?}", req.path);
+            api::send_error(conn, &e).await
+        }
+    }
+}
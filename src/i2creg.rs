@@ -0,0 +1,227 @@
+//! Typed register access for I2C devices.  A device names its bus address
+//! and its register enum, each `#[bitfield]` register struct names the
+//! register it lives in, and `Bus` reads, writes and modifies them.  Wider
+//! registers are little endian.
+
+use core::marker::PhantomData;
+
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use embedded_hal_async::i2c::I2c;
+
+use crate::{Error, Result};
+
+pub trait Device {
+    /// 7 bit bus address.
+    const ADDR: u8;
+    type Register: Copy + Into<u8>;
+}
+
+/// The width of a register.
+pub trait Raw: Copy {
+    const LEN: usize;
+    fn from_le(data: &[u8]) -> Self;
+    fn to_le(self, data: &mut [u8]);
+}
+
+impl Raw for u8 {
+    const LEN: usize = 1;
+
+    fn from_le(data: &[u8]) -> Self {
+        data[0]
+    }
+
+    fn to_le(self, data: &mut [u8]) {
+        data[0] = self;
+    }
+}
+
+impl Raw for u16 {
+    const LEN: usize = 2;
+
+    fn from_le(data: &[u8]) -> Self {
+        u16::from_le_bytes([data[0], data[1]])
+    }
+
+    fn to_le(self, data: &mut [u8]) {
+        data[..2].copy_from_slice(&self.to_le_bytes());
+    }
+}
+
+/// The contents of a register.  Layouts shared by several registers only
+/// implement this and are read with `Bus::read_at`.
+pub trait Value: Copy + From<Self::Raw> + Into<Self::Raw> {
+    type Raw: Raw;
+}
+
+impl Value for u8 {
+    type Raw = u8;
+}
+
+impl Value for u16 {
+    type Raw = u16;
+}
+
+/// A value that lives in one register of `Device`.
+pub trait Register: Value {
+    type Device: Device;
+    const REGISTER: <Self::Device as Device>::Register;
+}
+
+/// Implement `Register` for bitfield structs named after the variants of a
+/// device's register enum, all `raw` wide.
+#[macro_export]
+macro_rules! i2c_registers {
+    ($device:ty, $enum:ident, $raw:ty: $($name:ident),* $(,)?) => {
+        $(
+            impl $crate::i2creg::Value for $name {
+                type Raw = $raw;
+            }
+
+            impl $crate::i2creg::Register for $name {
+                type Device = $device;
+                const REGISTER: $enum = $enum::$name;
+            }
+        )*
+    };
+}
+
+/// Define `#[bitfield]` register structs that also list their fields as
+/// `FIELDS` for the I2C debug register map, so the map is built from the
+/// same definition the driver uses.  Fields may only carry `#[bits(n)]`.
+#[macro_export]
+macro_rules! bitfield_registers {
+    ($(
+        #[bitfield($raw:ident)]
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[bits($bits:literal)])? $field_vis:vis $field:ident: $ty:ident),* $(,)?
+        }
+    )*) => {
+        $(
+            #[bitfield_struct::bitfield($raw)]
+            $(#[$meta])*
+            $vis struct $name {
+                $($(#[bits($bits)])? $field_vis $field: $ty),*
+            }
+
+            #[cfg(feature = "i2c-debug")]
+            impl $name {
+                pub const FIELDS: &'static [$crate::regmap::Field] = &[$(
+                    $crate::regmap::Field::new(
+                        stringify!($field),
+                        $crate::bitfield_registers!(@bits $ty $(, $bits)?),
+                    )
+                ),*];
+            }
+        )*
+    };
+    (@bits $ty:ident, $bits:literal) => {
+        $bits
+    };
+    (@bits $ty:ident) => {
+        <$ty as $crate::regmap::Width>::BITS
+    };
+}
+
+/// Device `D` on a bus shared through a mutex.
+pub struct Bus<D, I2C: 'static> {
+    i2c: &'static Mutex<NoopRawMutex, &'static mut I2C>,
+    _device: PhantomData<D>,
+}
+
+impl<D, I2C> Clone for Bus<D, I2C> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<D, I2C> Copy for Bus<D, I2C> {}
+
+impl<D, I2C, E> Bus<D, I2C>
+where
+    D: Device,
+    I2C: I2c<Error = E>,
+    Error: From<E>,
+{
+    pub fn new(i2c: &'static Mutex<NoopRawMutex, &'static mut I2C>) -> Self {
+        Self {
+            i2c,
+            _device: PhantomData,
+        }
+    }
+
+    pub async fn read<R: Register<Device = D>>(&self) -> Result<R> {
+        self.read_at(R::REGISTER).await
+    }
+
+    pub async fn write<R: Register<Device = D>>(&self, value: R) -> Result<()> {
+        self.write_at(R::REGISTER, value).await
+    }
+
+    /// Read, change and write back a register without letting anyone else
+    /// on the bus in between.  Returns the value written.
+    pub async fn modify<R, F>(&self, f: F) -> Result<R>
+    where
+        R: Register<Device = D>,
+        F: FnOnce(R) -> R,
+    {
+        let mut i2c = self.i2c.lock().await;
+        let value = f(read_value(&mut **i2c, D::ADDR, R::REGISTER.into()).await?);
+        write_value(&mut **i2c, D::ADDR, R::REGISTER.into(), value).await?;
+        Ok(value)
+    }
+
+    pub async fn read_at<V: Value>(&self, register: D::Register) -> Result<V> {
+        let mut i2c = self.i2c.lock().await;
+        read_value(&mut **i2c, D::ADDR, register.into()).await
+    }
+
+    pub async fn write_at<V: Value>(&self, register: D::Register, value: V) -> Result<()> {
+        let mut i2c = self.i2c.lock().await;
+        write_value(&mut **i2c, D::ADDR, register.into(), value).await
+    }
+
+    /// Read `data.len()` bytes starting at `first`.  Whether the address
+    /// moves on after each byte is up to the device.
+    pub async fn read_burst(&self, first: D::Register, data: &mut [u8]) -> Result<()> {
+        let mut i2c = self.i2c.lock().await;
+        i2c.write_read(D::ADDR, &[first.into()], data).await?;
+        Ok(())
+    }
+
+    /// Write each of `frames`, register address first, while holding the
+    /// bus.
+    pub async fn write_frames(&self, frames: &[&[u8]]) -> Result<()> {
+        let mut i2c = self.i2c.lock().await;
+        for frame in frames {
+            i2c.write(D::ADDR, frame).await?;
+        }
+        Ok(())
+    }
+}
+
+async fn read_value<I2C, E, V>(i2c: &mut I2C, addr: u8, register: u8) -> Result<V>
+where
+    I2C: I2c<Error = E>,
+    Error: From<E>,
+    V: Value,
+{
+    let mut data = [0u8; 2];
+    let data = &mut data[..V::Raw::LEN];
+    i2c.write_read(addr, &[register], data).await?;
+    Ok(V::from(V::Raw::from_le(data)))
+}
+
+async fn write_value<I2C, E, V>(i2c: &mut I2C, addr: u8, register: u8, value: V) -> Result<()>
+where
+    I2C: I2c<Error = E>,
+    Error: From<E>,
+    V: Value,
+{
+    let mut frame = [0u8; 3];
+    frame[0] = register;
+    let raw: V::Raw = value.into();
+    raw.to_le(&mut frame[1..]);
+    i2c.write(addr, &frame[..1 + V::Raw::LEN]).await?;
+    Ok(())
+}
@@ -0,0 +1,201 @@
+use core::fmt::{Display, Write};
+
+use crate::{Error, Result};
+
+/// Deepest nesting of objects and arrays we can track.
+const MAX_DEPTH: u8 = 32;
+
+/// Streaming JSON encoder on top of any `core::fmt::Write`.
+///
+/// Values are written as they are added, so nothing is buffered beyond
+/// what the underlying writer holds.  Commas are tracked with one bit per
+/// nesting level.  Object members are a [`Json::key`] followed by a value,
+/// or a [`Json::field`] for both at once.
+pub struct Json<W> {
+    out: W,
+    depth: u8,
+    /// Bit n is set once level n has a member.
+    members: u32,
+    /// A key was just written so the next value needs no separator.
+    after_key: bool,
+}
+
+/// Things that can be written as a JSON value.
+pub trait Value {
+    fn write<W: Write>(&self, json: &mut Json<W>) -> Result<()>;
+}
+
+impl<W: Write> Json<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            depth: 0,
+            members: 0,
+            after_key: false,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    fn raw(&mut self, s: &str) -> Result<()> {
+        self.out.write_str(s).map_err(|_| Error::Index)
+    }
+
+    /// Start a value, writing a comma if it isn't the first in its parent.
+    fn separator(&mut self) -> Result<()> {
+        if self.after_key {
+            self.after_key = false;
+            return Ok(());
+        }
+        let bit = 1 << self.depth;
+        if self.members & bit != 0 {
+            self.raw(",")?;
+        }
+        self.members |= bit;
+        Ok(())
+    }
+
+    fn open(&mut self, bracket: &str) -> Result<()> {
+        if self.depth + 1 >= MAX_DEPTH {
+            return Err(Error::Index);
+        }
+        self.separator()?;
+        self.raw(bracket)?;
+        self.depth += 1;
+        self.members &= !(1 << self.depth);
+        Ok(())
+    }
+
+    fn close(&mut self, bracket: &str) -> Result<()> {
+        if self.depth == 0 {
+            return Err(Error::Generic("unbalanced JSON"));
+        }
+        self.depth -= 1;
+        self.raw(bracket)
+    }
+
+    pub fn begin_object(&mut self) -> Result<()> {
+        self.open("{")
+    }
+
+    pub fn end_object(&mut self) -> Result<()> {
+        self.close("}")
+    }
+
+    pub fn begin_array(&mut self) -> Result<()> {
+        self.open("[")
+    }
+
+    pub fn end_array(&mut self) -> Result<()> {
+        self.close("]")
+    }
+
+    /// Write the key of the next object member.
+    pub fn key(&mut self, name: &str) -> Result<()> {
+        self.separator()?;
+        self.escaped(name)?;
+        self.raw(":")?;
+        self.after_key = true;
+        Ok(())
+    }
+
+    /// Write a key and its value.
+    pub fn field(&mut self, name: &str, value: impl Value) -> Result<()> {
+        self.key(name)?;
+        value.write(self)
+    }
+
+    pub fn value(&mut self, value: impl Value) -> Result<()> {
+        value.write(self)
+    }
+
+    pub fn null(&mut self) -> Result<()> {
+        self.separator()?;
+        self.raw("null")
+    }
+
+    /// Write `value` as a string using its `Display` impl.
+    pub fn display(&mut self, value: impl Display) -> Result<()> {
+        self.separator()?;
+        self.raw("\"")?;
+        let mut escaper = Escaper(&mut self.out);
+        write!(escaper, "{value}").map_err(|_| Error::Index)?;
+        self.raw("\"")
+    }
+
+    fn number(&mut self, value: impl Display) -> Result<()> {
+        self.separator()?;
+        write!(self.out, "{value}").map_err(|_| Error::Index)
+    }
+
+    fn escaped(&mut self, s: &str) -> Result<()> {
+        self.raw("\"")?;
+        Escaper(&mut self.out)
+            .write_str(s)
+            .map_err(|_| Error::Index)?;
+        self.raw("\"")
+    }
+}
+
+/// Escapes string contents on their way to the underlying writer.
+struct Escaper<'a, W>(&'a mut W);
+
+impl<'a, W: Write> Write for Escaper<'a, W> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut start = 0;
+        for (i, c) in s.char_indices() {
+            if c != '"' && c != '\\' && c >= ' ' {
+                continue;
+            }
+            self.0.write_str(&s[start..i])?;
+            match c {
+                '"' => self.0.write_str("\\\"")?,
+                '\\' => self.0.write_str("\\\\")?,
+                '\n' => self.0.write_str("\\n")?,
+                '\r' => self.0.write_str("\\r")?,
+                '\t' => self.0.write_str("\\t")?,
+                c => write!(self.0, "\\u{:04x}", c as u32)?,
+            }
+            // Everything we escape is ASCII.
+            start = i + 1;
+        }
+        self.0.write_str(&s[start..])
+    }
+}
+
+impl Value for &str {
+    fn write<W: Write>(&self, json: &mut Json<W>) -> Result<()> {
+        json.separator()?;
+        json.escaped(self)
+    }
+}
+
+impl Value for bool {
+    fn write<W: Write>(&self, json: &mut Json<W>) -> Result<()> {
+        json.separator()?;
+        json.raw(if *self { "true" } else { "false" })
+    }
+}
+
+impl<T: Value> Value for Option<T> {
+    fn write<W: Write>(&self, json: &mut Json<W>) -> Result<()> {
+        match self {
+            Some(value) => value.write(json),
+            None => json.null(),
+        }
+    }
+}
+
+macro_rules! number_value {
+    ($($t:ty),*) => {
+        $(impl Value for $t {
+            fn write<W: Write>(&self, json: &mut Json<W>) -> Result<()> {
+                json.number(self)
+            }
+        })*
+    };
+}
+
+number_value!(u8, u16, u32, u64, usize, i8, i16, i32, i64);
@@ -7,20 +7,22 @@
 #![feature(iter_array_chunks)]
 #![feature(async_closure)]
 
+use core::fmt::Write as _;
 use core::option_env;
 
 use embassy_executor::Executor;
 use embassy_executor::_export::StaticCell;
 use embassy_net::tcp::TcpSocket;
-use embassy_net::{Config, IpListenEndpoint, Stack, StackResources};
+use embassy_net::{
+    Config, IpListenEndpoint, Ipv4Address, Ipv4Cidr, Stack, StackResources, StaticConfig,
+};
 use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
-use embassy_time::{Duration, Timer};
-use embedded_svc::wifi::{ClientConfiguration, Configuration, Wifi};
+use embassy_time::{Duration, Instant, Timer};
 use esp32c3_hal as hal;
 use esp_backtrace as _;
 use esp_println::logger::init_logger;
 use esp_println::println;
-use esp_wifi::wifi::{WifiController, WifiDevice, WifiEvent, WifiMode, WifiState};
+use esp_wifi::wifi::{WifiDevice, WifiMode};
 use esp_wifi::{initialize, EspWifiInitFor};
 use hal::clock::{ClockControl, CpuClock};
 use hal::dma::{DmaPriority, *};
@@ -39,18 +41,75 @@ use hal::{
 use hal::{Rng, IO};
 use smoltcp::socket::tcp::State;
 
+use auth::Auth;
+use matrix::Layout;
+use networks::Networks;
+use ota::Ota;
+use output::{Output, Scene, SharedOutput};
+use pd::PowerStatus;
+use playlist::{Player, Playlist};
+use provision::Portal;
+use schedule::Schedule;
+use settings::Settings;
+use script::Script;
+use show::Show;
+use storage::Storage;
+use sync::Group;
+use text::Text;
+use timecode::Clock;
+
+mod api;
 mod artnet;
+mod assets;
+mod auth;
 mod buffer;
+mod color;
+mod dhcp;
+mod dns;
+mod ed25519;
+mod effects;
 mod error;
+mod font;
+mod http;
+#[cfg(feature = "i2c-debug")]
+mod i2cdebug;
 mod i2creg;
+mod json;
+mod matrix;
+mod networks;
+mod ota;
+mod output;
 mod pd;
+mod playlist;
+mod preset;
+mod provision;
+#[cfg(feature = "i2c-debug")]
+mod regmap;
+mod sacn;
+mod schedule;
+mod script;
+mod settings;
+mod show;
+mod storage;
+mod sync;
+mod text;
+mod timecode;
 mod web;
+mod websocket;
 mod ws2812;
 
 pub use error::{Error, Result};
 
+/// Factory default Wi-Fi network, until one is set at run time.
 const SSID: Option<&str> = option_env!("SSID");
 const PASSWORD: Option<&str> = option_env!("PASSWORD");
+/// Initial password for the web admin, until one is set at run time.
+const ADMIN_PASSWORD: Option<&str> = option_env!("ADMIN_PASSWORD");
+/// Factory default, like the Art-Net names and the number of LEDs.
+const HTTP_PORT: u16 = 8080;
+/// Ed25519 public key, in hex, firmware updates have to be signed with.
+/// Without one, updates are refused.
+const OTA_PUBLIC_KEY: Option<&str> = option_env!("OTA_PUBLIC_KEY");
 
 macro_rules! singleton {
     ($val:expr) => {{
@@ -156,8 +215,26 @@ fn main() -> ! {
     )
     .unwrap();
 
+    let mut storage = Storage::new();
+    if let Err(e) = ota::boot(&mut storage) {
+        println!("failed to read otadata: {e:?}");
+    }
+    let settings = Settings::load(&mut storage).unwrap_or_else(|e| {
+        println!("failed to load settings: {e:?}");
+        Settings::new()
+    });
+    let networks = Networks::load(&mut storage)
+        .unwrap_or_else(|e| {
+            println!("failed to load saved networks: {e:?}");
+            None
+        })
+        .unwrap_or_else(Networks::new);
+    let has_network = !settings.ssid().is_empty() || networks.has_saved();
+    let portal = provision::wanted(&mut storage, has_network);
+
     let (wifi, _) = peripherals.RADIO.split();
-    let (wifi_interface, controller) = esp_wifi::wifi::new_with_mode(&init, wifi, WifiMode::Sta);
+    let mode = if portal { WifiMode::Ap } else { WifiMode::Sta };
+    let (wifi_interface, controller) = esp_wifi::wifi::new_with_mode(&init, wifi, mode);
 
     let timer_group0 = TimerGroup::new(
         peripherals.TIMG0,
@@ -172,7 +249,15 @@ fn main() -> ! {
     )
     .unwrap();
 
-    let config = Config::Dhcp(Default::default());
+    let config = if portal {
+        Config::Static(StaticConfig {
+            address: Ipv4Cidr::new(provision::AP_ADDRESS, provision::AP_PREFIX_LEN),
+            gateway: None,
+            dns_servers: Default::default(),
+        })
+    } else {
+        Config::Dhcp(Default::default())
+    };
 
     let seed = 1234; // very random, very secure seed
 
@@ -191,52 +276,132 @@ fn main() -> ! {
     )
     .unwrap();
 
-    let executor = EXECUTOR.init(Executor::new());
-    executor.run(|spawner| {
-        spawner.spawn(connection(controller)).ok();
-        spawner.spawn(net_task(&stack)).ok();
-        spawner.spawn(artnet::task(&stack, spi)).ok();
-        spawner.spawn(pd::task(i2c, pd_int_n)).ok();
-        spawner.spawn(task(1, &stack, i2c)).ok();
-        spawner.spawn(task(2, &stack, i2c)).ok();
-        spawner.spawn(task(3, &stack, i2c)).ok();
+    let scene = match Scene::load(&mut storage) {
+        Ok(Some(scene)) => scene,
+        Ok(None) => Scene::default(),
+        Err(e) => {
+            println!("failed to load scene: {e:?}");
+            Scene::default()
+        }
+    };
+    let matrix = Layout::load(&mut storage).unwrap_or_else(|e| {
+        println!("failed to load matrix layout: {e:?}");
+        None
     });
-}
-
-#[embassy_executor::task]
-async fn connection(mut controller: WifiController<'static>) {
-    println!("start connection task");
-    println!("Device capabilities: {:?}", controller.get_capabilities());
-    loop {
-        match esp_wifi::wifi::get_wifi_state() {
-            WifiState::StaConnected => {
-                // wait until we're no longer connected
-                controller.wait_for_event(WifiEvent::StaDisconnected).await;
-                Timer::after(Duration::from_millis(5000)).await
+    let schedule = Schedule::load(&mut storage)
+        .unwrap_or_else(|e| {
+            println!("failed to load schedule: {e:?}");
+            None
+        })
+        .unwrap_or(Schedule::new());
+    let script = Script::load(&mut storage).unwrap_or_else(|e| {
+        println!("failed to load script: {e:?}");
+        None
+    });
+    let timecode = timecode::Config::load(&mut storage)
+        .unwrap_or_else(|e| {
+            println!("failed to load timecode config: {e:?}");
+            None
+        })
+        .unwrap_or(timecode::Config::new());
+    let sync_config = sync::Config::load(&mut storage)
+        .unwrap_or_else(|e| {
+            println!("failed to load sync config: {e:?}");
+            None
+        })
+        .unwrap_or(sync::Config::new());
+    let auth_config = auth::Config::load(&mut storage)
+        .unwrap_or_else(|e| {
+            println!("failed to load auth config: {e:?}");
+            None
+        })
+        .unwrap_or_else(auth::Config::new);
+    let mut player = Player::new();
+    for index in 0..storage::MAX_PLAYLISTS as u8 {
+        match Playlist::load(index, &mut storage) {
+            Ok(Some(playlist)) if playlist.autostart => {
+                println!("starting playlist {index}");
+                player.play(index, playlist);
+                break;
             }
-            _ => {}
-        }
-        if !matches!(controller.is_started(), Ok(true)) {
-            let client_config = Configuration::Client(ClientConfiguration {
-                ssid: SSID.unwrap().into(),
-                password: PASSWORD.unwrap().into(),
-                ..Default::default()
-            });
-            controller.set_configuration(&client_config).unwrap();
-            println!("Starting wifi");
-            controller.start().await.unwrap();
-            println!("Wifi started!");
+            Ok(_) => (),
+            Err(e) => println!("failed to load playlist {index}: {e:?}"),
         }
-        println!("About to connect...");
+    }
+    let storage = &*singleton!(Mutex::<NoopRawMutex, Storage>::new(storage));
+    let player = &*singleton!(Mutex::<NoopRawMutex, Player>::new(player));
+    let show = &*singleton!(Mutex::<NoopRawMutex, Show>::new(Show::new()));
+    let schedule = &*singleton!(Mutex::<NoopRawMutex, Schedule>::new(schedule));
+    let clock = &*singleton!(Mutex::<NoopRawMutex, Clock>::new(Clock::new(timecode)));
+    let group = &*singleton!(Mutex::<NoopRawMutex, Group>::new(Group::new(sync_config)));
+    let mut output = Output::new(scene, matrix);
+    output.num_leds = settings.num_leds();
+    output.smooth = settings.smooth();
+    if let Some(script) = script {
+        output.script = script;
+    }
+    let output = &*singleton!(Mutex::<NoopRawMutex, Output>::new(output));
+    let power = &*singleton!(Mutex::<NoopRawMutex, PowerStatus>::new(PowerStatus::new()));
+    let auth = &*singleton!(Mutex::<NoopRawMutex, Auth>::new(Auth::new(auth_config)));
+    let ota = &*singleton!(Ota::new());
+    let settings = &*singleton!(Mutex::<NoopRawMutex, Settings>::new(settings));
+    let networks = &*singleton!(Mutex::<NoopRawMutex, Networks>::new(networks));
+    let portal = if portal {
+        Some(&*singleton!(Mutex::<NoopRawMutex, Portal>::new(Portal::new())))
+    } else {
+        None
+    };
+    let web_context = &*singleton!(web::Context {
+        stack,
+        i2c,
+        output,
+        storage,
+        schedule,
+        player,
+        show,
+        clock,
+        group,
+        power,
+        auth,
+        ota,
+        settings,
+        portal,
+        networks,
+        event_stream: Mutex::new(()),
+    });
 
-        match controller.connect().await {
-            Ok(_) => println!("Wifi connected!"),
-            Err(e) => {
-                println!("Failed to connect to wifi: {e:?}");
-                Timer::after(Duration::from_millis(5000)).await
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        match portal {
+            Some(portal) => {
+                spawner.spawn(provision::task(controller, has_network, portal)).ok();
+                spawner.spawn(dhcp::task(&stack)).ok();
+                spawner.spawn(dns::task(&stack)).ok();
+            }
+            None => {
+                spawner
+                    .spawn(networks::task(controller, settings, storage, networks))
+                    .ok();
             }
         }
-    }
+        spawner.spawn(net_task(&stack)).ok();
+        spawner
+            .spawn(artnet::task(
+                &stack, output, storage, show, player, clock, ota, settings,
+            ))
+            .ok();
+        spawner.spawn(sacn::task(&stack, output, settings)).ok();
+        spawner.spawn(output::task(spi, output)).ok();
+        spawner.spawn(sync::task(&stack, group, output)).ok();
+        spawner.spawn(pd::task(i2c, pd_int_n, power)).ok();
+        spawner.spawn(schedule::task(schedule, output, storage)).ok();
+        spawner.spawn(playlist::task(player, output, storage, clock)).ok();
+        spawner.spawn(show::task(show, output, storage, clock)).ok();
+        spawner.spawn(ota::task(&stack, storage, ota)).ok();
+        spawner.spawn(task(1, &stack, web_context)).ok();
+        spawner.spawn(task(2, &stack, web_context)).ok();
+        spawner.spawn(task(3, &stack, web_context)).ok();
+    });
 }
 
 #[embassy_executor::task]
@@ -244,11 +409,28 @@ async fn net_task(stack: &'static Stack<WifiDevice<'_>>) {
     stack.run().await
 }
 
+/// Show the device's address on the matrix, if one is configured and wants it.
+async fn scroll_ip(output: &SharedOutput, address: Ipv4Address) {
+    let mut output = output.lock().await;
+    if !output.matrix.map_or(false, |layout| layout.scroll_ip) {
+        return;
+    }
+
+    let mut buffer = [0u8; 32];
+    let mut msg = web::FmtBuffer::new(&mut buffer);
+    if write!(msg, "IP {address}").is_err() {
+        return;
+    }
+    let mut text = Text::new(msg.as_bytes(), Instant::now().as_millis() as u32);
+    text.repeat = 3;
+    output.text = Some(text);
+}
+
 #[embassy_executor::task(pool_size = 4)]
 async fn task(
     task_n: u32,
     stack: &'static Stack<WifiDevice<'_>>,
-    i2c: &'static Mutex<NoopRawMutex, &'static mut I2C<'_, I2C0>>,
+    ctx: &'static web::Context,
 ) {
     let mut rx_buffer = [0; 4096];
     let mut tx_buffer = [0; 4096];
@@ -264,11 +446,18 @@ async fn task(
     loop {
         if let Some(config) = stack.config() {
             println!("Got IP: {}", config.address);
+            if task_n == 1 {
+                scroll_ip(ctx.output, config.address.address()).await;
+            }
             break;
         }
         Timer::after(Duration::from_millis(500)).await;
     }
 
+    let port = match ctx.portal {
+        Some(_) => provision::PORTAL_PORT,
+        None => ctx.settings.lock().await.http_port(),
+    };
     loop {
         //Timer::after(Duration::from_millis(1_000)).await;
 
@@ -277,7 +466,7 @@ async fn task(
         if let Err(e) = socket
             .accept(IpListenEndpoint {
                 addr: None,
-                port: 8080,
+                port,
             })
             .await
         {
@@ -290,7 +479,7 @@ async fn task(
             println!("Connect from {:?}", remote);
         }
 
-        if let Err(e) = web::handle_connection(task_n, &mut socket, &i2c).await {
+        if let Err(e) = web::handle_connection(task_n, &mut socket, ctx).await {
             println!("web error {:?}", e)
         }
 
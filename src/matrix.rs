@@ -0,0 +1,372 @@
+use byteorder::LittleEndian;
+use num_derive::{FromPrimitive, ToPrimitive};
+use num_traits::{FromPrimitive, ToPrimitive};
+
+use crate::buffer::{MutBuffer, OldBuffer};
+use crate::color::Rgb;
+use crate::output::NUM_LEDS;
+use crate::storage::{Slot, Storage};
+use crate::{Error, Result};
+
+const LAYOUT_VERSION: u16 = 1;
+const LAYOUT_LEN: usize = 12;
+
+/// Corner of a panel where the first pixel is wired.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, FromPrimitive, ToPrimitive)]
+#[repr(u8)]
+pub enum Corner {
+    TopLeft = 0,
+    TopRight = 1,
+    BottomLeft = 2,
+    BottomRight = 3,
+}
+
+impl Corner {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "tl" => Some(Corner::TopLeft),
+            "tr" => Some(Corner::TopRight),
+            "bl" => Some(Corner::BottomLeft),
+            "br" => Some(Corner::BottomRight),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Corner::TopLeft => "tl",
+            Corner::TopRight => "tr",
+            Corner::BottomLeft => "bl",
+            Corner::BottomRight => "br",
+        }
+    }
+
+    fn flip_x(&self) -> bool {
+        matches!(self, Corner::TopRight | Corner::BottomRight)
+    }
+
+    fn flip_y(&self) -> bool {
+        matches!(self, Corner::BottomLeft | Corner::BottomRight)
+    }
+}
+
+/// Clockwise rotation applied to the whole display.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, FromPrimitive, ToPrimitive)]
+#[repr(u8)]
+pub enum Rotation {
+    R0 = 0,
+    R90 = 1,
+    R180 = 2,
+    R270 = 3,
+}
+
+impl Rotation {
+    pub fn from_degrees(degrees: u16) -> Option<Self> {
+        match degrees {
+            0 => Some(Rotation::R0),
+            90 => Some(Rotation::R90),
+            180 => Some(Rotation::R180),
+            270 => Some(Rotation::R270),
+            _ => None,
+        }
+    }
+
+    pub fn degrees(&self) -> u16 {
+        *self as u16 * 90
+    }
+}
+
+/// Maps (x, y) coordinates onto strip indices for one or more identical
+/// panels.
+///
+/// Panels are chained row by row starting at the top left of the display.
+/// Within a panel pixels run along rows starting from `origin`, either all
+/// in the same direction (progressive) or alternating (serpentine).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Layout {
+    /// Strip index of the first pixel of the first panel.
+    pub start: u16,
+    /// Panel width in pixels.
+    pub width: u16,
+    /// Panel height in pixels.
+    pub height: u16,
+    pub serpentine: bool,
+    pub origin: Corner,
+    pub rotation: Rotation,
+    pub tiles_x: u8,
+    pub tiles_y: u8,
+    /// Alternate the direction of every other row of panels.
+    pub tile_serpentine: bool,
+    /// Scroll the IP address across the matrix once the network is up.
+    pub scroll_ip: bool,
+}
+
+impl Layout {
+    pub const fn new(width: u16, height: u16) -> Self {
+        Self {
+            start: 0,
+            width,
+            height,
+            serpentine: true,
+            origin: Corner::TopLeft,
+            rotation: Rotation::R0,
+            tiles_x: 1,
+            tiles_y: 1,
+            tile_serpentine: false,
+            scroll_ip: true,
+        }
+    }
+
+    /// Size of the unrotated display made up of all panels, if it fits in
+    /// display coordinates.
+    fn checked_size(&self) -> Option<(u16, u16)> {
+        let w = (self.width as usize).checked_mul(self.tiles_x.max(1) as usize)?;
+        let h = (self.height as usize).checked_mul(self.tiles_y.max(1) as usize)?;
+        Some((u16::try_from(w).ok()?, u16::try_from(h).ok()?))
+    }
+
+    /// Reject a layout that is empty, too large for display coordinates or
+    /// runs past the end of a strip of `num_leds`.  Layouts are checked
+    /// before any size is derived from them.
+    pub fn check(&self, num_leds: usize) -> Result<()> {
+        if self.width == 0 || self.height == 0 || self.tiles_x == 0 || self.tiles_y == 0 {
+            return Err(Error::Generic("Empty matrix"));
+        }
+        let (w, h) = self
+            .checked_size()
+            .ok_or(Error::Generic("Matrix larger than strip"))?;
+        let end = (w as usize)
+            .checked_mul(h as usize)
+            .and_then(|pixels| pixels.checked_add(self.start as usize));
+        if end.map_or(true, |end| end > num_leds) {
+            return Err(Error::Generic("Matrix larger than strip"));
+        }
+        Ok(())
+    }
+
+    /// Size of the unrotated display made up of all panels.  Empty for a
+    /// layout that fails [`Layout::check`].
+    fn physical_size(&self) -> (u16, u16) {
+        self.checked_size().unwrap_or((0, 0))
+    }
+
+    /// Width of the display as seen by effects, after rotation.
+    pub fn display_width(&self) -> u16 {
+        let (w, h) = self.physical_size();
+        match self.rotation {
+            Rotation::R0 | Rotation::R180 => w,
+            Rotation::R90 | Rotation::R270 => h,
+        }
+    }
+
+    /// Height of the display as seen by effects, after rotation.
+    pub fn display_height(&self) -> u16 {
+        let (w, h) = self.physical_size();
+        match self.rotation {
+            Rotation::R0 | Rotation::R180 => h,
+            Rotation::R90 | Rotation::R270 => w,
+        }
+    }
+
+    pub fn num_pixels(&self) -> usize {
+        let (w, h) = self.physical_size();
+        w as usize * h as usize
+    }
+
+    /// Strip index of the pixel at display coordinates (`x`, `y`).
+    pub fn index(&self, x: u16, y: u16) -> Option<usize> {
+        if x >= self.display_width() || y >= self.display_height() || self.width == 0 {
+            return None;
+        }
+
+        let (pw, ph) = self.physical_size();
+        let (px, py) = match self.rotation {
+            Rotation::R0 => (x, y),
+            Rotation::R90 => (y, ph - 1 - x),
+            Rotation::R180 => (pw - 1 - x, ph - 1 - y),
+            Rotation::R270 => (pw - 1 - y, x),
+        };
+
+        let tiles_x = self.tiles_x.max(1) as u16;
+        let (ty, mut tx) = (py / self.height, px / self.width);
+        if self.tile_serpentine && ty % 2 == 1 {
+            tx = tiles_x - 1 - tx;
+        }
+        let tile = (ty * tiles_x + tx) as usize;
+
+        let (mut lx, mut ly) = (px % self.width, py % self.height);
+        if self.origin.flip_x() {
+            lx = self.width - 1 - lx;
+        }
+        if self.origin.flip_y() {
+            ly = self.height - 1 - ly;
+        }
+        if self.serpentine && ly % 2 == 1 {
+            lx = self.width - 1 - lx;
+        }
+
+        let panel_len = self.width as usize * self.height as usize;
+        Some(
+            self.start as usize
+                + tile * panel_len
+                + ly as usize * self.width as usize
+                + lx as usize,
+        )
+    }
+
+    /// Display coordinates of strip pixel `index`, the inverse of
+    /// [`Layout::index`].
+    pub fn coords(&self, index: usize) -> Option<(u16, u16)> {
+        let offset = index.checked_sub(self.start as usize)?;
+        if offset >= self.num_pixels() {
+            return None;
+        }
+
+        let panel_len = self.width as usize * self.height as usize;
+        let tiles_x = self.tiles_x.max(1) as u16;
+        let tile = (offset / panel_len) as u16;
+        let pixel = offset % panel_len;
+
+        let ly = (pixel / self.width as usize) as u16;
+        let mut lx = (pixel % self.width as usize) as u16;
+        if self.serpentine && ly % 2 == 1 {
+            lx = self.width - 1 - lx;
+        }
+        let lx = if self.origin.flip_x() {
+            self.width - 1 - lx
+        } else {
+            lx
+        };
+        let ly = if self.origin.flip_y() {
+            self.height - 1 - ly
+        } else {
+            ly
+        };
+
+        let ty = tile / tiles_x;
+        let mut tx = tile % tiles_x;
+        if self.tile_serpentine && ty % 2 == 1 {
+            tx = tiles_x - 1 - tx;
+        }
+
+        let (pw, ph) = self.physical_size();
+        let (px, py) = (tx * self.width + lx, ty * self.height + ly);
+        Some(match self.rotation {
+            Rotation::R0 => (px, py),
+            Rotation::R90 => (ph - 1 - py, px),
+            Rotation::R180 => (pw - 1 - px, ph - 1 - py),
+            Rotation::R270 => (py, pw - 1 - px),
+        })
+    }
+
+    pub fn write(&self, buf: &mut MutBuffer<LittleEndian>) -> Result<()> {
+        buf.write_u16(self.start)?;
+        buf.write_u16(self.width)?;
+        buf.write_u16(self.height)?;
+        buf.write_u8(
+            self.serpentine as u8 | (self.tile_serpentine as u8) << 1 | (self.scroll_ip as u8) << 2,
+        )?;
+        buf.write_u8(self.origin.to_u8().unwrap())?;
+        buf.write_u8(self.rotation.to_u8().unwrap())?;
+        buf.write_u8(self.tiles_x)?;
+        buf.write_u8(self.tiles_y)?;
+        buf.write_u8(0)?; // reserved
+        Ok(())
+    }
+
+    pub fn parse(buf: &mut OldBuffer<LittleEndian>) -> Result<Self> {
+        let start = buf.read_u16()?;
+        let width = buf.read_u16()?;
+        let height = buf.read_u16()?;
+        let flags = buf.read_u8()?;
+        let origin = Corner::from_u8(buf.read_u8()?).ok_or(Error::Generic("bad corner"))?;
+        let rotation = Rotation::from_u8(buf.read_u8()?).ok_or(Error::Generic("bad rotation"))?;
+        let tiles_x = buf.read_u8()?;
+        let tiles_y = buf.read_u8()?;
+        let _reserved = buf.read_u8()?;
+        Ok(Self {
+            start,
+            width,
+            height,
+            serpentine: flags & 0x1 != 0,
+            origin,
+            rotation,
+            tiles_x,
+            tiles_y,
+            tile_serpentine: flags & 0x2 != 0,
+            scroll_ip: flags & 0x4 != 0,
+        })
+    }
+
+    pub fn load(storage: &mut Storage) -> Result<Option<Self>> {
+        let mut data = [0u8; LAYOUT_LEN];
+        let Some((version, data)) = storage.load(Slot::Matrix, &mut data)? else {
+            return Ok(None);
+        };
+        if version != LAYOUT_VERSION {
+            return Ok(None);
+        }
+        let layout = Self::parse(&mut OldBuffer::new(data))?;
+        layout.check(NUM_LEDS)?;
+        Ok(Some(layout))
+    }
+
+    pub fn save(layout: Option<&Self>, storage: &mut Storage) -> Result<()> {
+        let Some(layout) = layout else {
+            return storage.erase(Slot::Matrix);
+        };
+        let mut data = [0u8; LAYOUT_LEN];
+        let buf = &mut MutBuffer::<LittleEndian>::new(&mut data);
+        layout.write(buf)?;
+        storage.store(Slot::Matrix, LAYOUT_VERSION, &data)
+    }
+}
+
+/// 2D view of a frame through a [`Layout`].
+pub struct Canvas<'a> {
+    layout: &'a Layout,
+    pixels: &'a mut [Rgb],
+}
+
+impl<'a> Canvas<'a> {
+    pub fn new(layout: &'a Layout, pixels: &'a mut [Rgb]) -> Self {
+        Self { layout, pixels }
+    }
+
+    pub fn width(&self) -> u16 {
+        self.layout.display_width()
+    }
+
+    pub fn height(&self) -> u16 {
+        self.layout.display_height()
+    }
+
+    pub fn get(&self, x: u16, y: u16) -> Option<Rgb> {
+        self.layout
+            .index(x, y)
+            .and_then(|i| self.pixels.get(i))
+            .copied()
+    }
+
+    /// Set the pixel at (`x`, `y`).  Out of range coordinates are ignored.
+    pub fn set(&mut self, x: u16, y: u16, color: Rgb) {
+        if let Some(pixel) = self.layout.index(x, y).and_then(|i| self.pixels.get_mut(i)) {
+            *pixel = color;
+        }
+    }
+
+    /// Like [`Canvas::set`] but accepts coordinates that may be off screen.
+    pub fn set_signed(&mut self, x: i32, y: i32, color: Rgb) {
+        if x >= 0 && y >= 0 && x <= u16::MAX as i32 && y <= u16::MAX as i32 {
+            self.set(x as u16, y as u16, color);
+        }
+    }
+
+    pub fn fill(&mut self, color: Rgb) {
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                self.set(x, y, color);
+            }
+        }
+    }
+}
@@ -0,0 +1,573 @@
+//! The Wi-Fi networks a node knows, and the station that joins them.
+//!
+//! Besides the network in [`crate::settings`] up to [`MAX_SAVED`] more can
+//! be saved, each with a priority.  Without a connection the station scans
+//! and joins the known network in range with the highest priority, the
+//! stronger signal breaking ties, through its strongest access point.  A
+//! network that fails to connect is left alone for a while, twice as long
+//! after each failure, so a rack that moves between venues settles on
+//! whatever is there.  Losing the connection starts the choice over, which
+//! moves to a stronger access point if there is one.
+
+use core::fmt::Write;
+
+use byteorder::LittleEndian;
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_svc::wifi::{AccessPointInfo, ClientConfiguration, Configuration, Wifi};
+use esp_println::println;
+use esp_wifi::wifi::{WifiController, WifiError, WifiEvent};
+
+use crate::buffer::{MutBuffer, OldBuffer};
+use crate::http::{Connection, Request, Status, JSON};
+use crate::json::Json;
+use crate::provision;
+use crate::settings::SharedSettings;
+use crate::storage::{SharedStorage, Slot, Storage};
+use crate::web::{parse_param, query_param, url_decode, Context, FmtBuffer};
+use crate::{Error, Result};
+
+const NETWORKS_VERSION: u16 = 1;
+pub const MAX_SAVED: usize = 8;
+const MAX_SSID_LEN: usize = 32;
+const MAX_PASSWORD_LEN: usize = 64;
+/// Each saved network is stored as:
+///
+///   ssid_len: u8, ssid, password_len: u8, password, priority: u8
+const SAVED_LEN: usize = 1 + MAX_SAVED * (3 + MAX_SSID_LEN + MAX_PASSWORD_LEN);
+const MAX_FORM_LEN: usize = 3 * (MAX_SSID_LEN + MAX_PASSWORD_LEN) + 64;
+/// Priority of the network in the settings.
+const SETTINGS_PRIORITY: u8 = 0;
+
+/// Access points kept from a scan.
+const MAX_SCAN: usize = 16;
+const MAX_HISTORY: usize = 8;
+/// Wait before trying a network again after its first failure.
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// How often to look again while no known network is in range.
+const SCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Ssid {
+    data: [u8; MAX_SSID_LEN],
+    len: u8,
+}
+
+impl Ssid {
+    pub fn new(ssid: &str) -> Result<Self> {
+        let mut data = [0; MAX_SSID_LEN];
+        data.get_mut(..ssid.len())
+            .ok_or(Error::Generic("ssid"))?
+            .copy_from_slice(ssid.as_bytes());
+        Ok(Self {
+            data,
+            len: ssid.len() as u8,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.data[..self.len as usize]).unwrap_or("")
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Network {
+    pub ssid: Ssid,
+    password: [u8; MAX_PASSWORD_LEN],
+    password_len: u8,
+    /// Higher is preferred.
+    pub priority: u8,
+}
+
+impl Network {
+    pub fn new(ssid: &str, password: &str, priority: u8) -> Result<Self> {
+        if ssid.is_empty() {
+            return Err(Error::Generic("ssid"));
+        }
+        let mut network = Self {
+            ssid: Ssid::new(ssid)?,
+            password: [0; MAX_PASSWORD_LEN],
+            password_len: password.len() as u8,
+            priority,
+        };
+        network
+            .password
+            .get_mut(..password.len())
+            .ok_or(Error::Generic("password"))?
+            .copy_from_slice(password.as_bytes());
+        Ok(network)
+    }
+
+    pub fn password(&self) -> &str {
+        core::str::from_utf8(&self.password[..self.password_len as usize]).unwrap_or("")
+    }
+
+    fn write(&self, buf: &mut MutBuffer<LittleEndian>) -> Result<()> {
+        buf.write_u8(self.ssid.len)?;
+        buf.write(self.ssid.as_str().as_bytes())?;
+        buf.write_u8(self.password_len)?;
+        buf.write(self.password().as_bytes())?;
+        buf.write_u8(self.priority)?;
+        Ok(())
+    }
+
+    fn parse(buf: &mut OldBuffer<LittleEndian>) -> Result<Self> {
+        let len = buf.read_u8()? as usize;
+        let ssid = core::str::from_utf8(buf.take(len)?).map_err(|_| Error::Generic("ssid"))?;
+        let len = buf.read_u8()? as usize;
+        let password =
+            core::str::from_utf8(buf.take(len)?).map_err(|_| Error::Generic("password"))?;
+        Self::new(ssid, password, buf.read_u8()?)
+    }
+}
+
+/// A network picked to join, and the access point it was seen through.
+#[derive(Clone, Copy, Debug)]
+pub struct Target {
+    pub network: Network,
+    pub bssid: Option<[u8; 6]>,
+    pub channel: Option<u8>,
+    pub rssi: Option<i8>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Event {
+    Connected,
+    Failed,
+    Disconnected,
+}
+
+impl Event {
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::Connected => "connected",
+            Event::Failed => "failed",
+            Event::Disconnected => "disconnected",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct HistoryEntry {
+    at: Instant,
+    event: Event,
+    ssid: Ssid,
+    rssi: Option<i8>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Backoff {
+    ssid: Ssid,
+    failures: u8,
+    retry_at: Instant,
+}
+
+pub struct Networks {
+    saved: [Option<Network>; MAX_SAVED],
+    /// Networks that failed to connect, one more than can be saved for the
+    /// one in the settings.
+    backoff: [Option<Backoff>; MAX_SAVED + 1],
+    /// Newest first.
+    history: [Option<HistoryEntry>; MAX_HISTORY],
+    /// The network joined, with the access point and signal it was joined
+    /// at.
+    current: Option<(Target, Instant)>,
+}
+
+pub type SharedNetworks = Mutex<NoopRawMutex, Networks>;
+
+impl Networks {
+    pub fn new() -> Self {
+        Self {
+            saved: [None; MAX_SAVED],
+            backoff: [None; MAX_SAVED + 1],
+            history: [None; MAX_HISTORY],
+            current: None,
+        }
+    }
+
+    pub fn load(storage: &mut Storage) -> Result<Option<Self>> {
+        let mut data = [0u8; SAVED_LEN];
+        let Some((version, data)) = storage.load(Slot::Networks, &mut data)? else {
+            return Ok(None);
+        };
+        if version != NETWORKS_VERSION {
+            return Ok(None);
+        }
+        let buf = &mut OldBuffer::new(data);
+        let mut networks = Self::new();
+        let count = buf.read_u8()? as usize;
+        for slot in networks.saved.iter_mut().take(count) {
+            *slot = Some(Network::parse(buf)?);
+        }
+        Ok(Some(networks))
+    }
+
+    pub fn save(&self, storage: &mut Storage) -> Result<()> {
+        let mut data = [0u8; SAVED_LEN];
+        let buf = &mut MutBuffer::<LittleEndian>::new(&mut data);
+        buf.write_u8(self.saved().count() as u8)?;
+        for network in self.saved() {
+            network.write(buf)?;
+        }
+        let len = buf.pos();
+        storage.store(Slot::Networks, NETWORKS_VERSION, &data[..len])
+    }
+
+    pub fn saved(&self) -> impl Iterator<Item = &Network> {
+        self.saved.iter().flatten()
+    }
+
+    pub fn has_saved(&self) -> bool {
+        self.saved().next().is_some()
+    }
+
+    /// Save `network`, replacing a saved one of the same name.
+    pub fn add(&mut self, network: Network) -> Result<()> {
+        let slot = match self.find(network.ssid.as_str()) {
+            Some(index) => index,
+            None => self
+                .saved
+                .iter()
+                .position(Option::is_none)
+                .ok_or(Error::Generic("Too many networks"))?,
+        };
+        self.saved[slot] = Some(network);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, ssid: &str) -> Result<()> {
+        let index = self.find(ssid).ok_or(Error::Generic("Unknown network"))?;
+        if let Some(network) = self.saved[index].take() {
+            self.forgive(&network.ssid);
+        }
+        Ok(())
+    }
+
+    fn find(&self, ssid: &str) -> Option<usize> {
+        self.saved
+            .iter()
+            .position(|saved| saved.map_or(false, |saved| saved.ssid.as_str() == ssid))
+    }
+
+    /// Stop backing off from `ssid`.
+    fn forgive(&mut self, ssid: &Ssid) {
+        for backoff in self.backoff.iter_mut() {
+            if backoff.map_or(false, |backoff| backoff.ssid == *ssid) {
+                *backoff = None;
+            }
+        }
+    }
+
+    fn retry_at(&self, ssid: &Ssid) -> Option<Instant> {
+        self.backoff
+            .iter()
+            .flatten()
+            .find(|backoff| backoff.ssid == *ssid)
+            .map(|backoff| backoff.retry_at)
+    }
+
+    /// When the first network backing off may be tried again.
+    pub fn next_retry(&self, now: Instant) -> Option<Instant> {
+        self.backoff
+            .iter()
+            .flatten()
+            .map(|backoff| backoff.retry_at)
+            .filter(|at| *at > now)
+            .min()
+    }
+
+    /// Pick the network to join from the known ones that aren't backing
+    /// off.  Those in `found` come first, by priority and then by the
+    /// signal of their strongest access point.  When none are in range a
+    /// known one is tried blind, in case it's hidden.  `settings` is the
+    /// network in the settings, unless a saved one has its name.
+    pub fn choose(
+        &self,
+        settings: Option<Network>,
+        found: &[AccessPointInfo],
+        now: Instant,
+    ) -> Option<Target> {
+        let settings = settings.filter(|network| self.find(network.ssid.as_str()).is_none());
+        let known = self
+            .saved()
+            .copied()
+            .chain(settings)
+            .filter(|network| self.retry_at(&network.ssid).map_or(true, |at| at <= now));
+
+        let mut best: Option<Target> = None;
+        for network in known {
+            let strongest = found
+                .iter()
+                .filter(|info| info.ssid.as_str() == network.ssid.as_str())
+                .max_by_key(|info| info.signal_strength);
+            let target = Target {
+                network,
+                bssid: strongest.map(|info| info.bssid),
+                channel: strongest.map(|info| info.channel),
+                rssi: strongest.map(|info| info.signal_strength),
+            };
+            let rank = |target: &Target| {
+                (
+                    target.rssi.is_some(),
+                    target.network.priority,
+                    target.rssi.unwrap_or(i8::MIN),
+                )
+            };
+            if best.map_or(true, |best| rank(&target) > rank(&best)) {
+                best = Some(target);
+            }
+        }
+        best
+    }
+
+    fn record(&mut self, at: Instant, event: Event, ssid: Ssid, rssi: Option<i8>) {
+        self.history.copy_within(..MAX_HISTORY - 1, 1);
+        self.history[0] = Some(HistoryEntry {
+            at,
+            event,
+            ssid,
+            rssi,
+        });
+    }
+
+    pub fn connected(&mut self, target: Target, now: Instant) {
+        self.forgive(&target.network.ssid);
+        self.current = Some((target, now));
+        self.record(now, Event::Connected, target.network.ssid, target.rssi);
+    }
+
+    pub fn disconnected(&mut self, now: Instant) {
+        if let Some((target, _)) = self.current.take() {
+            self.record(now, Event::Disconnected, target.network.ssid, target.rssi);
+        }
+    }
+
+    /// Back off from the network of `target`, twice as long as last time.
+    /// A free entry is taken first, then one whose backoff has run out, and
+    /// only then the one that is due soonest.
+    pub fn failed(&mut self, target: Target, now: Instant) {
+        let ssid = target.network.ssid;
+        let index = self
+            .backoff
+            .iter()
+            .position(|backoff| backoff.map_or(false, |backoff| backoff.ssid == ssid))
+            .or_else(|| self.backoff.iter().position(Option::is_none))
+            .or_else(|| {
+                self.backoff
+                    .iter()
+                    .position(|backoff| backoff.map_or(false, |backoff| backoff.retry_at <= now))
+            })
+            .or_else(|| {
+                (0..self.backoff.len())
+                    .min_by_key(|&index| self.backoff[index].map(|backoff| backoff.retry_at))
+            });
+        self.record(now, Event::Failed, ssid, target.rssi);
+        let Some(index) = index else {
+            return;
+        };
+        let failures = match self.backoff[index] {
+            Some(backoff) if backoff.ssid == ssid => backoff.failures.saturating_add(1),
+            _ => 1,
+        };
+        let delay = MIN_BACKOFF.as_secs() << (failures - 1).min(16);
+        let delay = Duration::from_secs(delay.min(MAX_BACKOFF.as_secs()));
+        self.backoff[index] = Some(Backoff {
+            ssid,
+            failures,
+            retry_at: now + delay,
+        });
+    }
+
+    /// The connection, the saved networks and what happened lately, for
+    /// the status API.  Times are in seconds.
+    pub fn write_status<W: Write>(&self, json: &mut Json<W>, now: Instant) -> Result<()> {
+        json.begin_object()?;
+        match self.current {
+            Some((target, since)) => {
+                json.field("ssid", target.network.ssid.as_str())?;
+                json.field("rssi", target.rssi)?;
+                json.key("bssid")?;
+                match target.bssid {
+                    Some([a, b, c, d, e, f]) => json.display(format_args!(
+                        "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{f:02x}"
+                    ))?,
+                    None => json.null()?,
+                }
+                json.field("channel", target.channel)?;
+                json.field(
+                    "connected_s",
+                    now.saturating_duration_since(since).as_secs(),
+                )?;
+            }
+            None => json.field("ssid", None::<&str>)?,
+        }
+        json.key("saved")?;
+        json.begin_array()?;
+        for network in self.saved() {
+            json.begin_object()?;
+            json.field("ssid", network.ssid.as_str())?;
+            json.field("priority", network.priority)?;
+            let retry_in = self
+                .retry_at(&network.ssid)
+                .map(|at| at.saturating_duration_since(now).as_secs());
+            json.field("retry_in_s", retry_in)?;
+            json.end_object()?;
+        }
+        json.end_array()?;
+        json.key("history")?;
+        json.begin_array()?;
+        for entry in self.history.iter().flatten() {
+            json.begin_object()?;
+            json.field("ago_s", now.saturating_duration_since(entry.at).as_secs())?;
+            json.field("event", entry.event.name())?;
+            json.field("ssid", entry.ssid.as_str())?;
+            json.field("rssi", entry.rssi)?;
+            json.end_object()?;
+        }
+        json.end_array()?;
+        json.end_object()
+    }
+}
+
+/// Scanning needs the station running.
+async fn start(controller: &mut WifiController<'static>) -> core::result::Result<(), WifiError> {
+    if !matches!(controller.is_started(), Ok(true)) {
+        controller.set_configuration(&Configuration::Client(Default::default()))?;
+        controller.start().await?;
+    }
+    Ok(())
+}
+
+async fn join(
+    controller: &mut WifiController<'static>,
+    target: &Target,
+) -> core::result::Result<(), WifiError> {
+    let config = Configuration::Client(ClientConfiguration {
+        ssid: target.network.ssid.as_str().into(),
+        password: target.network.password().into(),
+        bssid: target.bssid,
+        channel: target.channel,
+        ..Default::default()
+    });
+    controller.stop().await?;
+    controller.set_configuration(&config)?;
+    controller.start().await?;
+    controller.connect().await
+}
+
+/// Keeps the station connected to the best known network, and starts the
+/// setup portal when none has worked for [`provision::PORTAL_AFTER`].
+#[embassy_executor::task]
+pub(crate) async fn task(
+    mut controller: WifiController<'static>,
+    settings: &'static SharedSettings,
+    storage: &'static SharedStorage,
+    networks: &'static SharedNetworks,
+) {
+    println!("start connection task");
+    println!("Device capabilities: {:?}", controller.get_capabilities());
+    // Since boot, or since the connection was lost.
+    let mut disconnected_at = Instant::now();
+    loop {
+        if let Err(e) = start(&mut controller).await {
+            println!("Failed to start wifi: {e:?}");
+        }
+        let scan = controller.scan_n::<MAX_SCAN>();
+        let found = match &scan {
+            Ok((found, _)) => &found[..],
+            Err(e) => {
+                println!("Wi-Fi scan failed: {e:?}");
+                &[]
+            }
+        };
+        let settings_network = {
+            let settings = settings.lock().await;
+            Network::new(settings.ssid(), settings.password(), SETTINGS_PRIORITY).ok()
+        };
+        let target = networks
+            .lock()
+            .await
+            .choose(settings_network, found, Instant::now());
+
+        let result = match target {
+            Some(target) => {
+                println!(
+                    "Joining {} ({:?} dBm)",
+                    target.network.ssid.as_str(),
+                    target.rssi
+                );
+                Some((target, join(&mut controller, &target).await))
+            }
+            None => None,
+        };
+        match result {
+            Some((target, Ok(()))) => {
+                println!("Wifi connected!");
+                networks.lock().await.connected(target, Instant::now());
+                controller.wait_for_event(WifiEvent::StaDisconnected).await;
+                println!("Wifi disconnected");
+                disconnected_at = Instant::now();
+                networks.lock().await.disconnected(disconnected_at);
+                continue;
+            }
+            Some((target, Err(e))) => {
+                println!("Failed to connect to wifi: {e:?}");
+                networks.lock().await.failed(target, Instant::now());
+            }
+            None => (),
+        }
+
+        if disconnected_at.elapsed() > provision::PORTAL_AFTER {
+            println!("Starting the Wi-Fi setup portal");
+            provision::start(storage).await;
+        }
+        // Nothing to try until a network's backoff runs out or a scan finds
+        // one.
+        let now = Instant::now();
+        let next_scan = now + SCAN_INTERVAL;
+        let retry_at = networks.lock().await.next_retry(now).unwrap_or(next_scan);
+        Timer::at(retry_at.min(next_scan)).await;
+    }
+}
+
+fn form_value<'a>(form: &str, key: &'static str, buf: &'a mut [u8]) -> Result<&'a str> {
+    let value = url_decode(query_param(form, key).unwrap_or(""), buf)?;
+    core::str::from_utf8(value).map_err(|_| Error::Generic(key))
+}
+
+/// `GET /networks` describes the connection and the saved networks,
+/// `POST /networks/add` saves the `ssid`, `password` and `priority` in its
+/// form encoded body and `POST /networks/remove` forgets `ssid`.
+pub async fn handle(conn: &mut Connection<'_, '_>, ctx: &Context, req: &Request<'_>) -> Result<()> {
+    let command = req.param("command");
+    if command.is_some() {
+        let mut body = [0u8; MAX_FORM_LEN];
+        let form = core::str::from_utf8(conn.read_body(&mut body).await?)
+            .map_err(|_| Error::Generic("Form isn't UTF-8"))?;
+        let mut ssid = [0u8; MAX_SSID_LEN];
+        let ssid = form_value(form, "ssid", &mut ssid)?;
+        let mut networks = ctx.networks.lock().await;
+        match command {
+            Some("add") => {
+                let mut password = [0u8; MAX_PASSWORD_LEN];
+                let password = form_value(form, "password", &mut password)?;
+                let priority = parse_param(form, "priority")?.unwrap_or(0);
+                networks.add(Network::new(ssid, password, priority)?)?;
+            }
+            Some("remove") => networks.remove(ssid)?,
+            _ => return Err(Error::Generic("Unknown networks command")),
+        }
+        networks.save(&mut *ctx.storage.lock().await)?;
+    }
+
+    let mut buffer = [0u8; 1536];
+    let mut json = Json::new(FmtBuffer::new(&mut buffer));
+    ctx.networks
+        .lock()
+        .await
+        .write_status(&mut json, Instant::now())?;
+    conn.send(Status::Ok, JSON, json.into_inner().as_bytes())
+        .await
+}
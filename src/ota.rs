@@ -0,0 +1,829 @@
+//! Firmware updates over the network.  An image is streamed into whichever
+//! of the two app partitions isn't running, checked, and the ESP-IDF
+//! bootloader is pointed at it through the `otadata` partition.
+//!
+//! A new image boots on trial.  `task` confirms it once the network has
+//! come up and it has stayed up for a while.  If it resets before then, the
+//! next boot marks it aborted and restarts into the previous image.  This
+//! relies on the bootloader being built without its own app rollback, as
+//! the one `espflash` flashes is.
+//!
+//! An update, over HTTP or as an Art-Net firmware upload, is the image
+//! written by `espflash save-image` followed by an Ed25519 signature of
+//! the image's SHA-256.  Only the public key, `OTA_PUBLIC_KEY`, is built
+//! in.  With a key made once by
+//!
+//!   openssl genpkey -algorithm ed25519 -out ota.pem
+//!   openssl pkey -in ota.pem -pubout -outform DER | tail -c 32 | xxd -p -c 32
+//!
+//! the second line printing `OTA_PUBLIC_KEY`, an update is made by
+//!
+//!   openssl dgst -sha256 -binary rgb.bin > rgb.sha256
+//!   openssl pkeyutl -sign -inkey ota.pem -rawin -in rgb.sha256 | cat rgb.bin - > rgb.ota
+//!
+//! No upload is taken while an image is on trial.  It would go into the
+//! slot of the previous image, which is what a rollback boots.
+
+use core::fmt::Write;
+
+use embassy_futures::yield_now;
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::{Mutex, MutexGuard};
+use embassy_time::{Duration, Instant, Timer};
+use esp_println::println;
+use esp_wifi::wifi::WifiDevice;
+
+use crate::api;
+use crate::ed25519;
+use crate::http::{Connection, Method, Request, Status, JSON};
+use crate::json::Json;
+use crate::storage::{crc32_update, Region, SharedStorage, APP_REGIONS, OTADATA_REGION};
+use crate::storage::{Storage, SECTOR_SIZE};
+use crate::web::{Context, FmtBuffer};
+use crate::{Error, Result};
+
+const SIGNATURE_LEN: usize = 64;
+const PUBLIC_KEY_LEN: usize = 32;
+const PAGE_LEN: usize = 256;
+/// How much is read from flash between yields while checking an image.
+const CHUNK_LEN: usize = 1024;
+
+const IMAGE_MAGIC: u8 = 0xe9;
+/// The image header and its extended header.
+const IMAGE_HEADER_LEN: usize = 24;
+const SEGMENT_HEADER_LEN: usize = 8;
+const MAX_SEGMENTS: u8 = 16;
+const CHIP_ID_ESP32C3: u16 = 5;
+const CHECKSUM_SEED: u8 = 0xef;
+const HASH_LEN: usize = 32;
+
+const ENTRY_LEN: usize = 32;
+
+/// A new image has to have the network up this long after boot to be kept.
+const SELF_CHECK_TIME: Duration = Duration::from_secs(30);
+/// Give up on a new image that hasn't passed its self check by now.
+const SELF_CHECK_TIMEOUT: Duration = Duration::from_secs(300);
+const TICK: Duration = Duration::from_millis(1000);
+/// Time for the response to reach the client before restarting.
+const RESTART_DELAY: Duration = Duration::from_millis(1000);
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 over data fed in as it arrives.
+#[derive(Clone)]
+pub struct Sha256 {
+    h: [u32; 8],
+    block: [u8; 64],
+    len: u64,
+}
+
+impl Sha256 {
+    pub fn new() -> Self {
+        Self {
+            h: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            block: [0; 64],
+            len: 0,
+        }
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for (i, word) in self.block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.h;
+        for (k, w) in K.iter().zip(w) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(*k)
+                .wrapping_add(w);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            (h, g, f, e, d, c, b, a) = (g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2));
+        }
+        for (h, v) in self.h.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *h = h.wrapping_add(v);
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let used = (self.len % 64) as usize;
+            let len = (64 - used).min(data.len());
+            self.block[used..used + len].copy_from_slice(&data[..len]);
+            self.len += len as u64;
+            data = &data[len..];
+            if used + len == 64 {
+                self.compress();
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> [u8; 32] {
+        let bits = self.len * 8;
+        self.update(&[0x80]);
+        while self.len % 64 != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+
+        let mut digest = [0u8; 32];
+        for (out, h) in digest.chunks_exact_mut(4).zip(self.h) {
+            out.copy_from_slice(&h.to_be_bytes());
+        }
+        digest
+    }
+}
+
+/// HMAC-SHA256, as in RFC 2104.
+#[derive(Clone)]
+pub struct Hmac {
+    inner: Sha256,
+    key: [u8; 64],
+}
+
+impl Hmac {
+    pub fn new(key: &[u8]) -> Self {
+        let mut block = [0u8; 64];
+        if key.len() > block.len() {
+            let mut sha = Sha256::new();
+            sha.update(key);
+            block[..32].copy_from_slice(&sha.finish());
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+        let mut inner = Sha256::new();
+        inner.update(&block.map(|b| b ^ 0x36));
+        Self { inner, key: block }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    pub fn finish(self) -> [u8; 32] {
+        let mut outer = Sha256::new();
+        outer.update(&self.key.map(|b| b ^ 0x5c));
+        outer.update(&self.inner.finish());
+        outer.finish()
+    }
+}
+
+/// `ota_state` of an `otadata` entry.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ImageState {
+    New,
+    PendingVerify,
+    Valid,
+    Invalid,
+    Aborted,
+    Undefined,
+}
+
+impl ImageState {
+    fn from_raw(raw: u32) -> Self {
+        match raw {
+            0 => ImageState::New,
+            1 => ImageState::PendingVerify,
+            2 => ImageState::Valid,
+            3 => ImageState::Invalid,
+            4 => ImageState::Aborted,
+            _ => ImageState::Undefined,
+        }
+    }
+
+    fn raw(self) -> u32 {
+        match self {
+            ImageState::New => 0,
+            ImageState::PendingVerify => 1,
+            ImageState::Valid => 2,
+            ImageState::Invalid => 3,
+            ImageState::Aborted => 4,
+            ImageState::Undefined => u32::MAX,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ImageState::New => "new",
+            ImageState::PendingVerify => "pending_verify",
+            ImageState::Valid => "valid",
+            ImageState::Invalid => "invalid",
+            ImageState::Aborted => "aborted",
+            ImageState::Undefined => "undefined",
+        }
+    }
+}
+
+/// An `otadata` entry:
+///   ota_seq: u32, seq_label: [u8; 20], ota_state: u32, crc: u32
+/// The CRC covers `ota_seq` only.
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    seq: u32,
+    state: ImageState,
+}
+
+fn seq_crc(seq: u32) -> u32 {
+    // The ROM's crc32_le seeded with u32::MAX, which it inverts first.
+    !crc32_update(0, &seq.to_le_bytes())
+}
+
+impl Entry {
+    fn parse(data: &[u8; ENTRY_LEN]) -> Option<Self> {
+        let seq = u32::from_le_bytes(data[0..4].try_into().unwrap());
+        let state = u32::from_le_bytes(data[24..28].try_into().unwrap());
+        let crc = u32::from_le_bytes(data[28..32].try_into().unwrap());
+        if seq == 0 || seq == u32::MAX || crc != seq_crc(seq) {
+            return None;
+        }
+        Some(Self {
+            seq,
+            state: ImageState::from_raw(state),
+        })
+    }
+
+    fn to_bytes(self) -> [u8; ENTRY_LEN] {
+        let mut data = [0xff; ENTRY_LEN];
+        data[0..4].copy_from_slice(&self.seq.to_le_bytes());
+        data[24..28].copy_from_slice(&self.state.raw().to_le_bytes());
+        data[28..32].copy_from_slice(&seq_crc(self.seq).to_le_bytes());
+        data
+    }
+
+    /// Whether the bootloader will consider this entry.
+    fn bootable(&self) -> bool {
+        !matches!(self.state, ImageState::Invalid | ImageState::Aborted)
+    }
+
+    fn slot(&self) -> usize {
+        (self.seq as usize - 1) % APP_REGIONS.len()
+    }
+}
+
+/// Both entries of `otadata`, one per sector.  The bootloader boots the
+/// slot of the bootable entry with the highest sequence number, or `ota_0`
+/// if there is none.
+#[derive(Clone, Copy, Debug)]
+struct OtaData {
+    entries: [Option<Entry>; 2],
+}
+
+impl OtaData {
+    fn load(storage: &mut Storage) -> Result<Self> {
+        let mut entries = [None; 2];
+        for (sector, entry) in entries.iter_mut().enumerate() {
+            let mut data = [0u8; ENTRY_LEN];
+            storage.read_region(OTADATA_REGION, (sector * SECTOR_SIZE) as u32, &mut data)?;
+            *entry = Entry::parse(&data);
+        }
+        Ok(Self { entries })
+    }
+
+    /// The sector of the entry that was booted.
+    fn current(&self) -> Option<usize> {
+        (0..self.entries.len())
+            .filter(|sector| self.entries[*sector].map_or(false, |entry| entry.bootable()))
+            .max_by_key(|sector| self.entries[*sector].map(|entry| entry.seq))
+    }
+
+    fn running_slot(&self) -> usize {
+        self.current()
+            .and_then(|sector| self.entries[sector])
+            .map_or(0, |entry| entry.slot())
+    }
+
+    /// The slot the bootloader falls back to once the running image is
+    /// aborted.
+    fn fallback_slot(&self) -> usize {
+        let current = self.current();
+        (0..self.entries.len())
+            .filter(|sector| Some(*sector) != current)
+            .filter_map(|sector| self.entries[sector])
+            .find(|entry| entry.bootable())
+            .map_or(0, |entry| entry.slot())
+    }
+
+    fn running_state(&self) -> ImageState {
+        self.current()
+            .and_then(|sector| self.entries[sector])
+            .map_or(ImageState::Undefined, |entry| entry.state)
+    }
+
+    fn store(storage: &mut Storage, sector: usize, entry: Entry) -> Result<()> {
+        let offset = (sector * SECTOR_SIZE) as u32;
+        storage.erase_region(OTADATA_REGION, offset)?;
+        storage.write_region(OTADATA_REGION, offset, &entry.to_bytes())
+    }
+
+    /// Change the state of the running image.
+    fn set_state(&mut self, storage: &mut Storage, state: ImageState) -> Result<()> {
+        let Some(sector) = self.current() else {
+            return Err(Error::Generic("No OTA image is running"));
+        };
+        let entry = self.entries[sector].as_mut().ok_or(Error::Index)?;
+        entry.state = state;
+        Self::store(storage, sector, *entry)
+    }
+
+    /// Abort the entry of `slot` unless it is running, so the bootloader
+    /// can't fall back to it while it is overwritten.
+    fn invalidate(&mut self, storage: &mut Storage, slot: usize) -> Result<()> {
+        let current = self.current();
+        for sector in 0..self.entries.len() {
+            if Some(sector) == current {
+                continue;
+            }
+            let Some(entry) = self.entries[sector].as_mut() else {
+                continue;
+            };
+            if entry.slot() == slot && entry.bootable() {
+                entry.state = ImageState::Aborted;
+                Self::store(storage, sector, *entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Boot `slot` next, on trial.
+    fn activate(&mut self, storage: &mut Storage, slot: usize) -> Result<()> {
+        let last = self.entries.iter().flatten().map(|entry| entry.seq).max();
+        let mut seq = last.unwrap_or(0) + 1;
+        if (seq as usize - 1) % APP_REGIONS.len() != slot {
+            seq += 1;
+        }
+        let sector = self.current().map_or(0, |sector| 1 - sector);
+        let entry = Entry {
+            seq,
+            state: ImageState::New,
+        };
+        self.entries[sector] = Some(entry);
+        Self::store(storage, sector, entry)
+    }
+}
+
+pub(crate) fn restart() -> ! {
+    esp32c3_hal::reset::software_reset();
+    loop {}
+}
+
+/// Look at the image being booted before anything else runs.  A first boot
+/// of a new image starts its trial; a second boot that finds the trial
+/// still running means the image never passed its self check, so it is
+/// aborted and the previous one started instead.
+pub fn boot(storage: &mut Storage) -> Result<()> {
+    let mut otadata = OtaData::load(storage)?;
+    let slot = otadata.running_slot();
+    match otadata.running_state() {
+        ImageState::New => {
+            println!("ota: trying the new image in ota_{slot}");
+            otadata.set_state(storage, ImageState::PendingVerify)
+        }
+        ImageState::PendingVerify => {
+            println!("ota: the image in ota_{slot} never passed its self check, rolling back");
+            otadata.set_state(storage, ImageState::Aborted)?;
+            restart()
+        }
+        _ => Ok(()),
+    }
+}
+
+/// `OTA_PUBLIC_KEY`, from hex.
+fn public_key() -> Result<[u8; PUBLIC_KEY_LEN]> {
+    let hex = crate::OTA_PUBLIC_KEY.ok_or(Error::Generic("Built without an OTA_PUBLIC_KEY"))?;
+    let hex = hex.trim().as_bytes();
+    let mut key = [0u8; PUBLIC_KEY_LEN];
+    if hex.len() != 2 * key.len() {
+        return Err(Error::Generic("OTA_PUBLIC_KEY isn't 32 bytes of hex"));
+    }
+    for (b, digits) in key.iter_mut().zip(hex.chunks_exact(2)) {
+        *b = core::str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+            .ok_or(Error::Generic("OTA_PUBLIC_KEY isn't 32 bytes of hex"))?;
+    }
+    Ok(key)
+}
+
+/// Where an upload is written and how it is checked.
+pub struct Update {
+    slot: usize,
+    region: Region,
+    /// Bytes received.
+    len: u32,
+    page: [u8; PAGE_LEN],
+}
+
+impl Update {
+    /// Start an update of the slot that isn't running, unless the running
+    /// image is still on trial.  The image in that slot can't be rolled
+    /// back to from here on.
+    pub async fn new(storage: &SharedStorage) -> Result<Self> {
+        public_key()?;
+        let mut storage = storage.lock().await;
+        let mut otadata = OtaData::load(&mut storage)?;
+        if otadata.running_state() == ImageState::PendingVerify {
+            return Err(Error::Generic(
+                "Confirm or roll back the running image first",
+            ));
+        }
+        let slot = 1 - otadata.running_slot();
+        otadata.invalidate(&mut storage, slot)?;
+        println!("ota: writing ota_{slot}");
+        Ok(Self {
+            slot,
+            region: APP_REGIONS[slot],
+            len: 0,
+            page: [0xff; PAGE_LEN],
+        })
+    }
+
+    pub fn slot(&self) -> usize {
+        self.slot
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    /// Write out the page starting at `offset`, erasing sectors as they are
+    /// reached.
+    async fn flush(&mut self, storage: &SharedStorage, offset: u32, used: usize) -> Result<()> {
+        // Flash writes are whole words.
+        let write_len = (used + 3) & !3;
+        let mut storage = storage.lock().await;
+        if offset % SECTOR_SIZE as u32 == 0 {
+            storage.erase_region(self.region, offset)?;
+        }
+        storage.write_region(self.region, offset, &self.page[..write_len])?;
+        self.page = [0xff; PAGE_LEN];
+        Ok(())
+    }
+
+    pub async fn write(&mut self, storage: &SharedStorage, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            if self.len as usize + data.len() > self.region.len as usize {
+                return Err(Error::Http(Status::PayloadTooLarge));
+            }
+            let used = self.len as usize % PAGE_LEN;
+            let len = (PAGE_LEN - used).min(data.len());
+            self.page[used..used + len].copy_from_slice(&data[..len]);
+            data = &data[len..];
+            self.len += len as u32;
+            if used + len == PAGE_LEN {
+                self.flush(storage, self.len - PAGE_LEN as u32, PAGE_LEN)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write what is left, check the image and its signature and boot it
+    /// next time.  Returns the length of the image.
+    pub async fn finish(mut self, storage: &SharedStorage) -> Result<u32> {
+        let used = self.len as usize % PAGE_LEN;
+        if used != 0 {
+            self.flush(storage, self.len - used as u32, used).await?;
+        }
+        let Some(image_len) = self.len.checked_sub(SIGNATURE_LEN as u32) else {
+            return Err(Error::Generic("Update too short"));
+        };
+        let (hashed_len, len) = check_image(storage, self.region, image_len).await?;
+        if len != image_len {
+            return Err(Error::Generic("Update isn't one image and a signature"));
+        }
+        check_digests(storage, self.region, image_len, hashed_len).await?;
+
+        let mut storage = storage.lock().await;
+        OtaData::load(&mut storage)?.activate(&mut storage, self.slot)?;
+        println!("ota: ota_{} will boot next", self.slot);
+        Ok(image_len)
+    }
+}
+
+async fn read(storage: &SharedStorage, region: Region, offset: u32, buf: &mut [u8]) -> Result<()> {
+    storage.lock().await.read_region(region, offset, buf)
+}
+
+/// Walk the segments of the app image at the start of `region` and check
+/// its XOR checksum, as the bootloader will.  Returns the length covered by
+/// the appended SHA-256, if any, and the length of the whole image.
+async fn check_image(storage: &SharedStorage, region: Region, len: u32) -> Result<(u32, u32)> {
+    let mut header = [0u8; IMAGE_HEADER_LEN];
+    read(storage, region, 0, &mut header).await?;
+    if header[0] != IMAGE_MAGIC || header[1] > MAX_SEGMENTS {
+        return Err(Error::Generic("Not an app image"));
+    }
+    if u16::from_le_bytes([header[12], header[13]]) != CHIP_ID_ESP32C3 {
+        return Err(Error::Generic("Image is for another chip"));
+    }
+
+    let mut pos = IMAGE_HEADER_LEN as u32;
+    let mut checksum = CHECKSUM_SEED;
+    let mut chunk = [0u8; CHUNK_LEN];
+    for _ in 0..header[1] {
+        let mut segment = [0u8; SEGMENT_HEADER_LEN];
+        read(storage, region, pos, &mut segment).await?;
+        let segment_len = u32::from_le_bytes(segment[4..8].try_into().unwrap());
+        pos += SEGMENT_HEADER_LEN as u32;
+        let end = pos
+            .checked_add(segment_len)
+            .filter(|end| *end < len)
+            .ok_or(Error::Generic("Image truncated"))?;
+        while pos < end {
+            let chunk = &mut chunk[..CHUNK_LEN.min((end - pos) as usize)];
+            read(storage, region, pos, chunk).await?;
+            checksum = chunk.iter().fold(checksum, |sum, b| sum ^ b);
+            pos += chunk.len() as u32;
+            yield_now().await;
+        }
+    }
+
+    // The checksum is the last byte of the next 16 byte boundary.
+    let hashed_len = (pos & !15) + 16;
+    if hashed_len > len {
+        return Err(Error::Generic("Image truncated"));
+    }
+    let mut stored = [0u8];
+    read(storage, region, hashed_len - 1, &mut stored).await?;
+    if stored[0] != checksum {
+        return Err(Error::Generic("Image checksum mismatch"));
+    }
+    let hash_len = if header[23] == 1 { HASH_LEN as u32 } else { 0 };
+    Ok((hashed_len, hashed_len + hash_len))
+}
+
+/// Check the SHA-256 the image may carry and the signature that follows
+/// it.
+async fn check_digests(
+    storage: &SharedStorage,
+    region: Region,
+    image_len: u32,
+    hashed_len: u32,
+) -> Result<()> {
+    let key = public_key()?;
+    let mut digest = Sha256::new();
+    let mut sha = Sha256::new();
+    let mut chunk = [0u8; CHUNK_LEN];
+    let mut pos = 0;
+    while pos < image_len {
+        let chunk = &mut chunk[..CHUNK_LEN.min((image_len - pos) as usize)];
+        read(storage, region, pos, chunk).await?;
+        digest.update(chunk);
+        if pos < hashed_len {
+            sha.update(&chunk[..chunk.len().min((hashed_len - pos) as usize)]);
+        }
+        pos += chunk.len() as u32;
+        yield_now().await;
+    }
+
+    let mut stored = [0u8; HASH_LEN];
+    if image_len > hashed_len {
+        read(storage, region, hashed_len, &mut stored).await?;
+        if sha.finish() != stored {
+            return Err(Error::Generic("Image hash mismatch"));
+        }
+    }
+    let mut signature = [0u8; SIGNATURE_LEN];
+    read(storage, region, image_len, &mut signature).await?;
+    if !ed25519::verify(&key, &digest.finish(), &signature) {
+        return Err(Error::Generic("Bad signature"));
+    }
+    Ok(())
+}
+
+/// Check an image already in `region` the way an upload is checked.
+async fn check_installed(storage: &SharedStorage, region: Region) -> Result<()> {
+    let max_len = region.len - SIGNATURE_LEN as u32;
+    let (hashed_len, image_len) = check_image(storage, region, max_len).await?;
+    if image_len > max_len {
+        return Err(Error::Generic("Image truncated"));
+    }
+    check_digests(storage, region, image_len, hashed_len).await
+}
+
+/// Abort the running image so that the previous one boots, once that is
+/// known to be whole and signed.
+async fn rollback(storage: &SharedStorage) -> Result<()> {
+    let otadata = OtaData::load(&mut *storage.lock().await)?;
+    let slot = otadata.fallback_slot();
+    if slot == otadata.running_slot() {
+        return Err(Error::Generic("No image to roll back to"));
+    }
+    check_installed(storage, APP_REGIONS[slot]).await?;
+    let mut storage = storage.lock().await;
+    OtaData::load(&mut storage)?.set_state(&mut storage, ImageState::Aborted)
+}
+
+pub struct Ota {
+    /// Held for the length of an upload so that only one runs at a time.
+    upload: Mutex<NoopRawMutex, ()>,
+    restart_at: Mutex<NoopRawMutex, Option<Instant>>,
+    /// An upload waiting for `task` to check and install it.
+    pending: Mutex<NoopRawMutex, Option<Update>>,
+    /// How the last upload `task` installed went.
+    finished: Mutex<NoopRawMutex, Option<Result<u32>>>,
+}
+
+impl Ota {
+    pub const fn new() -> Self {
+        Self {
+            upload: Mutex::new(()),
+            restart_at: Mutex::new(None),
+            pending: Mutex::new(None),
+            finished: Mutex::new(None),
+        }
+    }
+
+    /// Have `task` finish `update`, for callers that can't wait the
+    /// seconds it takes to read back and check a whole image.  The outcome
+    /// comes from `finished`.
+    pub async fn finish_later(&self, update: Update) {
+        *self.pending.lock().await = Some(update);
+    }
+
+    /// The outcome of the update passed to `finish_later`, once it is in.
+    pub async fn finished(&self) -> Option<Result<u32>> {
+        self.finished.lock().await.take()
+    }
+
+    /// Claim the right to upload, unless an upload is already running over
+    /// HTTP or Art-Net.  It lasts as long as the guard.
+    pub fn try_upload(&self) -> Option<MutexGuard<'_, NoopRawMutex, ()>> {
+        self.upload.try_lock().ok()
+    }
+
+    pub async fn restart_soon(&self) {
+        *self.restart_at.lock().await = Some(Instant::now() + RESTART_DELAY);
+    }
+}
+
+/// Runs the self check of an image on trial, finishes updates handed to it
+/// and restarts after an update.
+#[embassy_executor::task]
+pub(crate) async fn task(
+    stack: &'static Stack<WifiDevice<'static>>,
+    storage: &'static SharedStorage,
+    ota: &'static Ota,
+) {
+    let mut on_trial = match OtaData::load(&mut *storage.lock().await) {
+        Ok(otadata) => otadata.running_state() == ImageState::PendingVerify,
+        Err(e) => {
+            println!("ota: failed to read otadata: {e:?}");
+            false
+        }
+    };
+
+    loop {
+        Timer::after(TICK).await;
+
+        let pending = ota.pending.lock().await.take();
+        if let Some(update) = pending {
+            let result = update.finish(storage).await;
+            *ota.finished.lock().await = Some(result);
+        }
+
+        if let Some(at) = *ota.restart_at.lock().await {
+            if Instant::now() >= at {
+                println!("ota: restarting");
+                restart();
+            }
+        }
+
+        if !on_trial {
+            continue;
+        }
+        let uptime = Instant::now().duration_since(Instant::from_ticks(0));
+        if uptime >= SELF_CHECK_TIME && stack.config().is_some() {
+            on_trial = false;
+            let mut storage = storage.lock().await;
+            match OtaData::load(&mut storage)
+                .and_then(|mut otadata| otadata.set_state(&mut storage, ImageState::Valid))
+            {
+                Ok(()) => println!("ota: self check passed, keeping this image"),
+                Err(e) => println!("ota: failed to confirm this image: {e:?}"),
+            }
+        } else if uptime >= SELF_CHECK_TIMEOUT {
+            println!("ota: self check timed out, rolling back");
+            let mut storage = storage.lock().await;
+            if let Err(e) = OtaData::load(&mut storage)
+                .and_then(|mut otadata| otadata.set_state(&mut storage, ImageState::Aborted))
+            {
+                println!("ota: failed to abort this image: {e:?}");
+            }
+            restart();
+        }
+    }
+}
+
+async fn write_status<W: Write>(json: &mut Json<W>, ctx: &Context) -> Result<()> {
+    let otadata = OtaData::load(&mut *ctx.storage.lock().await)?;
+    let slot = otadata.running_slot();
+    json.begin_object()?;
+    json.field("running", slot)?;
+    json.field("state", otadata.running_state().name())?;
+    json.field("next", 1 - slot)?;
+    json.field("max_len", APP_REGIONS[1 - slot].len - SIGNATURE_LEN as u32)?;
+    json.field("signed", public_key().is_ok())?;
+    json.field("uploading", ctx.ota.try_upload().is_none())?;
+    json.end_object()
+}
+
+async fn upload<W: Write>(
+    conn: &mut Connection<'_, '_>,
+    ctx: &Context,
+    json: &mut Json<W>,
+) -> Result<()> {
+    let Some(_guard) = ctx.ota.try_upload() else {
+        return Err(Error::Http(Status::ServiceUnavailable));
+    };
+    let mut update = Update::new(ctx.storage).await?;
+    let mut buf = [0u8; CHUNK_LEN];
+    loop {
+        match conn.read(&mut buf).await? {
+            0 => break,
+            len => update.write(ctx.storage, &buf[..len]).await?,
+        }
+    }
+    let slot = update.slot();
+    let len = update.finish(ctx.storage).await?;
+    ctx.ota.restart_soon().await;
+
+    json.begin_object()?;
+    json.field("slot", slot)?;
+    json.field("len", len)?;
+    json.field("restarting", true)?;
+    json.end_object()
+}
+
+async fn run<W: Write>(
+    conn: &mut Connection<'_, '_>,
+    ctx: &Context,
+    req: &Request<'_>,
+    json: &mut Json<W>,
+) -> Result<()> {
+    match (req.method, req.param("command")) {
+        (Method::Post | Method::Put, None) => upload(conn, ctx, json).await,
+        (_, None) => write_status(json, ctx).await,
+        (_, Some("confirm")) => {
+            let mut storage = ctx.storage.lock().await;
+            OtaData::load(&mut storage)?.set_state(&mut storage, ImageState::Valid)?;
+            drop(storage);
+            write_status(json, ctx).await
+        }
+        (_, Some("rollback")) => {
+            rollback(ctx.storage).await?;
+            ctx.ota.restart_soon().await;
+            write_status(json, ctx).await
+        }
+        (_, Some("restart")) => {
+            ctx.ota.restart_soon().await;
+            write_status(json, ctx).await
+        }
+        _ => Err(Error::Generic("Unknown OTA command")),
+    }
+}
+
+/// `GET /ota` describes the running image, `POST /ota` with an update as
+/// the body installs it and restarts, and `/ota/confirm`, `/ota/rollback`
+/// and `/ota/restart` do what they say.
+pub async fn handle(conn: &mut Connection<'_, '_>, ctx: &Context, req: &Request<'_>) -> Result<()> {
+    let mut buffer = [0u8; 256];
+    let mut json = Json::new(FmtBuffer::new(&mut buffer));
+    match run(conn, ctx, req, &mut json).await {
+        Ok(()) => {
+            conn.send(Status::Ok, JSON, json.into_inner().as_bytes())
+                .await
+        }
+        Err(e) => {
+            println!("ota {} failed: {e:?}", req.path);
+            api::send_error(conn, &e).await
+        }
+    }
+}
@@ -0,0 +1,720 @@
+use core::cmp::min;
+
+use byteorder::LittleEndian;
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal_async::spi::SpiBusWrite;
+use esp_println::println;
+use num_derive::{FromPrimitive, ToPrimitive};
+use num_traits::{FromPrimitive, ToPrimitive};
+
+use crate::buffer::{MutBuffer, OldBuffer};
+use crate::color::Rgb;
+use crate::effects::{Effect, EffectKind};
+use crate::matrix::{Canvas, Layout};
+use crate::script::Script;
+use crate::storage::{Slot, Storage};
+use crate::text::Text;
+use crate::ws2812::{self, Ws2812};
+use crate::{Error, Result};
+
+pub const NUM_LEDS: usize = 120;
+const LED_BUF_LEN: usize = ws2812::buffer_len(NUM_LEDS);
+const FRAME_INTERVAL: Duration = Duration::from_millis(10);
+
+pub const UNIVERSE_SIZE: usize = 512;
+pub const MAX_UNIVERSES: usize = 4;
+pub const MAX_SEGMENTS: usize = 8;
+
+const SCENE_VERSION: u16 = 1;
+// Header plus the largest possible encoding of every segment.
+pub const SCENE_MAX_LEN: usize = 1 + MAX_SEGMENTS * 16;
+
+/// Frames further apart than this are never interpolated.  It's well below
+/// any sensible controller frame rate, so it mostly catches the first frame
+/// of a stream and one off updates like preset recalls.
+const MAX_SMOOTH_INTERVAL: Duration = Duration::from_millis(100);
+/// Mean change per channel above which consecutive frames are treated as a
+/// scene cut rather than part of a fade.
+const CUT_THRESHOLD: u32 = 48;
+
+pub type Frame = [Rgb; NUM_LEDS];
+
+#[derive(Clone, Copy)]
+struct Universe {
+    port_address: u16,
+    data: [u8; UNIVERSE_SIZE],
+    /// The frame received before `data`.
+    prev: [u8; UNIVERSE_SIZE],
+    len: usize,
+    last_update: Instant,
+    /// Time between `prev` and `data`, or `None` if they shouldn't be
+    /// blended.
+    interval: Option<Duration>,
+    /// Frames received, wrapping.
+    packets: u32,
+}
+
+fn is_cut(prev: &[u8], data: &[u8]) -> bool {
+    let change: u32 = prev
+        .iter()
+        .zip(data.iter())
+        .map(|(a, b)| a.abs_diff(*b) as u32)
+        .sum();
+    change > CUT_THRESHOLD * data.len() as u32
+}
+
+/// The most recently received DMX data for each universe we have seen.
+pub struct Universes {
+    slots: [Option<Universe>; MAX_UNIVERSES],
+    /// When any universe last received data.
+    last_update: Option<Instant>,
+}
+
+impl Universes {
+    const fn new() -> Self {
+        Self {
+            slots: [None; MAX_UNIVERSES],
+            last_update: None,
+        }
+    }
+
+    pub fn last_update(&self) -> Option<Instant> {
+        self.last_update
+    }
+
+    /// Store `data` for `port_address`, evicting the least recently updated
+    /// universe if every slot is in use.
+    pub fn update(&mut self, port_address: u16, data: &[u8]) {
+        let now = Instant::now();
+        let index = self
+            .slots
+            .iter()
+            .position(|slot| matches!(slot, Some(u) if u.port_address == port_address))
+            .or_else(|| self.slots.iter().position(Option::is_none))
+            .unwrap_or_else(|| {
+                self.slots
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, slot)| slot.as_ref().map(|u| u.last_update))
+                    .map(|(i, _)| i)
+                    .unwrap_or(0)
+            });
+
+        let len = min(data.len(), UNIVERSE_SIZE);
+        let fresh = !matches!(&self.slots[index], Some(u) if u.port_address == port_address);
+        let universe = self.slots[index].get_or_insert(Universe {
+            port_address,
+            data: [0; UNIVERSE_SIZE],
+            prev: [0; UNIVERSE_SIZE],
+            len: 0,
+            last_update: now,
+            interval: None,
+            packets: 0,
+        });
+        let interval = now - universe.last_update;
+        let resized = !fresh && universe.len != len;
+        universe.port_address = port_address;
+        universe.prev = universe.data;
+        if resized {
+            // Past its old length `data` still holds what an older frame
+            // left there, which isn't a frame to blend from.
+            universe.prev[..len].fill(0);
+        }
+        universe.data[..len].copy_from_slice(&data[..len]);
+        universe.len = len;
+        universe.last_update = now;
+        self.last_update = Some(now);
+        universe.packets = if fresh {
+            1
+        } else {
+            universe.packets.wrapping_add(1)
+        };
+        universe.interval = (!fresh
+            && !resized
+            && interval <= MAX_SMOOTH_INTERVAL
+            && !is_cut(&universe.prev[..len], &universe.data[..len]))
+        .then_some(interval);
+    }
+
+    /// Every universe we hold data for as (port address, data).
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &[u8])> {
+        self.slots
+            .iter()
+            .flatten()
+            .map(|u| (u.port_address, &u.data[..u.len]))
+    }
+
+    /// How many frames each universe has received as (port address, count).
+    /// Counts wrap and restart when a universe loses its slot.
+    pub fn packets(&self) -> impl Iterator<Item = (u16, u32)> + '_ {
+        self.slots
+            .iter()
+            .flatten()
+            .map(|u| (u.port_address, u.packets))
+    }
+
+    pub fn get(&self, port_address: u16) -> Option<&[u8]> {
+        self.slots
+            .iter()
+            .flatten()
+            .find(|u| u.port_address == port_address)
+            .map(|u| &u.data[..u.len])
+    }
+
+    /// The last two frames for `port_address` and how far to blend from the
+    /// first to the second at `time_ms`.
+    ///
+    /// Blending runs one frame behind the controller: the previous frame
+    /// fades into the latest one over the time that passed between them.
+    pub fn interpolated(&self, port_address: u16, time_ms: u32) -> Option<(&[u8], &[u8], u8)> {
+        let u = self
+            .slots
+            .iter()
+            .flatten()
+            .find(|u| u.port_address == port_address)?;
+        let data = &u.data[..u.len];
+        let amount = match u.interval {
+            Some(interval) if interval.as_millis() > 0 => {
+                let elapsed = time_ms.wrapping_sub(u.last_update.as_millis() as u32);
+                min(elapsed as u64 * 255 / interval.as_millis(), 255) as u8
+            }
+            _ => 255,
+        };
+        Some((&u.prev[..u.len], data, amount))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Source {
+    /// RGB triplets from an Art-Net universe starting at channel `offset`.
+    Universe {
+        port_address: u16,
+        offset: u16,
+    },
+    Effect(Effect),
+    Solid(Rgb),
+    /// The user script in [`Output::script`].
+    Script,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, FromPrimitive, ToPrimitive)]
+#[repr(u8)]
+enum SourceType {
+    Universe = 0,
+    Effect = 1,
+    Solid = 2,
+    Script = 3,
+}
+
+impl Source {
+    fn write(&self, buf: &mut MutBuffer<LittleEndian>) -> Result<()> {
+        match self {
+            Source::Universe {
+                port_address,
+                offset,
+            } => {
+                buf.write_u8(SourceType::Universe as u8)?;
+                buf.write_u16(*port_address)?;
+                buf.write_u16(*offset)?;
+            }
+            Source::Effect(effect) => {
+                buf.write_u8(SourceType::Effect as u8)?;
+                buf.write_u8(effect.kind.to_u8().unwrap())?;
+                buf.write_u8(effect.palette)?;
+                buf.write_u8(effect.speed)?;
+                buf.write_u8(effect.scale)?;
+                write_rgb(buf, effect.color)?;
+            }
+            Source::Solid(color) => {
+                buf.write_u8(SourceType::Solid as u8)?;
+                write_rgb(buf, *color)?;
+            }
+            Source::Script => buf.write_u8(SourceType::Script as u8)?,
+        }
+        Ok(())
+    }
+
+    fn parse(buf: &mut OldBuffer<LittleEndian>) -> Result<Self> {
+        let source_type =
+            SourceType::from_u8(buf.read_u8()?).ok_or(Error::Generic("unknown segment source"))?;
+        Ok(match source_type {
+            SourceType::Universe => Source::Universe {
+                port_address: buf.read_u16()?,
+                offset: buf.read_u16()?,
+            },
+            SourceType::Effect => Source::Effect(Effect {
+                kind: EffectKind::from_u8(buf.read_u8()?)
+                    .ok_or(Error::Generic("unknown effect"))?,
+                palette: buf.read_u8()?,
+                speed: buf.read_u8()?,
+                scale: buf.read_u8()?,
+                color: read_rgb(buf)?,
+            }),
+            SourceType::Solid => Source::Solid(read_rgb(buf)?),
+            SourceType::Script => Source::Script,
+        })
+    }
+}
+
+fn write_rgb(buf: &mut MutBuffer<LittleEndian>, color: Rgb) -> Result<()> {
+    buf.write(&[color.r, color.g, color.b])?;
+    Ok(())
+}
+
+fn read_rgb(buf: &mut OldBuffer<LittleEndian>) -> Result<Rgb> {
+    let [r, g, b] = buf.read()?;
+    Ok(Rgb::new(r, g, b))
+}
+
+/// A run of pixels on the strip driven from a single source.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Segment {
+    pub start: u16,
+    pub len: u16,
+    /// Render the source back to front.
+    pub reverse: bool,
+    /// Render the source over the first half and mirror it onto the second.
+    pub mirror: bool,
+    /// Number of adjacent pixels that share one source pixel.
+    pub group: u8,
+    pub brightness: u8,
+    pub source: Source,
+}
+
+impl Segment {
+    pub const fn new(start: u16, len: u16, source: Source) -> Self {
+        Self {
+            start,
+            len,
+            reverse: false,
+            mirror: false,
+            group: 1,
+            brightness: 255,
+            source,
+        }
+    }
+
+    fn render(
+        &self,
+        time_ms: u32,
+        effect_ms: u32,
+        universes: &Universes,
+        smooth: bool,
+        layout: Option<&Layout>,
+        script: &mut Script,
+        frame: &mut [Rgb],
+    ) {
+        let start = min(self.start as usize, frame.len());
+        let end = min(start + self.len as usize, frame.len());
+        let pixels = &mut frame[start..end];
+
+        let len = if self.mirror {
+            (pixels.len() + 1) / 2
+        } else {
+            pixels.len()
+        };
+        let group = self.group.max(1) as usize;
+        let logical_len = (len + group - 1) / group;
+
+        let logical = &mut pixels[..logical_len];
+        match &self.source {
+            Source::Universe {
+                port_address,
+                offset,
+            } => {
+                let (from, to, amount) = if smooth {
+                    universes.interpolated(*port_address, time_ms)
+                } else {
+                    universes.get(*port_address).map(|data| (data, data, 255))
+                }
+                .unwrap_or((&[], &[], 255));
+                let offset = *offset as usize;
+                let from = from.get(offset..).unwrap_or(&[]).chunks_exact(3);
+                let mut triplets = from.zip(to.get(offset..).unwrap_or(&[]).chunks_exact(3));
+                for pixel in logical.iter_mut() {
+                    *pixel = triplets
+                        .next()
+                        .map(|(a, b)| {
+                            Rgb::new(a[0], a[1], a[2]).blend(Rgb::new(b[0], b[1], b[2]), amount)
+                        })
+                        .unwrap_or(Rgb::BLACK);
+                }
+            }
+            Source::Effect(effect) => effect.render(effect_ms, logical, layout, start),
+            Source::Solid(color) => logical.fill(*color),
+            Source::Script => script.render(effect_ms, logical, layout, start),
+        }
+
+        if self.brightness != 255 {
+            for pixel in logical.iter_mut() {
+                *pixel = pixel.scale(self.brightness);
+            }
+        }
+
+        // Expand groups in place working backwards so that we never
+        // overwrite a source pixel before it has been copied.
+        for i in (0..len).rev() {
+            pixels[i] = pixels[i / group];
+        }
+
+        if self.reverse {
+            pixels[..len].reverse();
+        }
+
+        if self.mirror {
+            let total = pixels.len();
+            for i in 0..total / 2 {
+                pixels[total - 1 - i] = pixels[i];
+            }
+        }
+    }
+
+    fn write(&self, buf: &mut MutBuffer<LittleEndian>) -> Result<()> {
+        buf.write_u16(self.start)?;
+        buf.write_u16(self.len)?;
+        buf.write_u8(self.reverse as u8 | (self.mirror as u8) << 1)?;
+        buf.write_u8(self.group)?;
+        buf.write_u8(self.brightness)?;
+        self.source.write(buf)
+    }
+
+    fn parse(buf: &mut OldBuffer<LittleEndian>) -> Result<Self> {
+        let start = buf.read_u16()?;
+        let len = buf.read_u16()?;
+        let flags = buf.read_u8()?;
+        Ok(Self {
+            start,
+            len,
+            reverse: flags & 0x1 != 0,
+            mirror: flags & 0x2 != 0,
+            group: buf.read_u8()?,
+            brightness: buf.read_u8()?,
+            source: Source::parse(buf)?,
+        })
+    }
+}
+
+/// The set of segments that make up the strip.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Scene {
+    segments: [Segment; MAX_SEGMENTS],
+    num_segments: usize,
+}
+
+impl Scene {
+    pub const fn empty() -> Self {
+        Self {
+            segments: [Segment::new(0, 0, Source::Solid(Rgb::BLACK)); MAX_SEGMENTS],
+            num_segments: 0,
+        }
+    }
+
+    /// A scene running `kind` over the whole strip.
+    pub fn effect(kind: EffectKind) -> Self {
+        let mut scene = Self::empty();
+        let segment = Segment::new(0, NUM_LEDS as u16, Source::Effect(Effect::new(kind)));
+        scene.set(0, segment).ok();
+        scene
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments[..self.num_segments]
+    }
+
+    /// Replace the segment at `index` or append it if `index` is one past
+    /// the end.
+    pub fn set(&mut self, index: usize, segment: Segment) -> Result<()> {
+        if index > self.num_segments || index >= MAX_SEGMENTS {
+            return Err(Error::Generic("No such segment"));
+        }
+        self.segments[index] = segment;
+        if index == self.num_segments {
+            self.num_segments += 1;
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, index: usize) -> Result<()> {
+        if index >= self.num_segments {
+            return Err(Error::Generic("No such segment"));
+        }
+        self.segments
+            .copy_within(index + 1..self.num_segments, index);
+        self.num_segments -= 1;
+        Ok(())
+    }
+
+    /// Render every segment into `frame`.  Effects and scripts run off
+    /// `effect_ms`, the effect clock that [`crate::sync`] keeps in step
+    /// across nodes, and everything else off the local `time_ms`.
+    pub fn render(
+        &self,
+        time_ms: u32,
+        effect_ms: u32,
+        universes: &Universes,
+        smooth: bool,
+        layout: Option<&Layout>,
+        script: &mut Script,
+        frame: &mut [Rgb],
+    ) {
+        frame.fill(Rgb::BLACK);
+        for segment in self.segments() {
+            segment.render(time_ms, effect_ms, universes, smooth, layout, script, frame);
+        }
+    }
+
+    pub fn write(&self, buf: &mut MutBuffer<LittleEndian>) -> Result<()> {
+        buf.write_u8(self.num_segments as u8)?;
+        for segment in self.segments() {
+            segment.write(buf)?;
+        }
+        Ok(())
+    }
+
+    pub fn parse(buf: &mut OldBuffer<LittleEndian>) -> Result<Self> {
+        let mut scene = Self::empty();
+        let num_segments = buf.read_u8()? as usize;
+        for i in 0..num_segments {
+            scene.set(i, Segment::parse(buf)?)?;
+        }
+        Ok(scene)
+    }
+
+    pub fn load(storage: &mut Storage) -> Result<Option<Self>> {
+        let mut data = [0u8; SCENE_MAX_LEN];
+        let Some((version, data)) = storage.load(Slot::Scene, &mut data)? else {
+            return Ok(None);
+        };
+        if version != SCENE_VERSION {
+            return Ok(None);
+        }
+        Self::parse(&mut OldBuffer::new(data)).map(Some)
+    }
+
+    pub fn save(&self, storage: &mut Storage) -> Result<()> {
+        let mut data = [0u8; SCENE_MAX_LEN];
+        let buf = &mut MutBuffer::<LittleEndian>::new(&mut data);
+        self.write(buf)?;
+        let len = buf.pos();
+        storage.store(Slot::Scene, SCENE_VERSION, &data[..len])
+    }
+}
+
+impl Default for Scene {
+    /// The original fixed mapping: ten RGB pixels starting at channel 33 of
+    /// universe 0, each driving twelve LEDs.
+    fn default() -> Self {
+        let mut scene = Self::empty();
+        let mut segment = Segment::new(
+            0,
+            NUM_LEDS as u16,
+            Source::Universe {
+                port_address: 0,
+                offset: 32,
+            },
+        );
+        segment.group = (NUM_LEDS / 10) as u8;
+        scene.set(0, segment).ok();
+        scene
+    }
+}
+
+/// Crossfade from a previous scene to the current one.  The previous scene
+/// is held as the frame it showed when the fade began in
+/// [`Output::fade_frame`], as the universes it read from may be replaced.
+#[derive(Clone, Copy)]
+struct Fade {
+    start_ms: u32,
+    duration_ms: u32,
+}
+
+pub struct Output {
+    pub scene: Scene,
+    pub universes: Universes,
+    /// Set when the strip is wired as one or more LED matrix panels.
+    pub matrix: Option<Layout>,
+    /// Message drawn over the scene on the matrix.
+    pub text: Option<Text>,
+    /// Frame from a playing show, shown instead of the scene.
+    pub show_frame: Option<Frame>,
+    /// Compiled user script for [`Source::Script`] segments.
+    pub script: Script,
+    /// The preset the scene was recalled from, if it still is one.
+    pub preset: Option<u8>,
+    /// Added to the local time to give the effect clock.  Zero unless we
+    /// are following another node.
+    pub clock_offset_ms: u32,
+    /// Master brightness, applied to the strip on top of everything else.
+    pub brightness: u8,
+    /// Blanks the strip without stopping rendering.
+    pub on: bool,
+    /// LEDs on the strip.  Frames are always [`NUM_LEDS`] long and the
+    /// pixels past the end of a shorter strip are left dark.
+    pub num_leds: usize,
+    /// Interpolate between received frames of universe sources so that
+    /// low frame rate fades come out smooth.
+    pub smooth: bool,
+    fade: Option<Fade>,
+    frame: Frame,
+    /// The outgoing scene's last frame while fading.
+    fade_frame: Frame,
+}
+
+pub type SharedOutput = Mutex<NoopRawMutex, Output>;
+
+impl Output {
+    pub fn new(scene: Scene, matrix: Option<Layout>) -> Self {
+        Self {
+            scene,
+            universes: Universes::new(),
+            matrix,
+            text: None,
+            show_frame: None,
+            script: Script::new(),
+            preset: None,
+            clock_offset_ms: 0,
+            brightness: 255,
+            on: true,
+            num_leds: NUM_LEDS,
+            smooth: false,
+            fade: None,
+            frame: [Rgb::BLACK; NUM_LEDS],
+            fade_frame: [Rgb::BLACK; NUM_LEDS],
+        }
+    }
+
+    /// Switch to `scene`, crossfading from the current scene over `fade_ms`.
+    pub fn set_scene(&mut self, scene: Scene, time_ms: u32, fade_ms: u32) {
+        if fade_ms > 0 {
+            self.render_scene(time_ms);
+            self.fade_frame = self.frame;
+        }
+        self.fade = (fade_ms > 0).then_some(Fade {
+            start_ms: time_ms,
+            duration_ms: fade_ms,
+        });
+        self.scene = scene;
+        self.preset = None;
+    }
+
+    /// How much of the current crossfade is left at `time_ms`.
+    pub fn fade_remaining(&self, time_ms: u32) -> u32 {
+        self.fade.as_ref().map_or(0, |fade| {
+            fade.duration_ms
+                .saturating_sub(time_ms.wrapping_sub(fade.start_ms))
+        })
+    }
+
+    /// The effect clock at local time `time_ms`.
+    pub fn effect_ms(&self, time_ms: u32) -> u32 {
+        time_ms.wrapping_add(self.clock_offset_ms)
+    }
+
+    /// The scale the strip is driven at.  Rendered frames don't include it
+    /// so recordings and previews don't depend on the dimmer.
+    pub fn level(&self) -> u8 {
+        if self.on {
+            self.brightness
+        } else {
+            0
+        }
+    }
+
+    /// The most recently rendered frame.
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    /// Render just the pixels mapped from Art-Net universes, as received
+    /// and without smoothing, fades or anything drawn over them.
+    pub fn artnet_frame(&mut self, frame: &mut Frame) {
+        let time_ms = Instant::now().as_millis() as u32;
+        frame.fill(Rgb::BLACK);
+        for segment in self.scene.segments() {
+            if let Source::Universe { .. } = segment.source {
+                segment.render(
+                    time_ms,
+                    time_ms,
+                    &self.universes,
+                    false,
+                    self.matrix.as_ref(),
+                    &mut self.script,
+                    frame,
+                );
+            }
+        }
+    }
+
+    /// Render the scene, crossfading from the previous one's last frame if a
+    /// fade is in progress.
+    fn render_scene(&mut self, time_ms: u32) {
+        let effect_ms = self.effect_ms(time_ms);
+        self.script.begin_frame();
+        self.scene.render(
+            time_ms,
+            effect_ms,
+            &self.universes,
+            self.smooth,
+            self.matrix.as_ref(),
+            &mut self.script,
+            &mut self.frame,
+        );
+
+        if let Some(fade) = &self.fade {
+            let elapsed = time_ms.wrapping_sub(fade.start_ms);
+            if elapsed >= fade.duration_ms {
+                self.fade = None;
+            } else {
+                let amount = (elapsed as u64 * 255 / fade.duration_ms as u64) as u8;
+                for (pixel, from) in self.frame.iter_mut().zip(self.fade_frame.iter()) {
+                    *pixel = from.blend(*pixel, amount);
+                }
+            }
+        }
+    }
+
+    pub fn render(&mut self, time_ms: u32) -> &Frame {
+        match &self.show_frame {
+            Some(show_frame) => self.frame = *show_frame,
+            None => self.render_scene(time_ms),
+        }
+
+        if let (Some(layout), Some(text)) = (&self.matrix, &self.text) {
+            if text.is_finished(time_ms, layout.display_width()) {
+                self.text = None;
+            } else {
+                text.render(time_ms, &mut Canvas::new(layout, &mut self.frame));
+            }
+        }
+        &self.frame
+    }
+}
+
+#[embassy_executor::task]
+pub(crate) async fn task(spi: &'static mut crate::SpiType<'static>, output: &'static SharedOutput) {
+    let mut led_buf = [0u8; LED_BUF_LEN];
+    let mut next_frame = Instant::now();
+
+    loop {
+        next_frame += FRAME_INTERVAL;
+        {
+            let mut output = output.lock().await;
+            let time_ms = Instant::now().as_millis() as u32;
+            let level = output.level();
+            let num_leds = output.num_leds;
+            let frame = output.render(time_ms);
+
+            let mut ws = Ws2812::<LED_BUF_LEN>::new(&mut led_buf);
+            for (i, color) in frame.iter().enumerate() {
+                let level = if i < num_leds { level } else { 0 };
+                ws.set_pixel(i, color.scale(level));
+            }
+        }
+
+        if let Err(e) = spi.write(&led_buf).await {
+            println!("spi error: {e:?}");
+        }
+        Timer::at(next_frame).await;
+    }
+}
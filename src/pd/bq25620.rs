@@ -1,13 +1,18 @@
-use bitfield_struct::bitfield;
 use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
 use embedded_hal_async::i2c::I2c;
 use esp_println::println;
 
-use super::i2c::{i2c_read_u16, i2c_read_u8, i2c_write_u8};
+use crate::i2creg::{Bus, Device, Value};
+#[cfg(feature = "i2c-debug")]
+use crate::regmap::{self, Chip, Field};
+use crate::{bitfield_registers, i2c_registers};
 use crate::{Error, Result};
+#[cfg(feature = "i2c-debug")]
+use Register as R;
 
 const ADDR: u8 = 0x6b;
 
+#[derive(Clone, Copy, Debug)]
 #[repr(u8)]
 #[allow(dead_code)]
 pub enum Register {
@@ -30,7 +35,7 @@ pub enum Register {
     NtcControl1 = 0x1b,
     NtcControl2 = 0x1c,
     ChargerStatus0 = 0x1d,
-    ChargerStatus1 = 0x13,
+    ChargerStatus1 = 0x1e,
     FaultStatus0 = 0x1f,
     ChargerFlag0 = 0x20,
     ChargerFlag1 = 0x21,
@@ -51,26 +56,99 @@ pub enum Register {
     PartInformation = 0x38,
 }
 
-#[bitfield(u8)]
-pub struct AdcControl {
-    #[bits(2)]
-    _res: u8,
-    adc_avg_int: bool,
-    adc_avg: bool,
-    #[bits(2)]
-    adc_sample: u8,
-    adc_rate: bool,
-    adc_en: bool,
+impl From<Register> for u8 {
+    fn from(register: Register) -> u8 {
+        register as u8
+    }
+}
+
+pub struct Bq25620Device;
+
+impl Device for Bq25620Device {
+    const ADDR: u8 = ADDR;
+    type Register = Register;
 }
 
-#[bitfield(u16)]
-pub struct AdcVoltage {
-    #[bits(2)]
-    _res0: u8,
-    #[bits(13)]
-    raw_voltage: u16,
-    #[bits(1)]
-    _res15: u8,
+bitfield_registers! {
+    #[bitfield(u8)]
+    pub struct ChargerStatus0 {
+        wd_stat: bool,
+        safety_tmr_stat: bool,
+        vindpm_stat: bool,
+        iindpm_stat: bool,
+        vsys_stat: bool,
+        treg_stat: bool,
+        adc_done_stat: bool,
+        _res: bool,
+    }
+
+    #[bitfield(u8)]
+    pub struct ChargerStatus1 {
+        #[bits(3)]
+        vbus_stat: u8,
+        #[bits(2)]
+        chg_stat: u8,
+        #[bits(3)]
+        _res: u8,
+    }
+
+    #[bitfield(u8)]
+    pub struct FaultStatus0 {
+        #[bits(3)]
+        ts_stat: u8,
+        tshut_stat: bool,
+        otg_fault_stat: bool,
+        sys_fault_stat: bool,
+        bat_fault_stat: bool,
+        vbus_fault_stat: bool,
+    }
+
+    #[bitfield(u8)]
+    pub struct AdcControl {
+        #[bits(2)]
+        _res: u8,
+        adc_avg_int: bool,
+        adc_avg: bool,
+        #[bits(2)]
+        adc_sample: u8,
+        adc_rate: bool,
+        adc_en: bool,
+    }
+
+    #[bitfield(u16)]
+    pub struct AdcVoltage {
+        #[bits(2)]
+        _res0: u8,
+        #[bits(13)]
+        raw_voltage: u16,
+        #[bits(1)]
+        _res15: u8,
+    }
+
+    #[bitfield(u16)]
+    pub struct IbusAdc {
+        _res0: bool,
+        #[bits(15)]
+        raw_current: u16,
+    }
+
+    #[bitfield(u16)]
+    pub struct IbatAdc {
+        #[bits(2)]
+        _res0: u8,
+        #[bits(14)]
+        raw_current: u16,
+    }
+
+    #[bitfield(u8)]
+    pub struct PartInformation {
+        #[bits(3)]
+        dev_rev: u8,
+        #[bits(3)]
+        pn: u8,
+        #[bits(2)]
+        _res: u8,
+    }
 }
 
 impl AdcVoltage {
@@ -79,54 +157,129 @@ impl AdcVoltage {
     }
 }
 
-type VbusAdc = AdcVoltage;
-type VsysAdc = AdcVoltage;
-
-#[bitfield(u8)]
-pub struct PartInformation {
-    #[bits(3)]
-    dev_rev: u8,
-    #[bits(3)]
-    pn: u8,
-    #[bits(2)]
-    _res: u8,
+/// Shared by `VbusAdc` and `VsysAdc`.
+impl Value for AdcVoltage {
+    type Raw = u16;
 }
 
-pub struct Bq25620<I2C, E>
-where
-    I2C: I2c<Error = E> + 'static,
-    Error: From<E>,
-{
-    i2c: &'static Mutex<NoopRawMutex, &'static mut I2C>,
+impl IbusAdc {
+    /// Input current, negative in OTG mode.
+    pub fn milliamps(&self) -> i32 {
+        ((self.raw_current() << 1) as i16 >> 1) as i32 * 2
+    }
 }
 
-#[macro_export]
-macro_rules! bq25620_read_reg8 {
-    ($bq:expr, $reg:ident) => {
-        async || -> Result<crate::pd::bq25620::$reg> {
-            $bq.read_u8(crate::pd::bq25620::Register::$reg)
-                .await
-                .map(|val| crate::pd::bq25620::$reg::from(val))
-        }()
-    };
+impl IbatAdc {
+    /// Battery current, positive while charging.
+    pub fn milliamps(&self) -> i32 {
+        ((self.raw_current() << 2) as i16 >> 2) as i32 * 4
+    }
 }
 
-#[macro_export]
-macro_rules! bq25620_write_reg8 {
-    ($bq:expr, $reg:ident, $data:expr) => {
-        $bq.write_u8(crate::pd::bq25620::Register::$reg, $data.into())
-    };
+i2c_registers!(
+    Bq25620Device,
+    Register,
+    u8: ChargerStatus0,
+    ChargerStatus1,
+    FaultStatus0,
+    AdcControl,
+    PartInformation,
+);
+i2c_registers!(Bq25620Device, Register, u16: IbusAdc, IbatAdc);
+
+/// Fields come from the register structs; registers without one decode as
+/// raw values.
+#[cfg(feature = "i2c-debug")]
+pub const CHIP: Chip = Chip {
+    name: "bq25620",
+    addr: ADDR,
+    registers: &[
+        reg(R::ChargeCurrentLimit, "ChargeCurrentLimit", 2, &[]),
+        reg(
+            R::ChargeCurrentVoltageLimit,
+            "ChargeCurrentVoltageLimit",
+            2,
+            &[],
+        ),
+        reg(R::InputCurrentLimit, "InputCurrentLimit", 2, &[]),
+        reg(R::InputVoltageLimit, "InputVoltageLimit", 2, &[]),
+        reg(R::IotgRegulation, "IotgRegulation", 2, &[]),
+        reg(R::BotgRegulation, "BotgRegulation", 2, &[]),
+        reg(R::MinimalSystemVoltage, "MinimalSystemVoltage", 2, &[]),
+        reg(R::PreChargeCurrent, "PreChargeCurrent", 2, &[]),
+        reg(R::TerminationControl, "TerminationControl", 2, &[]),
+        reg(R::ChargeControl0, "ChargeControl0", 1, &[]),
+        reg(R::ChargeTimerControl, "ChargeTimerControl", 1, &[]),
+        reg(R::ChargerControl1, "ChargerControl1", 1, &[]),
+        reg(R::ChargerControl2, "ChargerControl2", 1, &[]),
+        reg(R::ChargerControl3, "ChargerControl3", 1, &[]),
+        reg(R::ChargerControl4, "ChargerControl4", 1, &[]),
+        reg(R::NtcControl0, "NtcControl0", 1, &[]),
+        reg(R::NtcControl1, "NtcControl1", 1, &[]),
+        reg(R::NtcControl2, "NtcControl2", 1, &[]),
+        reg(
+            R::ChargerStatus0,
+            "ChargerStatus0",
+            1,
+            ChargerStatus0::FIELDS,
+        ),
+        reg(
+            R::ChargerStatus1,
+            "ChargerStatus1",
+            1,
+            ChargerStatus1::FIELDS,
+        ),
+        reg(R::FaultStatus0, "FaultStatus0", 1, FaultStatus0::FIELDS),
+        reg(R::ChargerFlag0, "ChargerFlag0", 1, &[]).read_clears(),
+        reg(R::ChargerFlag1, "ChargerFlag1", 1, &[]).read_clears(),
+        reg(R::FaultFlag0, "FaultFlag0", 1, &[]).read_clears(),
+        reg(R::ChargerMask0, "ChargerMask0", 1, &[]),
+        reg(R::ChargerMask, "ChargerMask", 1, &[]),
+        reg(R::FaultMask0, "FaultMask0", 1, &[]),
+        reg(R::AdcControl, "AdcControl", 1, AdcControl::FIELDS),
+        reg(R::AdcFunctionDisable, "AdcFunctionDisable", 1, &[]),
+        reg(R::IbusAdc, "IbusAdc", 2, IbusAdc::FIELDS),
+        reg(R::IbatAdc, "IbatAdc", 2, IbatAdc::FIELDS),
+        reg(R::VbusAdc, "VbusAdc", 2, AdcVoltage::FIELDS),
+        reg(R::VpmidAdc, "VpmidAdc", 2, &[]),
+        reg(R::VbatAdc, "VbatAdc", 2, &[]),
+        reg(R::VsysAdc, "VsysAdc", 2, AdcVoltage::FIELDS),
+        reg(R::TsAdc, "TsAdc", 2, &[]),
+        reg(R::TdieAdc, "TdieAdc", 2, &[]),
+        reg(
+            R::PartInformation,
+            "PartInformation",
+            1,
+            PartInformation::FIELDS,
+        ),
+    ],
+};
+
+#[cfg(feature = "i2c-debug")]
+const fn reg(
+    register: Register,
+    name: &'static str,
+    len: u8,
+    fields: &'static [Field],
+) -> regmap::Register {
+    regmap::Register::new(name, register as u8, len, fields)
 }
 
-#[macro_export]
-macro_rules! bq25620_read_reg16 {
-    ($bq:expr, $reg:ident) => {
-        async || -> Result<crate::pd::bq25620::$reg> {
-            $bq.read_u16(crate::pd::bq25620::Register::$reg)
-                .await
-                .map(|val| crate::pd::bq25620::$reg::from(val))
-        }()
-    };
+/// One set of ADC readings.
+#[derive(Clone, Copy, Debug)]
+pub struct Charger {
+    pub vbus_uv: u32,
+    pub vsys_uv: u32,
+    pub ibus_ma: i32,
+    pub ibat_ma: i32,
+}
+
+pub struct Bq25620<I2C, E>
+where
+    I2C: I2c<Error = E> + 'static,
+    Error: From<E>,
+{
+    regs: Bus<Bq25620Device, I2C>,
 }
 
 impl<I2C, E> Bq25620<I2C, E>
@@ -135,36 +288,28 @@ where
     Error: From<E>,
 {
     pub fn new(i2c: &'static Mutex<NoopRawMutex, &'static mut I2C>) -> Self {
-        Self { i2c }
+        Self {
+            regs: Bus::new(i2c),
+        }
     }
 
     pub async fn init(&mut self) -> Result<()> {
-        let part_info = bq25620_read_reg8!(self, PartInformation).await?;
+        let part_info = self.regs.read::<PartInformation>().await?;
         println!("bq part_info: {:?}", part_info);
 
         Ok(())
     }
 
-    pub async fn tick(&mut self) -> Result<()> {
-        bq25620_write_reg8!(self, AdcControl, AdcControl::new().with_adc_en(true)).await?;
-
-        let val = bq25620_read_reg16!(self, VsysAdc).await?;
-        println!("bq sys: {} uV", val.microvolts());
-        let val = bq25620_read_reg16!(self, VbusAdc).await?;
-        println!("bq bus: {} uV", val.microvolts());
-
-        Ok(())
-    }
-
-    pub(crate) async fn read_u8(&self, register: Register) -> Result<u8> {
-        i2c_read_u8(self.i2c, ADDR, register as u8).await
-    }
-
-    pub(crate) async fn read_u16(&self, register: Register) -> Result<u16> {
-        i2c_read_u16(self.i2c, ADDR, register as u8).await
-    }
+    pub async fn tick(&mut self) -> Result<Charger> {
+        self.regs.write(AdcControl::new().with_adc_en(true)).await?;
 
-    pub(crate) async fn write_u8(&self, register: Register, data: u8) -> Result<()> {
-        i2c_write_u8(self.i2c, ADDR, register as u8, data).await
+        let vbus: AdcVoltage = self.regs.read_at(Register::VbusAdc).await?;
+        let vsys: AdcVoltage = self.regs.read_at(Register::VsysAdc).await?;
+        Ok(Charger {
+            vbus_uv: vbus.microvolts(),
+            vsys_uv: vsys.microvolts(),
+            ibus_ma: self.regs.read::<IbusAdc>().await?.milliamps(),
+            ibat_ma: self.regs.read::<IbatAdc>().await?.milliamps(),
+        })
     }
 }
@@ -1,13 +1,18 @@
 use bitfield_struct::bitfield;
-use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
 use embedded_hal_async::i2c::I2c;
 use num_derive::{FromPrimitive, ToPrimitive};
 
-use super::i2c::{i2c_read_u8, i2c_write_u8};
+use crate::i2creg::{Bus, Device};
+#[cfg(feature = "i2c-debug")]
+use crate::regmap::{Chip, Field, Register};
+use crate::{bitfield_registers, i2c_registers};
 use crate::{Error, Result};
+#[cfg(feature = "i2c-debug")]
+use Fusb302Register as R;
 
 const FUSB302_ADDR: u8 = 0x22;
 
+#[derive(Clone, Copy, Debug)]
 #[repr(u8)]
 #[allow(unused)]
 pub enum Fusb302Register {
@@ -37,253 +42,336 @@ pub enum Fusb302Register {
     Fifos = 0x43,
 }
 
-#[bitfield(u8)]
-pub struct DeviceId {
-    #[bits(2)]
-    pub revision: u8,
-    #[bits(2)]
-    pub product: u8,
-    #[bits(4)]
-    pub version: u8,
+impl From<Fusb302Register> for u8 {
+    fn from(register: Fusb302Register) -> u8 {
+        register as u8
+    }
 }
 
-#[bitfield(u8)]
-pub struct Switches0 {
-    pub pdwn1: bool,
-    pub pdwn2: bool,
-    pub meas_cc1: bool,
-    pub meas_cc2: bool,
-    pub vconn_cc1: bool,
-    pub vconn_cc2: bool,
-    pub pu_en1: bool,
-    pub pu_en2: bool,
-}
+pub struct Fusb302;
+
+impl Device for Fusb302 {
+    const ADDR: u8 = FUSB302_ADDR;
+    type Register = Fusb302Register;
+}
+
+pub(crate) type Fusb302Bus<I2C> = Bus<Fusb302, I2C>;
+
+/// Register map for the I2C debug API.  `Fifos` is left out as reading it
+/// takes data out of the receive FIFO.
+#[cfg(feature = "i2c-debug")]
+pub const CHIP: Chip = Chip {
+    name: "fusb302",
+    addr: FUSB302_ADDR,
+    registers: &[
+        reg(R::DeviceId, "DeviceId", DeviceId::FIELDS),
+        reg(R::Switches0, "Switches0", Switches0::FIELDS),
+        reg(R::Switches1, "Switches1", Switches1::FIELDS),
+        reg(R::Measure, "Measure", Measure::FIELDS),
+        reg(R::Slice, "Slice", Slice::FIELDS),
+        reg(R::Control0, "Control0", Control0::FIELDS),
+        reg(R::Control1, "Control1", Control1::FIELDS),
+        reg(R::Control2, "Control2", Control2::FIELDS),
+        reg(R::Control3, "Control3", Control3::FIELDS),
+        reg(R::Mask1, "Mask1", Mask1::FIELDS),
+        reg(R::Power, "Power", Power::FIELDS),
+        reg(R::Reset, "Reset", Reset::FIELDS),
+        reg(R::OCPreg, "OCPreg", OCPreg::FIELDS),
+        reg(R::MaskA, "MaskA", MaskA::FIELDS),
+        reg(R::MaskB, "MaskB", MaskB::FIELDS),
+        reg(R::Control4, "Control4", Control4::FIELDS),
+        reg(R::Status0A, "Status0A", Status0A::FIELDS),
+        reg(R::Status1A, "Status1A", Status1A::FIELDS),
+        reg(R::InterruptA, "InterruptA", InterruptA::FIELDS).read_clears(),
+        reg(R::InterruptB, "InterruptB", InterruptB::FIELDS).read_clears(),
+        reg(R::Status0, "Status0", Status0::FIELDS),
+        reg(R::Status1, "Status1", Status1::FIELDS),
+        reg(R::Interrupt, "Interrupt", Interrupt::FIELDS).read_clears(),
+    ],
+};
+
+#[cfg(feature = "i2c-debug")]
+const fn reg(register: Fusb302Register, name: &'static str, fields: &'static [Field]) -> Register {
+    Register::new(name, register as u8, 1, fields)
+}
+
+bitfield_registers! {
+    #[bitfield(u8)]
+    pub struct DeviceId {
+        #[bits(2)]
+        pub revision: u8,
+        #[bits(2)]
+        pub product: u8,
+        #[bits(4)]
+        pub version: u8,
+    }
 
-#[bitfield(u8)]
-pub struct Switches1 {
-    pub txcc1: bool,
-    pub txcc2: bool,
-    pub auto_crc: bool,
-    _reserved: bool,
-    pub data_role: bool,
-    #[bits(2)]
-    pub spec_rev: u8,
-    pub power_role: bool,
-}
+    #[bitfield(u8)]
+    pub struct Switches0 {
+        pub pdwn1: bool,
+        pub pdwn2: bool,
+        pub meas_cc1: bool,
+        pub meas_cc2: bool,
+        pub vconn_cc1: bool,
+        pub vconn_cc2: bool,
+        pub pu_en1: bool,
+        pub pu_en2: bool,
+    }
 
-#[bitfield(u8)]
-pub struct Measure {
-    #[bits(6)]
-    pub mdac: u8,
-    pub meas_vbus: bool,
-    _reserved: bool,
-}
+    #[bitfield(u8)]
+    pub struct Switches1 {
+        pub txcc1: bool,
+        pub txcc2: bool,
+        pub auto_crc: bool,
+        _reserved: bool,
+        pub data_role: bool,
+        #[bits(2)]
+        pub spec_rev: u8,
+        pub power_role: bool,
+    }
 
-#[bitfield(u8)]
-pub struct Slice {
-    pub sdac0: bool,
-    pub sdac1: bool,
-    pub sdac2: bool,
-    pub sdac3: bool,
-    pub sdac4: bool,
-    pub sdac5: bool,
-    pub sdac_hys2: bool,
-    pub sdac_hys1: bool,
-}
+    #[bitfield(u8)]
+    pub struct Measure {
+        #[bits(6)]
+        pub mdac: u8,
+        pub meas_vbus: bool,
+        _reserved: bool,
+    }
 
-#[bitfield(u8)]
-pub struct Control0 {
-    pub tx_start: bool,
-    pub auto_pre: bool,
-    #[bits(2)]
-    pub host_cur: u8,
-    _reserved0: bool,
-    pub int_mask: bool,
-    pub tx_flush: bool,
-    _reserved1: bool,
-}
+    #[bitfield(u8)]
+    pub struct Slice {
+        pub sdac0: bool,
+        pub sdac1: bool,
+        pub sdac2: bool,
+        pub sdac3: bool,
+        pub sdac4: bool,
+        pub sdac5: bool,
+        pub sdac_hys2: bool,
+        pub sdac_hys1: bool,
+    }
 
-#[bitfield(u8)]
-pub struct Control1 {
-    pub ensop1: bool,
-    pub ensop2: bool,
-    pub rx_flush: bool,
-    _reserved0: bool,
-    pub bist_mode2: bool,
-    pub ensop1db: bool,
-    pub ensop2db: bool,
-    _reserved1: bool,
-}
+    #[bitfield(u8)]
+    pub struct Control0 {
+        pub tx_start: bool,
+        pub auto_pre: bool,
+        #[bits(2)]
+        pub host_cur: u8,
+        _reserved0: bool,
+        pub int_mask: bool,
+        pub tx_flush: bool,
+        _reserved1: bool,
+    }
 
-#[bitfield(u8)]
-pub struct Control2 {
-    pub toggle: bool,
-    #[bits(2)]
-    pub mode: u8,
-    pub wake_en: bool,
-    _reserved0: bool,
-    pub tog_rd_only: bool,
-    pub tog_save_pwr1: bool,
-    pub tog_save_pwr2: bool,
-}
+    #[bitfield(u8)]
+    pub struct Control1 {
+        pub ensop1: bool,
+        pub ensop2: bool,
+        pub rx_flush: bool,
+        _reserved0: bool,
+        pub bist_mode2: bool,
+        pub ensop1db: bool,
+        pub ensop2db: bool,
+        _reserved1: bool,
+    }
 
-#[bitfield(u8)]
-pub struct Control3 {
-    pub auto_retry: bool,
-    #[bits(2)]
-    pub n_retries: u8,
-    pub auto_hardreset: bool,
-    pub auto_softreset: bool,
-    pub bist_t_mode: bool,
-    pub send_hard_reset: bool,
-    _reserved: bool,
-}
+    #[bitfield(u8)]
+    pub struct Control2 {
+        pub toggle: bool,
+        #[bits(2)]
+        pub mode: u8,
+        pub wake_en: bool,
+        _reserved0: bool,
+        pub tog_rd_only: bool,
+        pub tog_save_pwr1: bool,
+        pub tog_save_pwr2: bool,
+    }
 
-#[bitfield(u8)]
-pub struct Mask1 {
-    pub m_bc_lvl: bool,
-    pub m_collision: bool,
-    pub m_wake: bool,
-    pub m_alert: bool,
-    pub m_crc_chk: bool,
-    pub m_comp_chng: bool,
-    pub m_activity: bool,
-    pub m_vbusok: bool,
-}
+    #[bitfield(u8)]
+    pub struct Control3 {
+        pub auto_retry: bool,
+        #[bits(2)]
+        pub n_retries: u8,
+        pub auto_hardreset: bool,
+        pub auto_softreset: bool,
+        pub bist_t_mode: bool,
+        pub send_hard_reset: bool,
+        _reserved: bool,
+    }
 
-#[bitfield(u8)]
-pub struct Power {
-    pub pwr0: bool,
-    pub pwr1: bool,
-    pub pwr2: bool,
-    pub pwr3: bool,
-    #[bits(4)]
-    _reserved: u8,
-}
+    #[bitfield(u8)]
+    pub struct Mask1 {
+        pub m_bc_lvl: bool,
+        pub m_collision: bool,
+        pub m_wake: bool,
+        pub m_alert: bool,
+        pub m_crc_chk: bool,
+        pub m_comp_chng: bool,
+        pub m_activity: bool,
+        pub m_vbusok: bool,
+    }
 
-#[bitfield(u8)]
-pub struct Reset {
-    pub sw_res: bool,
-    pub pd_reset: bool,
-    #[bits(6)]
-    _reserved: u8,
-}
+    #[bitfield(u8)]
+    pub struct Power {
+        pub pwr0: bool,
+        pub pwr1: bool,
+        pub pwr2: bool,
+        pub pwr3: bool,
+        #[bits(4)]
+        _reserved: u8,
+    }
 
-#[bitfield(u8)]
-pub struct OCPreg {
-    pub ocp_cur0: bool,
-    pub ocp_cur1: bool,
-    pub ocp_cur2: bool,
-    pub ocp_cur3: bool,
-    #[bits(4)]
-    _reserved: u8,
-}
+    #[bitfield(u8)]
+    pub struct Reset {
+        pub sw_res: bool,
+        pub pd_reset: bool,
+        #[bits(6)]
+        _reserved: u8,
+    }
 
-#[bitfield(u8)]
-pub struct MaskA {
-    pub m_hardrst: bool,
-    pub m_softrst: bool,
-    pub m_txsent: bool,
-    pub m_hardsent: bool,
-    pub m_retryfail: bool,
-    pub m_softfail: bool,
-    pub m_togdone: bool,
-    pub m_ocp_temp: bool,
-}
+    #[bitfield(u8)]
+    pub struct OCPreg {
+        pub ocp_cur0: bool,
+        pub ocp_cur1: bool,
+        pub ocp_cur2: bool,
+        pub ocp_cur3: bool,
+        #[bits(4)]
+        _reserved: u8,
+    }
 
-#[bitfield(u8)]
-pub struct MaskB {
-    pub m_gcrcsent: bool,
-    #[bits(7)]
-    _reserved: u8,
-}
+    #[bitfield(u8)]
+    pub struct MaskA {
+        pub m_hardrst: bool,
+        pub m_softrst: bool,
+        pub m_txsent: bool,
+        pub m_hardsent: bool,
+        pub m_retryfail: bool,
+        pub m_softfail: bool,
+        pub m_togdone: bool,
+        pub m_ocp_temp: bool,
+    }
 
-#[bitfield(u8)]
-pub struct Control4 {
-    pub tog_exit_aud: bool,
-    #[bits(7)]
-    _reserved: u8,
-}
+    #[bitfield(u8)]
+    pub struct MaskB {
+        pub m_gcrcsent: bool,
+        #[bits(7)]
+        _reserved: u8,
+    }
 
-#[bitfield(u8)]
-pub struct Status0A {
-    pub hardrst: bool,
-    pub softrst: bool,
-    pub power2: bool,
-    pub power3: bool,
-    pub retryfail: bool,
-    pub softfail: bool,
-    #[bits(2)]
-    _reserved: u8,
-}
+    #[bitfield(u8)]
+    pub struct Control4 {
+        pub tog_exit_aud: bool,
+        #[bits(7)]
+        _reserved: u8,
+    }
 
-#[bitfield(u8)]
-pub struct Status1A {
-    pub rxsop: bool,
-    pub rxsop1db: bool,
-    pub rssop2db: bool,
-    pub togss1: bool,
-    pub togss2: bool,
-    pub togss3: bool,
-    #[bits(2)]
-    _reserved: u8,
-}
+    #[bitfield(u8)]
+    pub struct Status0A {
+        pub hardrst: bool,
+        pub softrst: bool,
+        pub power2: bool,
+        pub power3: bool,
+        pub retryfail: bool,
+        pub softfail: bool,
+        #[bits(2)]
+        _reserved: u8,
+    }
 
-#[bitfield(u8)]
-pub struct InterruptA {
-    pub i_hardrst: bool,
-    pub i_softrst: bool,
-    pub i_txsent: bool,
-    pub i_hardsent: bool,
-    pub i_retryfail: bool,
-    pub i_softfail: bool,
-    pub i_togdone: bool,
-    pub i_ocp_temp: bool,
-}
+    #[bitfield(u8)]
+    pub struct Status1A {
+        pub rxsop: bool,
+        pub rxsop1db: bool,
+        pub rssop2db: bool,
+        pub togss1: bool,
+        pub togss2: bool,
+        pub togss3: bool,
+        #[bits(2)]
+        _reserved: u8,
+    }
 
-#[bitfield(u8)]
-pub struct InterruptB {
-    pub i_gcrcsent: bool,
-    #[bits(7)]
-    _reserved: u8,
-}
+    #[bitfield(u8)]
+    pub struct InterruptA {
+        pub i_hardrst: bool,
+        pub i_softrst: bool,
+        pub i_txsent: bool,
+        pub i_hardsent: bool,
+        pub i_retryfail: bool,
+        pub i_softfail: bool,
+        pub i_togdone: bool,
+        pub i_ocp_temp: bool,
+    }
 
-#[bitfield(u8)]
-pub struct Status0 {
-    #[bits(2)]
-    pub bc_lvl: u8,
-    pub wake: bool,
-    pub alert: bool,
-    pub crc_chk: bool,
-    pub comp: bool,
-    pub activity: bool,
-    pub vbusok: bool,
-}
+    #[bitfield(u8)]
+    pub struct InterruptB {
+        pub i_gcrcsent: bool,
+        #[bits(7)]
+        _reserved: u8,
+    }
 
-#[bitfield(u8)]
-pub struct Status1 {
-    pub ocp: bool,
-    pub overtemp: bool,
-    pub tx_full: bool,
-    pub tx_empty: bool,
-    pub rx_full: bool,
-    pub rx_empty: bool,
-    pub rxsop1: bool,
-    pub rxsop2: bool,
-}
+    #[bitfield(u8)]
+    pub struct Status0 {
+        #[bits(2)]
+        pub bc_lvl: u8,
+        pub wake: bool,
+        pub alert: bool,
+        pub crc_chk: bool,
+        pub comp: bool,
+        pub activity: bool,
+        pub vbusok: bool,
+    }
 
-#[bitfield(u8)]
-pub struct Interrupt {
-    pub i_bc_lvl: bool,
-    pub i_collision: bool,
-    pub i_wake: bool,
-    pub i_alert: bool,
-    pub i_crc_chk: bool,
-    pub i_comp_chng: bool,
-    pub i_activity: bool,
-    pub i_vbusok: bool,
+    #[bitfield(u8)]
+    pub struct Status1 {
+        pub ocp: bool,
+        pub overtemp: bool,
+        pub tx_full: bool,
+        pub tx_empty: bool,
+        pub rx_full: bool,
+        pub rx_empty: bool,
+        pub rxsop1: bool,
+        pub rxsop2: bool,
+    }
+
+    #[bitfield(u8)]
+    pub struct Interrupt {
+        pub i_bc_lvl: bool,
+        pub i_collision: bool,
+        pub i_wake: bool,
+        pub i_alert: bool,
+        pub i_crc_chk: bool,
+        pub i_comp_chng: bool,
+        pub i_activity: bool,
+        pub i_vbusok: bool,
+    }
 }
 
+i2c_registers!(
+    Fusb302,
+    Fusb302Register,
+    u8: DeviceId,
+    Switches0,
+    Switches1,
+    Measure,
+    Slice,
+    Control0,
+    Control1,
+    Control2,
+    Control3,
+    Mask1,
+    Power,
+    Reset,
+    OCPreg,
+    MaskA,
+    MaskB,
+    Control4,
+    Status0A,
+    Status1A,
+    InterruptA,
+    InterruptB,
+    Status0,
+    Status1,
+    Interrupt,
+);
+
 #[derive(Debug)]
 pub struct Status {
     pub status_0a: Status0A,
@@ -358,53 +446,14 @@ pub struct RxToken {
     pub token: RxTokenType,
 }
 
-pub(crate) async fn fusb302_read<I2C, E>(
-    i2c: &'static Mutex<NoopRawMutex, &'static mut I2C>,
-    register: Fusb302Register,
-    data: &mut [u8],
-) -> Result<()>
-where
-    I2C: I2c<Error = E>,
-    Error: From<E>,
-{
-    let mut i2c = i2c.lock().await;
-    i2c.write_read(FUSB302_ADDR, &[register as u8], data)
-        .await?;
-    Ok(())
-}
-
-pub(crate) async fn fusb302_read_u8<I2C, E>(
-    i2c: &'static Mutex<NoopRawMutex, &'static mut I2C>,
-    register: Fusb302Register,
-) -> Result<u8>
-where
-    I2C: I2c<Error = E>,
-    Error: From<E>,
-{
-    i2c_read_u8(i2c, FUSB302_ADDR, register as u8).await
-}
-
-pub(crate) async fn fusb302_write_u8<I2C, E>(
-    i2c: &'static Mutex<NoopRawMutex, &'static mut I2C>,
-    register: Fusb302Register,
-    data: u8,
-) -> Result<()>
-where
-    I2C: I2c<Error = E>,
-    Error: From<E>,
-{
-    i2c_write_u8(i2c, FUSB302_ADDR, register as u8, data).await
-}
-
-pub(crate) async fn fusb302_read_status<I2C, E>(
-    i2c: &'static Mutex<NoopRawMutex, &'static mut I2C>,
-) -> Result<Status>
+pub(crate) async fn fusb302_read_status<I2C, E>(fusb: &Fusb302Bus<I2C>) -> Result<Status>
 where
     I2C: I2c<Error = E>,
     Error: From<E>,
 {
     let mut data = [0u8; 7];
-    fusb302_read(i2c, Fusb302Register::Status0A, &mut data).await?;
+    fusb.read_burst(Fusb302Register::Status0A, &mut data)
+        .await?;
     Ok(Status {
         status_0a: Status0A::from(data[0]),
         status_1a: Status1A::from(data[1]),
@@ -417,50 +466,39 @@ where
 }
 
 pub(crate) async fn fusb302_read_fifo<I2C, E>(
-    i2c: &'static Mutex<NoopRawMutex, &'static mut I2C>,
+    fusb: &Fusb302Bus<I2C>,
     buffer: &mut [u8],
 ) -> Result<()>
 where
     I2C: I2c<Error = E>,
     Error: From<E>,
 {
-    fusb302_read(i2c, Fusb302Register::Fifos, buffer).await?;
-    Ok(())
+    fusb.read_burst(Fusb302Register::Fifos, buffer).await
 }
 
-pub(crate) async fn fusb302_read_fifo_u8<I2C, E>(
-    i2c: &'static Mutex<NoopRawMutex, &'static mut I2C>,
-) -> Result<u8>
+pub(crate) async fn fusb302_read_fifo_u8<I2C, E>(fusb: &Fusb302Bus<I2C>) -> Result<u8>
 where
     I2C: I2c<Error = E>,
     Error: From<E>,
 {
-    let mut buffer = [0u8];
-    fusb302_read(i2c, Fusb302Register::Fifos, &mut buffer).await?;
-    Ok(buffer[0])
+    fusb.read_at(Fusb302Register::Fifos).await
 }
 
-pub(crate) async fn fusb302_read_fifo_u16<I2C, E>(
-    i2c: &'static Mutex<NoopRawMutex, &'static mut I2C>,
-) -> Result<u16>
+pub(crate) async fn fusb302_read_fifo_u16<I2C, E>(fusb: &Fusb302Bus<I2C>) -> Result<u16>
 where
     I2C: I2c<Error = E>,
     Error: From<E>,
 {
-    let mut buffer = [0u8; 2];
-    fusb302_read(i2c, Fusb302Register::Fifos, &mut buffer).await?;
-    Ok(u16::from_le_bytes(buffer))
+    fusb.read_at(Fusb302Register::Fifos).await
 }
 
-pub(crate) async fn fusb302_read_fifo_u32<I2C, E>(
-    i2c: &'static Mutex<NoopRawMutex, &'static mut I2C>,
-) -> Result<u32>
+pub(crate) async fn fusb302_read_fifo_u32<I2C, E>(fusb: &Fusb302Bus<I2C>) -> Result<u32>
 where
     I2C: I2c<Error = E>,
     Error: From<E>,
 {
     let mut buffer = [0u8; 4];
-    fusb302_read(i2c, Fusb302Register::Fifos, &mut buffer).await?;
+    fusb302_read_fifo(fusb, &mut buffer).await?;
     Ok(u32::from_le_bytes(buffer))
 }
 
@@ -531,15 +569,11 @@ impl Fusb302MessageBuffer {
         Ok(())
     }
 
-    pub async fn send<I2C, E>(
-        &self,
-        i2c: &'static Mutex<NoopRawMutex, &'static mut I2C>,
-    ) -> Result<()>
+    pub async fn send<I2C, E>(&self, fusb: &Fusb302Bus<I2C>) -> Result<()>
     where
         I2C: I2c<Error = E>,
         Error: From<E>,
     {
-        let mut i2c = i2c.lock().await;
         let sop_seq = [
             Fusb302Register::Fifos as u8,
             TxToken::Sop1 as u8,
@@ -548,8 +582,6 @@ impl Fusb302MessageBuffer {
             TxToken::Sop2 as u8,
             TxToken::PackSym as u8 + (self.len - 1) as u8,
         ];
-        i2c.write(FUSB302_ADDR, &sop_seq).await?;
-        i2c.write(FUSB302_ADDR, &self.buffer[..self.len]).await?;
         let eop_seq = [
             Fusb302Register::Fifos as u8,
             TxToken::JamCrc as u8,
@@ -557,29 +589,7 @@ impl Fusb302MessageBuffer {
             TxToken::TxOff as u8,
             TxToken::TxOn as u8,
         ];
-        i2c.write(FUSB302_ADDR, &eop_seq).await?;
-
-        Ok(())
-    }
-}
-
-#[macro_export]
-macro_rules! fusb302_read_reg {
-    ($i2c:expr, $reg:ident) => {
-        crate::pd::fusb302::fusb302_read_u8($i2c, crate::pd::fusb302::Fusb302Register::$reg)
+        fusb.write_frames(&[&sop_seq, &self.buffer[..self.len], &eop_seq])
             .await
-            .map(|val| $reg::from(val))
-    };
-}
-
-#[macro_export]
-macro_rules! fusb302_write_reg {
-    ($i2c:expr, $reg:ident, $data:expr) => {
-        crate::pd::fusb302::fusb302_write_u8(
-            $i2c,
-            crate::pd::fusb302::Fusb302Register::$reg,
-            $data.into(),
-        )
-        .await
-    };
+    }
 }
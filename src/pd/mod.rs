@@ -14,37 +14,82 @@ use num_traits::FromPrimitive;
 
 mod bq25620;
 mod fusb302;
-mod i2c;
 mod proto;
 
-use crate::{fusb302_read_reg, fusb302_write_reg};
 use crate::{Error, Result};
 use fusb302::{
     fusb302_read_fifo, fusb302_read_fifo_u16, fusb302_read_fifo_u32, fusb302_read_fifo_u8,
-    fusb302_read_status, fusb302_read_u8, Control0, Control1, Control2, Control3, DeviceId,
+    fusb302_read_status, Control0, Control1, Control2, Control3, DeviceId, Fusb302Bus,
     Fusb302MessageBuffer, Fusb302Register, Mask1, MaskA, MaskB, Measure, Power, Reset, RxToken,
     RxTokenType, Status, Status0, Status1, Switches0, Switches1,
 };
 use proto::*;
 
-use self::bq25620::Bq25620;
+use self::bq25620::{Bq25620, Charger};
 
 // Data messges have a max of 7 * 32 bit objects.
 const MAX_PAYLOAD_SIZE: usize = 7 * 4;
 
-enum PdState {
+const CHARGER_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// Register maps of the chips on the bus, for the I2C debug endpoints.
+#[cfg(feature = "i2c-debug")]
+pub const CHIPS: &[&crate::regmap::Chip] = &[&fusb302::CHIP, &bq25620::CHIP];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PdState {
     Reset,
     WaitForVbus,
     PollCC,
     Online,
 }
 
+impl PdState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PdState::Reset => "reset",
+            PdState::WaitForVbus => "wait_for_vbus",
+            PdState::PollCC => "poll_cc",
+            PdState::Online => "online",
+        }
+    }
+}
+
+/// What the power task last learned about the PD source and the charger.
+pub struct PowerStatus {
+    pub state: PdState,
+    pdos: [FixedSupplyPdo; 7],
+    num_pdos: usize,
+    /// Index into the source's PDOs of the one we requested.
+    pub contract: Option<usize>,
+    pub charger: Option<Charger>,
+}
+
+pub type SharedPowerStatus = Mutex<NoopRawMutex, PowerStatus>;
+
+impl PowerStatus {
+    pub const fn new() -> Self {
+        Self {
+            state: PdState::Reset,
+            pdos: [FixedSupplyPdo::new(); 7],
+            num_pdos: 0,
+            contract: None,
+            charger: None,
+        }
+    }
+
+    /// The source capabilities from the last Source_Capabilities message.
+    pub fn pdos(&self) -> &[FixedSupplyPdo] {
+        &self.pdos[..self.num_pdos]
+    }
+}
+
 struct Pd<I2C, E>
 where
     I2C: I2c<Error = E> + 'static,
     Error: From<E>,
 {
-    i2c: &'static Mutex<NoopRawMutex, &'static mut I2C>,
+    fusb: Fusb302Bus<I2C>,
     pd_int_n: GpioPin<
         Input<Floating>,
         Bank0GpioRegisterAccess,
@@ -54,6 +99,7 @@ where
         7,
     >,
     state: PdState,
+    power: &'static SharedPowerStatus,
     status: Status,
     pdos: [FixedSupplyPdo; 7],
     num_pdos: usize,
@@ -75,11 +121,13 @@ where
             Gpio7Signals,
             7,
         >,
+        power: &'static SharedPowerStatus,
     ) -> Self {
         Self {
-            i2c,
+            fusb: Fusb302Bus::new(i2c),
             pd_int_n,
             state: PdState::Reset,
+            power,
             status: Default::default(),
             pdos: [FixedSupplyPdo::new(); 7],
             num_pdos: 0,
@@ -88,26 +136,24 @@ where
     }
 
     async fn flush_rx_fifo(&mut self) -> Result<()> {
-        fusb302_write_reg!(self.i2c, Control1, Control1::new().with_rx_flush(true))
+        self.fusb.write(Control1::new().with_rx_flush(true)).await
     }
 
     async fn fusb_reset(&mut self) -> Result<()> {
         // flush tx buffer
-        fusb302_write_reg!(
-            self.i2c,
-            Control0,
-            Control0::new().with_host_cur(1).with_tx_flush(true)
-        )?;
+        self.fusb
+            .write(Control0::new().with_host_cur(1).with_tx_flush(true))
+            .await?;
 
         self.flush_rx_fifo().await?;
 
-        fusb302_write_reg!(self.i2c, Reset, Reset::new().with_pd_reset(true))?;
+        self.fusb.write(Reset::new().with_pd_reset(true)).await?;
 
         Ok(())
     }
 
     async fn fusb_read_id(&mut self) -> Result<DeviceId> {
-        let val = fusb302_read_u8(self.i2c, Fusb302Register::DeviceId).await?;
+        let val: u8 = self.fusb.read_at(Fusb302Register::DeviceId).await?;
         if val == 0 || val == 0xff {
             return Err(Error::InvalidDeviceId);
         }
@@ -117,7 +163,7 @@ where
 
     async fn fusb_setup(&mut self) -> Result<()> {
         // Software reset the chip.
-        fusb302_write_reg!(self.i2c, Reset, Reset::new().with_sw_res(true))?;
+        self.fusb.write(Reset::new().with_sw_res(true)).await?;
 
         // Wait till the chip responds with its ID.
         let mut retries = 5;
@@ -132,31 +178,29 @@ where
         }
 
         // Power up entire chip.
-        fusb302_write_reg!(
-            self.i2c,
-            Power,
-            Power::new()
-                .with_pwr0(true)
-                .with_pwr1(true)
-                .with_pwr2(true)
-                .with_pwr3(true)
-        )?;
+        self.fusb
+            .write(
+                Power::new()
+                    .with_pwr0(true)
+                    .with_pwr1(true)
+                    .with_pwr2(true)
+                    .with_pwr3(true),
+            )
+            .await?;
 
         // Unmask interrupts.
-        fusb302_write_reg!(self.i2c, Mask1, Mask1::new())?;
-        fusb302_write_reg!(self.i2c, MaskA, MaskA::new())?;
-        fusb302_write_reg!(self.i2c, MaskB, MaskB::new())?;
-        fusb302_write_reg!(self.i2c, Control0, Control0::new().with_host_cur(3))?;
+        self.fusb.write(Mask1::new()).await?;
+        self.fusb.write(MaskA::new()).await?;
+        self.fusb.write(MaskB::new()).await?;
+        self.fusb.write(Control0::new().with_host_cur(3)).await?;
 
         // Enable packet retries
-        fusb302_write_reg!(
-            self.i2c,
-            Control3,
-            Control3::new().with_auto_retry(true).with_n_retries(3)
-        )?;
+        self.fusb
+            .write(Control3::new().with_auto_retry(true).with_n_retries(3))
+            .await?;
 
         // Set defaults for Control 2
-        fusb302_write_reg!(self.i2c, Control2, Control2::new())?;
+        self.fusb.write(Control2::new()).await?;
 
         self.flush_rx_fifo().await?;
 
@@ -165,31 +209,31 @@ where
 
     async fn detect_cc_line(&mut self) -> Result<()> {
         // Reset Measure register to default values
-        fusb302_write_reg!(self.i2c, Measure, Measure::new().with_mdac(0b11_0001))?;
+        self.fusb.write(Measure::new().with_mdac(0b11_0001)).await?;
 
         // sample CC1
-        fusb302_write_reg!(
-            self.i2c,
-            Switches0,
-            Switches0::new()
-                .with_pdwn1(true)
-                .with_pdwn2(true)
-                .with_meas_cc1(true)
-        )?;
+        self.fusb
+            .write(
+                Switches0::new()
+                    .with_pdwn1(true)
+                    .with_pdwn2(true)
+                    .with_meas_cc1(true),
+            )
+            .await?;
         Timer::after(Duration::from_millis(20)).await; // TODO: replace with poll of status bit
-        let cc1_val = fusb302_read_reg!(self.i2c, Status0)?.bc_lvl();
+        let cc1_val = self.fusb.read::<Status0>().await?.bc_lvl();
 
         // sample CC2
-        fusb302_write_reg!(
-            self.i2c,
-            Switches0,
-            Switches0::new()
-                .with_pdwn1(true)
-                .with_pdwn2(true)
-                .with_meas_cc2(true)
-        )?;
+        self.fusb
+            .write(
+                Switches0::new()
+                    .with_pdwn1(true)
+                    .with_pdwn2(true)
+                    .with_meas_cc2(true),
+            )
+            .await?;
         Timer::after(Duration::from_millis(20)).await; // TODO: replace with poll of status bit
-        let cc2_val = fusb302_read_reg!(self.i2c, Status0)?.bc_lvl();
+        let cc2_val = self.fusb.read::<Status0>().await?.bc_lvl();
 
         if cc1_val == cc2_val {
             return Err(Error::NoCcDetected);
@@ -198,36 +242,36 @@ where
         let use_cc1 = cc1_val > cc2_val;
         let use_cc2 = cc2_val > cc1_val;
 
-        fusb302_write_reg!(
-            self.i2c,
-            Switches0,
-            Switches0::new()
-                .with_pdwn1(true)
-                .with_pdwn2(true)
-                .with_meas_cc1(use_cc1)
-                .with_meas_cc2(use_cc2)
-        )?;
+        self.fusb
+            .write(
+                Switches0::new()
+                    .with_pdwn1(true)
+                    .with_pdwn2(true)
+                    .with_meas_cc1(use_cc1)
+                    .with_meas_cc2(use_cc2),
+            )
+            .await?;
 
         self.flush_rx_fifo().await?;
 
         // Enableing AutoCRC means that the FUSB302 will auto ACK packets
         // from our peer.  If we don't respond the messages in time, the
         // peer will likely disconnect.
-        fusb302_write_reg!(
-            self.i2c,
-            Switches1,
-            Switches1::new()
-                .with_txcc1(use_cc1)
-                .with_txcc2(use_cc2)
-                .with_auto_crc(true)
-                .with_spec_rev(0) // 0 == Revision 1.0
-        )?;
+        self.fusb
+            .write(
+                Switches1::new()
+                    .with_txcc1(use_cc1)
+                    .with_txcc2(use_cc2)
+                    .with_auto_crc(true)
+                    .with_spec_rev(0), // 0 == Revision 1.0
+            )
+            .await?;
 
         Ok(())
     }
 
     async fn poll_status(&mut self) -> Result<()> {
-        self.status = fusb302_read_status(self.i2c).await?;
+        self.status = fusb302_read_status(&self.fusb).await?;
         //println!("{:?}", status);
 
         if self.status.interrupt_a.i_txsent() {
@@ -257,12 +301,22 @@ where
     }
 
     async fn tick(&mut self) -> Result<()> {
-        match self.state {
+        let ret = match self.state {
             PdState::Reset => self.handle_reset_state().await,
             PdState::WaitForVbus => self.handle_wait_for_vbus_state().await,
             PdState::PollCC => self.handle_poll_cc_state().await,
             PdState::Online => self.handle_online_state().await,
+        };
+
+        let mut power = self.power.lock().await;
+        if power.state != self.state {
+            power.state = self.state;
+            if self.state != PdState::Online {
+                power.num_pdos = 0;
+                power.contract = None;
+            }
         }
+        ret
     }
 
     async fn handle_reset_state(&mut self) -> Result<()> {
@@ -275,17 +329,13 @@ where
 
     async fn handle_wait_for_vbus_state(&mut self) -> Result<()> {
         // Enable pulldowns and start measuring vbus.
-        fusb302_write_reg!(
-            self.i2c,
-            Measure,
-            Measure::new().with_meas_vbus(true).with_mdac(0)
-        )?;
+        self.fusb
+            .write(Measure::new().with_meas_vbus(true).with_mdac(0))
+            .await?;
 
-        fusb302_write_reg!(
-            self.i2c,
-            Switches0,
-            Switches0::new().with_pdwn1(true).with_pdwn2(true)
-        )?;
+        self.fusb
+            .write(Switches0::new().with_pdwn1(true).with_pdwn2(true))
+            .await?;
 
         loop {
             self.poll_status().await?;
@@ -340,22 +390,22 @@ where
     async fn handle_new_data(&mut self) -> Result<()> {
         let mut payload = [0u8; MAX_PAYLOAD_SIZE];
 
-        while !fusb302_read_reg!(self.i2c, Status1)?.rx_empty() {
-            let token = RxToken::from(fusb302_read_fifo_u8(self.i2c).await?);
+        while !self.fusb.read::<Status1>().await?.rx_empty() {
+            let token = RxToken::from(fusb302_read_fifo_u8(&self.fusb).await?);
             if token.token() != RxTokenType::Sop {
                 // Skip non SOP tokens.
                 continue;
             }
 
-            let header = Header::from(fusb302_read_fifo_u16(self.i2c).await?);
+            let header = Header::from(fusb302_read_fifo_u16(&self.fusb).await?);
             if header.num_data_objects() > 0 {
-                fusb302_read_fifo(self.i2c, &mut payload[0..(header.num_data_objects() * 4)])
+                fusb302_read_fifo(&self.fusb, &mut payload[0..(header.num_data_objects() * 4)])
                     .await?;
             }
 
             // The FUSB302 has already verified the crc but we still need to
             // clear it from the FIFO.
-            let _crc = fusb302_read_fifo_u32(self.i2c).await?;
+            let _crc = fusb302_read_fifo_u32(&self.fusb).await?;
 
             self.handle_message(header, &payload[0..(header.num_data_objects() * 4)])
                 .await?;
@@ -428,9 +478,15 @@ where
                 .with_object_position((selected_pdo + 1) as u8)
                 .into(),
         )?;
-        msg.send(self.i2c).await?;
+        msg.send(&self.fusb).await?;
 
         //TODO: wait for good crc and accept.
+        {
+            let mut status = self.power.lock().await;
+            status.pdos = self.pdos;
+            status.num_pdos = self.num_pdos;
+            status.contract = Some(selected_pdo);
+        }
 
         //TODO: remove debugging
         println!("sent {msg:x?}");
@@ -472,16 +528,21 @@ where
     }
 }
 
-async fn handle_bq<I2C, E>(mut bq: Bq25620<I2C, E>)
+async fn handle_bq<I2C, E>(mut bq: Bq25620<I2C, E>, power: &SharedPowerStatus)
 where
     I2C: I2c<Error = E>,
     Error: From<E>,
 {
     println!("{:?}", bq.init().await);
     loop {
-        if let Err(e) = bq.tick().await {
-            println!("bq_error: {e:?}");
+        match bq.tick().await {
+            Ok(charger) => power.lock().await.charger = Some(charger),
+            Err(e) => {
+                println!("bq_error: {e:?}");
+                power.lock().await.charger = None;
+            }
         }
+        Timer::after(CHARGER_INTERVAL).await;
     }
 }
 
@@ -496,8 +557,9 @@ pub(crate) async fn task(
         Gpio7Signals,
         7,
     >,
+    power: &'static SharedPowerStatus,
 ) {
-    let pd = Pd::new(i2c.clone(), pd_int_n);
+    let pd = Pd::new(i2c.clone(), pd_int_n, power);
     let bq = Bq25620::new(i2c);
-    join(handle_pd(pd), handle_bq(bq)).await;
+    join(handle_pd(pd), handle_bq(bq, power)).await;
 }
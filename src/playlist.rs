@@ -0,0 +1,355 @@
+use byteorder::LittleEndian;
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant, Timer};
+use esp_println::println;
+
+use crate::buffer::{MutBuffer, OldBuffer};
+use crate::output::SharedOutput;
+use crate::preset;
+use crate::storage::{SharedStorage, Slot, Storage, MAX_PLAYLISTS};
+use crate::timecode::{Chase, SharedClock};
+use crate::{Error, Result};
+
+const PLAYLIST_VERSION: u16 = 1;
+pub const MAX_CUES: usize = 32;
+const PLAYLIST_MAX_LEN: usize = 2 + MAX_CUES * 9;
+
+const FLAG_LOOP: u8 = 0x1;
+const FLAG_AUTOSTART: u8 = 0x2;
+
+const TICK: Duration = Duration::from_millis(20);
+
+/// Recall `preset`, fading to it over `fade_ms`, then hold it for `hold_ms`
+/// before moving on to the next cue.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Cue {
+    pub preset: u8,
+    pub fade_ms: u32,
+    pub hold_ms: u32,
+}
+
+impl Cue {
+    pub const fn new(preset: u8) -> Self {
+        Self {
+            preset,
+            fade_ms: 1000,
+            hold_ms: 10_000,
+        }
+    }
+
+    fn duration(&self) -> Duration {
+        Duration::from_millis(self.fade_ms as u64 + self.hold_ms as u64)
+    }
+
+    fn write(&self, buf: &mut MutBuffer<LittleEndian>) -> Result<()> {
+        buf.write_u8(self.preset)?;
+        buf.write_u32(self.fade_ms)?;
+        buf.write_u32(self.hold_ms)?;
+        Ok(())
+    }
+
+    fn parse(buf: &mut OldBuffer<LittleEndian>) -> Result<Self> {
+        Ok(Self {
+            preset: buf.read_u8()?,
+            fade_ms: buf.read_u32()?,
+            hold_ms: buf.read_u32()?,
+        })
+    }
+}
+
+/// An ordered list of cues that runs once or loops.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Playlist {
+    cues: [Cue; MAX_CUES],
+    num_cues: usize,
+    pub looping: bool,
+    /// Start this playlist at boot.
+    pub autostart: bool,
+}
+
+impl Playlist {
+    pub const fn empty() -> Self {
+        Self {
+            cues: [Cue::new(0); MAX_CUES],
+            num_cues: 0,
+            looping: true,
+            autostart: false,
+        }
+    }
+
+    pub fn cues(&self) -> &[Cue] {
+        &self.cues[..self.num_cues]
+    }
+
+    /// Replace the cue at `index` or append it if `index` is one past the
+    /// end.
+    pub fn set(&mut self, index: usize, cue: Cue) -> Result<()> {
+        if index > self.num_cues || index >= MAX_CUES {
+            return Err(Error::Generic("No such cue"));
+        }
+        self.cues[index] = cue;
+        if index == self.num_cues {
+            self.num_cues += 1;
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, index: usize) -> Result<()> {
+        if index >= self.num_cues {
+            return Err(Error::Generic("No such cue"));
+        }
+        self.cues.copy_within(index + 1..self.num_cues, index);
+        self.num_cues -= 1;
+        Ok(())
+    }
+
+    pub fn write(&self, buf: &mut MutBuffer<LittleEndian>) -> Result<()> {
+        let mut flags = 0;
+        if self.looping {
+            flags |= FLAG_LOOP;
+        }
+        if self.autostart {
+            flags |= FLAG_AUTOSTART;
+        }
+        buf.write_u8(flags)?;
+        buf.write_u8(self.num_cues as u8)?;
+        for cue in self.cues() {
+            cue.write(buf)?;
+        }
+        Ok(())
+    }
+
+    pub fn parse(buf: &mut OldBuffer<LittleEndian>) -> Result<Self> {
+        let mut playlist = Self::empty();
+        let flags = buf.read_u8()?;
+        playlist.looping = flags & FLAG_LOOP != 0;
+        playlist.autostart = flags & FLAG_AUTOSTART != 0;
+        let num_cues = buf.read_u8()? as usize;
+        for i in 0..num_cues {
+            playlist.set(i, Cue::parse(buf)?)?;
+        }
+        Ok(playlist)
+    }
+
+    fn slot(index: u8) -> Result<Slot> {
+        if index as usize >= MAX_PLAYLISTS {
+            return Err(Error::Generic("No such playlist"));
+        }
+        Ok(Slot::Playlist(index))
+    }
+
+    pub fn load(index: u8, storage: &mut Storage) -> Result<Option<Self>> {
+        let mut data = [0u8; PLAYLIST_MAX_LEN];
+        let Some((version, data)) = storage.load(Self::slot(index)?, &mut data)? else {
+            return Ok(None);
+        };
+        if version != PLAYLIST_VERSION {
+            return Ok(None);
+        }
+        Self::parse(&mut OldBuffer::new(data)).map(Some)
+    }
+
+    pub fn save(&self, index: u8, storage: &mut Storage) -> Result<()> {
+        let mut data = [0u8; PLAYLIST_MAX_LEN];
+        let buf = &mut MutBuffer::<LittleEndian>::new(&mut data);
+        self.write(buf)?;
+        let len = buf.pos();
+        storage.store(Self::slot(index)?, PLAYLIST_VERSION, &data[..len])
+    }
+
+    pub fn delete(index: u8, storage: &mut Storage) -> Result<()> {
+        storage.erase(Self::slot(index)?)
+    }
+}
+
+struct Playing {
+    index: u8,
+    playlist: Playlist,
+    cue: usize,
+    /// When the current cue was recalled.  `None` until the first cue has
+    /// been recalled.
+    cue_start: Option<Instant>,
+}
+
+/// Steps through the cues of the running playlist.
+pub struct Player {
+    playing: Option<Playing>,
+}
+
+pub type SharedPlayer = Mutex<NoopRawMutex, Player>;
+
+impl Player {
+    pub const fn new() -> Self {
+        Self { playing: None }
+    }
+
+    pub fn play(&mut self, index: u8, playlist: Playlist) {
+        self.playing = Some(Playing {
+            index,
+            playlist,
+            cue: 0,
+            cue_start: None,
+        });
+    }
+
+    pub fn stop(&mut self) {
+        self.playing = None;
+    }
+
+    /// Pick up edits to playlist `index` if it is the one playing.
+    pub fn update(&mut self, index: u8, playlist: &Playlist) {
+        if let Some(playing) = self.playing.as_mut().filter(|p| p.index == index) {
+            playing.playlist = *playlist;
+            if playing.cue >= playing.playlist.cues().len() {
+                playing.cue = 0;
+                playing.cue_start = None;
+            }
+        }
+    }
+
+    /// Jump to the next cue, or the previous one if `forward` is false,
+    /// wrapping at either end.  The new cue is recalled on the next tick.
+    pub fn step(&mut self, forward: bool) {
+        let Some(playing) = self.playing.as_mut() else {
+            return;
+        };
+        let len = playing.playlist.cues().len().max(1);
+        playing.cue = if forward {
+            (playing.cue + 1) % len
+        } else {
+            (playing.cue + len - 1) % len
+        };
+        playing.cue_start = None;
+    }
+
+    /// The running playlist and its current cue.
+    pub fn status(&self) -> Option<(u8, usize)> {
+        self.playing.as_ref().map(|p| (p.index, p.cue))
+    }
+
+    /// Returns the cue to recall if one is due.
+    fn poll(&mut self, now: Instant) -> Option<Cue> {
+        let playing = self.playing.as_mut()?;
+        let cues = playing.playlist.cues();
+        let Some(cue) = cues.get(playing.cue) else {
+            self.playing = None;
+            return None;
+        };
+
+        let Some(cue_start) = playing.cue_start else {
+            playing.cue_start = Some(now);
+            return Some(*cue);
+        };
+        let next_start = cue_start + cue.duration();
+        if now < next_start {
+            return None;
+        }
+
+        let mut next = playing.cue + 1;
+        if next == cues.len() {
+            if !playing.playlist.looping {
+                // The last cue stays live once the playlist ends.
+                self.playing = None;
+                return None;
+            }
+            next = 0;
+        }
+        playing.cue = next;
+        // Step from the scheduled start rather than `now` so that timing
+        // doesn't drift with the tick.
+        playing.cue_start = Some(next_start);
+        Some(cues[next])
+    }
+
+    /// Returns the cue to recall if the cue at `position_ms` into the
+    /// playlist differs from the current one.  Past the end, looping
+    /// playlists wrap and others hold their last cue.
+    fn chase(&mut self, position_ms: u32, now: Instant) -> Option<Cue> {
+        let playing = self.playing.as_mut()?;
+        let cues = playing.playlist.cues();
+        let total: u64 = cues.iter().map(|cue| cue.duration().as_millis()).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut position = position_ms as u64;
+        if playing.playlist.looping {
+            position %= total;
+        }
+
+        let mut index = cues.len() - 1;
+        let mut start = 0;
+        for (i, cue) in cues.iter().enumerate() {
+            let end = start + cue.duration().as_millis();
+            if position < end {
+                index = i;
+                break;
+            }
+            start = end;
+        }
+
+        if playing.cue == index && playing.cue_start.is_some() {
+            return None;
+        }
+        playing.cue = index;
+        playing.cue_start = Some(now);
+        Some(cues[index])
+    }
+}
+
+/// Load playlist `index` and start playing it from the first cue.
+pub async fn play(index: u8, player: &SharedPlayer, storage: &SharedStorage) -> Result<()> {
+    let playlist = Playlist::load(index, &mut *storage.lock().await)?
+        .ok_or(Error::Generic("Empty playlist"))?;
+    player.lock().await.play(index, playlist);
+    Ok(())
+}
+
+/// Steps through the running playlist.
+///
+/// When chasing timecode, the playlist starts as soon as timecode locks and
+/// its cues follow the timecode clock, holding the current cue if it drops
+/// out.
+#[embassy_executor::task]
+pub(crate) async fn task(
+    player: &'static SharedPlayer,
+    output: &'static SharedOutput,
+    storage: &'static SharedStorage,
+    clock: &'static SharedClock,
+) {
+    let mut locked = false;
+    loop {
+        Timer::after(TICK).await;
+        let now = Instant::now();
+        let (chase, position) = {
+            let clock = clock.lock().await;
+            (clock.config.chase, clock.position(now))
+        };
+
+        let chasing = match chase {
+            Chase::Playlist(index) => {
+                if position.is_some() && !locked {
+                    let running = player.lock().await.status().map(|(i, _)| i);
+                    if running != Some(index) {
+                        if let Err(e) = play(index, player, storage).await {
+                            println!("playlist: failed to chase playlist {index}: {e:?}");
+                        }
+                    }
+                }
+                true
+            }
+            _ => false,
+        };
+        locked = position.is_some();
+
+        let due = match (chasing, position) {
+            (false, _) => player.lock().await.poll(now),
+            (true, Some(position)) => player.lock().await.chase(position, now),
+            (true, None) => None,
+        };
+        if let Some(cue) = due {
+            if let Err(e) = preset::recall(cue.preset, cue.fade_ms, output, storage).await {
+                println!("playlist: failed to recall preset {}: {e:?}", cue.preset);
+            }
+        }
+    }
+}
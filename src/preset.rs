@@ -0,0 +1,152 @@
+use byteorder::LittleEndian;
+use embassy_time::Instant;
+
+use crate::buffer::{MutBuffer, OldBuffer};
+use crate::output::{Output, Scene, SharedOutput};
+use crate::storage::{SharedStorage, Slot, Storage, MAX_PRESETS, MAX_RECORD_LEN};
+use crate::{Error, Result};
+
+/// Bumped whenever the record layout below changes.  Records written by
+/// other versions are ignored rather than misparsed.
+const PRESET_VERSION: u16 = 1;
+pub const NAME_LEN: usize = 16;
+
+const FLAG_CAPTURE: u8 = 0x1;
+
+/// A preset record is:
+///   name_len: u8, name: [u8; name_len], flags: u8, scene,
+///   and when FLAG_CAPTURE is set:
+///   num_universes: u8, { port_address: u16, len: u16, data: [u8; len] }*
+pub struct Preset<'a> {
+    pub name: &'a str,
+    pub scene: Scene,
+    /// Raw universe data captured with the preset.
+    capture: Option<&'a [u8]>,
+}
+
+impl<'a> Preset<'a> {
+    pub fn has_capture(&self) -> bool {
+        self.capture.is_some()
+    }
+
+    /// Captured universes as (port address, data).
+    fn universes(&self) -> impl Iterator<Item = Result<(u16, &'a [u8])>> {
+        let mut buf = OldBuffer::<LittleEndian>::new(self.capture.unwrap_or(&[]));
+        let count = buf.read_u8().unwrap_or(0);
+        (0..count).map(move |_| {
+            let port_address = buf.read_u16()?;
+            let len = buf.read_u16()?;
+            Ok((port_address, buf.take(len as usize)?))
+        })
+    }
+
+    fn parse(data: &'a [u8]) -> Result<Self> {
+        let buf = &mut OldBuffer::<LittleEndian>::new(data);
+        let name_len = buf.read_u8()? as usize;
+        let name = core::str::from_utf8(buf.take(name_len)?)
+            .map_err(|_| Error::Generic("bad preset name"))?;
+        let flags = buf.read_u8()?;
+        let scene = Scene::parse(buf)?;
+        let capture = if flags & FLAG_CAPTURE != 0 {
+            Some(&data[buf.pos()..])
+        } else {
+            None
+        };
+        Ok(Self {
+            name,
+            scene,
+            capture,
+        })
+    }
+
+    /// Encode the current state of `output` as a preset record.
+    fn encode(name: &str, output: &Output, capture: bool, data: &mut [u8]) -> Result<usize> {
+        let mut name_len = name.len().min(NAME_LEN);
+        while !name.is_char_boundary(name_len) {
+            name_len -= 1;
+        }
+        let name = &name.as_bytes()[..name_len];
+        let buf = &mut MutBuffer::<LittleEndian>::new(data);
+        buf.write_u8(name.len() as u8)?;
+        buf.write(name)?;
+        buf.write_u8(if capture { FLAG_CAPTURE } else { 0 })?;
+        output.scene.write(buf)?;
+        if capture {
+            buf.write_u8(output.universes.iter().count() as u8)?;
+            for (port_address, universe) in output.universes.iter() {
+                buf.write_u16(port_address)?;
+                buf.write_u16(universe.len() as u16)?;
+                buf.write(universe)?;
+            }
+        }
+        Ok(buf.pos())
+    }
+}
+
+fn slot(index: u8) -> Result<Slot> {
+    if index as usize >= MAX_PRESETS {
+        return Err(Error::Generic("No such preset"));
+    }
+    Ok(Slot::Preset(index))
+}
+
+/// Read preset `index` from `storage` into `data`.
+pub fn load<'a>(
+    index: u8,
+    storage: &mut Storage,
+    data: &'a mut [u8],
+) -> Result<Option<Preset<'a>>> {
+    let Some((version, data)) = storage.load(slot(index)?, data)? else {
+        return Ok(None);
+    };
+    if version != PRESET_VERSION {
+        return Ok(None);
+    }
+    Preset::parse(data).map(Some)
+}
+
+/// Save the current output state as preset `index`.  With `capture` the
+/// most recent Art-Net data is stored too so the preset looks the same
+/// without a controller.
+pub async fn save(
+    index: u8,
+    name: &str,
+    capture: bool,
+    output: &SharedOutput,
+    storage: &SharedStorage,
+) -> Result<()> {
+    let slot = slot(index)?;
+    let mut data = [0u8; MAX_RECORD_LEN];
+    let len = Preset::encode(name, &*output.lock().await, capture, &mut data)?;
+    storage
+        .lock()
+        .await
+        .store(slot, PRESET_VERSION, &data[..len])
+}
+
+pub fn delete(index: u8, storage: &mut Storage) -> Result<()> {
+    storage.erase(slot(index)?)
+}
+
+/// Make preset `index` the live state, crossfading to it over `fade_ms`.
+/// Recalling does not change the scene loaded at boot.
+pub async fn recall(
+    index: u8,
+    fade_ms: u32,
+    output: &SharedOutput,
+    storage: &SharedStorage,
+) -> Result<()> {
+    let mut data = [0u8; MAX_RECORD_LEN];
+    let Some(preset) = load(index, &mut *storage.lock().await, &mut data)? else {
+        return Err(Error::Generic("Empty preset"));
+    };
+
+    let mut output = output.lock().await;
+    output.set_scene(preset.scene, Instant::now().as_millis() as u32, fade_ms);
+    output.preset = Some(index);
+    for universe in preset.universes() {
+        let (port_address, data) = universe?;
+        output.universes.update(port_address, data);
+    }
+    Ok(())
+}
@@ -0,0 +1,308 @@
+//! Wi-Fi setup for a node that has no network to join, or can't reach the
+//! one it has.  It then comes up as an open access point instead, where
+//! [`crate::dhcp`] and [`crate::dns`] lead every client to a setup page
+//! that lists the networks in range and saves the one picked to
+//! [`crate::settings`].
+//!
+//! The Wi-Fi driver is either a station or an access point for as long as
+//! the node runs, so switching between the two takes a restart.  A station
+//! that gives up leaves a request in flash that the next boot clears as it
+//! starts the portal, so the boot after that tries the network again.
+
+use core::cmp::Reverse;
+
+use embassy_net::Ipv4Address;
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_svc::wifi::Wifi;
+use embedded_svc::wifi::{AccessPointConfiguration, AccessPointInfo, AuthMethod, Configuration};
+use esp_println::println;
+use esp_wifi::wifi::{WifiController, WifiError};
+
+use crate::http::{Connection, Request, Status, JSON};
+use crate::json::Json;
+use crate::ota;
+use crate::settings::{Key, Value, MAX_VALUE_LEN};
+use crate::storage::{SharedStorage, Slot, Storage};
+use crate::web::{query_param, url_decode, Context, FmtBuffer};
+use crate::{Error, Result};
+
+/// Name of the open network the portal runs.
+pub const AP_SSID: &str = "rgb-setup";
+/// The node's address while the portal runs, on a /24 of its own.
+pub const AP_ADDRESS: Ipv4Address = Ipv4Address::new(192, 168, 4, 1);
+pub const AP_PREFIX_LEN: u8 = 24;
+/// [`AP_ADDRESS`] as it appears in a `Host` header.
+const AP_HOST: &str = "192.168.4.1";
+pub const SETUP_URL: &str = "http://192.168.4.1/setup.html";
+/// Clients probe for captive portals on the standard port, so the portal
+/// serves the web UI there instead of on the configured one.
+pub const PORTAL_PORT: u16 = 80;
+
+/// How long a station goes without a connection before it starts the
+/// portal.
+pub const PORTAL_AFTER: Duration = Duration::from_secs(5 * 60);
+/// How long the portal goes without a visit before a node that has a
+/// network restarts to try it again.
+const PORTAL_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+const TICK: Duration = Duration::from_millis(1000);
+
+const REQUEST_VERSION: u16 = 1;
+/// Networks kept from a scan, the strongest first.
+pub const MAX_NETWORKS: usize = 16;
+const MAX_FORM_LEN: usize = 2 * (16 + 3 * MAX_VALUE_LEN);
+
+/// Whether this boot runs the portal: there is no network to join, or the
+/// last boot asked for it.
+pub fn wanted(storage: &mut Storage, has_network: bool) -> bool {
+    let requested = match storage.load(Slot::Portal, &mut []) {
+        Ok(Some(_)) => {
+            if let Err(e) = storage.erase(Slot::Portal) {
+                println!("portal: failed to clear the request: {e:?}");
+            }
+            true
+        }
+        Ok(None) => false,
+        Err(e) => {
+            println!("portal: failed to read the request: {e:?}");
+            false
+        }
+    };
+    requested || !has_network
+}
+
+/// Restart into the portal.
+pub async fn start(storage: &SharedStorage) -> ! {
+    if let Err(e) = storage
+        .lock()
+        .await
+        .store(Slot::Portal, REQUEST_VERSION, &[])
+    {
+        println!("portal: failed to store the request: {e:?}");
+    }
+    ota::restart()
+}
+
+/// Whether a request to the portal goes to the setup page instead: the
+/// front page, and anything for another host, which is how clients look
+/// for a captive portal.
+pub fn redirect(req: &Request<'_>) -> bool {
+    let host = req.header("Host").unwrap_or(AP_HOST);
+    let host = host.split(':').next().unwrap_or(host);
+    host != AP_HOST || req.path == "/"
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Network {
+    ssid: [u8; 32],
+    ssid_len: u8,
+    pub rssi: i8,
+    pub secure: bool,
+}
+
+impl Network {
+    fn new(ssid: &str, rssi: i8, secure: bool) -> Option<Self> {
+        let mut network = Self {
+            ssid: [0; 32],
+            ssid_len: ssid.len() as u8,
+            rssi,
+            secure,
+        };
+        network
+            .ssid
+            .get_mut(..ssid.len())?
+            .copy_from_slice(ssid.as_bytes());
+        Some(network)
+    }
+
+    pub fn ssid(&self) -> &str {
+        core::str::from_utf8(&self.ssid[..self.ssid_len as usize]).unwrap_or("")
+    }
+}
+
+pub struct Portal {
+    networks: [Option<Network>; MAX_NETWORKS],
+    /// When the portal was last asked for anything.
+    last_visit: Instant,
+    /// Set by the setup page to look for networks again.
+    rescan: bool,
+}
+
+pub type SharedPortal = Mutex<NoopRawMutex, Portal>;
+
+impl Portal {
+    pub fn new() -> Self {
+        Self {
+            networks: [None; MAX_NETWORKS],
+            last_visit: Instant::now(),
+            rescan: false,
+        }
+    }
+
+    pub fn visit(&mut self, now: Instant) {
+        self.last_visit = now;
+    }
+
+    pub fn networks(&self) -> impl Iterator<Item = &Network> {
+        self.networks.iter().flatten()
+    }
+
+    /// Keep the results of a scan.  A network seen through several access
+    /// points is listed once, with the strongest signal.
+    pub fn found(&mut self, found: &[AccessPointInfo]) {
+        self.networks = [None; MAX_NETWORKS];
+        let mut count = 0;
+        for info in found {
+            // Hidden networks have no name to pick them by.
+            if info.ssid.is_empty() {
+                continue;
+            }
+            let secure = info.auth_method != AuthMethod::None;
+            let Some(network) = Network::new(info.ssid.as_str(), info.signal_strength, secure)
+            else {
+                continue;
+            };
+            let known = self.networks[..count]
+                .iter_mut()
+                .flatten()
+                .find(|known| known.ssid() == network.ssid());
+            match known {
+                Some(known) => known.rssi = known.rssi.max(network.rssi),
+                None if count < MAX_NETWORKS => {
+                    self.networks[count] = Some(network);
+                    count += 1;
+                }
+                None => (),
+            }
+        }
+        self.networks[..count].sort_unstable_by_key(|network| Reverse(network.map(|n| n.rssi)));
+    }
+}
+
+/// Scan from the station side while the access point stays up.
+async fn scan(
+    controller: &mut WifiController<'static>,
+    portal: &SharedPortal,
+) -> core::result::Result<(), WifiError> {
+    let (found, _) = controller.scan_n::<MAX_NETWORKS>()?;
+    portal.lock().await.found(&found);
+    Ok(())
+}
+
+/// Start the access point alongside an unconfigured station, which is only
+/// there to scan with.
+async fn start_access_point(
+    controller: &mut WifiController<'static>,
+) -> core::result::Result<(), WifiError> {
+    let config = Configuration::Mixed(
+        Default::default(),
+        AccessPointConfiguration {
+            ssid: AP_SSID.into(),
+            auth_method: AuthMethod::None,
+            ..Default::default()
+        },
+    );
+    controller.set_configuration(&config)?;
+    controller.start().await
+}
+
+/// Runs the access point of the portal, and restarts once the portal goes
+/// unused if there is a network to try again.
+#[embassy_executor::task]
+pub(crate) async fn task(
+    mut controller: WifiController<'static>,
+    has_network: bool,
+    portal: &'static SharedPortal,
+) {
+    while let Err(e) = start_access_point(&mut controller).await {
+        println!("portal: failed to start the access point: {e:?}");
+        Timer::after(TICK).await;
+    }
+    println!("portal: join {AP_SSID} and open {SETUP_URL} to set up Wi-Fi");
+    portal.lock().await.visit(Instant::now());
+
+    loop {
+        if let Err(e) = scan(&mut controller, portal).await {
+            println!("portal: scan failed: {e:?}");
+        }
+        portal.lock().await.rescan = false;
+
+        loop {
+            Timer::after(TICK).await;
+            let portal = portal.lock().await;
+            if portal.rescan {
+                break;
+            }
+            if has_network && portal.last_visit.elapsed() > PORTAL_TIMEOUT {
+                println!("portal: unused, trying the network again");
+                ota::restart();
+            }
+        }
+    }
+}
+
+fn form_value(form: &str, key: Key) -> Result<Value> {
+    let mut buf = [0u8; MAX_VALUE_LEN];
+    let text = url_decode(query_param(form, key.name()).unwrap_or(""), &mut buf)
+        .map_err(|_| Error::Generic(key.name()))?;
+    let text = core::str::from_utf8(text).map_err(|_| Error::Generic(key.name()))?;
+    key.parse(text)
+}
+
+/// Save the network in the form and restart to join it.
+async fn connect(conn: &mut Connection<'_, '_>, ctx: &Context) -> Result<()> {
+    let mut body = [0u8; MAX_FORM_LEN];
+    let form = core::str::from_utf8(conn.read_body(&mut body).await?)
+        .map_err(|_| Error::Generic("Form isn't UTF-8"))?;
+    let ssid = form_value(form, Key::Ssid)?;
+    if ssid.is_empty() {
+        return Err(Error::Generic(Key::Ssid.name()));
+    }
+    let password = form_value(form, Key::Password)?;
+
+    let mut settings = ctx.settings.lock().await;
+    let mut storage = ctx.storage.lock().await;
+    settings.set(&mut storage, Key::Ssid, ssid)?;
+    settings.set(&mut storage, Key::Password, password)?;
+    println!("portal: joining {}", settings.ssid());
+    drop((settings, storage));
+    ctx.ota.restart_soon().await;
+    Ok(())
+}
+
+/// `GET /setup` lists the networks in range, `POST /setup/scan` looks for
+/// them again and `POST /setup/connect` saves the `ssid` and `password` in
+/// its form encoded body and restarts to join that network.  There is
+/// nothing here unless the portal runs.
+pub async fn handle(conn: &mut Connection<'_, '_>, ctx: &Context, req: &Request<'_>) -> Result<()> {
+    let Some(portal) = ctx.portal else {
+        return Err(Error::Http(Status::NotFound));
+    };
+    match req.param("command") {
+        None => (),
+        Some("scan") => portal.lock().await.rescan = true,
+        Some("connect") => connect(conn, ctx).await?,
+        _ => return Err(Error::Generic("Unknown setup command")),
+    }
+
+    let mut buffer = [0u8; 1536];
+    let mut json = Json::new(FmtBuffer::new(&mut buffer));
+    json.begin_object()?;
+    json.field("ssid", ctx.settings.lock().await.ssid())?;
+    let portal = portal.lock().await;
+    json.key("networks")?;
+    json.begin_array()?;
+    for network in portal.networks() {
+        json.begin_object()?;
+        json.field("ssid", network.ssid())?;
+        json.field("rssi", network.rssi)?;
+        json.field("secure", network.secure)?;
+        json.end_object()?;
+    }
+    json.end_array()?;
+    json.field("scanning", portal.rescan)?;
+    json.end_object()?;
+    drop(portal);
+    conn.send(Status::Ok, JSON, json.into_inner().as_bytes())
+        .await
+}
@@ -0,0 +1,145 @@
+use core::fmt::Write;
+
+use crate::json::Json;
+use crate::{Error, Result};
+
+/// A field of a register.  Fields are listed from the least significant
+/// bit up, as `bitfield_registers!` takes them from the `#[bitfield]`
+/// struct, and reserved ones start with `_`.
+#[derive(Clone, Copy, Debug)]
+pub struct Field {
+    pub name: &'static str,
+    pub bits: u8,
+}
+
+impl Field {
+    pub const fn new(name: &'static str, bits: u8) -> Self {
+        Self { name, bits }
+    }
+
+    pub fn is_reserved(&self) -> bool {
+        self.name.starts_with('_')
+    }
+}
+
+/// Width of a field whose type sets it, without `#[bits(n)]`.
+pub trait Width {
+    const BITS: u8;
+}
+
+impl Width for bool {
+    const BITS: u8 = 1;
+}
+
+impl Width for u8 {
+    const BITS: u8 = 8;
+}
+
+impl Width for u16 {
+    const BITS: u8 = 16;
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Register {
+    pub name: &'static str,
+    pub addr: u8,
+    /// 1 or 2 bytes.  Wider registers are little endian.
+    pub len: u8,
+    /// Reading clears the register, so it is left out of full dumps.
+    pub read_clears: bool,
+    /// Empty for registers we only know the name of.
+    pub fields: &'static [Field],
+}
+
+impl Register {
+    pub const fn new(name: &'static str, addr: u8, len: u8, fields: &'static [Field]) -> Self {
+        Self {
+            name,
+            addr,
+            len,
+            read_clears: false,
+            fields,
+        }
+    }
+
+    pub const fn read_clears(self) -> Self {
+        Self {
+            read_clears: true,
+            ..self
+        }
+    }
+
+    pub fn from_bytes(&self, data: &[u8]) -> u16 {
+        match self.len {
+            2 => u16::from_le_bytes([data[0], data[1]]),
+            _ => data[0] as u16,
+        }
+    }
+
+    pub fn to_bytes(&self, raw: u16, buf: &mut [u8; 2]) -> usize {
+        *buf = raw.to_le_bytes();
+        self.len as usize
+    }
+
+    /// Each field with its shift.
+    fn layout(&self) -> impl Iterator<Item = (&'static Field, u8)> {
+        self.fields.iter().scan(0, |shift, field| {
+            let this = *shift;
+            *shift += field.bits;
+            Some((field, this))
+        })
+    }
+
+    /// Replace the field called `name` in `raw`.  Values that don't fit
+    /// are refused rather than truncated.
+    pub fn set_field(&self, raw: u16, name: &str, value: u16) -> Result<u16> {
+        let (field, shift) = self
+            .layout()
+            .find(|(field, _)| field.name == name && !field.is_reserved())
+            .ok_or(Error::Generic("Unknown field"))?;
+        let mask = ((1u32 << field.bits) - 1) as u16;
+        if value > mask {
+            return Err(Error::Generic("Value doesn't fit the field"));
+        }
+        Ok(raw & !(mask << shift) | value << shift)
+    }
+
+    /// `{"name":..,"address":..,"raw":..,"fields":{..}}`.  One bit fields
+    /// are booleans.
+    pub fn decode<W: Write>(&self, json: &mut Json<W>, raw: u16) -> Result<()> {
+        json.begin_object()?;
+        json.field("name", self.name)?;
+        json.field("address", self.addr)?;
+        json.field("raw", raw)?;
+        json.key("fields")?;
+        json.begin_object()?;
+        for (field, shift) in self.layout().filter(|(field, _)| !field.is_reserved()) {
+            let value = raw >> shift & ((1u32 << field.bits) - 1) as u16;
+            match field.bits {
+                1 => json.field(field.name, value != 0)?,
+                _ => json.field(field.name, value)?,
+            }
+        }
+        json.end_object()?;
+        json.end_object()
+    }
+}
+
+#[derive(Debug)]
+pub struct Chip {
+    pub name: &'static str,
+    pub addr: u8,
+    pub registers: &'static [Register],
+}
+
+impl Chip {
+    pub fn register(&self, name: &str) -> Option<&'static Register> {
+        self.registers.iter().find(|reg| reg.name == name)
+    }
+}
+
+pub use crate::pd::CHIPS;
+
+pub fn chip(name: &str) -> Option<&'static Chip> {
+    CHIPS.iter().copied().find(|chip| chip.name == name)
+}
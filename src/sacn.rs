@@ -0,0 +1,243 @@
+//! sACN (E1.31) input.  Data packets feed the same universes as Art-Net,
+//! with sACN universe `n` standing in for Art-Net port address `n - 1` as
+//! consoles number them.  Whichever protocol sent a universe last wins, and
+//! the priority field isn't looked at.
+//!
+//! Unicast works for any universe.  For multicast we join the group of
+//! each universe a segment reads, as far as the network stack has room
+//! for groups.
+
+use byteorder::BigEndian;
+use embassy_futures::select::{select, Either};
+use embassy_net::{udp::UdpSocket, Ipv4Address, PacketMetadata, Stack};
+use embassy_time::{Duration, Instant, Timer};
+use esp_println::println;
+use esp_wifi::wifi::WifiDevice;
+
+use crate::buffer::OldBuffer;
+use crate::output::{Output, SharedOutput, Source, MAX_SEGMENTS, UNIVERSE_SIZE};
+use crate::settings::SharedSettings;
+use crate::{Error, Result};
+
+pub const PORT: u16 = 5568;
+
+const ACN_PACKET_ID: &[u8; 12] = b"ASC-E1.17\0\0\0";
+const PREAMBLE_SIZE: u16 = 0x0010;
+const VECTOR_ROOT_DATA: u32 = 0x0000_0004;
+const VECTOR_FRAMING_DATA: u32 = 0x0000_0002;
+const VECTOR_DMP_SET_PROPERTY: u8 = 0x02;
+const DMP_ADDRESS_TYPE: u8 = 0xa1;
+
+/// The source is only previewing this data; it isn't for output.
+const OPTION_PREVIEW: u8 = 0x80;
+/// The source is going away and this packet carries no data to use.
+const OPTION_TERMINATED: u8 = 0x40;
+
+const MAX_UNIVERSE: u16 = 63999;
+/// Header up to the start code, the start code and a full universe.
+const MAX_PACKET_LEN: usize = 126 + UNIVERSE_SIZE;
+
+/// How often the groups joined are brought in line with the scene and the
+/// settings.
+const TICK: Duration = Duration::from_millis(1000);
+
+/// The parts of an E1.31 data packet we use.
+#[derive(Debug)]
+pub struct Data<'a> {
+    pub universe: u16,
+    pub priority: u8,
+    pub sequence: u8,
+    pub options: u8,
+    /// The DMX slots after the start code.
+    pub data: &'a [u8],
+}
+
+impl<'a> Data<'a> {
+    pub fn parse(packet: &'a [u8]) -> Result<Self> {
+        let buf = &mut OldBuffer::<BigEndian>::new(packet);
+        if buf.read_u16()? != PREAMBLE_SIZE {
+            return Err(Error::Generic("not an sACN packet"));
+        }
+        let _postamble_size = buf.read_u16()?;
+        if &buf.read::<12>()? != ACN_PACKET_ID {
+            return Err(Error::Generic("not an sACN packet"));
+        }
+        let _flags_length = buf.read_u16()?;
+        if buf.read_u32()? != VECTOR_ROOT_DATA {
+            return Err(Error::Generic("not an sACN data packet"));
+        }
+        let _cid: [u8; 16] = buf.read()?;
+
+        let _flags_length = buf.read_u16()?;
+        if buf.read_u32()? != VECTOR_FRAMING_DATA {
+            return Err(Error::Generic("not an sACN data packet"));
+        }
+        let _source_name: [u8; 64] = buf.read()?;
+        let priority = buf.read_u8()?;
+        let _sync_address = buf.read_u16()?;
+        let sequence = buf.read_u8()?;
+        let options = buf.read_u8()?;
+        let universe = buf.read_u16()?;
+
+        let _flags_length = buf.read_u16()?;
+        if buf.read_u8()? != VECTOR_DMP_SET_PROPERTY || buf.read_u8()? != DMP_ADDRESS_TYPE {
+            return Err(Error::Generic("bad sACN DMP layer"));
+        }
+        let _first_address = buf.read_u16()?;
+        let _increment = buf.read_u16()?;
+        let count = buf.read_u16()? as usize;
+        if count == 0 || buf.read_u8()? != 0 {
+            return Err(Error::Generic("sACN packet isn't DMX"));
+        }
+        let data = buf.take((count - 1).min(buf.remaining()))?;
+        Ok(Self {
+            universe,
+            priority,
+            sequence,
+            options,
+            data,
+        })
+    }
+}
+
+/// The Art-Net port address sACN `universe` feeds.
+pub fn port_address(universe: u16) -> Option<u16> {
+    universe
+        .checked_sub(1)
+        .filter(|_| universe <= MAX_UNIVERSE)
+        .filter(|port_address| *port_address < 0x8000)
+}
+
+/// The sACN universe that feeds Art-Net `port_address`.
+pub fn universe(port_address: u16) -> Option<u16> {
+    port_address
+        .checked_add(1)
+        .filter(|universe| *universe <= MAX_UNIVERSE)
+}
+
+/// The multicast group `universe` is sent to.
+pub fn group(universe: u16) -> Ipv4Address {
+    let [hi, lo] = universe.to_be_bytes();
+    Ipv4Address([239, 255, hi, lo])
+}
+
+/// The sACN universes the segments of the scene read.
+fn wanted(output: &Output) -> [Option<u16>; MAX_SEGMENTS] {
+    let mut wanted = [None; MAX_SEGMENTS];
+    let universes = output
+        .scene
+        .segments()
+        .iter()
+        .filter_map(|segment| match segment.source {
+            Source::Universe { port_address, .. } => universe(port_address),
+            _ => None,
+        });
+    for universe in universes {
+        if !wanted.contains(&Some(universe)) {
+            if let Some(slot) = wanted.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(universe);
+            }
+        }
+    }
+    wanted
+}
+
+/// Leave the groups of the universes in `joined` that aren't `wanted` and
+/// join the rest.
+fn update_groups(
+    stack: &Stack<WifiDevice<'static>>,
+    joined: &mut [Option<u16>; MAX_SEGMENTS],
+    wanted: &[Option<u16>; MAX_SEGMENTS],
+) {
+    for slot in joined.iter_mut() {
+        let Some(universe) = *slot else {
+            continue;
+        };
+        if !wanted.contains(&Some(universe)) {
+            if let Err(e) = stack.leave_multicast_group(group(universe)) {
+                println!("sacn: failed to leave universe {universe}: {e:?}");
+            }
+            *slot = None;
+        }
+    }
+    for universe in wanted.iter().flatten() {
+        if joined.contains(&Some(*universe)) {
+            continue;
+        }
+        let Some(slot) = joined.iter_mut().find(|slot| slot.is_none()) else {
+            break;
+        };
+        match stack.join_multicast_group(group(*universe)) {
+            Ok(_) => *slot = Some(*universe),
+            Err(e) => println!("sacn: failed to join universe {universe}: {e:?}"),
+        }
+    }
+}
+
+/// Receives sACN data into the universes while the `sacn` setting is on.
+#[embassy_executor::task]
+pub(crate) async fn task(
+    stack: &'static Stack<WifiDevice<'static>>,
+    output: &'static SharedOutput,
+    settings: &'static SharedSettings,
+) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 8];
+    let mut rx_buffer = [0; 4096];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; 16];
+    let mut buf = [0; MAX_PACKET_LEN];
+
+    while stack.config().is_none() {
+        Timer::after(Duration::from_millis(500)).await;
+    }
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(PORT).unwrap();
+
+    let mut enabled = false;
+    let mut joined = [None; MAX_SEGMENTS];
+    let mut next_tick = Instant::now();
+    loop {
+        match select(socket.recv_from(&mut buf), Timer::at(next_tick)).await {
+            Either::First(Ok((length, _))) => {
+                if !enabled {
+                    continue;
+                }
+                // Discovery and sync packets aren't data and fail to parse.
+                let Ok(packet) = Data::parse(&buf[..length]) else {
+                    continue;
+                };
+                if packet.options & (OPTION_PREVIEW | OPTION_TERMINATED) != 0 {
+                    continue;
+                }
+                if let Some(port_address) = port_address(packet.universe) {
+                    output
+                        .lock()
+                        .await
+                        .universes
+                        .update(port_address, packet.data);
+                }
+                continue;
+            }
+            Either::First(Err(e)) => {
+                println!("sacn: receive failed: {e:?}");
+                continue;
+            }
+            Either::Second(()) => next_tick = (next_tick + TICK).max(Instant::now()),
+        }
+
+        enabled = settings.lock().await.sacn();
+        let wanted = if enabled {
+            wanted(&*output.lock().await)
+        } else {
+            [None; MAX_SEGMENTS]
+        };
+        update_groups(stack, &mut joined, &wanted);
+    }
+}
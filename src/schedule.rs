@@ -0,0 +1,174 @@
+use byteorder::LittleEndian;
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant, Timer};
+use esp_println::println;
+
+use crate::buffer::{MutBuffer, OldBuffer};
+use crate::output::SharedOutput;
+use crate::preset;
+use crate::storage::{SharedStorage, Slot, Storage};
+use crate::{Error, Result};
+
+const SCHEDULE_VERSION: u16 = 1;
+pub const MAX_ENTRIES: usize = 8;
+const SCHEDULE_MAX_LEN: usize = 3 + MAX_ENTRIES * 5;
+
+pub const MINUTES_PER_DAY: u16 = 24 * 60;
+pub const ALL_DAYS: u8 = 0x7f;
+
+/// Recall `preset` at `minute` past local midnight on `days`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Entry {
+    pub preset: u8,
+    pub minute: u16,
+    /// Bit 0 is Sunday, bit 6 Saturday.
+    pub days: u8,
+}
+
+impl Entry {
+    fn write(&self, buf: &mut MutBuffer<LittleEndian>) -> Result<()> {
+        buf.write_u8(self.preset)?;
+        buf.write_u16(self.minute)?;
+        buf.write_u8(self.days)?;
+        Ok(())
+    }
+
+    fn parse(buf: &mut OldBuffer<LittleEndian>) -> Result<Self> {
+        Ok(Self {
+            preset: buf.read_u8()?,
+            minute: buf.read_u16()?,
+            days: buf.read_u8()?,
+        })
+    }
+}
+
+/// Time of day based preset recall.
+///
+/// The device has no battery backed clock so the schedule does nothing until
+/// the wall clock has been set, normally by the web UI.
+pub struct Schedule {
+    entries: [Option<Entry>; MAX_ENTRIES],
+    /// Local time offset from UTC in minutes.
+    pub utc_offset: i16,
+    /// Unix time and the instant it was set at.
+    clock: Option<(u64, Instant)>,
+    last_minute: Option<u64>,
+}
+
+pub type SharedSchedule = Mutex<NoopRawMutex, Schedule>;
+
+impl Schedule {
+    pub const fn new() -> Self {
+        Self {
+            entries: [None; MAX_ENTRIES],
+            utc_offset: 0,
+            clock: None,
+            last_minute: None,
+        }
+    }
+
+    pub fn entries(&self) -> &[Option<Entry>] {
+        &self.entries
+    }
+
+    pub fn set(&mut self, index: usize, entry: Option<Entry>) -> Result<()> {
+        if entry.map_or(false, |e| e.minute >= MINUTES_PER_DAY) {
+            return Err(Error::Generic("Bad schedule time"));
+        }
+        *self
+            .entries
+            .get_mut(index)
+            .ok_or(Error::Generic("No such schedule entry"))? = entry;
+        Ok(())
+    }
+
+    pub fn set_time(&mut self, unix_secs: u64) {
+        self.clock = Some((unix_secs, Instant::now()));
+    }
+
+    /// Current local time in seconds since the Unix epoch.
+    pub fn local_time(&self) -> Option<u64> {
+        let (base, set_at) = self.clock?;
+        let now = base + (Instant::now() - set_at).as_secs();
+        Some(now.saturating_add_signed(self.utc_offset as i64 * 60))
+    }
+
+    /// Returns the preset to recall if an entry became due since the last
+    /// call.
+    fn poll(&mut self) -> Option<u8> {
+        let minute = self.local_time()? / 60;
+        if self.last_minute.replace(minute) == Some(minute) {
+            return None;
+        }
+
+        // 1970-01-01 was a Thursday.
+        let weekday = ((minute / MINUTES_PER_DAY as u64 + 4) % 7) as u8;
+        let minute_of_day = (minute % MINUTES_PER_DAY as u64) as u16;
+        self.entries
+            .iter()
+            .flatten()
+            .filter(|e| e.minute == minute_of_day && e.days & (1 << weekday) != 0)
+            .last()
+            .map(|e| e.preset)
+    }
+
+    pub fn write(&self, buf: &mut MutBuffer<LittleEndian>) -> Result<()> {
+        buf.write_u16(self.utc_offset as u16)?;
+        buf.write_u8(self.entries.iter().flatten().count() as u8)?;
+        for (index, entry) in self.entries.iter().enumerate() {
+            if let Some(entry) = entry {
+                buf.write_u8(index as u8)?;
+                entry.write(buf)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn parse(buf: &mut OldBuffer<LittleEndian>) -> Result<Self> {
+        let mut schedule = Self::new();
+        schedule.utc_offset = buf.read_u16()? as i16;
+        let count = buf.read_u8()? as usize;
+        for _ in 0..count {
+            let index = buf.read_u8()? as usize;
+            schedule.set(index, Some(Entry::parse(buf)?))?;
+        }
+        Ok(schedule)
+    }
+
+    pub fn load(storage: &mut Storage) -> Result<Option<Self>> {
+        let mut data = [0u8; SCHEDULE_MAX_LEN];
+        let Some((version, data)) = storage.load(Slot::Schedule, &mut data)? else {
+            return Ok(None);
+        };
+        if version != SCHEDULE_VERSION {
+            return Ok(None);
+        }
+        Self::parse(&mut OldBuffer::new(data)).map(Some)
+    }
+
+    pub fn save(&self, storage: &mut Storage) -> Result<()> {
+        let mut data = [0u8; SCHEDULE_MAX_LEN];
+        let buf = &mut MutBuffer::<LittleEndian>::new(&mut data);
+        self.write(buf)?;
+        let len = buf.pos();
+        storage.store(Slot::Schedule, SCHEDULE_VERSION, &data[..len])
+    }
+}
+
+#[embassy_executor::task]
+pub(crate) async fn task(
+    schedule: &'static SharedSchedule,
+    output: &'static SharedOutput,
+    storage: &'static SharedStorage,
+) {
+    loop {
+        Timer::after(Duration::from_secs(1)).await;
+        let due = schedule.lock().await.poll();
+        if let Some(index) = due {
+            println!("schedule: recalling preset {index}");
+            if let Err(e) = preset::recall(index, 0, output, storage).await {
+                println!("schedule: recall failed {e:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,815 @@
+//! A small pattern language for user defined effects.
+//!
+//! Scripts are the body of a per-pixel render function in the style of
+//! Pixelblaze's `render(index)`.  They are compiled on the device to a
+//! compact stack bytecode and run once for every pixel of a segment using
+//! [`Source::Script`](crate::output::Source::Script).
+//!
+//! ```text
+//! // Rainbow that drifts along the strip.
+//! h = x + time(0.1)
+//! v = wave(x * 3 - time(0.05))
+//! hsv(h, 1, v * v)
+//! ```
+//!
+//! Numbers are 16.16 fixed point, so values range from -32768 to 32767 with
+//! a resolution of 1/65536.  Colours and coordinates are in the range 0 to 1.
+//!
+//! Statements are `name = expr` (also `+=`, `-=`, `*=`, `/=`),
+//! `rgb(r, g, b)`, `hsv(h, s, v)` and `if cond { ... } else { ... }`.
+//! Expressions support `+ - * / %`, comparisons, `&& || !` and `c ? a : b`.
+//! Variables keep their values between pixels and frames.
+//!
+//! Built in values are `index`, `pixelCount`, `x` and `y`, plus the
+//! constants `PI` and `PI2`.  `x` and `y` are the pixel's position on the
+//! matrix, or `index / pixelCount` and 0 on a plain strip.
+//!
+//! There are no loops, so a pixel's cost is bounded by the length of the
+//! script.  On top of that every frame has a hard instruction budget; pixels
+//! left over once it runs out are drawn black so that a heavy script can't
+//! starve the LED and network tasks.
+
+use byteorder::{ByteOrder, LittleEndian};
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+
+use crate::color::{Hsv, Rgb};
+use crate::effects::{noise1, noise2};
+use crate::matrix::Layout;
+use crate::storage::{Slot, Storage};
+use crate::{Error, Result};
+
+const SCRIPT_VERSION: u16 = 1;
+pub const MAX_SOURCE_LEN: usize = 2048;
+pub const MAX_CODE_LEN: usize = 1024;
+const MAX_VARS: usize = 32;
+/// Deepest nesting of parentheses, operators, conditionals and `if`s the
+/// compiler recurses into.  Keeps it well inside the task's stack.
+const MAX_NESTING: usize = 16;
+const STACK_DEPTH: usize = 16;
+
+/// Instructions that may be executed per frame, shared by every segment
+/// showing the script.
+pub const FRAME_BUDGET: u32 = 16384;
+
+type Fixed = i32;
+const ONE: Fixed = 1 << 16;
+const PI: Fixed = 205_887;
+const PI2: Fixed = 411_775;
+/// Turns per radian.
+const INV_PI2: Fixed = 10_430;
+
+/// Read only values available to every script.  They occupy the first
+/// variable slots.
+const BUILTIN_VARS: [&str; 4] = ["index", "pixelCount", "x", "y"];
+const VAR_INDEX: usize = 0;
+const VAR_PIXEL_COUNT: usize = 1;
+const VAR_X: usize = 2;
+const VAR_Y: usize = 3;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, FromPrimitive)]
+#[repr(u8)]
+enum Op {
+    /// Followed by an i32 constant.
+    Const = 0,
+    /// Followed by a variable index.
+    Load = 1,
+    /// Followed by a variable index.
+    Store = 2,
+    Add = 3,
+    Sub = 4,
+    Mul = 5,
+    Div = 6,
+    Mod = 7,
+    Neg = 8,
+    Not = 9,
+    Lt = 10,
+    Le = 11,
+    Gt = 12,
+    Ge = 13,
+    Eq = 14,
+    Ne = 15,
+    And = 16,
+    Or = 17,
+    /// Followed by a u16 code offset.
+    Jump = 18,
+    /// Pops a value and jumps to the following u16 code offset if it is zero.
+    JumpIfZero = 19,
+    /// Followed by an index into [`FUNCTIONS`].
+    Call = 20,
+    Rgb = 21,
+    Hsv = 22,
+}
+
+/// Math functions scripts can call as (name, number of arguments).
+const FUNCTIONS: [(&str, usize); 16] = [
+    ("sin", 1),
+    ("cos", 1),
+    ("wave", 1),
+    ("triangle", 1),
+    ("square", 2),
+    ("abs", 1),
+    ("floor", 1),
+    ("frac", 1),
+    ("sqrt", 1),
+    ("min", 2),
+    ("max", 2),
+    ("clamp", 3),
+    ("mix", 3),
+    ("time", 1),
+    ("noise", 1),
+    ("noise2", 2),
+];
+
+fn mul(a: Fixed, b: Fixed) -> Fixed {
+    ((a as i64 * b as i64) >> 16) as Fixed
+}
+
+fn div(a: Fixed, b: Fixed) -> Fixed {
+    if b == 0 {
+        return 0;
+    }
+    ((a as i64) << 16).checked_div(b as i64).map_or(0, |q| {
+        q.clamp(Fixed::MIN as i64, Fixed::MAX as i64) as Fixed
+    })
+}
+
+fn truth(val: bool) -> Fixed {
+    if val {
+        ONE
+    } else {
+        0
+    }
+}
+
+/// Sine of `turns` full turns, using a parabolic approximation that is
+/// accurate to about 0.1%.
+fn sin_turns(turns: Fixed) -> Fixed {
+    // Wrap to [-0.5, 0.5).
+    let u = ((turns.wrapping_add(ONE / 2)) & (ONE - 1)) - ONE / 2;
+    let y = 8 * u - ((16 * u as i64 * u.abs() as i64) >> 16) as Fixed;
+    y + mul(14_746, mul(y, y.abs()) - y)
+}
+
+fn sqrt(val: Fixed) -> Fixed {
+    if val <= 0 {
+        return 0;
+    }
+    let val = (val as u64) << 16;
+    let mut root = 0u64;
+    let mut bit = 1u64 << 62;
+    let mut rem = val;
+    while bit > rem {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if rem >= root + bit {
+            rem -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root as Fixed
+}
+
+/// Scale a 0 to 1 value to 0 to 255, clamping out of range values.
+fn to_u8(val: Fixed) -> u8 {
+    (val.clamp(0, ONE) as u32 * 255 / ONE as u32) as u8
+}
+
+fn call(function: usize, args: &[Fixed], time_ms: u32) -> Fixed {
+    let frac = |v: Fixed| v & (ONE - 1);
+    match (function, args) {
+        (0, [a]) => sin_turns(mul(*a, INV_PI2)),
+        (1, [a]) => sin_turns(mul(*a, INV_PI2) + ONE / 4),
+        (2, [a]) => (sin_turns(*a) + ONE) / 2,
+        (3, [a]) => {
+            let t = frac(*a) * 2;
+            if t > ONE {
+                2 * ONE - t
+            } else {
+                t
+            }
+        }
+        (4, [a, duty]) => truth(frac(*a) < *duty),
+        (5, [a]) => a.wrapping_abs(),
+        (6, [a]) => a & !(ONE - 1),
+        (7, [a]) => frac(*a),
+        (8, [a]) => sqrt(*a),
+        (9, [a, b]) => *a.min(b),
+        (10, [a, b]) => *a.max(b),
+        (11, [a, lo, hi]) => (*a).max(*lo).min(*hi),
+        (12, [a, b, t]) => a.wrapping_add(mul(b.wrapping_sub(*a), *t)),
+        (13, [interval]) => {
+            // A sawtooth from 0 to 1 every `interval * 65.536` seconds, as
+            // in Pixelblaze.  In milliseconds that is the raw fixed point
+            // value of `interval`.
+            if *interval <= 0 {
+                return 0;
+            }
+            let period = *interval as u64;
+            (((time_ms as u64 % period) << 16) / period) as Fixed
+        }
+        (14, [a]) => (noise1((*a >> 8) as u32) as Fixed) << 8,
+        (15, [a, b]) => (noise2((*a >> 8) as u32, (*b >> 8) as u32) as Fixed) << 8,
+        _ => 0,
+    }
+}
+
+/// Why a script failed to compile.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CompileError {
+    pub line: u16,
+    pub msg: &'static str,
+}
+
+impl core::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+type CompileResult<T> = core::result::Result<T, CompileError>;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Token<'a> {
+    Num(Fixed),
+    Ident(&'a str),
+    /// Operators and punctuation.
+    Punct(&'static str),
+    Eof,
+}
+
+const PUNCTUATION: [&str; 27] = [
+    "+=", "-=", "*=", "/=", "<=", ">=", "==", "!=", "&&", "||", "+", "-", "*", "/", "%", "<", ">",
+    "=", "!", "?", ":", "(", ")", "{", "}", ",", ";",
+];
+
+struct Lexer<'a> {
+    src: &'a str,
+    pos: usize,
+    line: u16,
+    peeked: Option<Token<'a>>,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(src: &'a str) -> Self {
+        Self {
+            src,
+            pos: 0,
+            line: 1,
+            peeked: None,
+        }
+    }
+
+    fn error(&self, msg: &'static str) -> CompileError {
+        CompileError {
+            line: self.line,
+            msg,
+        }
+    }
+
+    fn number(&mut self) -> CompileResult<Token<'a>> {
+        let rest = &self.src[self.pos..];
+        let len = rest
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(rest.len());
+        self.pos += len;
+        let (int, frac) = rest[..len].split_once('.').unwrap_or((&rest[..len], ""));
+        let int: Fixed = if int.is_empty() {
+            0
+        } else {
+            int.parse()
+                .ok()
+                .filter(|i| *i <= Fixed::MAX >> 16)
+                .ok_or(self.error("number too large"))?
+        };
+        // Only the first few fraction digits matter at this resolution.
+        let (mut num, mut den) = (0i64, 1i64);
+        for digit in frac.bytes().take(6) {
+            if !digit.is_ascii_digit() {
+                return Err(self.error("bad number"));
+            }
+            num = num * 10 + (digit - b'0') as i64;
+            den *= 10;
+        }
+        Ok(Token::Num((int << 16) + ((num << 16) / den) as Fixed))
+    }
+
+    fn lex(&mut self) -> CompileResult<Token<'a>> {
+        loop {
+            let rest = &self.src[self.pos..];
+            let Some(c) = rest.chars().next() else {
+                return Ok(Token::Eof);
+            };
+            if rest.starts_with("//") {
+                self.pos += rest.find('\n').unwrap_or(rest.len());
+            } else if c == '\n' {
+                self.pos += 1;
+                self.line += 1;
+            } else if c.is_whitespace() {
+                self.pos += c.len_utf8();
+            } else if c.is_ascii_digit() || c == '.' {
+                return self.number();
+            } else if c.is_ascii_alphabetic() || c == '_' {
+                let len = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(rest.len());
+                self.pos += len;
+                return Ok(Token::Ident(&rest[..len]));
+            } else {
+                let punct = PUNCTUATION
+                    .iter()
+                    .find(|p| rest.starts_with(**p))
+                    .ok_or(self.error("unexpected character"))?;
+                self.pos += punct.len();
+                return Ok(Token::Punct(punct));
+            }
+        }
+    }
+
+    fn peek(&mut self) -> CompileResult<Token<'a>> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.lex()?);
+        }
+        Ok(self.peeked.unwrap())
+    }
+
+    fn next(&mut self) -> CompileResult<Token<'a>> {
+        let token = self.peek()?;
+        self.peeked = None;
+        Ok(token)
+    }
+
+    /// Consume the next token if it is `punct`.
+    fn eat(&mut self, punct: &str) -> CompileResult<bool> {
+        if matches!(self.peek()?, Token::Punct(p) if p == punct) {
+            self.peeked = None;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn expect(&mut self, punct: &str, msg: &'static str) -> CompileResult<()> {
+        if !self.eat(punct)? {
+            return Err(self.error(msg));
+        }
+        Ok(())
+    }
+}
+
+/// Single pass recursive descent compiler straight to bytecode.
+struct Compiler<'a> {
+    lex: Lexer<'a>,
+    code: [u8; MAX_CODE_LEN],
+    len: usize,
+    vars: [&'a str; MAX_VARS],
+    num_vars: usize,
+    /// Values on the stack at the current point of the code.
+    depth: usize,
+    /// How deep the compiler has recursed.
+    nesting: usize,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(src: &'a str) -> Self {
+        let mut vars = [""; MAX_VARS];
+        vars[..BUILTIN_VARS.len()].copy_from_slice(&BUILTIN_VARS);
+        Self {
+            lex: Lexer::new(src),
+            code: [0; MAX_CODE_LEN],
+            len: 0,
+            vars,
+            num_vars: BUILTIN_VARS.len(),
+            depth: 0,
+            nesting: 0,
+        }
+    }
+
+    /// Run `parse` one level deeper, failing once scripts nest too deeply.
+    fn nested(&mut self, parse: fn(&mut Self) -> CompileResult<()>) -> CompileResult<()> {
+        if self.nesting == MAX_NESTING {
+            return Err(self.lex.error("nested too deeply"));
+        }
+        self.nesting += 1;
+        let result = parse(self);
+        self.nesting -= 1;
+        result
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> CompileResult<()> {
+        let end = self.len + bytes.len();
+        if end > MAX_CODE_LEN {
+            return Err(self.lex.error("script too long"));
+        }
+        self.code[self.len..end].copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    /// Emit `op` which pops `pops` values and pushes `pushes`.
+    fn op(&mut self, op: Op, pops: usize, pushes: usize, arg: &[u8]) -> CompileResult<()> {
+        self.emit(&[op as u8])?;
+        self.emit(arg)?;
+        self.depth = self.depth - pops + pushes;
+        if self.depth > STACK_DEPTH {
+            return Err(self.lex.error("expression too complex"));
+        }
+        Ok(())
+    }
+
+    /// Emit a jump with a placeholder target and return where to patch it.
+    fn jump(&mut self, op: Op) -> CompileResult<usize> {
+        let pops = (op == Op::JumpIfZero) as usize;
+        self.op(op, pops, 0, &[0, 0])?;
+        Ok(self.len - 2)
+    }
+
+    fn patch(&mut self, at: usize) {
+        LittleEndian::write_u16(&mut self.code[at..at + 2], self.len as u16);
+    }
+
+    fn var(&self, name: &str) -> Option<usize> {
+        self.vars[..self.num_vars].iter().position(|v| *v == name)
+    }
+
+    fn constant(&mut self, val: Fixed) -> CompileResult<()> {
+        let mut arg = [0u8; 4];
+        LittleEndian::write_i32(&mut arg, val);
+        self.op(Op::Const, 0, 1, &arg)
+    }
+
+    fn args(&mut self, count: usize) -> CompileResult<()> {
+        self.lex.expect("(", "expected (")?;
+        for i in 0..count {
+            if i > 0 {
+                self.lex.expect(",", "wrong number of arguments")?;
+            }
+            self.expr()?;
+        }
+        self.lex.expect(")", "wrong number of arguments")
+    }
+
+    fn primary(&mut self) -> CompileResult<()> {
+        match self.lex.next()? {
+            Token::Num(val) => self.constant(val),
+            Token::Punct("(") => {
+                self.expr()?;
+                self.lex.expect(")", "expected )")
+            }
+            Token::Ident("PI") => self.constant(PI),
+            Token::Ident("PI2") => self.constant(PI2),
+            Token::Ident(name) => {
+                if let Some(function) = FUNCTIONS.iter().position(|(n, _)| *n == name) {
+                    let count = FUNCTIONS[function].1;
+                    self.args(count)?;
+                    return self.op(Op::Call, count, 1, &[function as u8]);
+                }
+                let var = self.var(name).ok_or(self.lex.error("unknown variable"))?;
+                self.op(Op::Load, 0, 1, &[var as u8])
+            }
+            _ => Err(self.lex.error("expected a value")),
+        }
+    }
+
+    fn unary(&mut self) -> CompileResult<()> {
+        if self.lex.eat("-")? {
+            self.nested(Self::unary)?;
+            self.op(Op::Neg, 1, 1, &[])
+        } else if self.lex.eat("!")? {
+            self.nested(Self::unary)?;
+            self.op(Op::Not, 1, 1, &[])
+        } else {
+            self.primary()
+        }
+    }
+
+    /// Left associative binary operators, lowest precedence `level` first.
+    fn binary(&mut self, level: usize) -> CompileResult<()> {
+        const LEVELS: [&[(&str, Op)]; 6] = [
+            &[("||", Op::Or)],
+            &[("&&", Op::And)],
+            &[("==", Op::Eq), ("!=", Op::Ne)],
+            &[("<=", Op::Le), (">=", Op::Ge), ("<", Op::Lt), (">", Op::Gt)],
+            &[("+", Op::Add), ("-", Op::Sub)],
+            &[("*", Op::Mul), ("/", Op::Div), ("%", Op::Mod)],
+        ];
+        let Some(ops) = LEVELS.get(level) else {
+            return self.unary();
+        };
+        self.binary(level + 1)?;
+        'outer: loop {
+            for (punct, op) in ops.iter() {
+                if self.lex.eat(punct)? {
+                    self.binary(level + 1)?;
+                    self.op(*op, 2, 1, &[])?;
+                    continue 'outer;
+                }
+            }
+            return Ok(());
+        }
+    }
+
+    fn expr(&mut self) -> CompileResult<()> {
+        self.nested(Self::conditional)
+    }
+
+    fn conditional(&mut self) -> CompileResult<()> {
+        self.binary(0)?;
+        if !self.lex.eat("?")? {
+            return Ok(());
+        }
+        let else_jump = self.jump(Op::JumpIfZero)?;
+        self.expr()?;
+        self.lex.expect(":", "expected :")?;
+        let end_jump = self.jump(Op::Jump)?;
+        self.patch(else_jump);
+        self.depth -= 1;
+        self.expr()?;
+        self.patch(end_jump);
+        Ok(())
+    }
+
+    fn block(&mut self) -> CompileResult<()> {
+        self.lex.expect("{", "expected {")?;
+        while !self.lex.eat("}")? {
+            if self.lex.peek()? == Token::Eof {
+                return Err(self.lex.error("expected }"));
+            }
+            self.statement()?;
+        }
+        Ok(())
+    }
+
+    fn if_statement(&mut self) -> CompileResult<()> {
+        self.expr()?;
+        let else_jump = self.jump(Op::JumpIfZero)?;
+        self.block()?;
+        if self.lex.peek()? != Token::Ident("else") {
+            self.patch(else_jump);
+            return Ok(());
+        }
+        self.lex.next()?;
+        let end_jump = self.jump(Op::Jump)?;
+        self.patch(else_jump);
+        if self.lex.peek()? == Token::Ident("if") {
+            self.lex.next()?;
+            self.nested(Self::if_statement)?;
+        } else {
+            self.block()?;
+        }
+        self.patch(end_jump);
+        Ok(())
+    }
+
+    fn statement(&mut self) -> CompileResult<()> {
+        let name = match self.lex.next()? {
+            Token::Punct(";") => return Ok(()),
+            Token::Ident("if") => return self.nested(Self::if_statement),
+            Token::Ident("rgb") => {
+                self.args(3)?;
+                return self.op(Op::Rgb, 3, 0, &[]);
+            }
+            Token::Ident("hsv") => {
+                self.args(3)?;
+                return self.op(Op::Hsv, 3, 0, &[]);
+            }
+            Token::Ident(name) => name,
+            _ => return Err(self.lex.error("expected a statement")),
+        };
+
+        if BUILTIN_VARS.contains(&name)
+            || FUNCTIONS.iter().any(|(n, _)| *n == name)
+            || ["PI", "PI2", "else"].contains(&name)
+        {
+            return Err(self.lex.error("can't assign to a built in"));
+        }
+        let compound = match self.lex.peek()? {
+            Token::Punct("+=") => Some(Op::Add),
+            Token::Punct("-=") => Some(Op::Sub),
+            Token::Punct("*=") => Some(Op::Mul),
+            Token::Punct("/=") => Some(Op::Div),
+            _ => None,
+        };
+        let var = match (self.var(name), compound) {
+            (Some(var), _) => var,
+            (None, None) if self.num_vars < MAX_VARS => {
+                self.vars[self.num_vars] = name;
+                self.num_vars += 1;
+                self.num_vars - 1
+            }
+            (None, None) => return Err(self.lex.error("too many variables")),
+            (None, Some(_)) => return Err(self.lex.error("unknown variable")),
+        };
+
+        if let Some(op) = compound {
+            self.lex.next()?;
+            self.op(Op::Load, 0, 1, &[var as u8])?;
+            self.expr()?;
+            self.op(op, 2, 1, &[])?;
+        } else {
+            self.lex.expect("=", "expected =")?;
+            self.expr()?;
+        }
+        self.op(Op::Store, 1, 0, &[var as u8])
+    }
+
+    fn compile(mut self) -> CompileResult<Script> {
+        while self.lex.peek()? != Token::Eof {
+            self.statement()?;
+        }
+        let mut script = Script::new();
+        script.code = self.code;
+        script.code_len = self.len;
+        Ok(script)
+    }
+}
+
+/// A compiled script and its variables.
+pub struct Script {
+    code: [u8; MAX_CODE_LEN],
+    code_len: usize,
+    vars: [Fixed; MAX_VARS],
+    budget: u32,
+    overran: bool,
+    /// Instructions executed during the last frame.
+    pub steps: u32,
+    /// Frames that ran out of budget before every pixel was drawn.
+    pub overruns: u32,
+}
+
+impl Script {
+    /// An empty script, which draws black.
+    pub const fn new() -> Self {
+        Self {
+            code: [0; MAX_CODE_LEN],
+            code_len: 0,
+            vars: [0; MAX_VARS],
+            budget: FRAME_BUDGET,
+            overran: false,
+            steps: 0,
+            overruns: 0,
+        }
+    }
+
+    pub fn compile(source: &str) -> core::result::Result<Self, CompileError> {
+        Compiler::new(source).compile()
+    }
+
+    pub fn code_len(&self) -> usize {
+        self.code_len
+    }
+
+    /// Refill the instruction budget.  Called once per output frame.
+    pub(crate) fn begin_frame(&mut self) {
+        self.steps = FRAME_BUDGET - self.budget;
+        self.overruns += self.overran as u32;
+        self.budget = FRAME_BUDGET;
+        self.overran = false;
+    }
+
+    /// Run the script for one pixel.  Returns `None` if the frame's budget
+    /// ran out.
+    fn run(&mut self, time_ms: u32) -> Option<Rgb> {
+        let mut stack = [0 as Fixed; STACK_DEPTH];
+        let mut sp = 0;
+        let mut pc = 0;
+        let mut color = Rgb::BLACK;
+
+        while pc < self.code_len {
+            self.budget = self.budget.checked_sub(1)?;
+            let op = Op::from_u8(self.code[pc])?;
+            pc += 1;
+            match op {
+                Op::Const => {
+                    stack[sp] = LittleEndian::read_i32(&self.code[pc..pc + 4]);
+                    sp += 1;
+                    pc += 4;
+                }
+                Op::Load => {
+                    stack[sp] = self.vars[self.code[pc] as usize];
+                    sp += 1;
+                    pc += 1;
+                }
+                Op::Store => {
+                    sp -= 1;
+                    self.vars[self.code[pc] as usize] = stack[sp];
+                    pc += 1;
+                }
+                Op::Neg => stack[sp - 1] = stack[sp - 1].wrapping_neg(),
+                Op::Not => stack[sp - 1] = truth(stack[sp - 1] == 0),
+                Op::Jump => pc = LittleEndian::read_u16(&self.code[pc..pc + 2]) as usize,
+                Op::JumpIfZero => {
+                    sp -= 1;
+                    if stack[sp] == 0 {
+                        pc = LittleEndian::read_u16(&self.code[pc..pc + 2]) as usize;
+                    } else {
+                        pc += 2;
+                    }
+                }
+                Op::Call => {
+                    let function = self.code[pc] as usize;
+                    pc += 1;
+                    let count = FUNCTIONS[function].1;
+                    sp -= count;
+                    stack[sp] = call(function, &stack[sp..sp + count], time_ms);
+                    sp += 1;
+                }
+                Op::Rgb | Op::Hsv => {
+                    sp -= 3;
+                    let [a, b, c] = [stack[sp], stack[sp + 1], stack[sp + 2]];
+                    color = if op == Op::Rgb {
+                        Rgb::new(to_u8(a), to_u8(b), to_u8(c))
+                    } else {
+                        // Hue wraps rather than clamps.
+                        Hsv::new((a >> 8) as u8, to_u8(b), to_u8(c)).into()
+                    };
+                }
+                _ => {
+                    sp -= 1;
+                    let (a, b) = (stack[sp - 1], stack[sp]);
+                    stack[sp - 1] = match op {
+                        Op::Add => a.wrapping_add(b),
+                        Op::Sub => a.wrapping_sub(b),
+                        Op::Mul => mul(a, b),
+                        Op::Div => div(a, b),
+                        Op::Mod => a.checked_rem(b).unwrap_or(0),
+                        Op::Lt => truth(a < b),
+                        Op::Le => truth(a <= b),
+                        Op::Gt => truth(a > b),
+                        Op::Ge => truth(a >= b),
+                        Op::Eq => truth(a == b),
+                        Op::Ne => truth(a != b),
+                        Op::And => truth(a != 0 && b != 0),
+                        _ => truth(a != 0 || b != 0),
+                    };
+                }
+            }
+        }
+        Some(color)
+    }
+
+    /// Render the script into `pixels`, where `pixels[0]` is strip pixel
+    /// `first`.
+    pub fn render(
+        &mut self,
+        time_ms: u32,
+        pixels: &mut [Rgb],
+        layout: Option<&Layout>,
+        first: usize,
+    ) {
+        let count = pixels.len() as Fixed;
+        self.vars[VAR_PIXEL_COUNT] = count << 16;
+        for i in 0..pixels.len() {
+            let (x, y) = match layout.and_then(|l| Some((l, l.coords(first + i)?))) {
+                Some((layout, (x, y))) => (
+                    ((x as Fixed) << 16) / (layout.display_width() as Fixed - 1).max(1),
+                    ((y as Fixed) << 16) / (layout.display_height() as Fixed - 1).max(1),
+                ),
+                None => (((i as Fixed) << 16) / count, 0),
+            };
+            self.vars[VAR_INDEX] = (i as Fixed) << 16;
+            self.vars[VAR_X] = x;
+            self.vars[VAR_Y] = y;
+
+            match self.run(time_ms) {
+                Some(color) => pixels[i] = color,
+                None => {
+                    self.overran = true;
+                    pixels[i..].fill(Rgb::BLACK);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Read the script source saved in `storage` into `data`.
+    pub fn load_source<'a>(storage: &mut Storage, data: &'a mut [u8]) -> Result<Option<&'a str>> {
+        let Some((version, data)) = storage.load(Slot::Script, data)? else {
+            return Ok(None);
+        };
+        if version != SCRIPT_VERSION {
+            return Ok(None);
+        }
+        core::str::from_utf8(data)
+            .map(Some)
+            .map_err(|_| Error::Generic("bad script"))
+    }
+
+    /// Load and compile the script saved in `storage`.
+    pub fn load(storage: &mut Storage) -> Result<Option<Self>> {
+        let mut data = [0u8; MAX_SOURCE_LEN];
+        let Some(source) = Self::load_source(storage, &mut data)? else {
+            return Ok(None);
+        };
+        Self::compile(source)
+            .map(Some)
+            .map_err(|_| Error::Generic("saved script doesn't compile"))
+    }
+
+    pub fn save(source: &str, storage: &mut Storage) -> Result<()> {
+        storage.store(Slot::Script, SCRIPT_VERSION, source.as_bytes())
+    }
+}
@@ -0,0 +1,633 @@
+//! Settings that used to be fixed at build time, kept in flash as key/value
+//! records so they can be changed at run time.
+//!
+//! Records are appended to one sector of [`SETTINGS_REGION`] until it
+//! fills.  The live values are then copied to the next sector, which takes
+//! over once its header is written, so erases are spread over the whole
+//! region and a power loss part way through leaves the old sector in use.
+//! A sector is laid out as:
+//!
+//!   magic: u32, seq: u32, schema: u16, 0xffff: u16, crc32: u32,
+//!   records: [key: u16, len: u16, crc32: u32, data: [u8; len], padding]
+//!
+//! The header CRC covers the fields before it and a record's covers its
+//! key, length and data.  A later record for a key replaces an earlier one
+//! and a record with a length of `REMOVED` puts the key back to its factory
+//! default.  Keys without a record take their factory default, which is
+//! what the build time constants now are.
+
+use core::fmt::Write;
+
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use esp_println::println;
+use num_derive::{FromPrimitive, ToPrimitive};
+use num_traits::FromPrimitive;
+
+use crate::artnet;
+use crate::http::{Connection, Request, Status, JSON};
+use crate::json::Json;
+use crate::output::NUM_LEDS;
+use crate::storage::{crc32, crc32_update, Storage, SECTOR_SIZE, SETTINGS_REGION};
+use crate::web::{query_param, url_decode, Context, FmtBuffer};
+use crate::{Error, Result};
+
+const MAGIC: u32 = 0x3147_4643; // "CFG1"
+/// Bump when a key changes meaning, and teach [`migrate`] about the old
+/// one.
+const SCHEMA_VERSION: u16 = 1;
+
+const SECTORS: usize = SETTINGS_REGION.len as usize / SECTOR_SIZE;
+const HEADER_LEN: usize = 16;
+const RECORD_HEADER_LEN: usize = 8;
+/// No key's value is longer than this.
+pub const MAX_VALUE_LEN: usize = 64;
+/// `key` of the erased space after the last record.
+const ERASED: u16 = 0xffff;
+/// `len` of a record that removes its key.
+const REMOVED: u16 = 0xffff;
+
+const MAX_FORM_LEN: usize = Key::ALL.len() * (16 + 3 * MAX_VALUE_LEN);
+
+/// The settings.  Numbers stay the same for the life of a schema.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, FromPrimitive, ToPrimitive)]
+#[repr(u16)]
+pub enum Key {
+    Ssid = 1,
+    Password = 2,
+    HttpPort = 3,
+    NumLeds = 4,
+    ShortName = 5,
+    LongName = 6,
+    Smooth = 7,
+    ArtnetFirmware = 8,
+    Sacn = 9,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Kind {
+    Text { max_len: usize },
+    Number { min: u16, max: u16 },
+}
+
+impl Key {
+    pub const ALL: [Key; 9] = [
+        Key::Ssid,
+        Key::Password,
+        Key::HttpPort,
+        Key::NumLeds,
+        Key::ShortName,
+        Key::LongName,
+        Key::Smooth,
+        Key::ArtnetFirmware,
+        Key::Sacn,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Key::Ssid => "ssid",
+            Key::Password => "password",
+            Key::HttpPort => "http_port",
+            Key::NumLeds => "num_leds",
+            Key::ShortName => "short_name",
+            Key::LongName => "long_name",
+            Key::Smooth => "smooth",
+            Key::ArtnetFirmware => "artnet_firmware",
+            Key::Sacn => "sacn",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|key| key.name() == name)
+    }
+
+    fn kind(self) -> Kind {
+        match self {
+            Key::Ssid => Kind::Text { max_len: 32 },
+            Key::Password => Kind::Text { max_len: 64 },
+            Key::HttpPort => Kind::Number {
+                min: 1,
+                max: u16::MAX,
+            },
+            Key::NumLeds => Kind::Number {
+                min: 1,
+                max: NUM_LEDS as u16,
+            },
+            // ArtPollReply keeps a terminating zero in its name fields.
+            Key::ShortName => Kind::Text { max_len: 17 },
+            Key::LongName => Kind::Text { max_len: 63 },
+            Key::Smooth => Kind::Number { min: 0, max: 1 },
+            Key::ArtnetFirmware => Kind::Number { min: 0, max: 1 },
+            Key::Sacn => Kind::Number { min: 0, max: 1 },
+        }
+    }
+
+    /// Kept out of the API, which only says whether it is set.
+    pub fn is_secret(self) -> bool {
+        self == Key::Password
+    }
+
+    /// Read once at boot, so a change needs a restart.
+    pub fn needs_restart(self) -> bool {
+        matches!(self, Key::Ssid | Key::Password | Key::HttpPort)
+    }
+
+    fn factory_default(self) -> Value {
+        match self {
+            Key::Ssid => Value::text(crate::SSID.unwrap_or("")),
+            Key::Password => Value::text(crate::PASSWORD.unwrap_or("")),
+            Key::HttpPort => Value::number(crate::HTTP_PORT),
+            Key::NumLeds => Value::number(NUM_LEDS as u16),
+            Key::ShortName => Value::text(artnet::SHORT_NAME),
+            Key::LongName => Value::text(artnet::LONG_NAME),
+            Key::Smooth => Value::number(0),
+            Key::ArtnetFirmware => Value::number(0),
+            Key::Sacn => Value::number(0),
+        }
+    }
+
+    /// Turn the text form of a value into one, refusing values that don't
+    /// fit.
+    pub fn parse(self, text: &str) -> Result<Value> {
+        let value = match self.kind() {
+            Kind::Text { .. } => Value::new(text.as_bytes()),
+            Kind::Number { .. } => text.parse().ok().map(Value::number),
+        };
+        value
+            .filter(|value| self.check(value))
+            .ok_or(Error::Generic(self.name()))
+    }
+
+    /// Whether a value, possibly read back from flash, is one this key can
+    /// take.
+    fn check(self, value: &Value) -> bool {
+        match self.kind() {
+            Kind::Text { max_len } => {
+                value.len() <= max_len && core::str::from_utf8(value.as_bytes()).is_ok()
+            }
+            Kind::Number { min, max } => {
+                value.len() == 2 && (min..=max).contains(&value.as_number())
+            }
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize - 1
+    }
+}
+
+/// The key current firmware uses for `key` as written under `schema`, or
+/// `None` if it has been dropped.  Values are checked against the key
+/// after this, so a key whose values changed shape needs a new number.
+/// Records from newer firmware are ignored since there is no telling what
+/// they mean.
+fn migrate(schema: u16, key: u16) -> Option<Key> {
+    match schema {
+        SCHEMA_VERSION => Key::from_u16(key),
+        _ => None,
+    }
+}
+
+/// The raw bytes of a setting.  Numbers are little endian `u16`s and text
+/// is UTF-8.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Value {
+    len: u8,
+    data: [u8; MAX_VALUE_LEN],
+}
+
+impl Value {
+    fn new(data: &[u8]) -> Option<Self> {
+        let mut value = Self {
+            len: data.len() as u8,
+            data: [0; MAX_VALUE_LEN],
+        };
+        value.data.get_mut(..data.len())?.copy_from_slice(data);
+        Some(value)
+    }
+
+    fn text(text: &str) -> Self {
+        Self::new(text.as_bytes()).unwrap_or(Self {
+            len: 0,
+            data: [0; MAX_VALUE_LEN],
+        })
+    }
+
+    fn number(number: u16) -> Self {
+        Self::new(&number.to_le_bytes()).unwrap()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len()]
+    }
+
+    /// Checked to be UTF-8 for text keys.
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(self.as_bytes()).unwrap_or("")
+    }
+
+    pub fn as_number(&self) -> u16 {
+        match self.as_bytes() {
+            [low, high] => u16::from_le_bytes([*low, *high]),
+            _ => 0,
+        }
+    }
+}
+
+/// Space a record takes in flash.
+fn record_len(data_len: usize) -> usize {
+    RECORD_HEADER_LEN + ((data_len + 3) & !3)
+}
+
+fn record_crc(key: u16, len: u16, data: &[u8]) -> u32 {
+    let crc = crc32_update(0xffff_ffff, &key.to_le_bytes());
+    let crc = crc32_update(crc, &len.to_le_bytes());
+    !crc32_update(crc, data)
+}
+
+fn sector_offset(sector: usize) -> u32 {
+    (sector * SECTOR_SIZE) as u32
+}
+
+/// `(seq, schema)` of a sector with a good header.
+fn read_header(storage: &mut Storage, sector: usize) -> Result<Option<(u32, u16)>> {
+    let mut header = [0u8; HEADER_LEN];
+    storage.read_region(SETTINGS_REGION, sector_offset(sector), &mut header)?;
+    let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let seq = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let schema = u16::from_le_bytes(header[8..10].try_into().unwrap());
+    let crc = u32::from_le_bytes(header[12..16].try_into().unwrap());
+    if magic != MAGIC || crc != crc32(&header[..12]) {
+        return Ok(None);
+    }
+    Ok(Some((seq, schema)))
+}
+
+fn write_header(storage: &mut Storage, sector: usize, seq: u32) -> Result<()> {
+    let mut header = [0xffu8; HEADER_LEN];
+    header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    header[4..8].copy_from_slice(&seq.to_le_bytes());
+    header[8..10].copy_from_slice(&SCHEMA_VERSION.to_le_bytes());
+    let crc = crc32(&header[..12]);
+    header[12..16].copy_from_slice(&crc.to_le_bytes());
+    storage.write_region(SETTINGS_REGION, sector_offset(sector), &header)
+}
+
+/// Write a record for `key` at `pos` in one go, so that a power loss leaves
+/// at most a torn record that fails its CRC.  Returns the space it took.
+fn write_record(
+    storage: &mut Storage,
+    sector: usize,
+    pos: usize,
+    key: Key,
+    value: Option<&Value>,
+) -> Result<usize> {
+    let data = value.map_or(&[][..], |value| value.as_bytes());
+    let len = value.map_or(REMOVED, |value| value.len() as u16);
+    let mut record = [0xffu8; RECORD_HEADER_LEN + MAX_VALUE_LEN];
+    record[0..2].copy_from_slice(&(key as u16).to_le_bytes());
+    record[2..4].copy_from_slice(&len.to_le_bytes());
+    record[4..8].copy_from_slice(&record_crc(key as u16, len, data).to_le_bytes());
+    record[RECORD_HEADER_LEN..RECORD_HEADER_LEN + data.len()].copy_from_slice(data);
+    let record = &record[..record_len(data.len())];
+    storage.write_region(SETTINGS_REGION, sector_offset(sector) + pos as u32, record)?;
+    Ok(record.len())
+}
+
+pub struct Settings {
+    values: [Value; Key::ALL.len()],
+    /// Which values have a record, as opposed to being factory defaults.
+    stored: [bool; Key::ALL.len()],
+    /// The sector records are appended to, once there is one.
+    sector: Option<usize>,
+    seq: u32,
+    /// Where the next record goes.
+    end: usize,
+    /// The schema the records in `sector` were written with.
+    schema: u16,
+}
+
+pub type SharedSettings = Mutex<NoopRawMutex, Settings>;
+
+impl Settings {
+    /// Factory defaults, with nothing in flash.
+    pub fn new() -> Self {
+        Self {
+            values: Key::ALL.map(Key::factory_default),
+            stored: [false; Key::ALL.len()],
+            sector: None,
+            seq: 0,
+            end: SECTOR_SIZE,
+            schema: SCHEMA_VERSION,
+        }
+    }
+
+    pub fn load(storage: &mut Storage) -> Result<Self> {
+        let mut settings = Self::new();
+        let mut newest = None;
+        for sector in 0..SECTORS {
+            if let Some((seq, schema)) = read_header(storage, sector)? {
+                if newest.map_or(true, |(_, newest_seq, _)| seq > newest_seq) {
+                    newest = Some((sector, seq, schema));
+                }
+            }
+        }
+        let Some((sector, seq, schema)) = newest else {
+            return Ok(settings);
+        };
+        if schema > SCHEMA_VERSION {
+            println!("settings: ignoring schema {schema} from newer firmware");
+        }
+        settings.sector = Some(sector);
+        settings.seq = seq;
+        settings.schema = schema;
+        settings.read_records(storage, sector)?;
+        Ok(settings)
+    }
+
+    fn read_records(&mut self, storage: &mut Storage, sector: usize) -> Result<()> {
+        let mut pos = HEADER_LEN;
+        while pos + RECORD_HEADER_LEN <= SECTOR_SIZE {
+            let offset = sector_offset(sector) + pos as u32;
+            let mut header = [0u8; RECORD_HEADER_LEN];
+            storage.read_region(SETTINGS_REGION, offset, &mut header)?;
+            let key = u16::from_le_bytes(header[0..2].try_into().unwrap());
+            let len = u16::from_le_bytes(header[2..4].try_into().unwrap());
+            let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+            if key == ERASED {
+                break;
+            }
+
+            let data_len = if len == REMOVED { 0 } else { len as usize };
+            if data_len > MAX_VALUE_LEN || pos + record_len(data_len) > SECTOR_SIZE {
+                // A torn header.  There's no telling where the next record
+                // would start, so the next change moves to a fresh sector.
+                pos = SECTOR_SIZE;
+                break;
+            }
+            let mut data = [0u8; MAX_VALUE_LEN];
+            let data = &mut data[..data_len];
+            if !data.is_empty() {
+                storage.read_region(SETTINGS_REGION, offset + RECORD_HEADER_LEN as u32, data)?;
+            }
+            pos += record_len(data_len);
+
+            if crc != record_crc(key, len, data) {
+                continue;
+            }
+            let Some(key) = migrate(self.schema, key) else {
+                continue;
+            };
+            if len == REMOVED {
+                self.values[key.index()] = key.factory_default();
+                self.stored[key.index()] = false;
+            } else if let Some(value) = Value::new(data).filter(|value| key.check(value)) {
+                self.values[key.index()] = value;
+                self.stored[key.index()] = true;
+            }
+        }
+        self.end = pos;
+        Ok(())
+    }
+
+    pub fn get(&self, key: Key) -> &Value {
+        &self.values[key.index()]
+    }
+
+    pub fn text(&self, key: Key) -> &str {
+        self.get(key).as_str()
+    }
+
+    pub fn number(&self, key: Key) -> u16 {
+        self.get(key).as_number()
+    }
+
+    pub fn is_default(&self, key: Key) -> bool {
+        !self.stored[key.index()]
+    }
+
+    pub fn ssid(&self) -> &str {
+        self.text(Key::Ssid)
+    }
+
+    pub fn password(&self) -> &str {
+        self.text(Key::Password)
+    }
+
+    pub fn http_port(&self) -> u16 {
+        self.number(Key::HttpPort)
+    }
+
+    /// LEDs actually on the strip, up to [`NUM_LEDS`].
+    pub fn num_leds(&self) -> usize {
+        self.number(Key::NumLeds) as usize
+    }
+
+    pub fn short_name(&self) -> &str {
+        self.text(Key::ShortName)
+    }
+
+    pub fn long_name(&self) -> &str {
+        self.text(Key::LongName)
+    }
+
+    /// Whether the output interpolates between Art-Net frames.
+    pub fn smooth(&self) -> bool {
+        self.number(Key::Smooth) != 0
+    }
+
+    /// Whether ArtFirmwareMaster uploads are taken.  Art-Net has no
+    /// authentication, so they are off unless turned on.
+    pub fn artnet_firmware(&self) -> bool {
+        self.number(Key::ArtnetFirmware) != 0
+    }
+
+    /// Whether universes are also taken from sACN (E1.31).
+    pub fn sacn(&self) -> bool {
+        self.number(Key::Sacn) != 0
+    }
+
+    pub fn set(&mut self, storage: &mut Storage, key: Key, value: Value) -> Result<()> {
+        if !key.check(&value) {
+            return Err(Error::Generic(key.name()));
+        }
+        if self.stored[key.index()] && *self.get(key) == value {
+            return Ok(());
+        }
+        self.values[key.index()] = value;
+        self.stored[key.index()] = true;
+        self.append(storage, key, Some(&value))
+    }
+
+    /// Put `key` back to its factory default.
+    pub fn reset(&mut self, storage: &mut Storage, key: Key) -> Result<()> {
+        if !self.stored[key.index()] {
+            return Ok(());
+        }
+        self.values[key.index()] = key.factory_default();
+        self.stored[key.index()] = false;
+        self.append(storage, key, None)
+    }
+
+    /// Put everything back to factory defaults.
+    pub fn factory_reset(&mut self, storage: &mut Storage) -> Result<()> {
+        for sector in 0..SECTORS {
+            storage.erase_region(SETTINGS_REGION, sector_offset(sector))?;
+        }
+        *self = Self::new();
+        Ok(())
+    }
+
+    fn append(&mut self, storage: &mut Storage, key: Key, value: Option<&Value>) -> Result<()> {
+        let len = record_len(value.map_or(0, |value| value.len()));
+        match self.sector {
+            Some(sector) if self.schema == SCHEMA_VERSION && self.end + len <= SECTOR_SIZE => {
+                self.end += write_record(storage, sector, self.end, key, value)?;
+                Ok(())
+            }
+            _ => self.compact(storage),
+        }
+    }
+
+    /// Copy the stored values into the next sector and switch to it.  The
+    /// records go first so the old sector stays in use until the header
+    /// that makes the new one current is written.
+    fn compact(&mut self, storage: &mut Storage) -> Result<()> {
+        let sector = self.sector.map_or(0, |sector| (sector + 1) % SECTORS);
+        storage.erase_region(SETTINGS_REGION, sector_offset(sector))?;
+        let mut pos = HEADER_LEN;
+        for key in Key::ALL {
+            if self.stored[key.index()] {
+                let value = self.values[key.index()];
+                pos += write_record(storage, sector, pos, key, Some(&value))?;
+            }
+        }
+        let seq = self.seq.wrapping_add(1);
+        write_header(storage, sector, seq)?;
+        self.sector = Some(sector);
+        self.seq = seq;
+        self.end = pos;
+        self.schema = SCHEMA_VERSION;
+        Ok(())
+    }
+}
+
+fn write_settings<W: Write>(json: &mut Json<W>, settings: &Settings) -> Result<()> {
+    json.begin_object()?;
+    for key in Key::ALL {
+        let value = settings.get(key);
+        match key.kind() {
+            _ if key.is_secret() => json.field(key.name(), !value.is_empty())?,
+            Kind::Text { .. } => json.field(key.name(), value.as_str())?,
+            Kind::Number { .. } => json.field(key.name(), value.as_number())?,
+        }
+    }
+    json.key("defaults")?;
+    json.begin_array()?;
+    for key in Key::ALL.into_iter().filter(|key| settings.is_default(*key)) {
+        json.value(key.name())?;
+    }
+    json.end_array()?;
+    json.field("schema", SCHEMA_VERSION)?;
+    json.end_object()
+}
+
+async fn read_form<'a>(conn: &mut Connection<'_, '_>, body: &'a mut [u8]) -> Result<&'a str> {
+    core::str::from_utf8(conn.read_body(body).await?)
+        .map_err(|_| Error::Generic("Form isn't UTF-8"))
+}
+
+/// Apply the form in the body.  Every field has to be a setting and every
+/// value has to fit before any of them are changed.  Returns whether a
+/// restart is needed.
+async fn set(conn: &mut Connection<'_, '_>, ctx: &Context) -> Result<bool> {
+    let mut body = [0u8; MAX_FORM_LEN];
+    let form = read_form(conn, &mut body).await?;
+
+    let mut changes = [None; Key::ALL.len()];
+    for (name, value) in form.split('&').filter_map(|pair| pair.split_once('=')) {
+        let key = Key::from_name(name).ok_or(Error::Generic("Unknown setting"))?;
+        let mut buf = [0u8; MAX_VALUE_LEN];
+        let value = url_decode(value, &mut buf).map_err(|_| Error::Generic(key.name()))?;
+        let value = core::str::from_utf8(value).map_err(|_| Error::Generic(key.name()))?;
+        changes[key.index()] = Some(key.parse(value)?);
+    }
+
+    let mut restart = false;
+    let mut settings = ctx.settings.lock().await;
+    let mut storage = ctx.storage.lock().await;
+    for (key, value) in Key::ALL.into_iter().zip(changes) {
+        if let Some(value) = value {
+            restart |= key.needs_restart() && *settings.get(key) != value;
+            settings.set(&mut storage, key, value)?;
+        }
+    }
+    let (num_leds, smooth) = (settings.num_leds(), settings.smooth());
+    drop((settings, storage));
+    let mut output = ctx.output.lock().await;
+    output.num_leds = num_leds;
+    output.smooth = smooth;
+    Ok(restart)
+}
+
+/// Put the settings listed in `keys=a,b` back to their factory defaults,
+/// or all of them without it.
+async fn reset(conn: &mut Connection<'_, '_>, ctx: &Context) -> Result<bool> {
+    let mut body = [0u8; MAX_FORM_LEN];
+    let form = read_form(conn, &mut body).await?;
+    let mut settings = ctx.settings.lock().await;
+    let mut storage = ctx.storage.lock().await;
+    let mut restart = false;
+    match query_param(form, "keys") {
+        Some(keys) => {
+            let mut reset = [false; Key::ALL.len()];
+            for name in keys.split(',') {
+                let key = Key::from_name(name).ok_or(Error::Generic("Unknown setting"))?;
+                reset[key.index()] = true;
+            }
+            for key in Key::ALL.into_iter().filter(|key| reset[key.index()]) {
+                restart |= key.needs_restart() && !settings.is_default(key);
+                settings.reset(&mut storage, key)?;
+            }
+        }
+        None => {
+            restart = true;
+            settings.factory_reset(&mut storage)?;
+        }
+    }
+    let (num_leds, smooth) = (settings.num_leds(), settings.smooth());
+    drop((settings, storage));
+    let mut output = ctx.output.lock().await;
+    output.num_leds = num_leds;
+    output.smooth = smooth;
+    Ok(restart)
+}
+
+/// `GET /settings` lists the settings, `POST /settings/set` changes the
+/// ones in its form encoded body and `POST /settings/reset` goes back to
+/// factory defaults.  Wi-Fi and the HTTP port change after a restart.
+pub async fn handle(conn: &mut Connection<'_, '_>, ctx: &Context, req: &Request<'_>) -> Result<()> {
+    let restart = match req.param("command") {
+        None => false,
+        Some("set") => set(conn, ctx).await?,
+        Some("reset") => reset(conn, ctx).await?,
+        _ => return Err(Error::Generic("Unknown settings command")),
+    };
+
+    let mut buffer = [0u8; 512];
+    let mut json = Json::new(FmtBuffer::new(&mut buffer));
+    json.begin_object()?;
+    json.key("settings")?;
+    write_settings(&mut json, &*ctx.settings.lock().await)?;
+    json.field("restart_needed", restart)?;
+    json.end_object()?;
+    conn.send(Status::Ok, JSON, json.into_inner().as_bytes())
+        .await
+}
@@ -0,0 +1,561 @@
+use byteorder::LittleEndian;
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant, Timer};
+use esp_println::println;
+
+use crate::buffer::{MutBuffer, OldBuffer};
+use crate::color::Rgb;
+use crate::output::{Frame, SharedOutput, NUM_LEDS};
+use crate::storage::{crc32_update, SharedStorage, Storage, SECTOR_SIZE, SHOW_REGION};
+use crate::timecode::{Chase, SharedClock};
+use crate::{Error, Result};
+
+const SHOW_MAGIC: u32 = 0x3157_4853; // "SHW1"
+const SHOW_VERSION: u16 = 1;
+const HEADER_LEN: usize = 24;
+/// Frame records start after the sector holding the header.
+const DATA_START: u32 = SECTOR_SIZE as u32;
+const DATA_CAPACITY: u32 = SHOW_REGION.len - DATA_START;
+const PAGE_LEN: usize = 256;
+const TICK: Duration = Duration::from_millis(10);
+
+// A frame record is a u16 delay in ms since the previous record followed by
+// ops that patch the previous frame until every pixel is covered:
+//   0nnnnnnn: n + 1 pixels are unchanged
+//   10nnnnnn: n + 1 RGB pixels follow
+//   11nnnnnn: one RGB pixel follows, repeated n + 1 times
+const OP_SKIP: u8 = 0x00;
+const OP_LITERAL: u8 = 0x80;
+const OP_RUN: u8 = 0xc0;
+const MAX_SKIP: usize = 128;
+const MAX_RUN: usize = 64;
+/// Worst case length of an encoded record.
+const MAX_RECORD_LEN: usize = 2 + NUM_LEDS * 4;
+
+/// Number of consecutive indices from `start` for which `pred` holds.
+fn count(start: usize, max: usize, pred: impl Fn(usize) -> bool) -> usize {
+    (start..NUM_LEDS.min(start + max))
+        .take_while(|i| pred(*i))
+        .count()
+}
+
+fn write_rgb(buf: &mut MutBuffer<LittleEndian>, color: Rgb) -> Result<()> {
+    buf.write(&[color.r, color.g, color.b])?;
+    Ok(())
+}
+
+fn read_rgb(buf: &mut OldBuffer<LittleEndian>) -> Result<Rgb> {
+    let [r, g, b] = buf.read()?;
+    Ok(Rgb::new(r, g, b))
+}
+
+/// Encode `frame` as a record of changes from `prev`.
+fn encode(delay_ms: u16, prev: &Frame, frame: &Frame, out: &mut [u8]) -> Result<usize> {
+    let buf = &mut MutBuffer::<LittleEndian>::new(out);
+    buf.write_u16(delay_ms)?;
+
+    let mut i = 0;
+    while i < NUM_LEDS {
+        let unchanged = count(i, MAX_SKIP, |j| frame[j] == prev[j]);
+        if unchanged > 0 {
+            buf.write_u8(OP_SKIP | (unchanged - 1) as u8)?;
+            i += unchanged;
+            continue;
+        }
+
+        let run = count(i, MAX_RUN, |j| frame[j] == frame[i]);
+        if run > 1 {
+            buf.write_u8(OP_RUN | (run - 1) as u8)?;
+            write_rgb(buf, frame[i])?;
+            i += run;
+            continue;
+        }
+
+        // Changed pixels up to the next unchanged pixel or run.
+        let literal = count(i, MAX_RUN, |j| {
+            frame[j] != prev[j] && frame.get(j + 1).map_or(true, |next| *next != frame[j])
+        });
+        buf.write_u8(OP_LITERAL | (literal - 1) as u8)?;
+        for pixel in &frame[i..i + literal] {
+            write_rgb(buf, *pixel)?;
+        }
+        i += literal;
+    }
+
+    Ok(buf.pos())
+}
+
+/// Apply the record at the start of `data` to `frame`.  Returns the record's
+/// delay and length.
+fn decode(data: &[u8], frame: &mut Frame) -> Result<(u16, usize)> {
+    let buf = &mut OldBuffer::<LittleEndian>::new(data);
+    let delay_ms = buf.read_u16()?;
+
+    let mut i = 0;
+    while i < NUM_LEDS {
+        let op = buf.read_u8()?;
+        let n = if op & 0x80 == OP_SKIP {
+            (op & 0x7f) as usize + 1
+        } else {
+            (op & 0x3f) as usize + 1
+        };
+        let pixels = frame
+            .get_mut(i..i + n)
+            .ok_or(Error::Generic("bad show record"))?;
+        if op & 0x80 == OP_SKIP {
+            // Unchanged.
+        } else if op & 0xc0 == OP_LITERAL {
+            for pixel in pixels {
+                *pixel = read_rgb(buf)?;
+            }
+        } else {
+            pixels.fill(read_rgb(buf)?);
+        }
+        i += n;
+    }
+
+    Ok((delay_ms, buf.pos()))
+}
+
+/// Summary of a recorded show.
+///
+/// The header is written to the first sector of the show region once
+/// recording finishes so an interrupted recording never looks valid.
+///   magic: u32, version: u16, num_leds: u16, frames: u32, data_len: u32,
+///   length_ms: u32, crc32: u32
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ShowInfo {
+    pub frames: u32,
+    pub data_len: u32,
+    pub length_ms: u32,
+    crc: u32,
+}
+
+impl ShowInfo {
+    fn write(&self, buf: &mut MutBuffer<LittleEndian>) -> Result<()> {
+        buf.write_u32(SHOW_MAGIC)?;
+        buf.write_u16(SHOW_VERSION)?;
+        buf.write_u16(NUM_LEDS as u16)?;
+        buf.write_u32(self.frames)?;
+        buf.write_u32(self.data_len)?;
+        buf.write_u32(self.length_ms)?;
+        buf.write_u32(self.crc)?;
+        Ok(())
+    }
+
+    /// Read the header of the stored show, if there is a usable one.
+    pub fn load(storage: &mut Storage) -> Result<Option<Self>> {
+        let mut header = [0u8; HEADER_LEN];
+        storage.read_region(SHOW_REGION, 0, &mut header)?;
+        let buf = &mut OldBuffer::<LittleEndian>::new(&header);
+        if buf.read_u32()? != SHOW_MAGIC
+            || buf.read_u16()? != SHOW_VERSION
+            || buf.read_u16()? != NUM_LEDS as u16
+        {
+            return Ok(None);
+        }
+        let info = Self {
+            frames: buf.read_u32()?,
+            data_len: buf.read_u32()?,
+            length_ms: buf.read_u32()?,
+            crc: buf.read_u32()?,
+        };
+        if info.data_len > DATA_CAPACITY {
+            return Ok(None);
+        }
+        Ok(Some(info))
+    }
+
+    fn verify(&self, storage: &mut Storage) -> Result<bool> {
+        let mut crc = 0xffff_ffff;
+        let mut chunk = [0u8; PAGE_LEN];
+        let mut offset = 0;
+        while offset < self.data_len {
+            let len = PAGE_LEN.min((self.data_len - offset) as usize);
+            storage.read_region(SHOW_REGION, DATA_START + offset, &mut chunk[..len])?;
+            crc = crc32_update(crc, &chunk[..len]);
+            offset += len as u32;
+        }
+        Ok(!crc == self.crc)
+    }
+}
+
+struct Recorder {
+    page: [u8; PAGE_LEN],
+    page_len: usize,
+    info: ShowInfo,
+    prev: Frame,
+    start: Instant,
+    last: Instant,
+    /// Arrival of the Art-Net data last looked at.
+    received: Instant,
+}
+
+impl Recorder {
+    fn start(storage: &mut Storage, now: Instant) -> Result<Self> {
+        // Invalidate the old show before overwriting its data.
+        storage.erase_region(SHOW_REGION, 0)?;
+        Ok(Self {
+            page: [0xff; PAGE_LEN],
+            page_len: 0,
+            info: ShowInfo {
+                crc: 0xffff_ffff,
+                ..ShowInfo::default()
+            },
+            prev: [Rgb::BLACK; NUM_LEDS],
+            start: now,
+            last: now,
+            received: now,
+        })
+    }
+
+    fn flush(&mut self, storage: &mut Storage) -> Result<()> {
+        let offset = self.info.data_len - self.page_len as u32;
+        if offset % SECTOR_SIZE as u32 == 0 {
+            storage.erase_region(SHOW_REGION, DATA_START + offset)?;
+        }
+        // Writes must be whole words; the padding stays erased.
+        let len = (self.page_len + 3) & !3;
+        storage.write_region(SHOW_REGION, DATA_START + offset, &self.page[..len])?;
+        self.page = [0xff; PAGE_LEN];
+        self.page_len = 0;
+        Ok(())
+    }
+
+    fn push(&mut self, storage: &mut Storage, mut data: &[u8]) -> Result<()> {
+        if self.info.data_len + data.len() as u32 > DATA_CAPACITY {
+            return Err(Error::Generic("show full"));
+        }
+        self.info.crc = crc32_update(self.info.crc, data);
+        while !data.is_empty() {
+            let len = data.len().min(PAGE_LEN - self.page_len);
+            self.page[self.page_len..self.page_len + len].copy_from_slice(&data[..len]);
+            self.page_len += len;
+            self.info.data_len += len as u32;
+            data = &data[len..];
+            if self.page_len == PAGE_LEN {
+                self.flush(storage)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, storage: &mut Storage, frame: &Frame, now: Instant) -> Result<()> {
+        if self.info.frames > 0 && *frame == self.prev {
+            return Ok(());
+        }
+
+        let mut record = [0u8; MAX_RECORD_LEN];
+        let mut delay = (now - self.last).as_millis();
+        // Pad gaps longer than a record's delay with empty records.
+        while delay > u16::MAX as u64 {
+            let len = encode(u16::MAX, &self.prev, &self.prev, &mut record)?;
+            self.push(storage, &record[..len])?;
+            self.info.frames += 1;
+            delay -= u16::MAX as u64;
+        }
+        let len = encode(delay as u16, &self.prev, frame, &mut record)?;
+        self.push(storage, &record[..len])?;
+        self.info.frames += 1;
+        self.prev = *frame;
+        self.last = now;
+        Ok(())
+    }
+
+    fn finish(mut self, storage: &mut Storage, now: Instant) -> Result<ShowInfo> {
+        if self.page_len > 0 {
+            self.flush(storage)?;
+        }
+        self.info.length_ms = (now - self.start).as_millis() as u32;
+        self.info.crc = !self.info.crc;
+
+        let mut header = [0u8; HEADER_LEN];
+        self.info
+            .write(&mut MutBuffer::<LittleEndian>::new(&mut header))?;
+        storage.write_region(SHOW_REGION, 0, &header)?;
+        Ok(self.info)
+    }
+}
+
+struct Player {
+    info: ShowInfo,
+    frame: Frame,
+    /// Offset of the next record.
+    offset: u32,
+    /// Show time at which the next record's delay started.
+    time_ms: u32,
+    loop_start: Instant,
+}
+
+impl Player {
+    fn start(storage: &mut Storage, now: Instant) -> Result<Self> {
+        let info = ShowInfo::load(storage)?.ok_or(Error::Generic("no show recorded"))?;
+        if !info.verify(storage)? {
+            return Err(Error::Generic("show corrupt"));
+        }
+        Ok(Self {
+            info,
+            frame: [Rgb::BLACK; NUM_LEDS],
+            offset: 0,
+            time_ms: 0,
+            loop_start: now,
+        })
+    }
+
+    fn rewind(&mut self) {
+        self.frame = [Rgb::BLACK; NUM_LEDS];
+        self.offset = 0;
+        self.time_ms = 0;
+    }
+
+    /// Apply every record due by `show_time` milliseconds into the show.
+    /// Returns true if the frame changed.
+    fn play_until(&mut self, storage: &mut Storage, show_time: u32) -> Result<bool> {
+        let mut changed = false;
+        while self.offset < self.info.data_len {
+            let mut delay = [0u8; 2];
+            storage.read_region(SHOW_REGION, DATA_START + self.offset, &mut delay)?;
+            if show_time < self.time_ms + u16::from_le_bytes(delay) as u32 {
+                break;
+            }
+
+            let mut record = [0u8; MAX_RECORD_LEN];
+            let len = record
+                .len()
+                .min((self.info.data_len - self.offset) as usize);
+            storage.read_region(SHOW_REGION, DATA_START + self.offset, &mut record[..len])?;
+            let (delay_ms, len) = decode(&record[..len], &mut self.frame)?;
+            self.offset += len as u32;
+            self.time_ms += delay_ms as u32;
+            changed = true;
+        }
+        Ok(changed)
+    }
+
+    /// Apply every record that is due at `now`.  Returns true if the frame
+    /// changed.
+    fn advance(&mut self, storage: &mut Storage, now: Instant) -> Result<bool> {
+        let mut changed = false;
+        loop {
+            let show_time = (now - self.loop_start).as_millis() as u32;
+            changed |= self.play_until(storage, show_time)?;
+            // Loop once the show's full length has elapsed.
+            if self.offset < self.info.data_len
+                || show_time < self.info.length_ms
+                || self.info.length_ms == 0
+            {
+                return Ok(changed);
+            }
+            self.loop_start += Duration::from_millis(self.info.length_ms as u64);
+            self.rewind();
+        }
+    }
+
+    /// Follow an external clock to `show_time` milliseconds, wrapping at the
+    /// show's length.  Going backwards replays from the start of the show.
+    fn chase(&mut self, storage: &mut Storage, show_time: u32, now: Instant) -> Result<bool> {
+        let show_time = show_time.checked_rem(self.info.length_ms).unwrap_or(0);
+        let rewound = show_time < self.time_ms;
+        if rewound {
+            self.rewind();
+        }
+        // Keep the free running clock in step in case chasing stops.
+        self.loop_start = now
+            .checked_sub(Duration::from_millis(show_time as u64))
+            .unwrap_or(now);
+        Ok(self.play_until(storage, show_time)? || rewound)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum State {
+    Idle,
+    Recording,
+    Playing,
+}
+
+impl State {
+    pub fn name(&self) -> &'static str {
+        match self {
+            State::Idle => "idle",
+            State::Recording => "recording",
+            State::Playing => "playing",
+        }
+    }
+}
+
+/// Requests for the show task and its status.
+pub struct Show {
+    request: Option<State>,
+    state: State,
+    info: Option<ShowInfo>,
+}
+
+pub type SharedShow = Mutex<NoopRawMutex, Show>;
+
+impl Show {
+    pub const fn new() -> Self {
+        Self {
+            request: None,
+            state: State::Idle,
+            info: None,
+        }
+    }
+
+    pub fn record(&mut self) {
+        self.request = Some(State::Recording);
+    }
+
+    pub fn play(&mut self) {
+        self.request = Some(State::Playing);
+    }
+
+    pub fn stop(&mut self) {
+        self.request = Some(State::Idle);
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// The stored show, if any.
+    pub fn info(&self) -> Option<ShowInfo> {
+        self.info
+    }
+}
+
+enum Mode {
+    Idle,
+    Recording(Recorder),
+    Playing(Player),
+}
+
+impl Mode {
+    fn state(&self) -> State {
+        match self {
+            Mode::Idle => State::Idle,
+            Mode::Recording(_) => State::Recording,
+            Mode::Playing(_) => State::Playing,
+        }
+    }
+}
+
+async fn stop(mode: Mode, output: &SharedOutput, storage: &SharedStorage) {
+    match mode {
+        Mode::Idle => (),
+        Mode::Recording(recorder) => {
+            match recorder.finish(&mut *storage.lock().await, Instant::now()) {
+                Ok(info) => println!(
+                    "show: recorded {} frames, {} bytes, {} ms",
+                    info.frames, info.data_len, info.length_ms
+                ),
+                Err(e) => println!("show: failed to finish recording: {e:?}"),
+            }
+        }
+        Mode::Playing(_) => output.lock().await.show_frame = None,
+    }
+}
+
+async fn start(state: State, output: &SharedOutput, storage: &SharedStorage) -> Result<Mode> {
+    let now = Instant::now();
+    let mut storage = storage.lock().await;
+    match state {
+        State::Idle => Ok(Mode::Idle),
+        State::Recording => Ok(Mode::Recording(Recorder::start(&mut storage, now)?)),
+        State::Playing => {
+            let player = Player::start(&mut storage, now)?;
+            output.lock().await.show_frame = Some(player.frame);
+            Ok(Mode::Playing(player))
+        }
+    }
+}
+
+/// Records the pixels mapped from incoming Art-Net to flash or plays them
+/// back.
+///
+/// When chasing timecode, playback starts as soon as timecode locks and
+/// follows the timecode clock, holding the current frame if it drops out.
+#[embassy_executor::task]
+pub(crate) async fn task(
+    show: &'static SharedShow,
+    output: &'static SharedOutput,
+    storage: &'static SharedStorage,
+    clock: &'static SharedClock,
+) {
+    let mut mode = Mode::Idle;
+    let mut locked = false;
+    show.lock().await.info = ShowInfo::load(&mut *storage.lock().await).unwrap_or(None);
+    let mut next_tick = Instant::now();
+
+    loop {
+        // Flash erases can stall us for longer than a tick.  Don't try to
+        // catch up afterwards.
+        next_tick = (next_tick + TICK).max(Instant::now());
+        Timer::at(next_tick).await;
+
+        let (chasing, position) = {
+            let clock = clock.lock().await;
+            (
+                clock.config.chase == Chase::Show,
+                clock.position(Instant::now()),
+            )
+        };
+        if chasing && position.is_some() && !locked && mode.state() == State::Idle {
+            show.lock().await.play();
+        }
+        locked = position.is_some();
+
+        let request = show.lock().await.request.take();
+        if let Some(state) = request {
+            stop(core::mem::replace(&mut mode, Mode::Idle), output, storage).await;
+            match start(state, output, storage).await {
+                Ok(new_mode) => mode = new_mode,
+                Err(e) => println!("show: failed to start {}: {e:?}", state.name()),
+            }
+        }
+
+        let now = Instant::now();
+        let result = match &mut mode {
+            Mode::Idle => Ok(()),
+            Mode::Recording(recorder) => {
+                let mut output = output.lock().await;
+                // Record each frame as it arrives over Art-Net.
+                match output.universes.last_update() {
+                    Some(received) if received > recorder.received => {
+                        recorder.received = received;
+                        let mut frame = [Rgb::BLACK; NUM_LEDS];
+                        output.artnet_frame(&mut frame);
+                        drop(output);
+                        recorder.record(&mut *storage.lock().await, &frame, received)
+                    }
+                    _ => Ok(()),
+                }
+            }
+            Mode::Playing(player) => {
+                let changed = match (chasing, position) {
+                    (false, _) => player.advance(&mut *storage.lock().await, now),
+                    (true, Some(pos)) => player.chase(&mut *storage.lock().await, pos, now),
+                    (true, None) => Ok(false),
+                };
+                match changed {
+                    Ok(true) => {
+                        output.lock().await.show_frame = Some(player.frame);
+                        Ok(())
+                    }
+                    Ok(false) => Ok(()),
+                    Err(e) => Err(e),
+                }
+            }
+        };
+        if let Err(e) = result {
+            println!("show: {} stopped: {e:?}", mode.state().name());
+            stop(core::mem::replace(&mut mode, Mode::Idle), output, storage).await;
+        }
+
+        let mut show = show.lock().await;
+        if show.state == State::Recording && mode.state() != State::Recording {
+            show.info = ShowInfo::load(&mut *storage.lock().await).unwrap_or(None);
+        }
+        show.state = mode.state();
+    }
+}
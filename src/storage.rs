@@ -0,0 +1,214 @@
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use embedded_storage::{nor_flash, ReadStorage, Storage as _};
+use esp_storage::FlashStorage;
+
+use crate::{Error, Result};
+
+/// Start of the `storage` partition in `partitions.csv`.
+const STORAGE_OFFSET: u32 = 0x3c_0000;
+pub const SECTOR_SIZE: usize = 4096;
+
+const MAGIC: u32 = 0x3142_4752; // "RGB1"
+const HEADER_LEN: usize = 12;
+
+/// Largest payload a single slot can hold.
+pub const MAX_RECORD_LEN: usize = SECTOR_SIZE - HEADER_LEN;
+
+pub const MAX_PRESETS: usize = 16;
+pub const MAX_PLAYLISTS: usize = 4;
+
+/// Each slot is one flash sector holding a single record.
+#[derive(Clone, Copy, Debug)]
+pub enum Slot {
+    Scene,
+    Matrix,
+    Schedule,
+    Script,
+    Timecode,
+    Sync,
+    Auth,
+    /// Asks the next boot to start the Wi-Fi setup portal.
+    Portal,
+    /// Playlists occupy sectors 8 to 11.
+    Playlist(u8),
+    /// Saved Wi-Fi networks.
+    Networks,
+    /// Presets occupy sectors 16 and up.
+    Preset(u8),
+}
+
+impl Slot {
+    fn offset(self) -> u32 {
+        let sector = match self {
+            Slot::Scene => 0,
+            Slot::Matrix => 1,
+            Slot::Schedule => 2,
+            Slot::Script => 3,
+            Slot::Timecode => 4,
+            Slot::Sync => 5,
+            Slot::Auth => 6,
+            Slot::Portal => 7,
+            Slot::Playlist(index) => 8 + index as u32,
+            Slot::Networks => 12,
+            Slot::Preset(index) => 16 + index as u32,
+        };
+        STORAGE_OFFSET + sector * SECTOR_SIZE as u32
+    }
+}
+
+/// A raw area of flash for streamed data, outside of the record slots.
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub offset: u32,
+    pub len: u32,
+}
+
+/// The `show` partition in `partitions.csv`.
+pub const SHOW_REGION: Region = Region {
+    offset: 0x30_0000,
+    len: 0xc_0000,
+};
+
+/// The `otadata` partition, one sector for each of its two entries.
+pub const OTADATA_REGION: Region = Region {
+    offset: 0xd000,
+    len: 0x2000,
+};
+
+/// The `ota_0` and `ota_1` app partitions.
+pub const APP_REGIONS: [Region; 2] = [
+    Region {
+        offset: 0x1_0000,
+        len: 0x17_0000,
+    },
+    Region {
+        offset: 0x18_0000,
+        len: 0x17_0000,
+    },
+];
+
+/// The last four sectors of `storage`, which [`crate::settings`] cycles
+/// through.
+pub const SETTINGS_REGION: Region = Region {
+    offset: STORAGE_OFFSET + 60 * SECTOR_SIZE as u32,
+    len: 4 * SECTOR_SIZE as u32,
+};
+
+impl Region {
+    fn check(&self, offset: u32, len: usize) -> Result<u32> {
+        if offset as u64 + len as u64 > self.len as u64 {
+            return Err(Error::Index);
+        }
+        Ok(self.offset + offset)
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(0xffff_ffff, data)
+}
+
+/// Feed `data` into a running CRC.  Start from `0xffff_ffff` and invert the
+/// result once all data has been added.
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    crc
+}
+
+/// Versioned, CRC protected records in flash.
+///
+/// A record is stored as:
+///   magic: u32, version: u16, len: u16, crc32: u32, payload: [u8; len]
+pub struct Storage {
+    flash: FlashStorage,
+}
+
+pub type SharedStorage = Mutex<NoopRawMutex, Storage>;
+
+impl Storage {
+    pub fn new() -> Self {
+        Self {
+            flash: FlashStorage::new(),
+        }
+    }
+
+    /// Read the record in `slot` into `buf`.
+    ///
+    /// Returns the record's version and payload or `None` if the slot is
+    /// empty or corrupt.  Callers are responsible for migrating older
+    /// versions.
+    pub fn load<'a>(&mut self, slot: Slot, buf: &'a mut [u8]) -> Result<Option<(u16, &'a [u8])>> {
+        let mut header = [0u8; HEADER_LEN];
+        self.flash.read(slot.offset(), &mut header)?;
+
+        let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let version = u16::from_le_bytes(header[4..6].try_into().unwrap());
+        let len = u16::from_le_bytes(header[6..8].try_into().unwrap()) as usize;
+        let crc = u32::from_le_bytes(header[8..12].try_into().unwrap());
+
+        if magic != MAGIC || len > MAX_RECORD_LEN {
+            return Ok(None);
+        }
+        if len > buf.len() {
+            return Err(Error::Index);
+        }
+
+        let data = &mut buf[..len];
+        self.flash.read(slot.offset() + HEADER_LEN as u32, data)?;
+        if crc32(data) != crc {
+            return Ok(None);
+        }
+
+        Ok(Some((version, data)))
+    }
+
+    pub fn store(&mut self, slot: Slot, version: u16, data: &[u8]) -> Result<()> {
+        if data.len() > MAX_RECORD_LEN {
+            return Err(Error::Index);
+        }
+
+        let mut header = [0u8; HEADER_LEN];
+        header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..6].copy_from_slice(&version.to_le_bytes());
+        header[6..8].copy_from_slice(&(data.len() as u16).to_le_bytes());
+        header[8..12].copy_from_slice(&crc32(data).to_le_bytes());
+
+        // Write the payload first so that a power loss part way through
+        // leaves a record that fails its CRC check.
+        self.flash.write(slot.offset() + HEADER_LEN as u32, data)?;
+        self.flash.write(slot.offset(), &header)?;
+        Ok(())
+    }
+
+    pub fn erase(&mut self, slot: Slot) -> Result<()> {
+        self.flash.write(slot.offset(), &[0xff; HEADER_LEN])?;
+        Ok(())
+    }
+
+    pub fn read_region(&mut self, region: Region, offset: u32, buf: &mut [u8]) -> Result<()> {
+        let offset = region.check(offset, buf.len())?;
+        self.flash.read(offset, buf)?;
+        Ok(())
+    }
+
+    /// Erase the sector of `region` starting at `offset`.
+    pub fn erase_region(&mut self, region: Region, offset: u32) -> Result<()> {
+        let offset = region.check(offset, SECTOR_SIZE)?;
+        nor_flash::NorFlash::erase(&mut self.flash, offset, offset + SECTOR_SIZE as u32)?;
+        Ok(())
+    }
+
+    /// Write `data` to erased flash.  Unlike [`Storage::store`] this does
+    /// not erase first so `offset` and the length of `data` must be
+    /// multiples of four.
+    pub fn write_region(&mut self, region: Region, offset: u32, data: &[u8]) -> Result<()> {
+        let offset = region.check(offset, data.len())?;
+        nor_flash::NorFlash::write(&mut self.flash, offset, data)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,423 @@
+use byteorder::LittleEndian;
+use embassy_futures::select::{select, Either};
+use embassy_net::{udp::UdpSocket, IpAddress, Ipv4Address, PacketMetadata, Stack};
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant, Timer};
+use esp_println::println;
+use esp_wifi::wifi::WifiDevice;
+use smoltcp::wire::IpEndpoint;
+
+use crate::buffer::{MutBuffer, OldBuffer};
+use crate::output::{Scene, SharedOutput, SCENE_MAX_LEN};
+use crate::storage::{Slot, Storage};
+use crate::{Error, Result};
+
+const SYNC_VERSION: u16 = 1;
+const CONFIG_LEN: usize = 3;
+
+/// Nodes find each other on this multicast group.
+pub const SYNC_GROUP: Ipv4Address = Ipv4Address([239, 255, 82, 71]);
+pub const SYNC_PORT: u16 = 6460;
+
+const MAGIC: [u8; 8] = *b"rgb-sync";
+const PROTOCOL_VERSION: u8 = 1;
+const BEACON_MAX_LEN: usize = MAGIC.len() + 16 + SCENE_MAX_LEN;
+
+const NO_PRESET: u8 = 0xff;
+
+const TICK: Duration = Duration::from_millis(50);
+/// The leader sends a beacon at least this often, and straight away when
+/// its content changes.
+const BEACON_INTERVAL: Duration = Duration::from_millis(250);
+/// A leader that has been quiet for this long is gone.
+const LEADER_TIMEOUT: Duration = Duration::from_millis(1000);
+/// Extra time to listen before claiming leadership for each step of
+/// priority below the maximum, so that the preferred node usually wins
+/// without a contest.
+const ELECTION_STAGGER_MS: u64 = 4;
+
+/// Clock errors larger than this are corrected in one step.  Smaller ones
+/// are slewed out over a few beacons so that effects don't stutter.
+const CLOCK_JUMP_MS: u32 = 50;
+const CLOCK_SLEW: i32 = 4;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Config {
+    pub enabled: bool,
+    /// Nodes only sync with others in the same group.
+    pub group: u8,
+    /// The highest priority node becomes leader.  Ties go to the highest
+    /// IP address.
+    pub priority: u8,
+}
+
+impl Config {
+    pub const fn new() -> Self {
+        Self {
+            enabled: false,
+            group: 0,
+            priority: 128,
+        }
+    }
+
+    fn write(&self, buf: &mut MutBuffer<LittleEndian>) -> Result<()> {
+        buf.write_u8(self.enabled as u8)?;
+        buf.write_u8(self.group)?;
+        buf.write_u8(self.priority)?;
+        Ok(())
+    }
+
+    fn parse(buf: &mut OldBuffer<LittleEndian>) -> Result<Self> {
+        Ok(Self {
+            enabled: buf.read_u8()? != 0,
+            group: buf.read_u8()?,
+            priority: buf.read_u8()?,
+        })
+    }
+
+    pub fn load(storage: &mut Storage) -> Result<Option<Self>> {
+        let mut data = [0u8; CONFIG_LEN];
+        let Some((version, data)) = storage.load(Slot::Sync, &mut data)? else {
+            return Ok(None);
+        };
+        if version != SYNC_VERSION {
+            return Ok(None);
+        }
+        Self::parse(&mut OldBuffer::new(data)).map(Some)
+    }
+
+    pub fn save(&self, storage: &mut Storage) -> Result<()> {
+        let mut data = [0u8; CONFIG_LEN];
+        let buf = &mut MutBuffer::<LittleEndian>::new(&mut data);
+        self.write(buf)?;
+        let len = buf.pos();
+        storage.store(Slot::Sync, SYNC_VERSION, &data[..len])
+    }
+}
+
+/// The state a leader shares with its followers.
+///
+///   magic: [u8; 8], version: u8, group: u8, priority: u8, id: u32,
+///   clock_ms: u32, preset: u8, fade_ms: u32, scene
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Beacon {
+    pub group: u8,
+    pub priority: u8,
+    /// The sender's IPv4 address.
+    pub id: u32,
+    /// The sender's effect clock.
+    pub clock_ms: u32,
+    pub preset: Option<u8>,
+    /// What is left of the sender's crossfade into `scene`.
+    pub fade_ms: u32,
+    pub scene: Scene,
+}
+
+impl Beacon {
+    pub fn write(&self, buf: &mut MutBuffer<LittleEndian>) -> Result<()> {
+        buf.write(&MAGIC)?;
+        buf.write_u8(PROTOCOL_VERSION)?;
+        buf.write_u8(self.group)?;
+        buf.write_u8(self.priority)?;
+        buf.write_u32(self.id)?;
+        buf.write_u32(self.clock_ms)?;
+        buf.write_u8(self.preset.unwrap_or(NO_PRESET))?;
+        buf.write_u32(self.fade_ms)?;
+        self.scene.write(buf)
+    }
+
+    pub fn parse(buf: &mut OldBuffer<LittleEndian>) -> Result<Self> {
+        if buf.read::<8>()? != MAGIC {
+            return Err(Error::Generic("not a sync packet"));
+        }
+        if buf.read_u8()? != PROTOCOL_VERSION {
+            return Err(Error::Generic("unsupported sync version"));
+        }
+        Ok(Self {
+            group: buf.read_u8()?,
+            priority: buf.read_u8()?,
+            id: buf.read_u32()?,
+            clock_ms: buf.read_u32()?,
+            preset: Some(buf.read_u8()?).filter(|p| *p != NO_PRESET),
+            fade_ms: buf.read_u32()?,
+            scene: Scene::parse(buf)?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Role {
+    Off,
+    /// Waiting to hear from a leader before claiming leadership.
+    Listening,
+    Follower,
+    Leader,
+}
+
+impl Role {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Off => "off",
+            Role::Listening => "listening",
+            Role::Follower => "follower",
+            Role::Leader => "leader",
+        }
+    }
+}
+
+/// A node we have heard a beacon from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Peer {
+    pub id: u32,
+    pub priority: u8,
+    pub last_seen: Instant,
+}
+
+/// Our place among the nodes syncing on the network.
+///
+/// A node listens for a leader for a while after starting.  If it hears
+/// none it becomes leader itself.  Should two nodes lead at once, the lower
+/// ranked one steps down as soon as it hears the other.  A live leader is
+/// never displaced by a higher ranked node joining later, since taking
+/// over would make every node jump.
+pub struct Group {
+    pub config: Config,
+    /// Our IPv4 address, once we have one.
+    id: u32,
+    role: Role,
+    leader: Option<Peer>,
+    listen_start: Instant,
+}
+
+pub type SharedGroup = Mutex<NoopRawMutex, Group>;
+
+impl Group {
+    pub const fn new(config: Config) -> Self {
+        Self {
+            config,
+            id: 0,
+            role: Role::Off,
+            leader: None,
+            listen_start: Instant::from_ticks(0),
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// The node we are following.
+    pub fn leader(&self) -> Option<Peer> {
+        self.leader.filter(|_| self.role == Role::Follower)
+    }
+
+    /// (Re)start the election as node `id`, or stop syncing if disabled.
+    pub fn start(&mut self, id: u32, now: Instant) {
+        self.id = id;
+        self.leader = None;
+        self.listen_start = now;
+        self.role = if self.config.enabled {
+            Role::Listening
+        } else {
+            Role::Off
+        };
+    }
+
+    /// Apply a new configuration, restarting the election.
+    pub fn set_config(&mut self, config: Config, now: Instant) {
+        self.config = config;
+        if self.id != 0 {
+            self.start(self.id, now);
+        }
+    }
+
+    /// Handle a beacon from another node.  Returns true if we follow it and
+    /// should take on its state.
+    pub fn receive(&mut self, beacon: &Beacon, now: Instant) -> bool {
+        if self.role == Role::Off || beacon.group != self.config.group || beacon.id == self.id {
+            return false;
+        }
+        let rank = (beacon.priority, beacon.id);
+        match self.role {
+            // The other leader will step down when it hears from us.
+            Role::Leader if rank < (self.config.priority, self.id) => return false,
+            // Stick with the current leader unless this one outranks it.
+            Role::Follower => {
+                if let Some(leader) = self.leader {
+                    if leader.id != beacon.id
+                        && now - leader.last_seen < LEADER_TIMEOUT
+                        && rank < (leader.priority, leader.id)
+                    {
+                        return false;
+                    }
+                }
+            }
+            _ => (),
+        }
+        if self.role != Role::Follower || self.leader.map(|l| l.id) != Some(beacon.id) {
+            println!("sync: following {:08x}", beacon.id);
+        }
+        self.role = Role::Follower;
+        self.leader = Some(Peer {
+            id: beacon.id,
+            priority: beacon.priority,
+            last_seen: now,
+        });
+        true
+    }
+
+    /// Run the election timers.  Returns true while we are leader.
+    pub fn poll(&mut self, now: Instant) -> bool {
+        match self.role {
+            Role::Follower => {
+                if self
+                    .leader
+                    .map_or(true, |l| now - l.last_seen > LEADER_TIMEOUT)
+                {
+                    println!("sync: lost leader");
+                    self.leader = None;
+                    self.role = Role::Listening;
+                    self.listen_start = now;
+                }
+            }
+            Role::Listening => {
+                let stagger = (255 - self.config.priority) as u64 * ELECTION_STAGGER_MS;
+                if now - self.listen_start > LEADER_TIMEOUT + Duration::from_millis(stagger) {
+                    println!("sync: leading");
+                    self.role = Role::Leader;
+                }
+            }
+            Role::Off | Role::Leader => (),
+        }
+        self.role == Role::Leader
+    }
+}
+
+/// New clock offset that moves our effect clock, `local_ms + offset_ms`,
+/// towards the leader's `leader_ms`.
+pub fn steer(offset_ms: u32, local_ms: u32, leader_ms: u32) -> u32 {
+    let error = leader_ms.wrapping_sub(local_ms.wrapping_add(offset_ms)) as i32;
+    if error.unsigned_abs() > CLOCK_JUMP_MS {
+        leader_ms.wrapping_sub(local_ms)
+    } else {
+        offset_ms.wrapping_add((error / CLOCK_SLEW) as u32)
+    }
+}
+
+/// Take on the leader's clock and content.
+async fn follow(output: &SharedOutput, beacon: &Beacon) {
+    let time_ms = Instant::now().as_millis() as u32;
+    let mut output = output.lock().await;
+    output.clock_offset_ms = steer(output.clock_offset_ms, time_ms, beacon.clock_ms);
+    if output.scene.segments() != beacon.scene.segments() {
+        output.set_scene(beacon.scene, time_ms, beacon.fade_ms);
+    }
+    output.preset = beacon.preset;
+}
+
+/// Keeps local effects in step with the other nodes on the network.
+#[embassy_executor::task]
+pub(crate) async fn task(
+    stack: &'static Stack<WifiDevice<'static>>,
+    group: &'static SharedGroup,
+    output: &'static SharedOutput,
+) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 8];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 512];
+    let mut buf = [0; BEACON_MAX_LEN];
+
+    let my_address = loop {
+        if let Some(config) = stack.config() {
+            break config.address.address();
+        }
+        Timer::after(Duration::from_millis(500)).await;
+    };
+    if let Err(e) = stack.join_multicast_group(SYNC_GROUP) {
+        println!("sync: failed to join multicast group: {e:?}");
+    }
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(SYNC_PORT).unwrap();
+    let id = u32::from_be_bytes(my_address.0);
+    group.lock().await.start(id, Instant::now());
+
+    let group_endpoint = IpEndpoint {
+        addr: IpAddress::Ipv4(SYNC_GROUP),
+        port: SYNC_PORT,
+    };
+    let mut next_tick = Instant::now();
+    let mut last_sent: Option<(Instant, Option<u8>, Scene)> = None;
+    loop {
+        match select(socket.recv_from(&mut buf), Timer::at(next_tick)).await {
+            Either::First(Ok((length, _))) => {
+                let Ok(beacon) = Beacon::parse(&mut OldBuffer::new(&buf[..length])) else {
+                    continue;
+                };
+                if group.lock().await.receive(&beacon, Instant::now()) {
+                    follow(output, &beacon).await;
+                }
+                continue;
+            }
+            Either::First(Err(e)) => {
+                println!("sync: receive failed: {e:?}");
+                continue;
+            }
+            // Don't try to catch up on ticks missed while busy.
+            Either::Second(()) => next_tick = (next_tick + TICK).max(Instant::now()),
+        }
+
+        let now = Instant::now();
+        let (leading, config) = {
+            let mut group = group.lock().await;
+            (group.poll(now), group.config)
+        };
+        if !leading {
+            last_sent = None;
+            continue;
+        }
+
+        let beacon = {
+            let output = output.lock().await;
+            let time_ms = now.as_millis() as u32;
+            Beacon {
+                group: config.group,
+                priority: config.priority,
+                id,
+                clock_ms: output.effect_ms(time_ms),
+                preset: output.preset,
+                fade_ms: output.fade_remaining(time_ms),
+                scene: output.scene,
+            }
+        };
+        let due = match &last_sent {
+            Some((at, preset, scene)) => {
+                now - *at >= BEACON_INTERVAL
+                    || *preset != beacon.preset
+                    || scene.segments() != beacon.scene.segments()
+            }
+            None => true,
+        };
+        if !due {
+            continue;
+        }
+
+        let mut packet = MutBuffer::<LittleEndian>::new(&mut buf);
+        if let Err(e) = beacon.write(&mut packet) {
+            println!("sync: failed to build beacon: {e:?}");
+            continue;
+        }
+        let len = packet.pos();
+        if let Err(e) = socket.send_to(&buf[..len], group_endpoint).await {
+            println!("sync: send failed: {e:?}");
+        }
+        last_sent = Some((now, beacon.preset, beacon.scene));
+    }
+}
@@ -0,0 +1,100 @@
+use crate::color::Rgb;
+use crate::font::font;
+use crate::matrix::Canvas;
+
+pub const MAX_TEXT_LEN: usize = 64;
+
+/// A message scrolled across a matrix from right to left.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Text {
+    buf: [u8; MAX_TEXT_LEN],
+    len: u8,
+    /// Index into [`crate::font::FONTS`].
+    pub font: u8,
+    pub color: Rgb,
+    /// Fill for the rest of the matrix.  `None` draws the text over whatever
+    /// the scene rendered.
+    pub background: Option<Rgb>,
+    /// Scroll speed in pixels per second.  At 0 the text is drawn still,
+    /// starting at the left edge.
+    pub speed: u8,
+    /// Number of times the text scrolls across before it is removed.  0
+    /// scrolls forever.
+    pub repeat: u8,
+    /// Time at which the text enters from the right edge.
+    pub start_ms: u32,
+}
+
+impl Text {
+    /// Create a text from `text`.  Text longer than [`MAX_TEXT_LEN`] is
+    /// truncated.
+    pub fn new(text: &[u8], start_ms: u32) -> Self {
+        let mut new = Self {
+            buf: [0; MAX_TEXT_LEN],
+            len: 0,
+            font: 0,
+            color: Rgb::WHITE,
+            background: Some(Rgb::BLACK),
+            speed: 20,
+            repeat: 0,
+            start_ms,
+        };
+        let len = text.len().min(MAX_TEXT_LEN);
+        new.buf[..len].copy_from_slice(&text[..len]);
+        new.len = len as u8;
+        new
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len as usize]
+    }
+
+    /// Distance the text travels in one pass across a `width` wide display.
+    fn travel(&self, width: u16) -> u32 {
+        (width + font(self.font).text_width(self.as_bytes())) as u32 + 1
+    }
+
+    fn offset(&self, time_ms: u32) -> u32 {
+        time_ms.wrapping_sub(self.start_ms) / 10 * self.speed as u32 / 100
+    }
+
+    /// True once the text has made `repeat` passes across a `width` wide
+    /// display.
+    pub fn is_finished(&self, time_ms: u32, width: u16) -> bool {
+        self.repeat != 0
+            && self.speed != 0
+            && self.offset(time_ms) / self.travel(width) >= self.repeat as u32
+    }
+
+    pub fn render(&self, time_ms: u32, canvas: &mut Canvas) {
+        if let Some(background) = self.background {
+            canvas.fill(background);
+        }
+
+        let font = font(self.font);
+        let x = if self.speed == 0 {
+            0
+        } else {
+            let travel = self.travel(canvas.width());
+            canvas.width() as i32 - (self.offset(time_ms) % travel) as i32
+        };
+        let y = (canvas.height() as i32 - font.height as i32) / 2;
+
+        for (i, c) in self.as_bytes().iter().enumerate() {
+            let glyph_x = x + i as i32 * font.advance() as i32;
+            if glyph_x >= canvas.width() as i32 {
+                break;
+            }
+            if glyph_x + (font.width as i32) < 0 {
+                continue;
+            }
+            for (col, bits) in font.glyph(*c).iter().enumerate() {
+                for row in 0..font.height {
+                    if bits & (1 << row) != 0 {
+                        canvas.set_signed(glyph_x + col as i32, y + row as i32, self.color);
+                    }
+                }
+            }
+        }
+    }
+}
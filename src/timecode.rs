@@ -0,0 +1,256 @@
+use byteorder::LittleEndian;
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant};
+use num_derive::{FromPrimitive, ToPrimitive};
+use num_traits::FromPrimitive;
+
+use crate::buffer::{MutBuffer, OldBuffer};
+use crate::storage::{Slot, Storage};
+use crate::{Error, Result};
+
+const TIMECODE_VERSION: u16 = 1;
+const CONFIG_LEN: usize = 14;
+
+/// Timecode that hasn't changed for this long is parked, for instance while
+/// the controller is paused, and the clock stops with it.
+const PARKED_AFTER: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, FromPrimitive, ToPrimitive)]
+#[repr(u8)]
+pub enum TimecodeType {
+    Film = 0,
+    Ebu = 1,
+    DropFrame = 2,
+    Smpte = 3,
+}
+
+impl TimecodeType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TimecodeType::Film => "film",
+            TimecodeType::Ebu => "ebu",
+            TimecodeType::DropFrame => "df",
+            TimecodeType::Smpte => "smpte",
+        }
+    }
+
+    /// Frames per second as counted in the timecode.  Drop frame counts 30
+    /// but runs at 29.97.
+    pub fn fps(&self) -> u8 {
+        match self {
+            TimecodeType::Film => 24,
+            TimecodeType::Ebu => 25,
+            TimecodeType::DropFrame | TimecodeType::Smpte => 30,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Timecode {
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+    pub frames: u8,
+    pub kind: TimecodeType,
+}
+
+impl Timecode {
+    /// Validate the fields of a received timecode.
+    pub fn new(hours: u8, minutes: u8, seconds: u8, frames: u8, kind: u8) -> Option<Self> {
+        let kind = TimecodeType::from_u8(kind)?;
+        if hours >= 24 || minutes >= 60 || seconds >= 60 || frames >= kind.fps() {
+            return None;
+        }
+        Some(Self {
+            hours,
+            minutes,
+            seconds,
+            frames,
+            kind,
+        })
+    }
+
+    /// Milliseconds since 00:00:00:00.
+    pub fn to_ms(&self) -> u32 {
+        let seconds = self.hours as u32 * 3600 + self.minutes as u32 * 60 + self.seconds as u32;
+        if self.kind == TimecodeType::DropFrame {
+            // Frame numbers 0 and 1 are skipped at the start of every minute
+            // except each tenth, so count the real frames and play them at
+            // 29.97 fps.
+            let minutes = self.hours as u32 * 60 + self.minutes as u32;
+            let frame = seconds * 30 + self.frames as u32 - 2 * (minutes - minutes / 10);
+            (frame as u64 * 1001 / 30) as u32
+        } else {
+            seconds * 1000 + self.frames as u32 * 1000 / self.kind.fps() as u32
+        }
+    }
+}
+
+impl core::fmt::Display for Timecode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let separator = if self.kind == TimecodeType::DropFrame {
+            ';'
+        } else {
+            ':'
+        };
+        write!(
+            f,
+            "{:02}:{:02}:{:02}{separator}{:02}",
+            self.hours, self.minutes, self.seconds, self.frames
+        )
+    }
+}
+
+/// What follows the timecode clock.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Chase {
+    Off,
+    /// The recorded show.
+    Show,
+    Playlist(u8),
+}
+
+impl Chase {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Chase::Off => "off",
+            Chase::Show => "show",
+            Chase::Playlist(_) => "playlist",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Config {
+    pub chase: Chase,
+    /// Added to the received timecode, so that content can start at a
+    /// timecode other than zero.
+    pub offset_ms: i32,
+    /// How long to keep running after timecode stops arriving.
+    pub freewheel_ms: u32,
+    /// Differences between the received timecode and the local clock below
+    /// this are treated as jitter.  Larger ones make the clock jump.
+    pub jump_ms: u32,
+}
+
+impl Config {
+    pub const fn new() -> Self {
+        Self {
+            chase: Chase::Off,
+            offset_ms: 0,
+            freewheel_ms: 2000,
+            jump_ms: 100,
+        }
+    }
+
+    fn write(&self, buf: &mut MutBuffer<LittleEndian>) -> Result<()> {
+        let (chase, playlist) = match self.chase {
+            Chase::Off => (0, 0),
+            Chase::Show => (1, 0),
+            Chase::Playlist(index) => (2, index),
+        };
+        buf.write_u8(chase)?;
+        buf.write_u8(playlist)?;
+        buf.write_u32(self.offset_ms as u32)?;
+        buf.write_u32(self.freewheel_ms)?;
+        buf.write_u32(self.jump_ms)?;
+        Ok(())
+    }
+
+    fn parse(buf: &mut OldBuffer<LittleEndian>) -> Result<Self> {
+        let chase = match (buf.read_u8()?, buf.read_u8()?) {
+            (0, _) => Chase::Off,
+            (1, _) => Chase::Show,
+            (2, index) => Chase::Playlist(index),
+            _ => return Err(Error::Generic("bad chase target")),
+        };
+        Ok(Self {
+            chase,
+            offset_ms: buf.read_u32()? as i32,
+            freewheel_ms: buf.read_u32()?,
+            jump_ms: buf.read_u32()?,
+        })
+    }
+
+    pub fn load(storage: &mut Storage) -> Result<Option<Self>> {
+        let mut data = [0u8; CONFIG_LEN];
+        let Some((version, data)) = storage.load(Slot::Timecode, &mut data)? else {
+            return Ok(None);
+        };
+        if version != TIMECODE_VERSION {
+            return Ok(None);
+        }
+        Self::parse(&mut OldBuffer::new(data)).map(Some)
+    }
+
+    pub fn save(&self, storage: &mut Storage) -> Result<()> {
+        let mut data = [0u8; CONFIG_LEN];
+        let buf = &mut MutBuffer::<LittleEndian>::new(&mut data);
+        self.write(buf)?;
+        let len = buf.pos();
+        storage.store(Slot::Timecode, TIMECODE_VERSION, &data[..len])
+    }
+}
+
+/// A millisecond clock locked to received ArtTimeCode.
+///
+/// Timecode only arrives once a frame, so between frames the clock runs
+/// locally, and it keeps running for a while if timecode drops out.
+pub struct Clock {
+    pub config: Config,
+    /// The last timecode received and when it arrived.
+    last: Option<(Timecode, Instant)>,
+    /// When the received timecode last changed.
+    changed_at: Instant,
+    /// Clock position in milliseconds and the instant it was taken at.
+    anchor: (u32, Instant),
+    running: bool,
+}
+
+pub type SharedClock = Mutex<NoopRawMutex, Clock>;
+
+impl Clock {
+    pub const fn new(config: Config) -> Self {
+        Self {
+            config,
+            last: None,
+            changed_at: Instant::from_ticks(0),
+            anchor: (0, Instant::from_ticks(0)),
+            running: false,
+        }
+    }
+
+    pub fn last(&self) -> Option<Timecode> {
+        self.last.map(|(timecode, _)| timecode)
+    }
+
+    pub fn receive(&mut self, timecode: Timecode, now: Instant) {
+        let target = (timecode.to_ms() as i64 + self.config.offset_ms as i64).max(0) as u32;
+        let current = self.position(now);
+
+        if self.last() != Some(timecode) {
+            self.changed_at = now;
+        }
+        let running = now - self.changed_at < PARKED_AFTER;
+        let jitter = current.map_or(false, |pos| pos.abs_diff(target) <= self.config.jump_ms);
+        if !(running && self.running && jitter) {
+            self.anchor = (target, now);
+        }
+        self.running = running;
+        self.last = Some((timecode, now));
+    }
+
+    /// Current position in milliseconds, or `None` if there is no timecode
+    /// or it has been gone for longer than the freewheel time.
+    pub fn position(&self, now: Instant) -> Option<u32> {
+        let (_, received_at) = self.last?;
+        if (now - received_at).as_millis() > self.config.freewheel_ms as u64 {
+            return None;
+        }
+        let (pos, at) = self.anchor;
+        if !self.running {
+            return Some(pos);
+        }
+        Some(pos.wrapping_add((now - at).as_millis() as u32))
+    }
+}
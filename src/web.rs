@@ -1,192 +1,1202 @@
+use core::fmt::Write as _;
+use core::str::FromStr;
+
 use embassy_net::tcp::TcpSocket;
+use embassy_net::Stack;
 use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
-use embedded_hal_async::i2c::I2c;
-use embedded_io::asynch::Write;
+use embassy_time::Instant;
 use esp32c3_hal::i2c::I2C;
 use esp32c3_hal::peripherals::I2C0;
 use esp_println::println;
+use esp_wifi::wifi::WifiDevice;
 
+use crate::api::{self, Resource};
+use crate::assets::{self, Asset};
+use crate::auth::{self, Role, SharedAuth};
+use crate::color::{palette_index, Rgb};
+use crate::effects::{Effect, EffectKind};
+use crate::font::{font, font_index};
+use crate::http::{
+    self, Connection, Method, Request, Route, Status, MAX_HEADERS, MAX_HEAD_LEN, TEXT,
+};
+#[cfg(feature = "i2c-debug")]
+use crate::i2cdebug::{self, Command as I2cCommand};
+use crate::matrix::{Corner, Layout, Rotation};
+use crate::networks::{self, SharedNetworks};
+use crate::ota::{self, Ota};
+use crate::output::{Scene, Segment, SharedOutput, Source, NUM_LEDS};
+use crate::pd::SharedPowerStatus;
+use crate::playlist::{self, Cue, Playlist, SharedPlayer};
+use crate::preset::{self, NAME_LEN};
+use crate::provision::{self, SharedPortal};
+use crate::schedule::{Entry, Schedule, SharedSchedule, ALL_DAYS};
+use crate::script::{Script, FRAME_BUDGET, MAX_SOURCE_LEN};
+use crate::settings::{self, SharedSettings};
+use crate::show::SharedShow;
+use crate::storage::{SharedStorage, MAX_PLAYLISTS, MAX_PRESETS, MAX_RECORD_LEN};
+use crate::sync::SharedGroup;
+use crate::text::{Text, MAX_TEXT_LEN};
+use crate::timecode::{Chase, SharedClock};
+use crate::websocket;
 use crate::{Error, Result};
 
-async fn send_static_gzip(socket: &mut TcpSocket<'_>, data: &[u8]) -> Result<()> {
-    socket
-        .write_all(
-            b"HTTP/1.0 200 OK\r\nContent-type: image/svg+xml\r\nContent-Encoding: gzip\r\n\r\n",
+/// `core::fmt::Write` adapter over a fixed size byte buffer.
+pub(crate) struct FmtBuffer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> FmtBuffer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl<'a> core::fmt::Write for FmtBuffer<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        if end > self.buf.len() {
+            return Err(core::fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+pub(crate) fn query_param<'a>(query: &'a str, key: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+pub(crate) fn parse_param<T: FromStr>(query: &str, key: &'static str) -> Result<Option<T>> {
+    query_param(query, key)
+        .map(|val| val.parse().map_err(|_| Error::Generic(key)))
+        .transpose()
+}
+
+/// Decode a `application/x-www-form-urlencoded` value into `buf`.
+pub(crate) fn url_decode<'a>(val: &str, buf: &'a mut [u8]) -> Result<&'a [u8]> {
+    let mut bytes = val.bytes();
+    let mut len = 0;
+    while let Some(b) = bytes.next() {
+        let decoded = match b {
+            b'+' => b' ',
+            b'%' => {
+                let hex = [
+                    bytes.next().ok_or(Error::Generic("Bad escape"))?,
+                    bytes.next().ok_or(Error::Generic("Bad escape"))?,
+                ];
+                let hex = core::str::from_utf8(&hex).map_err(|_| Error::Generic("Bad escape"))?;
+                u8::from_str_radix(hex, 16).map_err(|_| Error::Generic("Bad escape"))?
+            }
+            b => b,
+        };
+        *buf.get_mut(len).ok_or(Error::Generic("Value too long"))? = decoded;
+        len += 1;
+    }
+    Ok(&buf[..len])
+}
+
+fn parse_color(val: &str) -> Result<Rgb> {
+    u32::from_str_radix(val, 16)
+        .map(Rgb::from_u32)
+        .map_err(|_| Error::Generic("Can't parse color"))
+}
+
+/// Serve an embedded file.  HTML is revalidated on every load so a
+/// firmware update shows up straight away; everything else may be cached
+/// for a while.
+async fn send_asset(conn: &mut Connection<'_, '_>, req: &Request<'_>, asset: &Asset) -> Result<()> {
+    let cache = if asset.mime.starts_with("text/html") {
+        "no-cache"
+    } else {
+        "max-age=3600"
+    };
+    let headers = [
+        ("ETag", asset.etag),
+        ("Cache-Control", cache),
+        ("Content-Type", asset.mime),
+        ("Content-Encoding", "gzip"),
+    ];
+
+    let cached = req.header("if-none-match").map_or(false, |tags| {
+        tags.split(',').any(|tag| tag.trim() == asset.etag)
+    });
+    // A 304 has no body, so it only carries the validators.
+    if cached {
+        return conn.send(Status::NotModified, &headers[..2], &[]).await;
+    }
+    let headers = if asset.gzip {
+        &headers[..]
+    } else {
+        &headers[..3]
+    };
+    conn.send(Status::Ok, headers, asset.data).await
+}
+
+async fn send_segments(conn: &mut Connection<'_, '_>, scene: &Scene) -> Result<()> {
+    let mut buffer = [0u8; 1024];
+    let mut text = FmtBuffer::new(&mut buffer);
+    for (i, segment) in scene.segments().iter().enumerate() {
+        write!(
+            text,
+            "{i}: start={} len={} reverse={} mirror={} group={} brightness={} ",
+            segment.start,
+            segment.len,
+            segment.reverse as u8,
+            segment.mirror as u8,
+            segment.group,
+            segment.brightness
         )
-        .await?;
-    socket.write_all(data).await?;
+        .map_err(|_| Error::Index)?;
+        let source = match &segment.source {
+            Source::Universe {
+                port_address,
+                offset,
+            } => write!(text, "universe={port_address} offset={offset}\n"),
+            Source::Effect(effect) => write!(
+                text,
+                "effect={} palette={} speed={} scale={} color={:06x}\n",
+                effect.kind.name(),
+                effect.palette_name(),
+                effect.speed,
+                effect.scale,
+                effect.color.to_u32()
+            ),
+            Source::Solid(color) => write!(text, "color={:06x}\n", color.to_u32()),
+            Source::Script => write!(text, "script=1\n"),
+        };
+        source.map_err(|_| Error::Index)?;
+    }
+
+    conn.start(Status::Ok, TEXT).await?;
+    conn.write(text.as_bytes()).await?;
     Ok(())
 }
 
-async fn send_static(socket: &mut TcpSocket<'_>, data: &[u8]) -> Result<()> {
-    socket.write_all(b"HTTP/1.0 200 OK\r\n\r\n").await?;
-    socket.write_all(data).await?;
+/// Apply the segment parameters in `query` on top of `segment`.
+fn update_segment(segment: &mut Segment, query: &str) -> Result<()> {
+    if let Some(start) = parse_param(query, "start")? {
+        segment.start = start;
+    }
+    if let Some(len) = parse_param(query, "len")? {
+        segment.len = len;
+    }
+    if let Some(reverse) = parse_param::<u8>(query, "reverse")? {
+        segment.reverse = reverse != 0;
+    }
+    if let Some(mirror) = parse_param::<u8>(query, "mirror")? {
+        segment.mirror = mirror != 0;
+    }
+    if let Some(group) = parse_param(query, "group")? {
+        segment.group = group;
+    }
+    if let Some(brightness) = parse_param(query, "brightness")? {
+        segment.brightness = brightness;
+    }
+
+    if let Some(port_address) = parse_param(query, "universe")? {
+        segment.source = Source::Universe {
+            port_address,
+            offset: parse_param(query, "offset")?.unwrap_or(0),
+        };
+    } else if let Some(name) = query_param(query, "effect") {
+        let mut effect = match segment.source {
+            Source::Effect(effect) => effect,
+            _ => Effect::new(EffectKind::Rainbow),
+        };
+        effect.kind = EffectKind::from_name(name).ok_or(Error::Generic("Unknown effect"))?;
+        if let Some(palette) = query_param(query, "palette") {
+            effect.palette = palette_index(palette).ok_or(Error::Generic("Unknown palette"))?;
+        }
+        if let Some(speed) = parse_param(query, "speed")? {
+            effect.speed = speed;
+        }
+        if let Some(scale) = parse_param(query, "scale")? {
+            effect.scale = scale;
+        }
+        if let Some(color) = query_param(query, "color") {
+            effect.color = parse_color(color)?;
+        }
+        segment.source = Source::Effect(effect);
+    } else if let Some(color) = query_param(query, "color") {
+        segment.source = Source::Solid(parse_color(color)?);
+    } else if query_param(query, "script").is_some() {
+        segment.source = Source::Script;
+    }
+
     Ok(())
 }
 
-async fn i2c_read<I2C, E>(
-    socket: &mut TcpSocket<'_>,
-    i2c: &Mutex<NoopRawMutex, &'static mut I2C>,
-    dev_addr: u8,
-    reg_addr: u8,
-) -> Result<()>
-where
-    I2C: I2c<Error = E>,
-    Error: From<E>,
-{
-    let mut i2c = i2c.lock().await;
-    let mut buffer = [0u8];
-    println!("reading {reg_addr:x} from {dev_addr:x}");
-    i2c.write_read(dev_addr, &[reg_addr], &mut buffer).await?;
-    println!("{buffer:x?}");
-    socket
-        .write_all(b"HTTP/1.0 200 OK\r\n\r\nlook at console")
-        .await?;
+async fn handle_segments(
+    conn: &mut Connection<'_, '_>,
+    ctx: &Context,
+    req: &Request<'_>,
+) -> Result<()> {
+    let (output, storage, query) = (ctx.output, ctx.storage, req.query);
+    let mut scene = output.lock().await.scene;
+
+    match (req.param("command"), req.param("index")) {
+        (None, _) => return send_segments(conn, &scene).await,
+        (Some("set"), Some(index_str)) => {
+            let index =
+                usize::from_str(index_str).map_err(|_| Error::Generic("Can't parse index"))?;
+            let mut segment = scene.segments().get(index).copied().unwrap_or(Segment::new(
+                0,
+                0,
+                Source::Solid(Rgb::BLACK),
+            ));
+            update_segment(&mut segment, query)?;
+            scene.set(index, segment)?;
+        }
+        (Some("remove"), Some(index_str)) => {
+            let index =
+                usize::from_str(index_str).map_err(|_| Error::Generic("Can't parse index"))?;
+            scene.remove(index)?;
+        }
+        (Some("reset"), _) => scene = Scene::default(),
+        _ => return Err(Error::Generic("Unknown segment command")),
+    }
+
+    {
+        let mut output = output.lock().await;
+        output.scene = scene;
+        output.preset = None;
+    }
+    if let Err(e) = scene.save(&mut *storage.lock().await) {
+        println!("failed to save scene: {e:?}");
+    }
+    send_segments(conn, &scene).await
+}
+
+async fn send_matrix(conn: &mut Connection<'_, '_>, layout: Option<&Layout>) -> Result<()> {
+    let mut buffer = [0u8; 256];
+    let mut text = FmtBuffer::new(&mut buffer);
+    match layout {
+        Some(layout) => write!(
+            text,
+            "start={} width={} height={} serpentine={} origin={} rotation={} \
+             tiles_x={} tiles_y={} tile_serpentine={} scroll_ip={}\n",
+            layout.start,
+            layout.width,
+            layout.height,
+            layout.serpentine as u8,
+            layout.origin.name(),
+            layout.rotation.degrees(),
+            layout.tiles_x,
+            layout.tiles_y,
+            layout.tile_serpentine as u8,
+            layout.scroll_ip as u8
+        ),
+        None => write!(text, "no matrix\n"),
+    }
+    .map_err(|_| Error::Index)?;
+
+    conn.start(Status::Ok, TEXT).await?;
+    conn.write(text.as_bytes()).await?;
     Ok(())
 }
 
-async fn i2c_read_multiple<I2C, E>(
-    socket: &mut TcpSocket<'_>,
-    i2c: &Mutex<NoopRawMutex, &'static mut I2C>,
-    dev_addr: u8,
-    reg_addr: u8,
-    len: usize,
-) -> Result<()>
-where
-    I2C: I2c<Error = E>,
-    Error: From<E>,
-{
-    if len > 16 {
-        return Err(Error::Generic("can't read more than 16 bytes at once"));
-    }
-
-    let mut i2c = i2c.lock().await;
-    let mut buffer = [0u8; 16];
-    println!("reading {len} bytes from {reg_addr:x} from {dev_addr:x}");
-    i2c.write_read(dev_addr, &[reg_addr], &mut buffer[..len])
-        .await?;
-    println!("{buffer:x?}");
-    socket
-        .write_all(b"HTTP/1.0 200 OK\r\n\r\nlook at console")
-        .await?;
+/// Apply the layout parameters in `query` on top of `layout`.
+fn update_matrix(layout: &mut Layout, query: &str) -> Result<()> {
+    if let Some(start) = parse_param(query, "start")? {
+        layout.start = start;
+    }
+    if let Some(width) = parse_param(query, "width")? {
+        layout.width = width;
+    }
+    if let Some(height) = parse_param(query, "height")? {
+        layout.height = height;
+    }
+    if let Some(serpentine) = parse_param::<u8>(query, "serpentine")? {
+        layout.serpentine = serpentine != 0;
+    }
+    if let Some(origin) = query_param(query, "origin") {
+        layout.origin = Corner::from_name(origin).ok_or(Error::Generic("Unknown origin"))?;
+    }
+    if let Some(rotation) = parse_param(query, "rotation")? {
+        layout.rotation =
+            Rotation::from_degrees(rotation).ok_or(Error::Generic("Unknown rotation"))?;
+    }
+    if let Some(tiles_x) = parse_param(query, "tiles_x")? {
+        layout.tiles_x = tiles_x;
+    }
+    if let Some(tiles_y) = parse_param(query, "tiles_y")? {
+        layout.tiles_y = tiles_y;
+    }
+    if let Some(tile_serpentine) = parse_param::<u8>(query, "tile_serpentine")? {
+        layout.tile_serpentine = tile_serpentine != 0;
+    }
+    if let Some(scroll_ip) = parse_param::<u8>(query, "scroll_ip")? {
+        layout.scroll_ip = scroll_ip != 0;
+    }
+
+    layout.check(NUM_LEDS)
+}
+
+async fn handle_matrix(
+    conn: &mut Connection<'_, '_>,
+    ctx: &Context,
+    req: &Request<'_>,
+) -> Result<()> {
+    let (output, storage, query) = (ctx.output, ctx.storage, req.query);
+    let mut matrix = output.lock().await.matrix;
+
+    match req.param("command") {
+        None => return send_matrix(conn, matrix.as_ref()).await,
+        Some("set") => {
+            let mut layout = matrix.unwrap_or(Layout::new(0, 0));
+            update_matrix(&mut layout, query)?;
+            matrix = Some(layout);
+        }
+        Some("clear") => matrix = None,
+        _ => return Err(Error::Generic("Unknown matrix command")),
+    }
+
+    output.lock().await.matrix = matrix;
+    if let Err(e) = Layout::save(matrix.as_ref(), &mut *storage.lock().await) {
+        println!("failed to save matrix layout: {e:?}");
+    }
+    send_matrix(conn, matrix.as_ref()).await
+}
+
+async fn send_text(conn: &mut Connection<'_, '_>, text: Option<&Text>) -> Result<()> {
+    conn.start(Status::Ok, TEXT).await?;
+    match text {
+        Some(text) => {
+            let mut buffer = [0u8; 128];
+            let mut info = FmtBuffer::new(&mut buffer);
+            write!(
+                info,
+                "font={} color={:06x} speed={} repeat={} text=",
+                font(text.font).name,
+                text.color.to_u32(),
+                text.speed,
+                text.repeat
+            )
+            .map_err(|_| Error::Index)?;
+            conn.write(info.as_bytes()).await?;
+            conn.write(text.as_bytes()).await?;
+            conn.write(b"\n").await?;
+        }
+        None => conn.write(b"no text\n").await?,
+    }
     Ok(())
 }
 
-async fn i2c_write<I2C, E>(
-    socket: &mut TcpSocket<'_>,
-    i2c: &Mutex<NoopRawMutex, &'static mut I2C>,
-    dev_addr: u8,
-    reg_addr: u8,
-    data: u8,
-) -> Result<()>
-where
-    I2C: I2c<Error = E>,
-    Error: From<E>,
-{
-    let mut i2c = i2c.lock().await;
-    println!("writing {data:x} to {reg_addr:x} from {dev_addr:x}");
-    i2c.write(dev_addr, &[reg_addr, data]).await?;
-    socket
-        .write_all(b"HTTP/1.0 200 OK\r\n\r\nlook at console")
-        .await?;
+async fn handle_text(
+    conn: &mut Connection<'_, '_>,
+    ctx: &Context,
+    req: &Request<'_>,
+) -> Result<()> {
+    let (output, query) = (ctx.output, req.query);
+    let mut text = output.lock().await.text;
+
+    match req.param("command") {
+        None => return send_text(conn, text.as_ref()).await,
+        Some("set") => {
+            let mut buf = [0u8; MAX_TEXT_LEN];
+            let msg = url_decode(query_param(query, "msg").unwrap_or(""), &mut buf)?;
+            let mut new = Text::new(msg, Instant::now().as_millis() as u32);
+            if let Some(name) = query_param(query, "font") {
+                new.font = font_index(name).ok_or(Error::Generic("Unknown font"))?;
+            }
+            if let Some(color) = query_param(query, "color") {
+                new.color = parse_color(color)?;
+            }
+            match query_param(query, "background") {
+                Some("none") => new.background = None,
+                Some(color) => new.background = Some(parse_color(color)?),
+                None => (),
+            }
+            if let Some(speed) = parse_param(query, "speed")? {
+                new.speed = speed;
+            }
+            if let Some(repeat) = parse_param(query, "repeat")? {
+                new.repeat = repeat;
+            }
+            text = Some(new);
+        }
+        Some("clear") => text = None,
+        _ => return Err(Error::Generic("Unknown text command")),
+    }
+
+    output.lock().await.text = text;
+    send_text(conn, text.as_ref()).await
+}
+
+async fn send_presets(conn: &mut Connection<'_, '_>, storage: &SharedStorage) -> Result<()> {
+    conn.start(Status::Ok, TEXT).await?;
+    for index in 0..MAX_PRESETS as u8 {
+        let mut data = [0u8; MAX_RECORD_LEN];
+        let mut buffer = [0u8; 64];
+        let mut line = FmtBuffer::new(&mut buffer);
+        match preset::load(index, &mut *storage.lock().await, &mut data) {
+            Ok(Some(preset)) => write!(
+                line,
+                "{index}: name={} capture={}\n",
+                preset.name,
+                preset.has_capture() as u8
+            ),
+            Ok(None) => continue,
+            Err(e) => write!(line, "{index}: error {e:?}\n"),
+        }
+        .map_err(|_| Error::Index)?;
+        conn.write(line.as_bytes()).await?;
+    }
     Ok(())
 }
 
-pub async fn handle_connection(
-    task_n: u32,
-    socket: &mut TcpSocket<'_>,
-    i2c: &Mutex<NoopRawMutex, &'static mut I2C<'_, I2C0>>,
+async fn handle_presets(
+    conn: &mut Connection<'_, '_>,
+    ctx: &Context,
+    req: &Request<'_>,
 ) -> Result<()> {
-    let mut buffer = [0u8; 1024];
+    let (command, query) = (req.param("command"), req.query);
+    let index = req
+        .param("index")
+        .map(|index| u8::from_str(index).map_err(|_| Error::Generic("Can't parse index")))
+        .transpose()?;
 
-    // read all headers
-    let mut offset = 0;
-    loop {
-        let read_len = socket.read(&mut buffer[offset..]).await?;
-        offset += read_len;
-        if buffer[..offset].ends_with(b"\r\n\r\n") {
-            break;
+    match (command, index) {
+        (None, _) => (),
+        (Some("save"), Some(index)) => {
+            let mut buf = [0u8; NAME_LEN * 3];
+            let name = url_decode(query_param(query, "name").unwrap_or(""), &mut buf)?;
+            let name = core::str::from_utf8(name).map_err(|_| Error::Generic("Bad name"))?;
+            let capture = parse_param::<u8>(query, "capture")?.unwrap_or(0) != 0;
+            preset::save(index, name, capture, ctx.output, ctx.storage).await?;
+        }
+        (Some("recall"), Some(index)) => {
+            let fade_ms = parse_param(query, "fade")?.unwrap_or(0);
+            preset::recall(index, fade_ms, ctx.output, ctx.storage).await?
         }
+        (Some("delete"), Some(index)) => preset::delete(index, &mut *ctx.storage.lock().await)?,
+        _ => return Err(Error::Generic("Unknown preset command")),
     }
 
-    let mut headers = [httparse::EMPTY_HEADER; 64];
-    let mut req = httparse::Request::new(&mut headers);
-    if !req
-        .parse(&buffer[..offset])
-        .map_err(|_| Error::Generic("header parsing error"))?
-        .is_complete()
-    {
-        return Err(Error::Generic("incomplete headers"));
-    }
-
-    println!("{} path = {:?}", task_n, req.path);
-
-    if let Some(path) = req.path {
-        if path.starts_with("/i2c/read/") {
-            let mut parts_iter = path.split("/");
-            let dev_addr_str = parts_iter
-                .nth(3)
-                .ok_or_else(|| Error::Generic("Can't find dev_addr"))?;
-            let reg_addr_str = parts_iter
-                .nth(0)
-                .ok_or_else(|| Error::Generic("Can't find reg_addr"))?;
-            let dev_addr = u8::from_str_radix(dev_addr_str, 16)
-                .map_err(|_| Error::Generic("Can't parse dev_addr"))?;
-            let reg_addr = u8::from_str_radix(reg_addr_str, 16)
-                .map_err(|_| Error::Generic("Can't parse reg_addr"))?;
-            println!("{dev_addr:x} {reg_addr:x}");
-            i2c_read(socket, i2c, dev_addr, reg_addr).await?;
-        } else if path.starts_with("/i2c/read_n/") {
-            let mut parts_iter = path.split("/");
-            let dev_addr_str = parts_iter
-                .nth(3)
-                .ok_or_else(|| Error::Generic("Can't find dev_addr"))?;
-            let reg_addr_str = parts_iter
-                .nth(0)
-                .ok_or_else(|| Error::Generic("Can't find reg_addr"))?;
-            let len_str = parts_iter
-                .nth(0)
-                .ok_or_else(|| Error::Generic("Can't find len"))?;
-            let dev_addr = u8::from_str_radix(dev_addr_str, 16)
-                .map_err(|_| Error::Generic("Can't parse dev_addr"))?;
-            let reg_addr = u8::from_str_radix(reg_addr_str, 16)
-                .map_err(|_| Error::Generic("Can't parse reg_addr"))?;
-            let len =
-                u8::from_str_radix(len_str, 16).map_err(|_| Error::Generic("Can't parse data"))?;
-
-            println!("{dev_addr:x} {reg_addr:x} {len:x}");
-            i2c_read_multiple(socket, i2c, dev_addr, reg_addr, len as usize).await?;
-        } else if path.starts_with("/i2c/write/") {
-            let mut parts_iter = path.split("/");
-            let dev_addr_str = parts_iter
-                .nth(3)
-                .ok_or_else(|| Error::Generic("Can't find dev_addr"))?;
-            let reg_addr_str = parts_iter
-                .nth(0)
-                .ok_or_else(|| Error::Generic("Can't find reg_addr"))?;
-            let data_str = parts_iter
-                .nth(0)
-                .ok_or_else(|| Error::Generic("Can't find data"))?;
-            let dev_addr = u8::from_str_radix(dev_addr_str, 16)
-                .map_err(|_| Error::Generic("Can't parse dev_addr"))?;
-            let reg_addr = u8::from_str_radix(reg_addr_str, 16)
-                .map_err(|_| Error::Generic("Can't parse reg_addr"))?;
-            let data =
-                u8::from_str_radix(data_str, 16).map_err(|_| Error::Generic("Can't parse data"))?;
-
-            println!("{dev_addr:x} {reg_addr:x} {data:x}");
-            i2c_write(socket, i2c, dev_addr, reg_addr, data).await?;
-        } else {
-            match path {
-                "/konkers-music.svg" => {
-                    send_static_gzip(socket, include_bytes!("html/konkers-music.svg.gz")).await?
+    send_presets(conn, ctx.storage).await
+}
+
+async fn send_schedule(conn: &mut Connection<'_, '_>, schedule: &Schedule) -> Result<()> {
+    let mut buffer = [0u8; 512];
+    let mut text = FmtBuffer::new(&mut buffer);
+    match schedule.local_time() {
+        Some(time) => write!(
+            text,
+            "time={:02}:{:02}:{:02} tz={}\n",
+            time / 3600 % 24,
+            time / 60 % 60,
+            time % 60,
+            schedule.utc_offset
+        ),
+        None => write!(text, "time=unset tz={}\n", schedule.utc_offset),
+    }
+    .map_err(|_| Error::Index)?;
+    for (i, entry) in schedule.entries().iter().enumerate() {
+        let Some(entry) = entry else {
+            continue;
+        };
+        write!(
+            text,
+            "{i}: preset={} time={:02}:{:02} days=",
+            entry.preset,
+            entry.minute / 60,
+            entry.minute % 60
+        )
+        .map_err(|_| Error::Index)?;
+        for day in (0..7).filter(|day| entry.days & (1 << day) != 0) {
+            write!(text, "{day}").map_err(|_| Error::Index)?;
+        }
+        write!(text, "\n").map_err(|_| Error::Index)?;
+    }
+
+    conn.start(Status::Ok, TEXT).await?;
+    conn.write(text.as_bytes()).await?;
+    Ok(())
+}
+
+/// Parse a schedule entry from `preset=<n>&time=<hh:mm>[&days=<0-6>*]`.
+fn parse_entry(query: &str) -> Result<Entry> {
+    let preset = parse_param(query, "preset")?.ok_or(Error::Generic("preset"))?;
+    let (hour, minute) = query_param(query, "time")
+        .and_then(|time| time.split_once("%3A").or_else(|| time.split_once(':')))
+        .ok_or(Error::Generic("time"))?;
+    let hour = u16::from_str(hour).map_err(|_| Error::Generic("time"))?;
+    let minute = u16::from_str(minute).map_err(|_| Error::Generic("time"))?;
+    if hour >= 24 || minute >= 60 {
+        return Err(Error::Generic("time"));
+    }
+    let days = match query_param(query, "days") {
+        Some(days) => days.bytes().try_fold(0u8, |mask, day| match day {
+            b'0'..=b'6' => Ok(mask | 1 << (day - b'0')),
+            _ => Err(Error::Generic("days")),
+        })?,
+        None => ALL_DAYS,
+    };
+    Ok(Entry {
+        preset,
+        minute: hour * 60 + minute,
+        days,
+    })
+}
+
+async fn handle_schedule(
+    conn: &mut Connection<'_, '_>,
+    ctx: &Context,
+    req: &Request<'_>,
+) -> Result<()> {
+    let query = req.query;
+    let mut schedule = ctx.schedule.lock().await;
+
+    match (req.param("command"), req.param("index")) {
+        (None, _) => return send_schedule(conn, &schedule).await,
+        (Some("set"), Some(index_str)) => {
+            let index =
+                usize::from_str(index_str).map_err(|_| Error::Generic("Can't parse index"))?;
+            schedule.set(index, Some(parse_entry(query)?))?;
+        }
+        (Some("remove"), Some(index_str)) => {
+            let index =
+                usize::from_str(index_str).map_err(|_| Error::Generic("Can't parse index"))?;
+            schedule.set(index, None)?;
+        }
+        (Some("clock"), _) => {
+            if let Some(now) = parse_param(query, "now")? {
+                schedule.set_time(now);
+            }
+            if let Some(tz) = parse_param(query, "tz")? {
+                schedule.utc_offset = tz;
+            }
+        }
+        _ => return Err(Error::Generic("Unknown schedule command")),
+    }
+
+    if let Err(e) = schedule.save(&mut *ctx.storage.lock().await) {
+        println!("failed to save schedule: {e:?}");
+    }
+    send_schedule(conn, &schedule).await
+}
+
+async fn send_playlists(conn: &mut Connection<'_, '_>, ctx: &Context) -> Result<()> {
+    let mut buffer = [0u8; 128];
+    let mut line = FmtBuffer::new(&mut buffer);
+    match ctx.player.lock().await.status() {
+        Some((index, cue)) => write!(line, "playing={index} cue={cue}\n"),
+        None => write!(line, "stopped\n"),
+    }
+    .map_err(|_| Error::Index)?;
+    conn.start(Status::Ok, TEXT).await?;
+    conn.write(line.as_bytes()).await?;
+
+    for index in 0..MAX_PLAYLISTS as u8 {
+        let Some(playlist) = Playlist::load(index, &mut *ctx.storage.lock().await)? else {
+            continue;
+        };
+        let mut buffer = [0u8; 1024];
+        let mut text = FmtBuffer::new(&mut buffer);
+        write!(
+            text,
+            "playlist {index}: loop={} autostart={}\n",
+            playlist.looping as u8, playlist.autostart as u8
+        )
+        .map_err(|_| Error::Index)?;
+        for (i, cue) in playlist.cues().iter().enumerate() {
+            write!(
+                text,
+                "  {i}: preset={} fade={} hold={}\n",
+                cue.preset, cue.fade_ms, cue.hold_ms
+            )
+            .map_err(|_| Error::Index)?;
+        }
+        conn.write(text.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+fn parse_index<T: FromStr>(index: Option<&str>) -> Result<T> {
+    index
+        .and_then(|index| T::from_str(index).ok())
+        .ok_or(Error::Generic("Can't parse index"))
+}
+
+async fn handle_playlists(
+    conn: &mut Connection<'_, '_>,
+    ctx: &Context,
+    req: &Request<'_>,
+) -> Result<()> {
+    let query = req.query;
+
+    match req.param("command") {
+        None => return send_playlists(conn, ctx).await,
+        Some("stop") => ctx.player.lock().await.stop(),
+        Some("next") => ctx.player.lock().await.step(true),
+        Some("prev") => ctx.player.lock().await.step(false),
+        Some("play") => {
+            playlist::play(parse_index(req.param("index"))?, ctx.player, ctx.storage).await?
+        }
+        Some("delete") => {
+            let index = parse_index(req.param("index"))?;
+            Playlist::delete(index, &mut *ctx.storage.lock().await)?;
+        }
+        Some(command @ ("set" | "remove" | "options")) => {
+            let index = parse_index(req.param("index"))?;
+            let mut storage = ctx.storage.lock().await;
+            let mut playlist = Playlist::load(index, &mut storage)?.unwrap_or(Playlist::empty());
+            match command {
+                "set" => {
+                    let cue_index = parse_index(req.param("cue"))?;
+                    let mut cue = playlist
+                        .cues()
+                        .get(cue_index)
+                        .copied()
+                        .unwrap_or(Cue::new(0));
+                    if let Some(preset) = parse_param(query, "preset")? {
+                        cue.preset = preset;
+                    }
+                    if let Some(fade) = parse_param(query, "fade")? {
+                        cue.fade_ms = fade;
+                    }
+                    if let Some(hold) = parse_param(query, "hold")? {
+                        cue.hold_ms = hold;
+                    }
+                    playlist.set(cue_index, cue)?;
                 }
+                "remove" => playlist.remove(parse_index(req.param("cue"))?)?,
+                _ => {
+                    if let Some(looping) = parse_param::<u8>(query, "loop")? {
+                        playlist.looping = looping != 0;
+                    }
+                    if let Some(autostart) = parse_param::<u8>(query, "autostart")? {
+                        playlist.autostart = autostart != 0;
+                    }
+                }
+            }
+            playlist.save(index, &mut storage)?;
+            ctx.player.lock().await.update(index, &playlist);
+        }
+        _ => return Err(Error::Generic("Unknown playlist command")),
+    }
 
-                _ => send_static(socket, include_bytes!("html/index.html")).await?,
+    send_playlists(conn, ctx).await
+}
+
+async fn handle_show(
+    conn: &mut Connection<'_, '_>,
+    ctx: &Context,
+    req: &Request<'_>,
+) -> Result<()> {
+    let mut show = ctx.show.lock().await;
+    match req.param("command") {
+        None => (),
+        Some("record") => show.record(),
+        Some("play") => show.play(),
+        Some("stop") => show.stop(),
+        _ => return Err(Error::Generic("Unknown show command")),
+    }
+
+    let mut buffer = [0u8; 128];
+    let mut text = FmtBuffer::new(&mut buffer);
+    write!(text, "state={}", show.state().name()).map_err(|_| Error::Index)?;
+    if let Some(info) = show.info() {
+        write!(
+            text,
+            " frames={} bytes={} length_ms={}",
+            info.frames, info.data_len, info.length_ms
+        )
+        .map_err(|_| Error::Index)?;
+    }
+    write!(text, "\n").map_err(|_| Error::Index)?;
+    drop(show);
+
+    conn.start(Status::Ok, TEXT).await?;
+    conn.write(text.as_bytes()).await?;
+    Ok(())
+}
+
+async fn handle_timecode(
+    conn: &mut Connection<'_, '_>,
+    ctx: &Context,
+    req: &Request<'_>,
+) -> Result<()> {
+    let query = req.query;
+    let mut clock = ctx.clock.lock().await;
+    match req.param("command") {
+        None => (),
+        Some("set") => {
+            let mut config = clock.config;
+            match query_param(query, "chase") {
+                None => (),
+                Some("off") => config.chase = Chase::Off,
+                Some("show") => config.chase = Chase::Show,
+                Some("playlist") => {
+                    let index: u8 = parse_param(query, "playlist")?.unwrap_or(0);
+                    if index as usize >= MAX_PLAYLISTS {
+                        return Err(Error::Generic("No such playlist"));
+                    }
+                    config.chase = Chase::Playlist(index);
+                }
+                Some(_) => return Err(Error::Generic("chase")),
+            }
+            if let Some(offset) = parse_param(query, "offset")? {
+                config.offset_ms = offset;
+            }
+            if let Some(freewheel) = parse_param(query, "freewheel")? {
+                config.freewheel_ms = freewheel;
+            }
+            if let Some(jump) = parse_param(query, "jump")? {
+                config.jump_ms = jump;
+            }
+            config.save(&mut *ctx.storage.lock().await)?;
+            clock.config = config;
+        }
+        _ => return Err(Error::Generic("Unknown timecode command")),
+    }
+
+    let mut buffer = [0u8; 256];
+    let mut text = FmtBuffer::new(&mut buffer);
+    let config = clock.config;
+    write!(text, "chase={}", config.chase.name()).map_err(|_| Error::Index)?;
+    if let Chase::Playlist(index) = config.chase {
+        write!(text, " playlist={index}").map_err(|_| Error::Index)?;
+    }
+    write!(
+        text,
+        " offset={} freewheel={} jump={}\n",
+        config.offset_ms, config.freewheel_ms, config.jump_ms
+    )
+    .map_err(|_| Error::Index)?;
+    match clock.last() {
+        Some(timecode) => write!(text, "timecode={timecode} type={}", timecode.kind.name()),
+        None => write!(text, "timecode=none"),
+    }
+    .map_err(|_| Error::Index)?;
+    match clock.position(Instant::now()) {
+        Some(position) => write!(text, " position_ms={position}\n"),
+        None => write!(text, " position_ms=none\n"),
+    }
+    .map_err(|_| Error::Index)?;
+    drop(clock);
+
+    conn.start(Status::Ok, TEXT).await?;
+    conn.write(text.as_bytes()).await?;
+    Ok(())
+}
+
+async fn handle_sync(
+    conn: &mut Connection<'_, '_>,
+    ctx: &Context,
+    req: &Request<'_>,
+) -> Result<()> {
+    let query = req.query;
+    let mut group = ctx.group.lock().await;
+    match req.param("command") {
+        None => (),
+        Some("set") => {
+            let mut config = group.config;
+            if let Some(enabled) = parse_param::<u8>(query, "enabled")? {
+                config.enabled = enabled != 0;
+            }
+            if let Some(id) = parse_param(query, "group")? {
+                config.group = id;
+            }
+            if let Some(priority) = parse_param(query, "priority")? {
+                config.priority = priority;
             }
+            config.save(&mut *ctx.storage.lock().await)?;
+            group.set_config(config, Instant::now());
         }
+        _ => return Err(Error::Generic("Unknown sync command")),
     }
 
-    socket.flush().await?;
+    let mut buffer = [0u8; 256];
+    let mut text = FmtBuffer::new(&mut buffer);
+    write!(
+        text,
+        "enabled={} group={} priority={} role={}",
+        group.config.enabled as u8,
+        group.config.group,
+        group.config.priority,
+        group.role().name()
+    )
+    .map_err(|_| Error::Index)?;
+    if let Some(leader) = group.leader() {
+        let [a, b, c, d] = leader.id.to_be_bytes();
+        write!(
+            text,
+            " leader={a}.{b}.{c}.{d} leader_priority={}",
+            leader.priority
+        )
+        .map_err(|_| Error::Index)?;
+    }
+    drop(group);
+    let output = ctx.output.lock().await;
+    write!(text, " clock_offset={}", output.clock_offset_ms).map_err(|_| Error::Index)?;
+    if let Some(preset) = output.preset {
+        write!(text, " preset={preset}").map_err(|_| Error::Index)?;
+    }
+    drop(output);
+    write!(text, "\n").map_err(|_| Error::Index)?;
+
+    conn.start(Status::Ok, TEXT).await?;
+    conn.write(text.as_bytes()).await?;
     Ok(())
 }
+
+async fn send_script(conn: &mut Connection<'_, '_>, ctx: &Context) -> Result<()> {
+    let mut buffer = [0u8; 128];
+    let mut text = FmtBuffer::new(&mut buffer);
+    {
+        let output = ctx.output.lock().await;
+        let script = &output.script;
+        write!(
+            text,
+            "code={} steps={} budget={} overruns={}\n",
+            script.code_len(),
+            script.steps,
+            FRAME_BUDGET,
+            script.overruns
+        )
+        .map_err(|_| Error::Index)?;
+    }
+
+    let mut data = [0u8; MAX_SOURCE_LEN];
+    let source = Script::load_source(&mut *ctx.storage.lock().await, &mut data)?;
+    conn.start(Status::Ok, TEXT).await?;
+    conn.write(text.as_bytes()).await?;
+    conn.write(source.unwrap_or("").as_bytes()).await?;
+    Ok(())
+}
+
+/// Compile an uploaded script and, if it compiles, run and save it.
+async fn handle_script(conn: &mut Connection<'_, '_>, ctx: &Context, source: &[u8]) -> Result<()> {
+    let source = core::str::from_utf8(source).map_err(|_| Error::Generic("Script isn't UTF-8"))?;
+    let script = match Script::compile(source) {
+        Ok(script) => script,
+        Err(e) => {
+            let mut buffer = [0u8; 64];
+            let mut text = FmtBuffer::new(&mut buffer);
+            write!(text, "{e}\n").map_err(|_| Error::Index)?;
+            return conn.send(Status::BadRequest, TEXT, text.as_bytes()).await;
+        }
+    };
+
+    ctx.output.lock().await.script = script;
+    if let Err(e) = Script::save(source, &mut *ctx.storage.lock().await) {
+        println!("failed to save script: {e:?}");
+    }
+    send_script(conn, ctx).await
+}
+
+/// Shared state the web server operates on.
+pub struct Context {
+    pub stack: &'static Stack<WifiDevice<'static>>,
+    pub i2c: &'static Mutex<NoopRawMutex, &'static mut I2C<'static, I2C0>>,
+    pub output: &'static SharedOutput,
+    pub storage: &'static SharedStorage,
+    pub schedule: &'static SharedSchedule,
+    pub player: &'static SharedPlayer,
+    pub show: &'static SharedShow,
+    pub clock: &'static SharedClock,
+    pub group: &'static SharedGroup,
+    pub power: &'static SharedPowerStatus,
+    pub auth: &'static SharedAuth,
+    pub ota: &'static Ota,
+    pub settings: &'static SharedSettings,
+    /// Set while the Wi-Fi setup portal runs instead of the station.
+    pub portal: Option<&'static SharedPortal>,
+    pub networks: &'static SharedNetworks,
+    /// Held by the connection serving the WebSocket event stream.
+    pub event_stream: Mutex<NoopRawMutex, ()>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Endpoint {
+    Segments,
+    Matrix,
+    Text,
+    Presets,
+    Schedule,
+    Playlists,
+    Show,
+    Timecode,
+    Sync,
+    Script,
+    UploadScript,
+    #[cfg(feature = "i2c-debug")]
+    I2c(I2cCommand),
+    Api(Resource),
+    Events,
+    Auth,
+    Ota,
+    Settings,
+    Setup,
+    Networks,
+}
+
+const GET: &[Method] = &[Method::Get];
+const UPLOAD: &[Method] = &[Method::Post, Method::Put];
+const UPDATE: &[Method] = &[Method::Get, Method::Post, Method::Put];
+const POST: &[Method] = &[Method::Post];
+/// `Allow` for the files of the web UI.
+const ASSET_METHODS: &str = "GET, HEAD";
+
+const ROUTES: &[Route<Endpoint>] = &[
+    Route::new(GET, "/segments", Endpoint::Segments),
+    Route::new(POST, "/segments/:command", Endpoint::Segments),
+    Route::new(POST, "/segments/:command/:index", Endpoint::Segments),
+    Route::new(GET, "/matrix", Endpoint::Matrix),
+    Route::new(POST, "/matrix/:command", Endpoint::Matrix),
+    Route::new(GET, "/text", Endpoint::Text),
+    Route::new(POST, "/text/:command", Endpoint::Text),
+    Route::new(GET, "/presets", Endpoint::Presets),
+    Route::new(POST, "/presets/:command/:index", Endpoint::Presets),
+    Route::new(GET, "/schedule", Endpoint::Schedule),
+    Route::new(POST, "/schedule/:command", Endpoint::Schedule),
+    Route::new(POST, "/schedule/:command/:index", Endpoint::Schedule),
+    Route::new(GET, "/playlists", Endpoint::Playlists),
+    Route::new(POST, "/playlists/:command", Endpoint::Playlists),
+    Route::new(POST, "/playlists/:command/:index", Endpoint::Playlists),
+    Route::new(POST, "/playlists/:command/:index/:cue", Endpoint::Playlists),
+    Route::new(GET, "/show", Endpoint::Show),
+    Route::new(POST, "/show/:command", Endpoint::Show),
+    Route::new(GET, "/timecode", Endpoint::Timecode),
+    Route::new(POST, "/timecode/:command", Endpoint::Timecode),
+    Route::new(GET, "/sync", Endpoint::Sync),
+    Route::new(POST, "/sync/:command", Endpoint::Sync),
+    Route::new(GET, "/script", Endpoint::Script),
+    Route::new(UPLOAD, "/script", Endpoint::UploadScript),
+    #[cfg(feature = "i2c-debug")]
+    Route::new(GET, "/i2c/scan", Endpoint::I2c(I2cCommand::Scan)),
+    #[cfg(feature = "i2c-debug")]
+    Route::new(
+        GET,
+        "/i2c/read/:dev_addr/:reg_addr",
+        Endpoint::I2c(I2cCommand::Read),
+    ),
+    #[cfg(feature = "i2c-debug")]
+    Route::new(
+        GET,
+        "/i2c/read_n/:dev_addr/:reg_addr/:len",
+        Endpoint::I2c(I2cCommand::Read),
+    ),
+    #[cfg(feature = "i2c-debug")]
+    Route::new(
+        GET,
+        "/i2c/dump/:dev_addr/:first/:last",
+        Endpoint::I2c(I2cCommand::Dump),
+    ),
+    #[cfg(feature = "i2c-debug")]
+    Route::new(
+        POST,
+        "/i2c/write/:dev_addr/:reg_addr/:data",
+        Endpoint::I2c(I2cCommand::Write),
+    ),
+    #[cfg(feature = "i2c-debug")]
+    Route::new(
+        UPLOAD,
+        "/i2c/write/:dev_addr/:reg_addr",
+        Endpoint::I2c(I2cCommand::Write),
+    ),
+    #[cfg(feature = "i2c-debug")]
+    Route::new(GET, "/i2c/chips", Endpoint::I2c(I2cCommand::Chips)),
+    #[cfg(feature = "i2c-debug")]
+    Route::new(GET, "/i2c/reg/:chip", Endpoint::I2c(I2cCommand::Registers)),
+    #[cfg(feature = "i2c-debug")]
+    Route::new(
+        UPDATE,
+        "/i2c/reg/:chip/:register",
+        Endpoint::I2c(I2cCommand::Register),
+    ),
+    Route::new(GET, "/api/v1", Endpoint::Api(Resource::Index)),
+    Route::new(UPDATE, "/api/v1/output", Endpoint::Api(Resource::Output)),
+    Route::new(GET, "/api/v1/network", Endpoint::Api(Resource::Network)),
+    Route::new(GET, "/api/v1/pd", Endpoint::Api(Resource::Pd)),
+    Route::new(GET, "/api/v1/charger", Endpoint::Api(Resource::Charger)),
+    Route::new(GET, "/api/v1/artnet", Endpoint::Api(Resource::Artnet)),
+    Route::new(GET, "/api/v1/events", Endpoint::Events),
+    Route::new(GET, "/auth", Endpoint::Auth),
+    Route::new(POST, "/auth/:command", Endpoint::Auth),
+    Route::new(GET, "/ota", Endpoint::Ota),
+    Route::new(UPLOAD, "/ota", Endpoint::Ota),
+    Route::new(POST, "/ota/:command", Endpoint::Ota),
+    Route::new(GET, "/settings", Endpoint::Settings),
+    Route::new(POST, "/settings/:command", Endpoint::Settings),
+    Route::new(GET, "/setup", Endpoint::Setup),
+    Route::new(POST, "/setup/:command", Endpoint::Setup),
+    Route::new(GET, "/networks", Endpoint::Networks),
+    Route::new(POST, "/networks/:command", Endpoint::Networks),
+];
+
+/// The role a request needs.  Reading is for viewers, anything that
+/// changes state or touches the I2C bus is for admins, and logging in is
+/// for everyone.
+fn required_role(endpoint: Endpoint, req: &Request<'_>) -> Option<Role> {
+    match endpoint {
+        Endpoint::Auth => match req.param("command") {
+            Some("set") => Some(Role::Admin),
+            _ => None,
+        },
+        #[cfg(feature = "i2c-debug")]
+        Endpoint::I2c(_) => Some(Role::Admin),
+        _ if !matches!(req.method, Method::Get | Method::Head) => Some(Role::Admin),
+        _ if req.param("command").is_some() => Some(Role::Admin),
+        _ => Some(Role::Viewer),
+    }
+}
+
+async fn dispatch(
+    conn: &mut Connection<'_, '_>,
+    ctx: &Context,
+    req: &Request<'_>,
+    endpoint: Endpoint,
+    role: Option<Role>,
+) -> Result<()> {
+    match endpoint {
+        Endpoint::Segments => handle_segments(conn, ctx, req).await,
+        Endpoint::Matrix => handle_matrix(conn, ctx, req).await,
+        Endpoint::Text => handle_text(conn, ctx, req).await,
+        Endpoint::Presets => handle_presets(conn, ctx, req).await,
+        Endpoint::Schedule => handle_schedule(conn, ctx, req).await,
+        Endpoint::Playlists => handle_playlists(conn, ctx, req).await,
+        Endpoint::Show => handle_show(conn, ctx, req).await,
+        Endpoint::Timecode => handle_timecode(conn, ctx, req).await,
+        Endpoint::Sync => handle_sync(conn, ctx, req).await,
+        Endpoint::Script => send_script(conn, ctx).await,
+        Endpoint::UploadScript => {
+            let mut body = [0u8; MAX_SOURCE_LEN];
+            let source = conn.read_body(&mut body).await?;
+            handle_script(conn, ctx, source).await
+        }
+        #[cfg(feature = "i2c-debug")]
+        Endpoint::I2c(command) => i2cdebug::handle(conn, ctx, req, command).await,
+        Endpoint::Api(resource) => api::handle(conn, ctx, req, resource).await,
+        Endpoint::Events => websocket::handle(conn, ctx, req).await,
+        Endpoint::Auth => auth::handle(conn, ctx, req, role).await,
+        Endpoint::Ota => ota::handle(conn, ctx, req).await,
+        Endpoint::Settings => settings::handle(conn, ctx, req).await,
+        Endpoint::Setup => provision::handle(conn, ctx, req).await,
+        Endpoint::Networks => networks::handle(conn, ctx, req).await,
+    }
+}
+
+/// Route a request and run its handler.  `OPTIONS` requests are answered
+/// here from the route table.
+async fn handle_request(
+    conn: &mut Connection<'_, '_>,
+    ctx: &Context,
+    req: &mut Request<'_>,
+) -> Result<()> {
+    if let Some(portal) = ctx.portal {
+        portal.lock().await.visit(Instant::now());
+        if provision::redirect(req) {
+            let location = ("Location", provision::SETUP_URL);
+            return conn.send(Status::Found, &[location], &[]).await;
+        }
+    }
+
+    let mut allow = [0u8; 64];
+    let mut allow = FmtBuffer::new(&mut allow);
+    let asset = assets::find(req.path);
+    if req.method == Method::Options {
+        http::allowed(ROUTES, req.path, &mut allow);
+        let allow = match core::str::from_utf8(allow.as_bytes()).unwrap_or("") {
+            "" if asset.is_some() => ASSET_METHODS,
+            "" => return Err(Error::Http(Status::NotFound)),
+            allow => allow,
+        };
+        return conn.send(Status::NoContent, &[("Allow", allow)], &[]).await;
+    }
+
+    let endpoint = match http::route(ROUTES, req.method, req.path, &mut req.params) {
+        Ok(endpoint) => *endpoint,
+        Err(status @ Status::MethodNotAllowed) => {
+            http::allowed(ROUTES, req.path, &mut allow);
+            let allow = core::str::from_utf8(allow.as_bytes()).unwrap_or("");
+            return conn
+                .send_error(&Error::Http(status), &[("Allow", allow)])
+                .await;
+        }
+        // Anything that isn't an API route may be a file of the web UI.
+        Err(Status::NotFound) => {
+            return match asset {
+                Some(asset) if matches!(req.method, Method::Get | Method::Head) => {
+                    send_asset(conn, req, asset).await
+                }
+                Some(_) => {
+                    let error = Error::Http(Status::MethodNotAllowed);
+                    conn.send_error(&error, &[("Allow", ASSET_METHODS)]).await
+                }
+                None => Err(Error::Http(Status::NotFound)),
+            };
+        }
+        Err(status) => return Err(Error::Http(status)),
+    };
+
+    let client = conn.remote();
+    let role = ctx
+        .auth
+        .lock()
+        .await
+        .role(req, client, Instant::now())
+        .await;
+    let role = match role {
+        Ok(role) => role,
+        Err(e) => return conn.send_error(&e, &[auth::CHALLENGE]).await,
+    };
+    match (required_role(endpoint, req), role) {
+        (Some(_), None) => {
+            let error = Error::Http(Status::Unauthorized);
+            return conn.send_error(&error, &[auth::CHALLENGE]).await;
+        }
+        (Some(required), Some(role)) if role < required => {
+            return Err(Error::Http(Status::Forbidden));
+        }
+        _ => (),
+    }
+    dispatch(conn, ctx, req, endpoint, role).await
+}
+
+/// Serve HTTP requests on `socket` until the client closes it or asks us
+/// to.
+pub async fn handle_connection(
+    task_n: u32,
+    socket: &mut TcpSocket<'_>,
+    ctx: &Context,
+) -> Result<()> {
+    let mut conn = Connection::new(socket);
+    loop {
+        let mut head = [0u8; MAX_HEAD_LEN];
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut req = match conn.read_request(&mut head, &mut headers).await {
+            Ok(Some(req)) => req,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                // The connection can't be trusted after a bad request.
+                conn.send_error(&e, &[]).await.ok();
+                conn.finish().await.ok();
+                return Err(e);
+            }
+        };
+        println!("{} {} {}", task_n, req.method.name(), req.path);
+
+        if let Err(e) = handle_request(&mut conn, ctx, &mut req).await {
+            println!("{} {} failed: {e:?}", task_n, req.path);
+            if conn.started() {
+                return Err(e);
+            }
+            conn.send_error(&e, &[]).await?;
+        }
+        if !conn.finish().await? {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,505 @@
+use core::fmt::Write;
+
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Instant, Timer};
+use esp_println::println;
+
+use crate::api::{self, Resource};
+use crate::color::Rgb;
+use crate::http::{Connection, Request, Status, MAX_HEAD_LEN};
+use crate::json::Json;
+use crate::output::{MAX_UNIVERSES, NUM_LEDS};
+use crate::pd::PdState;
+use crate::web::{Context, FmtBuffer};
+use crate::{Error, Result};
+
+/// From RFC 6455, appended to the client's key to prove we speak WebSocket.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Longest message we take from the client.
+const MAX_MESSAGE_LEN: usize = 256;
+const MAX_PUSH_LEN: usize = 1024;
+/// Streams can't be pushed more often than this.
+const MIN_INTERVAL_MS: u32 = 50;
+/// How long to wait for the client when nothing is subscribed.
+const IDLE: Duration = Duration::from_millis(1000);
+const DEFAULT_PREVIEW_PIXELS: usize = 30;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_UNSUPPORTED: u16 = 1003;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+fn sha1_block(h: &mut [u32; 5], block: &[u8; 64]) {
+    let mut w = [0u32; 80];
+    for (i, word) in block.chunks_exact(4).enumerate() {
+        w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    for i in 16..80 {
+        w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e] = *h;
+    for (i, w) in w.iter().enumerate() {
+        let (f, k) = match i {
+            0..=19 => ((b & c) | (!b & d), 0x5a827999),
+            20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+            40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+            _ => (b ^ c ^ d, 0xca62c1d6),
+        };
+        let t = a
+            .rotate_left(5)
+            .wrapping_add(f)
+            .wrapping_add(e)
+            .wrapping_add(k)
+            .wrapping_add(*w);
+        (e, d, c, b, a) = (d, c, b.rotate_left(30), a, t);
+    }
+    for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+        *h = h.wrapping_add(v);
+    }
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    // Room for the 0x80 terminator and the 64 bit length.
+    let blocks = (data.len() + 8) / 64 + 1;
+    let mut block = [0u8; 64];
+    for n in 0..blocks {
+        for (i, b) in block.iter_mut().enumerate() {
+            let pos = n * 64 + i;
+            *b = match pos.cmp(&data.len()) {
+                core::cmp::Ordering::Less => data[pos],
+                core::cmp::Ordering::Equal => 0x80,
+                core::cmp::Ordering::Greater => 0,
+            };
+        }
+        if n == blocks - 1 {
+            block[56..].copy_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+        }
+        sha1_block(&mut h, &block);
+    }
+
+    let mut digest = [0u8; 20];
+    for (out, h) in digest.chunks_exact_mut(4).zip(h) {
+        out.copy_from_slice(&h.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8], out: &mut impl Write) -> core::fmt::Result {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    for chunk in data.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.write_char(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char)?;
+            } else {
+                out.write_char('=')?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The `Sec-WebSocket-Accept` value for a client's `Sec-WebSocket-Key`.
+fn accept_key(key: &str, out: &mut impl Write) -> Result<()> {
+    let mut data = [0u8; 128];
+    let len = key.len() + GUID.len();
+    if len > data.len() {
+        return Err(Error::Generic("Sec-WebSocket-Key"));
+    }
+    data[..key.len()].copy_from_slice(key.as_bytes());
+    data[key.len()..len].copy_from_slice(GUID.as_bytes());
+    base64(&sha1(&data[..len]), out).map_err(|_| Error::Index)
+}
+
+/// The header of a frame at the start of the received data.
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    masked: bool,
+    mask: [u8; 4],
+    header_len: usize,
+    len: usize,
+}
+
+impl Frame {
+    /// Parse the frame header at the start of `data`.  Returns `None` until
+    /// the whole header has been received.
+    fn parse(data: &[u8]) -> Option<Frame> {
+        let (&b0, &b1) = (data.first()?, data.get(1)?);
+        let (len, pos) = match b1 & 0x7f {
+            126 => {
+                let len = u16::from_be_bytes([*data.get(2)?, *data.get(3)?]);
+                (len as usize, 4)
+            }
+            127 => {
+                let len = u64::from_be_bytes(data.get(2..10)?.try_into().ok()?);
+                (usize::try_from(len).unwrap_or(usize::MAX), 10)
+            }
+            len => (len as usize, 2),
+        };
+        let masked = b1 & 0x80 != 0;
+        let mut mask = [0u8; 4];
+        let header_len = if masked {
+            mask.copy_from_slice(data.get(pos..pos + 4)?);
+            pos + 4
+        } else {
+            pos
+        };
+        Some(Frame {
+            fin: b0 & 0x80 != 0,
+            opcode: b0 & 0x0f,
+            masked,
+            mask,
+            header_len,
+            len,
+        })
+    }
+
+    fn total_len(&self) -> usize {
+        self.header_len.saturating_add(self.len)
+    }
+}
+
+async fn send_frame(conn: &mut Connection<'_, '_>, opcode: u8, payload: &[u8]) -> Result<()> {
+    let mut header = [0x80 | opcode, 0, 0, 0];
+    let header = match payload.len() {
+        len if len < 126 => {
+            header[1] = len as u8;
+            &header[..2]
+        }
+        len if len <= u16::MAX as usize => {
+            header[1] = 126;
+            header[2..].copy_from_slice(&(len as u16).to_be_bytes());
+            &header[..]
+        }
+        _ => return Err(Error::Index),
+    };
+    conn.write_raw(header).await?;
+    conn.write_raw(payload).await
+}
+
+async fn send_close(conn: &mut Connection<'_, '_>, code: u16) -> Result<()> {
+    send_frame(conn, OP_CLOSE, &code.to_be_bytes()).await
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Stream {
+    Pd,
+    Charger,
+    Artnet,
+    Preview,
+}
+
+impl Stream {
+    const ALL: [Stream; 4] = [Stream::Pd, Stream::Charger, Stream::Artnet, Stream::Preview];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Stream::Pd => "pd",
+            Stream::Charger => "charger",
+            Stream::Artnet => "artnet",
+            Stream::Preview => "preview",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Subscription {
+    interval: Duration,
+    next: Instant,
+}
+
+/// One client's subscriptions and what it has been sent so far.
+struct Session {
+    subscriptions: [Option<Subscription>; Stream::ALL.len()],
+    preview_pixels: usize,
+    /// PD state, contract and PDO count last pushed.
+    pd_seen: Option<(PdState, Option<usize>, usize)>,
+    /// Universe packet counts at the last Art-Net push.
+    artnet_seen: [Option<(u16, u32)>; MAX_UNIVERSES],
+    artnet_time: Instant,
+}
+
+impl Session {
+    fn new() -> Self {
+        Self {
+            subscriptions: [None; Stream::ALL.len()],
+            preview_pixels: DEFAULT_PREVIEW_PIXELS,
+            pd_seen: None,
+            artnet_seen: [None; MAX_UNIVERSES],
+            artnet_time: Instant::now(),
+        }
+    }
+
+    /// Apply `<stream>=<interval ms>` pairs from a query string or text
+    /// message.  An interval of 0 unsubscribes.  `pixels` sets the preview
+    /// width.
+    fn update(&mut self, form: &str, now: Instant) -> Result<()> {
+        let pairs = form
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| pair.split_once('=').unwrap_or((pair, "")));
+        for (key, value) in pairs {
+            if key == "pixels" {
+                self.preview_pixels = value
+                    .parse()
+                    .ok()
+                    .filter(|pixels| (1..=NUM_LEDS).contains(pixels))
+                    .ok_or(Error::Generic("pixels"))?;
+                continue;
+            }
+            let index = Stream::ALL
+                .iter()
+                .position(|stream| stream.name() == key)
+                .ok_or(Error::Generic("Unknown stream"))?;
+            let interval_ms: u32 = value.parse().map_err(|_| Error::Generic("Bad interval"))?;
+            self.subscriptions[index] = (interval_ms > 0).then(|| Subscription {
+                interval: Duration::from_millis(interval_ms.max(MIN_INTERVAL_MS) as u64),
+                next: now,
+            });
+            if Stream::ALL[index] == Stream::Pd {
+                // Always start with the current state.
+                self.pd_seen = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn next_due(&self, now: Instant) -> Instant {
+        self.subscriptions
+            .iter()
+            .flatten()
+            .map(|s| s.next)
+            .min()
+            .unwrap_or(now + IDLE)
+    }
+
+    /// Push every stream that is due.
+    async fn push(&mut self, conn: &mut Connection<'_, '_>, ctx: &Context) -> Result<()> {
+        let now = Instant::now();
+        for (index, stream) in Stream::ALL.iter().enumerate() {
+            let Some(subscription) = &mut self.subscriptions[index] else {
+                continue;
+            };
+            if subscription.next > now {
+                continue;
+            }
+            subscription.next = (subscription.next + subscription.interval).max(now);
+            match stream {
+                Stream::Preview => self.push_preview(conn, ctx).await?,
+                stream => self.push_json(conn, ctx, *stream, now).await?,
+            }
+        }
+        Ok(())
+    }
+
+    async fn push_json(
+        &mut self,
+        conn: &mut Connection<'_, '_>,
+        ctx: &Context,
+        stream: Stream,
+        now: Instant,
+    ) -> Result<()> {
+        if stream == Stream::Pd {
+            let power = ctx.power.lock().await;
+            let seen = Some((power.state, power.contract, power.pdos().len()));
+            if seen == self.pd_seen {
+                return Ok(());
+            }
+            self.pd_seen = seen;
+        }
+        if stream == Stream::Charger && ctx.power.lock().await.charger.is_none() {
+            return Ok(());
+        }
+
+        let mut buffer = [0u8; MAX_PUSH_LEN];
+        let mut json = Json::new(FmtBuffer::new(&mut buffer));
+        json.begin_object()?;
+        json.field("stream", stream.name())?;
+        json.field("time_ms", now.as_millis())?;
+        json.key("data")?;
+        match stream {
+            Stream::Pd => api::write_resource(&mut json, ctx, Resource::Pd).await?,
+            Stream::Charger => api::write_resource(&mut json, ctx, Resource::Charger).await?,
+            _ => self.write_artnet(&mut json, ctx, now).await?,
+        }
+        json.end_object()?;
+        send_frame(conn, OP_TEXT, json.into_inner().as_bytes()).await
+    }
+
+    /// Frame rate of each universe since the last push.
+    async fn write_artnet<W: Write>(
+        &mut self,
+        json: &mut Json<W>,
+        ctx: &Context,
+        now: Instant,
+    ) -> Result<()> {
+        let elapsed_ms = (now - self.artnet_time).as_millis().max(1);
+        let mut seen = [None; MAX_UNIVERSES];
+        json.begin_object()?;
+        json.key("universes")?;
+        json.begin_array()?;
+        let output = ctx.output.lock().await;
+        for (slot, (port_address, packets)) in seen.iter_mut().zip(output.universes.packets()) {
+            let last = self
+                .artnet_seen
+                .iter()
+                .flatten()
+                .find(|(p, _)| *p == port_address)
+                .map_or(packets, |(_, count)| *count);
+            json.begin_object()?;
+            json.field("universe", port_address)?;
+            json.field("packets", packets)?;
+            json.field("fps", packets.wrapping_sub(last) as u64 * 1000 / elapsed_ms)?;
+            json.end_object()?;
+            *slot = Some((port_address, packets));
+        }
+        drop(output);
+        json.end_array()?;
+        json.end_object()?;
+        self.artnet_seen = seen;
+        self.artnet_time = now;
+        Ok(())
+    }
+
+    /// Send the last rendered frame, averaged down to `preview_pixels`, as
+    /// a binary message of RGB triplets.
+    async fn push_preview(&self, conn: &mut Connection<'_, '_>, ctx: &Context) -> Result<()> {
+        let mut data = [0u8; NUM_LEDS * 3];
+        let pixels = self.preview_pixels;
+        {
+            let output = ctx.output.lock().await;
+            let frame = output.frame();
+            for (i, out) in data.chunks_exact_mut(3).take(pixels).enumerate() {
+                let group = &frame[i * NUM_LEDS / pixels..(i + 1) * NUM_LEDS / pixels];
+                let sum = group.iter().fold([0u32; 3], |[r, g, b], c: &Rgb| {
+                    [r + c.r as u32, g + c.g as u32, b + c.b as u32]
+                });
+                for (out, sum) in out.iter_mut().zip(sum) {
+                    *out = (sum / group.len() as u32) as u8;
+                }
+            }
+        }
+        send_frame(conn, OP_BINARY, &data[..pixels * 3]).await
+    }
+}
+
+/// Handle every complete frame the client has sent.  Returns the close
+/// code once the session should end.
+async fn receive_frames(
+    conn: &mut Connection<'_, '_>,
+    session: &mut Session,
+) -> Result<Option<u16>> {
+    while let Some(frame) = Frame::parse(conn.pending()) {
+        // Clients must mask everything they send.
+        if !frame.masked {
+            return Ok(Some(CLOSE_PROTOCOL_ERROR));
+        }
+        if frame.len > MAX_MESSAGE_LEN {
+            return Ok(Some(CLOSE_TOO_BIG));
+        }
+        if conn.pending().len() < frame.total_len() {
+            break;
+        }
+        let mut payload = [0u8; MAX_MESSAGE_LEN];
+        let payload = &mut payload[..frame.len];
+        payload.copy_from_slice(&conn.pending()[frame.header_len..frame.total_len()]);
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= frame.mask[i % 4];
+        }
+        conn.consume(frame.total_len());
+
+        if !frame.fin || frame.opcode == OP_CONTINUATION {
+            return Ok(Some(CLOSE_UNSUPPORTED));
+        }
+        match frame.opcode {
+            OP_TEXT => {
+                let result = core::str::from_utf8(payload)
+                    .map_err(|_| Error::Generic("Message isn't UTF-8"))
+                    .and_then(|form| session.update(form, Instant::now()));
+                if let Err(e) = result {
+                    let mut buffer = [0u8; 128];
+                    let mut json = Json::new(FmtBuffer::new(&mut buffer));
+                    json.begin_object()?;
+                    json.key("error")?;
+                    json.display(&e)?;
+                    json.end_object()?;
+                    send_frame(conn, OP_TEXT, json.into_inner().as_bytes()).await?;
+                }
+            }
+            OP_PING => send_frame(conn, OP_PONG, payload).await?,
+            OP_PONG => (),
+            OP_CLOSE => return Ok(Some(CLOSE_NORMAL)),
+            OP_BINARY => return Ok(Some(CLOSE_UNSUPPORTED)),
+            _ => return Ok(Some(CLOSE_PROTOCOL_ERROR)),
+        }
+    }
+    Ok(None)
+}
+
+/// Upgrade to a WebSocket that pushes the streams the client subscribes
+/// to, either in the query string or later in text messages, as
+/// `pd=1000&charger=1000&artnet=500&preview=100&pixels=30`.  Intervals are
+/// in milliseconds, 0 unsubscribes.
+///
+/// The preview is pushed as binary RGB data, everything else as JSON
+/// objects with `stream`, `time_ms` and `data`.  PD state is only pushed
+/// when it changes.  Only one client is served at a time so that the
+/// other server tasks stay free for plain requests.
+pub async fn handle(conn: &mut Connection<'_, '_>, ctx: &Context, req: &Request<'_>) -> Result<()> {
+    let upgrade = req.header("upgrade").unwrap_or("");
+    if !upgrade.eq_ignore_ascii_case("websocket")
+        || req.header("sec-websocket-version") != Some("13")
+    {
+        return Err(Error::Generic("Not a WebSocket request"));
+    }
+    let key = req
+        .header("sec-websocket-key")
+        .ok_or(Error::Generic("Sec-WebSocket-Key"))?;
+
+    let mut session = Session::new();
+    session.update(req.query, Instant::now())?;
+
+    let Ok(_guard) = ctx.event_stream.try_lock() else {
+        return Err(Error::Http(Status::ServiceUnavailable));
+    };
+
+    let mut buffer = [0u8; 32];
+    let mut accept = FmtBuffer::new(&mut buffer);
+    accept_key(key.trim(), &mut accept)?;
+    let accept = core::str::from_utf8(accept.as_bytes()).unwrap_or("");
+    conn.upgrade("websocket", &[("Sec-WebSocket-Accept", accept)])
+        .await?;
+
+    loop {
+        if let Some(code) = receive_frames(conn, &mut session).await? {
+            println!("websocket closed: {code}");
+            return send_close(conn, code).await;
+        }
+        session.push(conn, ctx).await?;
+
+        let next = session.next_due(Instant::now());
+        let received = match select(conn.receive(), Timer::at(next)).await {
+            Either::First(received) => received?,
+            Either::Second(()) => continue,
+        };
+        if received == 0 {
+            if conn.pending().len() == MAX_HEAD_LEN {
+                return send_close(conn, CLOSE_TOO_BIG).await;
+            }
+            return Ok(());
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use crate::color::Rgb;
+
 const RESET_LEN: usize = 200;
 pub struct Ws2812<'a, const BUF_SIZE: usize> {
     data: &'a mut [u8],
@@ -20,6 +22,10 @@ impl<'a, const BUF_SIZE: usize> Ws2812<'a, BUF_SIZE> {
         Self::set_byte(buf, b);
     }
 
+    pub fn set_pixel(&mut self, index: usize, color: Rgb) {
+        self.set_led(index, color.r, color.g, color.b);
+    }
+
     fn set_byte(buf: &mut [u8], mut data: u8) -> &mut [u8] {
         let mut encoded = 0u32;
         for _ in 0..8 {